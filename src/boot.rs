@@ -0,0 +1,243 @@
+use anyhow::{anyhow, Result};
+use crate::disk::{Disk, Order};
+use crate::fs::fat::Bpb;
+use crate::fs::sectors_per_track;
+
+/// Boot loaders identified by the CRC-32 of their code, taken from the jump target up to the
+/// 0x55AA signature so that the BPB and volume fields do not affect the hash. Only hashes
+/// taken from a sample disk are built in: MS-DOS 3.2 from tests/360k (OEM "MSDOS3.2") and
+/// Windows 95/98 from tests/720k, 1.2M and 1.44M (OEM "MSWIN4.1", all with the same loader).
+/// Other loaders and viruses are added from a fingerprint file (see `parse_fingerprints`).
+/// PC-DOS, DR-DOS and virus hashes are deliberately not shipped: with no sample to take them
+/// from, `report` says so instead (see `FINGERPRINT_SCOPE`).
+const KNOWN_LOADERS: &[(u32, &str)] = &[
+    (0xF956DD42, "MS-DOS 3.2"),
+    (0x424F0392, "MS-DOS 7 (Windows 95/98)"),
+];
+
+/// Printed when the boot code matched no fingerprint, since the built-in table is narrower
+/// than the loaders and viruses it might be expected to know.
+const FINGERPRINT_SCOPE: &str = "Note: built-in boot fingerprints cover only MS-DOS 3.2 and Windows 95/98; \
+    PC-DOS, DR-DOS and boot-sector viruses (Stoned, Brain, Michelangelo) are not fingerprinted unless added with --fingerprints";
+
+/// Text that viruses leave in the boot sectors they replace. This is a heuristic: a match means
+/// the text is present, not that the code around it is the virus.
+const VIRUS_SIGNATURES: &[(&[u8], &str)] = &[
+    (b"Your PC is now Stoned!", "Stoned"),
+    (b"LEGALISE MARIJUANA", "Stoned"),
+    (b"Welcome to the Dungeon", "Brain"),
+    (b"Basit & Amjad", "Brain"),
+];
+
+/// A boot code hash loaded from a fingerprint file.
+#[derive(Debug, Clone)]
+pub struct Fingerprint {
+    pub virus: bool,
+    pub crc: u32,
+    pub name: String,
+}
+
+/// Parses a fingerprint file: one `loader CRC32 name` or `virus CRC32 name` per line, with the
+/// CRC-32 in hex as `display` prints it. Blank lines and `#` comments are ignored.
+pub fn parse_fingerprints(text: &str) -> Result<Vec<Fingerprint>> {
+    let mut fingerprints = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut words = line.splitn(3, char::is_whitespace);
+        let (Some(kind), Some(crc), Some(name)) = (words.next(), words.next(), words.next()) else {
+            return Err(anyhow!("fingerprints line {}: expected 'loader|virus CRC32 name'", number + 1));
+        };
+        let virus = match kind {
+            "loader" => false,
+            "virus" => true,
+            _ => return Err(anyhow!("fingerprints line {}: unknown kind '{}' (use 'loader' or 'virus')", number + 1, kind)),
+        };
+        let crc = u32::from_str_radix(crc.trim_start_matches("0x"), 16)
+            .map_err(|_| anyhow!("fingerprints line {}: invalid CRC-32 '{}'", number + 1, crc))?;
+        fingerprints.push(Fingerprint { virus, crc, name: name.trim().to_string() });
+    }
+    Ok(fingerprints)
+}
+
+/// Standard CRC-32 (IEEE 802.3), as used by zip and WOZ.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Offset of the boot code: the target of the initial short or near jump, or 0 if there is none.
+fn code_start(boot: &[u8]) -> usize {
+    let target = match boot {
+        [0xEB, rel, ..] => 2 + *rel as usize,
+        [0xE9, lo, hi, ..] => (3 + u16::from_le_bytes([*lo, *hi]) as usize) & 0xFFFF,
+        _ => 0,
+    };
+    if target < 0x1FE { target } else { 0 }
+}
+
+/// Names the boot loader from its hash, falling back to the system files it loads. The flag is
+/// true for a hash (fingerprint) match and false for a guess from the file names.
+fn identify_loader(boot: &[u8], oem: &str, hash: u32, extra: &[Fingerprint]) -> Option<(String, bool)> {
+    if let Some((_, name)) = KNOWN_LOADERS.iter().find(|(h, _)| *h == hash) {
+        return Some((name.to_string(), true));
+    }
+    if let Some(fingerprint) = extra.iter().find(|f| !f.virus && f.crc == hash) {
+        return Some((fingerprint.name.clone(), true));
+    }
+    if contains(boot, b"IBMBIO  COM") {
+        let family = if oem.starts_with("IBM") { "PC-DOS" } else if oem.starts_with("DR") { "DR-DOS" } else { "PC-DOS or DR-DOS" };
+        return Some((family.to_string(), false));
+    }
+    if contains(boot, b"IO      SYS") {
+        return Some(("MS-DOS".to_string(), false));
+    }
+    None
+}
+
+fn media_description(media: u8) -> &'static str {
+    match media {
+        0xF0 => "3.5\" 1.44M or 2.88M",
+        0xF8 => "fixed disk",
+        0xF9 => "3.5\" 720K or 5.25\" 1.2M",
+        0xFA => "5.25\" 320K single-sided",
+        0xFB => "3.5\" 640K",
+        0xFC => "5.25\" 180K single-sided",
+        0xFD => "5.25\" 360K double-sided",
+        0xFE => "5.25\" 160K single-sided",
+        0xFF => "5.25\" 320K double-sided",
+        _ => "unknown",
+    }
+}
+
+/// Standard media descriptor for a floppy with this many sectors, where one exists.
+fn expected_media(total_sectors: u32) -> Option<&'static [u8]> {
+    match total_sectors {
+        320 => Some(&[0xFE]),
+        360 => Some(&[0xFC]),
+        640 => Some(&[0xFF]),
+        720 => Some(&[0xFD]),
+        1440 | 2400 => Some(&[0xF9]),
+        2880 | 5760 => Some(&[0xF0]),
+        _ => None,
+    }
+}
+
+/// Boot sector lines for `display`: BPB fields, boot loader identification and any
+/// disagreement between the BPB and the geometry of the image. Empty if the first sector
+/// does not look like a PC boot sector. `extra` adds loader and virus hashes to the built-in ones.
+pub fn report(disk: &Disk, extra: &[Fingerprint]) -> Vec<String> {
+    let sectors = disk.sectors(Order::Logical);
+    let Some(boot) = sectors.first().and_then(|(_, s)| s.data.as_deref()) else {
+        return Vec::new();
+    };
+    let bpb = Bpb::parse(boot);
+    let signature = boot.len() >= 512 && boot[510..512] == [0x55, 0xAA];
+    let mut viruses: Vec<&str> = Vec::new();
+    for (text, name) in VIRUS_SIGNATURES {
+        if contains(boot, text) && !viruses.contains(name) {
+            viruses.push(name);
+        }
+    }
+    let end = boot.len().min(0x1FE);
+    let start = code_start(boot).min(end);
+    let hash = crc32(&boot[start..end]);
+    let virus = extra.iter().find(|f| f.virus && f.crc == hash);
+    if bpb.is_none() && !signature && viruses.is_empty() && virus.is_none() {
+        return Vec::new();
+    }
+
+    let mut lines = Vec::new();
+    let oem = bpb.as_ref().map_or_else(|| String::from_utf8_lossy(boot.get(3..11.min(boot.len())).unwrap_or_default()).to_string(), |b| b.oem.clone());
+    lines.push(format!("Boot Sector: OEM \"{}\", signature {}", oem, if signature { "0x55AA" } else { "missing" }));
+
+    if let Some(bpb) = &bpb {
+        lines.push(format!(
+            "BPB: {} bytes/sector, {} sectors/cluster, {} reserved, {} FATs of {} sectors, {} root entries, {} sectors",
+            bpb.bytes_per_sector, bpb.sectors_per_cluster, bpb.reserved_sectors, bpb.fat_count,
+            bpb.sectors_per_fat, bpb.root_entries, bpb.total_sectors
+        ));
+        lines.push(format!(
+            "BPB Geometry: {} sectors/track, {} heads, {} hidden sectors",
+            bpb.sectors_per_track, bpb.heads, bpb.hidden_sectors
+        ));
+        lines.push(format!("Media Descriptor: 0x{:02X} ({})", bpb.media, media_description(bpb.media)));
+        if let Some(ext) = &bpb.extended {
+            lines.push(format!(
+                "Volume: serial {:04X}-{:04X}, label \"{}\", type \"{}\", drive 0x{:02X}",
+                ext.serial >> 16, ext.serial & 0xFFFF, ext.label, ext.fs_type, ext.drive_number
+            ));
+        }
+    }
+
+    if let Some(virus) = virus {
+        lines.push(format!("Boot Loader: {} virus (CRC-32 {:08X}, fingerprint match)", virus.name, hash));
+        lines.push(format!("Warning: boot sector is the {} virus (CRC-32 match)", virus.name));
+    } else {
+        let loader = identify_loader(boot, &oem, hash, extra);
+        match &loader {
+            Some((name, true)) => lines.push(format!("Boot Loader: {} (CRC-32 {:08X}, fingerprint match)", name, hash)),
+            Some((family, false)) => lines.push(format!(
+                "Boot Loader: {} (heuristic: system file names only; unknown version; CRC-32 {:08X} has no fingerprint)",
+                family, hash
+            )),
+            None => lines.push(format!("Boot Loader: unknown (CRC-32 {:08X} has no fingerprint)", hash)),
+        }
+        if !matches!(loader, Some((_, true))) {
+            lines.push(FINGERPRINT_SCOPE.to_string());
+        }
+    }
+    for virus in viruses {
+        lines.push(format!("Warning: boot sector contains text from the {} virus (heuristic text match)", virus));
+    }
+
+    if let Some(bpb) = &bpb {
+        lines.extend(geometry_warnings(disk, bpb).into_iter().map(|w| format!("Warning: {}", w)));
+    }
+    lines
+}
+
+/// Differences between what the BPB claims and what the image actually holds.
+fn geometry_warnings(disk: &Disk, bpb: &Bpb) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut heads: Vec<u8> = disk.tracks.iter().map(|t| t.head).collect();
+    heads.sort_unstable();
+    heads.dedup();
+    let spt = sectors_per_track(disk);
+    let total: usize = disk.tracks.iter().map(|t| t.sectors.len()).sum();
+    let sector_size = disk.tracks.iter().flat_map(|t| t.sectors.first()).map(|s| s.size).next().unwrap_or(0);
+
+    if bpb.bytes_per_sector as usize != sector_size {
+        warnings.push(format!("BPB says {} bytes/sector but the image has {}", bpb.bytes_per_sector, sector_size));
+    }
+    if bpb.sectors_per_track as usize != spt {
+        warnings.push(format!("BPB says {} sectors/track but the image has {}", bpb.sectors_per_track, spt));
+    }
+    if bpb.heads as usize != heads.len() {
+        warnings.push(format!("BPB says {} heads but the image has {}", bpb.heads, heads.len()));
+    }
+    if bpb.total_sectors as usize != total {
+        warnings.push(format!("BPB says {} sectors but the image has {}", bpb.total_sectors, total));
+    }
+    if let Some(expected) = expected_media(bpb.total_sectors) {
+        if !expected.contains(&bpb.media) {
+            warnings.push(format!(
+                "media descriptor 0x{:02X} is unusual for a {}-sector disk (expected 0x{:02X})",
+                bpb.media, bpb.total_sectors, expected[0]
+            ));
+        }
+    }
+    warnings
+}
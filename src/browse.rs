@@ -0,0 +1,408 @@
+use crate::FormatHandler;
+use crate::disk::{Disk, Sector};
+use anyhow::Result;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use std::io::{Stdout, Write, stdout};
+use std::path::PathBuf;
+
+const MAP_LABEL_WIDTH: u16 = 9; // "C00 H0  " plus a space
+const HEX_WIDTH: u16 = 78;
+
+enum Prompt {
+    Jump,
+    Search,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Normal,
+    Compressed,
+    Deleted,
+    Error,
+    Missing,
+}
+
+impl Status {
+    fn of(sector: &Sector) -> Status {
+        if sector.data.is_none() {
+            Status::Missing
+        } else if sector.crc_error {
+            Status::Error
+        } else if sector.deleted {
+            Status::Deleted
+        } else if sector.compressed {
+            Status::Compressed
+        } else {
+            Status::Normal
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Status::Normal => Color::Green,
+            Status::Compressed => Color::Cyan,
+            Status::Deleted => Color::Yellow,
+            Status::Error => Color::Red,
+            Status::Missing => Color::DarkGrey,
+        }
+    }
+}
+
+/// Full-screen sector browser. When `save` is given, sectors can be edited and written back
+/// to that path using the handler's encoder.
+pub struct Browser<'a> {
+    disk: Disk,
+    order: Vec<usize>, // Track indices in cylinder/head order
+    track: usize,      // Index into `order`
+    sector: usize,     // Physical position on the track
+    map_scroll: usize,
+    hex_scroll: usize,
+    cursor: usize, // Byte offset of the edit cursor
+    editing: bool,
+    high_nibble: Option<u8>,
+    dirty: bool,
+    quit_armed: bool,
+    last_search: Option<Vec<u8>>,
+    prompt: Option<(Prompt, String)>,
+    message: String,
+    save: Option<(PathBuf, &'a dyn FormatHandler)>,
+}
+
+impl<'a> Browser<'a> {
+    pub fn new(disk: Disk, save: Option<(PathBuf, &'a dyn FormatHandler)>) -> Self {
+        let mut order: Vec<usize> = (0..disk.tracks.len()).collect();
+        order.sort_by_key(|&t| (disk.tracks[t].cylinder, disk.tracks[t].head));
+        Browser {
+            disk,
+            order,
+            track: 0,
+            sector: 0,
+            map_scroll: 0,
+            hex_scroll: 0,
+            cursor: 0,
+            editing: false,
+            high_nibble: None,
+            dirty: false,
+            quit_armed: false,
+            last_search: None,
+            prompt: None,
+            message: "Arrows move, g jump, / search, n next, e edit, s save, q quit".to_string(),
+            save,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let mut out = stdout();
+        terminal::enable_raw_mode()?;
+        execute!(out, EnterAlternateScreen, Hide)?;
+        let result = self.event_loop(&mut out);
+        execute!(out, Show, LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+        result
+    }
+
+    fn event_loop(&mut self, out: &mut Stdout) -> Result<()> {
+        loop {
+            self.draw(out)?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Release && !self.handle_key(key)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn current(&self) -> Option<&Sector> {
+        let track = &self.disk.tracks[*self.order.get(self.track)?];
+        track.sectors.get(self.sector)
+    }
+
+    fn track_len(&self) -> usize {
+        self.order.get(self.track).map_or(0, |&t| self.disk.tracks[t].sectors.len())
+    }
+
+    fn select(&mut self, track: usize, sector: usize) {
+        self.track = track;
+        self.sector = sector.min(self.track_len().saturating_sub(1));
+        self.hex_scroll = 0;
+        self.cursor = 0;
+        self.high_nibble = None;
+    }
+
+    /// Returns false when the browser should exit.
+    fn handle_key(&mut self, key: KeyEvent) -> Result<bool> {
+        if let Some((kind, mut text)) = self.prompt.take() {
+            match key.code {
+                KeyCode::Enter => self.submit_prompt(kind, &text),
+                KeyCode::Esc => self.message.clear(),
+                KeyCode::Backspace => {
+                    text.pop();
+                    self.prompt = Some((kind, text));
+                }
+                KeyCode::Char(c) => {
+                    text.push(c);
+                    self.prompt = Some((kind, text));
+                }
+                _ => self.prompt = Some((kind, text)),
+            }
+            return Ok(true);
+        }
+
+        let quit_armed = std::mem::take(&mut self.quit_armed);
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.editing = false;
+            return Ok(!self.may_quit(quit_armed));
+        }
+        if self.editing {
+            self.handle_edit_key(key);
+            return Ok(true);
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc if self.may_quit(quit_armed) => return Ok(false),
+            KeyCode::Up | KeyCode::Char('k') => self.select(self.track.saturating_sub(1), self.sector),
+            KeyCode::Down | KeyCode::Char('j') => self.select((self.track + 1).min(self.order.len().saturating_sub(1)), self.sector),
+            KeyCode::Left | KeyCode::Char('h') => self.select(self.track, self.sector.saturating_sub(1)),
+            KeyCode::Right | KeyCode::Char('l') => self.select(self.track, self.sector + 1),
+            KeyCode::PageDown => self.hex_scroll += 8,
+            KeyCode::PageUp => self.hex_scroll = self.hex_scroll.saturating_sub(8),
+            KeyCode::Char('g') => self.prompt = Some((Prompt::Jump, String::new())),
+            KeyCode::Char('/') => self.prompt = Some((Prompt::Search, String::new())),
+            KeyCode::Char('n') => match self.last_search.clone() {
+                Some(pattern) => self.search(&pattern),
+                None => self.message = "No previous search".to_string(),
+            },
+            KeyCode::Char('e') => {
+                if self.save.is_none() {
+                    self.message = "Read-only: start browse with --edit to modify sectors".to_string();
+                } else if self.current().is_some() {
+                    self.editing = true;
+                    self.message = "Editing: type hex digits, arrows move, Esc to stop".to_string();
+                }
+            }
+            KeyCode::Char('s') => self.save_disk(),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// True if the browser may exit: nothing is unsaved, or the quit was already asked for
+    /// once (`armed`). Otherwise warns and arms the next q or Ctrl-C to discard the changes.
+    fn may_quit(&mut self, armed: bool) -> bool {
+        if self.dirty && !armed {
+            self.message = "Unsaved changes: press s to save, or q or Ctrl-C again to discard".to_string();
+            self.quit_armed = true;
+            return false;
+        }
+        true
+    }
+
+    fn handle_edit_key(&mut self, key: KeyEvent) {
+        let size = self.current().map_or(0, |s| s.size);
+        match key.code {
+            KeyCode::Esc => {
+                self.editing = false;
+                self.high_nibble = None;
+                self.message.clear();
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(size.saturating_sub(1)),
+            KeyCode::Up => self.cursor = self.cursor.saturating_sub(16),
+            KeyCode::Down => self.cursor = (self.cursor + 16).min(size.saturating_sub(1)),
+            KeyCode::Char(c) if c.is_ascii_hexdigit() => {
+                let nibble = c.to_digit(16).unwrap_or(0) as u8;
+                match self.high_nibble.take() {
+                    None => self.high_nibble = Some(nibble),
+                    Some(high) => {
+                        let (track, position, cursor) = (self.order[self.track], self.sector, self.cursor);
+                        let sector = &mut self.disk.tracks[track].sectors[position];
+                        let mut data = sector.data.clone().unwrap_or_default();
+                        data.resize(data.len().max(sector.size), 0);
+                        data[cursor] = (high << 4) | nibble;
+                        if let Err(e) = sector.patch(&data) {
+                            self.message = e.to_string();
+                            return;
+                        }
+                        self.dirty = true;
+                        self.cursor = (cursor + 1).min(size.saturating_sub(1));
+                    }
+                }
+            }
+            _ => {}
+        }
+        // Keep the cursor line visible
+        let line = self.cursor / 16;
+        if line < self.hex_scroll {
+            self.hex_scroll = line;
+        }
+    }
+
+    fn submit_prompt(&mut self, kind: Prompt, text: &str) {
+        match kind {
+            Prompt::Jump => match crate::parse_chs(text) {
+                Ok((c, h, s)) => {
+                    let found = self.order.iter().position(|&t| self.disk.tracks[t].cylinder == c && self.disk.tracks[t].head == h);
+                    match found {
+                        Some(track) => match self.disk.tracks[self.order[track]].sectors.iter().position(|sec| sec.id == s) {
+                            Some(position) => {
+                                self.select(track, position);
+                                self.message = format!("Cyl {}, Head {}, Sector {}", c, h, s);
+                            }
+                            None => self.message = format!("Sector ID {} not found on Cyl {}, Head {}", s, c, h),
+                        },
+                        None => self.message = format!("Track Cyl {}, Head {} is not present in the image", c, h),
+                    }
+                }
+                Err(e) => self.message = e,
+            },
+            Prompt::Search => {
+                // "hex:eb3c90" searches bytes; anything else is literal text
+                let pattern = match text.strip_prefix("hex:") {
+                    Some(hex) => match crate::parse_hex(hex) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            self.message = e.to_string();
+                            return;
+                        }
+                    },
+                    None => text.as_bytes().to_vec(),
+                };
+                if pattern.is_empty() {
+                    return;
+                }
+                self.last_search = Some(pattern.clone());
+                self.search(&pattern);
+            }
+        }
+    }
+
+    /// Finds the next sector (after the selected one, wrapping) whose data contains `pattern`.
+    fn search(&mut self, pattern: &[u8]) {
+        let positions: Vec<(usize, usize)> = self.order.iter().enumerate()
+            .flat_map(|(i, &t)| (0..self.disk.tracks[t].sectors.len()).map(move |s| (i, s)))
+            .collect();
+        let current = positions.iter().position(|&p| p == (self.track, self.sector)).unwrap_or(0);
+        for step in 1..=positions.len() {
+            let (track, position) = positions[(current + step) % positions.len()];
+            let data = self.disk.tracks[self.order[track]].sectors[position].data.as_deref().unwrap_or(&[]);
+            if let Some(offset) = data.windows(pattern.len()).position(|w| w == pattern) {
+                self.select(track, position);
+                self.cursor = offset;
+                self.hex_scroll = offset / 16;
+                let sector = self.current().map_or(0, |s| s.id);
+                let track = &self.disk.tracks[self.order[track]];
+                self.message = format!("Found at Cyl {}, Head {}, Sector {}, offset {}", track.cylinder, track.head, sector, offset);
+                return;
+            }
+        }
+        self.message = "Pattern not found".to_string();
+    }
+
+    fn save_disk(&mut self) {
+        let Some((path, handler)) = &self.save else {
+            self.message = "Read-only: start browse with --edit to save changes".to_string();
+            return;
+        };
+        self.message = match handler.encode(&self.disk).and_then(|bytes| Ok(std::fs::write(path, bytes)?)) {
+            Ok(()) => {
+                self.dirty = false;
+                format!("Saved {}", path.display())
+            }
+            Err(e) => format!("Save failed: {}", e),
+        };
+    }
+
+    fn draw(&mut self, out: &mut Stdout) -> Result<()> {
+        let (width, height) = terminal::size()?;
+        let body_rows = height.saturating_sub(3) as usize;
+        queue!(out, Clear(ClearType::All))?;
+
+        // Track/sector map
+        if self.track < self.map_scroll {
+            self.map_scroll = self.track;
+        } else if body_rows > 0 && self.track >= self.map_scroll + body_rows {
+            self.map_scroll = self.track + 1 - body_rows;
+        }
+        for (row, &t) in self.order.iter().enumerate().skip(self.map_scroll).take(body_rows) {
+            let track = &self.disk.tracks[t];
+            let y = (row - self.map_scroll) as u16;
+            queue!(out, MoveTo(0, y), ResetColor, Print(format!("C{:02} H{} ", track.cylinder, track.head)))?;
+            for (position, sector) in track.sectors.iter().enumerate() {
+                let selected = row == self.track && position == self.sector;
+                queue!(out, MoveTo(MAP_LABEL_WIDTH + position as u16, y), SetForegroundColor(Status::of(sector).color()))?;
+                if selected {
+                    queue!(out, SetAttribute(Attribute::Reverse), Print("#"), SetAttribute(Attribute::NoReverse))?;
+                } else {
+                    queue!(out, Print("■"))?;
+                }
+            }
+        }
+        queue!(out, ResetColor)?;
+
+        // Hex/ASCII pane
+        let longest = self.order.iter().map(|&t| self.disk.tracks[t].sectors.len()).max().unwrap_or(0) as u16;
+        let pane_x = (MAP_LABEL_WIDTH + longest + 2).min(width.saturating_sub(HEX_WIDTH));
+        if let (Some(sector), Some(&t)) = (self.current(), self.order.get(self.track)) {
+            let track = &self.disk.tracks[t];
+            let mut flags = Vec::new();
+            if sector.compressed { flags.push("compressed"); }
+            if sector.deleted { flags.push("deleted"); }
+            if sector.crc_error { flags.push("CRC error"); }
+            if sector.data.is_none() { flags.push("no data"); }
+            queue!(out, MoveTo(pane_x, 0), Print(format!(
+                "Cyl {}, Head {}, Sector {} (physical {}), {} bytes, mode {}{}{}",
+                track.cylinder, track.head, sector.id, self.sector, sector.size, track.mode,
+                if flags.is_empty() { "" } else { ", " }, flags.join(", ")
+            )))?;
+            let data = sector.data.clone().unwrap_or_default();
+            let lines = data.len().div_ceil(16);
+            let visible = body_rows.saturating_sub(1);
+            if self.editing && self.cursor / 16 >= self.hex_scroll + visible {
+                self.hex_scroll = self.cursor / 16 + 1 - visible;
+            }
+            self.hex_scroll = self.hex_scroll.min(lines.saturating_sub(visible));
+            for (row, line) in (self.hex_scroll..lines).take(visible).enumerate() {
+                queue!(out, MoveTo(pane_x, row as u16 + 1), Print(format!("{:04x}  ", line * 16)))?;
+                let chunk = &data[line * 16..(line * 16 + 16).min(data.len())];
+                for (i, b) in chunk.iter().enumerate() {
+                    let at_cursor = self.editing && line * 16 + i == self.cursor;
+                    if at_cursor { queue!(out, SetAttribute(Attribute::Reverse))?; }
+                    queue!(out, Print(format!("{:02x}", b)))?;
+                    if at_cursor { queue!(out, SetAttribute(Attribute::NoReverse))?; }
+                    queue!(out, Print(if i == 7 { "  " } else { " " }))?;
+                }
+                let ascii: String = chunk.iter().map(|&b| if (32..=126).contains(&b) { b as char } else { '.' }).collect();
+                queue!(out, MoveTo(pane_x + 56, row as u16 + 1), Print(format!("|{}|", ascii)))?;
+            }
+        }
+
+        // Legend, status and prompt
+        let legend_y = height.saturating_sub(2);
+        queue!(out, MoveTo(0, legend_y))?;
+        for status in [Status::Normal, Status::Compressed, Status::Deleted, Status::Error, Status::Missing] {
+            let name = match status {
+                Status::Normal => "normal",
+                Status::Compressed => "compressed",
+                Status::Deleted => "deleted",
+                Status::Error => "error",
+                Status::Missing => "missing",
+            };
+            queue!(out, SetForegroundColor(status.color()), Print("■ "), ResetColor, Print(format!("{}  ", name)))?;
+        }
+        if self.dirty {
+            queue!(out, SetForegroundColor(Color::Yellow), Print("[modified]"), ResetColor)?;
+        }
+        let status_line = match &self.prompt {
+            Some((Prompt::Jump, text)) => format!("Jump to C/H/S: {}", text),
+            Some((Prompt::Search, text)) => format!("Search (text, or hex:eb3c90): {}", text),
+            None => self.message.clone(),
+        };
+        queue!(out, MoveTo(0, height.saturating_sub(1)), Print(status_line))?;
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::{Result, anyhow};
+
+/// A single sector as recorded in an image, in the order it appears on the track.
+#[derive(Debug, Clone, Default)]
+pub struct Sector {
+    pub id: u8,              // Logical sector ID from the ID field
+    pub cylinder: u8,        // Cylinder from the ID field (may differ from the physical track)
+    pub head: u8,            // Head from the ID field (may differ from the physical side)
+    pub size: usize,         // Sector size in bytes
+    pub data: Option<Vec<u8>>, // None when the image records no data for this sector
+    pub deleted: bool,       // Deleted data address mark
+    pub crc_error: bool,     // Data was read with a CRC error
+    pub compressed: bool,    // Stored as a single fill byte in the source image
+    pub status: Option<(u8, u8)>, // FDC ST1/ST2 recorded when the image was made (CPC DSK)
+    pub copies: Vec<Vec<u8>>, // Further reads of a weak sector, after `data`
+}
+
+impl Sector {
+    pub fn is_uniform(&self) -> bool {
+        match &self.data {
+            Some(data) => !data.is_empty() && data.iter().all(|&b| b == data[0]),
+            None => false,
+        }
+    }
+
+    /// Overwrites the start of the sector with `bytes` and recomputes whether it can be
+    /// stored compressed. A sector without data is created zero-filled first, data recorded
+    /// shorter than the sector size is zero-padded to it, and a weak sector keeps only the
+    /// patched copy. Fails, leaving the sector unchanged, if `bytes` runs past the sector.
+    pub fn patch(&mut self, bytes: &[u8]) -> Result<()> {
+        let size = self.size.max(self.data.as_ref().map_or(0, Vec::len));
+        if bytes.len() > size {
+            return Err(anyhow!("Sector {} holds {} bytes; cannot write {}", self.id, size, bytes.len()));
+        }
+        let data = self.data.get_or_insert_with(Vec::new);
+        data.resize(size, 0);
+        data[..bytes.len()].copy_from_slice(bytes);
+        self.compressed = self.is_uniform();
+        self.copies.clear();
+        Ok(())
+    }
+}
+
+/// A physical track: its recording mode and sectors in physical (rotational) order.
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    pub mode: u8, // IMD mode byte (0-2 FM, 3-5 MFM at 500/300/250 kbps)
+    pub cylinder: u8,
+    pub head: u8,
+    pub sectors: Vec<Sector>,
+    pub cylinder_map: bool, // Source carried an explicit cylinder map
+    pub head_map: bool,     // Source carried an explicit head map
+}
+
+impl Track {
+    /// Position (0-based) of the sector with the lowest ID.
+    fn first_position(&self) -> Option<usize> {
+        self.sectors.iter().enumerate().min_by_key(|(_, s)| s.id).map(|(i, _)| i)
+    }
+
+    /// Most common physical distance between logically consecutive sector IDs.
+    pub fn interleave(&self) -> Option<u8> {
+        let n = self.sectors.len();
+        if n < 2 {
+            return None;
+        }
+        let mut order: Vec<(u8, usize)> = self.sectors.iter().enumerate().map(|(i, s)| (s.id, i)).collect();
+        order.sort();
+        let distances = order.windows(2).map(|w| (w[1].1 + n - w[0].1) % n);
+        most_common(distances).map(|d| d as u8)
+    }
+
+    /// Reorders the sectors so that logically consecutive IDs are `interleave` slots apart,
+    /// with the lowest ID placed at physical position `start`.
+    pub fn reinterleave(&mut self, interleave: u8, start: usize) {
+        let n = self.sectors.len();
+        if n == 0 {
+            return;
+        }
+        let mut sorted = std::mem::take(&mut self.sectors);
+        sorted.sort_by_key(|s| s.id);
+        let mut slots: Vec<Option<Sector>> = vec![None; n];
+        let mut pos = start % n;
+        for sector in sorted {
+            while slots[pos].is_some() {
+                pos = (pos + 1) % n;
+            }
+            slots[pos] = Some(sector);
+            pos = (pos + interleave as usize) % n;
+        }
+        self.sectors = slots.into_iter().flatten().collect();
+    }
+
+    /// The copy of sector `id` to keep when the ID is repeated: the first read without a CRC
+    /// error, else the first with data, else the first.
+    fn pick(&self, id: u8) -> Option<&Sector> {
+        let copies: Vec<&Sector> = self.sectors.iter().filter(|s| s.id == id).collect();
+        copies.iter().find(|s| s.data.is_some() && !s.crc_error)
+            .or_else(|| copies.iter().find(|s| s.data.is_some()))
+            .or(copies.first())
+            .copied()
+    }
+
+    /// The track's sector data laid out for a raw image, with a note for every sector that is
+    /// repeated, missing, out of place or of another size. `layout` is the disk's `Layout`;
+    /// every track fills at least its slots, so later tracks stay aligned.
+    pub fn raw(&self, placement: Placement, layout: Layout) -> (Vec<u8>, Vec<String>) {
+        let mut notes = Vec::new();
+        let mut ids: Vec<u8> = self.sectors.iter().map(|s| s.id).collect();
+        ids.sort();
+        ids.dedup();
+        for &id in &ids {
+            let count = self.sectors.iter().filter(|s| s.id == id).count();
+            if count > 1 {
+                notes.push(match placement {
+                    Placement::Physical => format!("sector ID {} appears {} times; all copies kept in recorded order", id, count),
+                    _ => format!("sector ID {} appears {} times; kept one copy, preferring a good read", id, count),
+                });
+            }
+        }
+        let chosen: Vec<Option<&Sector>> = match placement {
+            Placement::Physical => {
+                let mut chosen: Vec<Option<&Sector>> = self.sectors.iter().map(Some).collect();
+                if chosen.len() < layout.slots {
+                    notes.push(format!("{} sectors where the disk uses {}; zero-filled at the end", chosen.len(), layout.slots));
+                    chosen.resize(layout.slots, None);
+                }
+                chosen
+            }
+            Placement::ById | Placement::Base(_) => {
+                let first = match placement { Placement::Base(base) => base, _ => layout.first };
+                let range = first as usize..first as usize + layout.slots;
+                let missing: Vec<String> = range.clone().filter(|&id| id > 255 || !ids.contains(&(id as u8))).map(|id| id.to_string()).collect();
+                match missing.len() {
+                    0 => {}
+                    1 => notes.push(format!("ID {} missing, zero-filled", missing[0])),
+                    _ => notes.push(format!("IDs {} missing, zero-filled", missing.join(", "))),
+                }
+                let mut chosen: Vec<Option<&Sector>> = range.clone().map(|id| if id > 255 { None } else { self.pick(id as u8) }).collect();
+                // Sectors that fit no slot go after the slots rather than being lost
+                let outside: Vec<u8> = ids.iter().copied().filter(|&id| !range.contains(&(id as usize))).collect();
+                if !outside.is_empty() {
+                    let list: Vec<String> = outside.iter().map(|id| id.to_string()).collect();
+                    notes.push(format!("sector IDs outside {}-{} placed after them: {}", range.start, range.end.saturating_sub(1), list.join(", ")));
+                    chosen.extend(outside.iter().map(|&id| self.pick(id)));
+                }
+                chosen
+            }
+        };
+
+        let mut raw = Vec::new();
+        for sector in chosen {
+            match sector {
+                Some(sector) => {
+                    if sector.size != layout.size {
+                        notes.push(format!("sector {} is {} bytes where the disk uses {}", sector.id, sector.size, layout.size));
+                    }
+                    match &sector.data {
+                        Some(data) => raw.extend_from_slice(data),
+                        None => {
+                            notes.push(format!("sector {} has no data (zero-filled)", sector.id));
+                            raw.resize(raw.len() + sector.size, 0);
+                        }
+                    }
+                }
+                None => raw.resize(raw.len() + layout.size, 0),
+            }
+        }
+        (raw, notes)
+    }
+}
+
+/// How sectors are walked: by logical ID within each track, or in recorded (physical) order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Logical,
+    Physical,
+}
+
+/// Where each sector of a track goes when the disk is flattened into a raw image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement {
+    ById,     // Sorted by sector ID, one slot per distinct ID
+    Physical, // In recorded order, repeated IDs included
+    Base(u8), // Slot = ID - base, for the disk's usual sector count; gaps are zero-filled
+}
+
+/// The regular shape a raw image gives every track.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub first: u8,    // Lowest sector ID on most tracks
+    pub slots: usize, // Most distinct sector IDs on any track
+    pub size: usize,  // Most common sector size
+}
+
+/// A decoded disk: the image comment/header followed by its tracks in file order.
+#[derive(Debug, Clone, Default)]
+pub struct Disk {
+    pub comment: Vec<u8>,
+    pub tracks: Vec<Track>,
+}
+
+impl Disk {
+    /// Slots per track come from the fullest track, so a track missing a sector is padded
+    /// rather than every later track shifting.
+    pub fn layout(&self) -> Layout {
+        let tracks = self.tracks.iter().filter(|t| !t.sectors.is_empty());
+        let distinct = |t: &Track| {
+            let mut ids: Vec<u8> = t.sectors.iter().map(|s| s.id).collect();
+            ids.sort();
+            ids.dedup();
+            ids.len()
+        };
+        Layout {
+            first: most_common(tracks.clone().filter_map(|t| t.sectors.iter().map(|s| s.id as usize).min())).unwrap_or(1) as u8,
+            slots: tracks.clone().map(distinct).max().unwrap_or(0),
+            size: most_common(tracks.flat_map(|t| t.sectors.iter().map(|s| s.size))).unwrap_or(512),
+        }
+    }
+
+    /// Most common interleave across all tracks, with the number of tracks that differ from it.
+    pub fn interleave(&self) -> Option<(u8, usize)> {
+        let per_track: Vec<u8> = self.tracks.iter().filter_map(|t| t.interleave()).collect();
+        let common = most_common(per_track.iter().map(|&i| i as usize))? as u8;
+        Some((common, per_track.iter().filter(|&&i| i != common).count()))
+    }
+
+    /// (track index, sector index) of every sector, tracks in cylinder/head order and
+    /// sectors in the given order.
+    pub fn sector_positions(&self, order: Order) -> Vec<(usize, usize)> {
+        let mut tracks: Vec<usize> = (0..self.tracks.len()).collect();
+        tracks.sort_by_key(|&t| (self.tracks[t].cylinder, self.tracks[t].head));
+        let mut result = Vec::new();
+        for t in tracks {
+            let mut sectors: Vec<usize> = (0..self.tracks[t].sectors.len()).collect();
+            if order == Order::Logical {
+                sectors.sort_by_key(|&s| self.tracks[t].sectors[s].id);
+            }
+            result.extend(sectors.into_iter().map(|s| (t, s)));
+        }
+        result
+    }
+
+    /// All sectors with their tracks, in the same order as `sector_positions`.
+    pub fn sectors(&self, order: Order) -> Vec<(&Track, &Sector)> {
+        self.sector_positions(order).into_iter()
+            .map(|(t, s)| (&self.tracks[t], &self.tracks[t].sectors[s]))
+            .collect()
+    }
+
+    /// Index into `sectors(order)` of cylinder/head/sector, where the sector is the ID
+    /// (logical) or the 0-based position on the track (physical).
+    pub fn find_sector(&self, cylinder: u8, head: u8, sector: u8, order: Order) -> Result<usize> {
+        let sectors = self.sectors(order);
+        let track_start = sectors.iter().position(|(t, _)| t.cylinder == cylinder && t.head == head)
+            .ok_or_else(|| anyhow!("Track Cyl {}, Head {} is not present in the image", cylinder, head))?;
+        let mut track = sectors[track_start..].iter().take_while(|(t, _)| t.cylinder == cylinder && t.head == head);
+        let found = match order {
+            Order::Logical => track.position(|(_, s)| s.id == sector),
+            Order::Physical => ((sector as usize) < track.count()).then_some(sector as usize),
+        };
+        found.map(|i| track_start + i).ok_or_else(|| match order {
+            Order::Logical => anyhow!("Sector ID {} not found on Cyl {}, Head {}", sector, cylinder, head),
+            Order::Physical => anyhow!("Physical position {} not found on Cyl {}, Head {} (positions are 0-based)", sector, cylinder, head),
+        })
+    }
+
+    /// Most common track-to-track skew, measured as the shift of the lowest sector ID from
+    /// each track to the same head on the next cylinder, where both have the same sector count.
+    pub fn skew(&self) -> Option<u8> {
+        let shifts = self.tracks.iter().filter_map(|track| {
+            let cylinder = track.cylinder.checked_add(1)?;
+            let next = self.tracks.iter().find(|t| t.cylinder == cylinder && t.head == track.head)?;
+            let n = track.sectors.len();
+            if n < 2 || next.sectors.len() != n {
+                return None;
+            }
+            Some((next.first_position()? + n - track.first_position()?) % n)
+        });
+        most_common(shifts).map(|s| s as u8)
+    }
+
+    /// Rewrites the sector order of every track using the given interleave and skew. The skew
+    /// is per cylinder: both heads of a cylinder start at the same position, shifted `skew`
+    /// sectors from the previous cylinder, whatever order the tracks are stored in.
+    pub fn reinterleave(&mut self, interleave: u8, skew: u8) -> Result<()> {
+        if interleave == 0 {
+            return Err(anyhow!("Invalid interleave 0. Use 1 for sequential sectors, 2 for 2:1, etc."));
+        }
+        for track in self.tracks.iter_mut() {
+            let start = track.cylinder as usize * skew as usize;
+            track.reinterleave(interleave, start);
+        }
+        Ok(())
+    }
+}
+
+pub fn most_common(values: impl Iterator<Item = usize>) -> Option<usize> {
+    let mut counts: Vec<(usize, usize)> = Vec::new();
+    for value in values {
+        match counts.iter_mut().find(|(v, _)| *v == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1)),
+        }
+    }
+    // Earliest value wins ties so the result is stable
+    counts.iter().rev().max_by_key(|(_, count)| *count).map(|(v, _)| *v)
+}
+
+/// Canonical hex+ASCII dump (16 bytes per line), like `hexdump -C`.
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut lines = Vec::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for j in 0..16 {
+            match chunk.get(j) {
+                Some(b) => hex.push_str(&format!("{:02x} ", b)),
+                None => hex.push_str("   "),
+            }
+            if j == 7 {
+                hex.push(' ');
+            }
+        }
+        let ascii: String = chunk.iter().map(|&b| if (32..=126).contains(&b) { b as char } else { '.' }).collect();
+        lines.push(format!("{:08x}  {} |{}|", i * 16, hex, ascii));
+    }
+    lines.join("\n")
+}
@@ -0,0 +1,133 @@
+use crate::disk::{Disk, Track};
+use crate::flux::{DecodedSector, DecodedTrack};
+
+const SYNC: u16 = 0x4489;
+const DATA_BITS: u32 = 0x5555_5555;
+const SECTOR_LONGS: usize = 270; // Raw MFM longwords after the sync words
+
+fn raw_long(bits: &[u8], at: usize) -> u32 {
+    bits[at..at + 32].iter().fold(0, |value, &bit| (value << 1) | bit as u32)
+}
+
+/// Joins the odd-bit and even-bit MFM longwords of one data longword.
+fn join(odd: u32, even: u32) -> u32 {
+    ((odd & DATA_BITS) << 1) | (even & DATA_BITS)
+}
+
+fn checksum(raw: &[u32]) -> u32 {
+    raw.iter().fold(0, |sum, &l| sum ^ l) & DATA_BITS
+}
+
+/// Splits data longwords into their odd-bit half followed by their even-bit half.
+fn split(longs: &[u32]) -> Vec<u32> {
+    let odd = longs.iter().map(|&l| (l >> 1) & DATA_BITS);
+    let even = longs.iter().map(|&l| l & DATA_BITS);
+    odd.chain(even).collect()
+}
+
+/// Whether the disk has the Amiga trackdisk layout: every track holds 11 (DD) or 22 (HD)
+/// 512-byte sectors numbered from 0, which no IBM format uses.
+pub fn is_amiga(disk: &Disk) -> bool {
+    !disk.tracks.is_empty() && disk.tracks.iter().all(|track| {
+        let n = track.sectors.len();
+        let mut ids: Vec<u8> = track.sectors.iter().map(|s| s.id).collect();
+        ids.sort_unstable();
+        (n == 11 || n == 22) && track.sectors.iter().all(|s| s.size == 512) && ids.iter().enumerate().all(|(i, &id)| id as usize == i)
+    })
+}
+
+/// Writes cells (one byte per cell) for a raw MFM word, or for data bits with the clock
+/// bits filled in, tracking the last data bit across calls.
+struct CellWriter {
+    cells: Vec<u8>,
+    last: u8,
+}
+
+impl CellWriter {
+    fn raw(&mut self, word: u16) {
+        self.cells.extend((0..16).rev().map(|i| (word >> i) as u8 & 1));
+        self.last = word as u8 & 1;
+    }
+
+    /// A longword whose data bits (0x55555555) are set; the clock bits are computed.
+    fn long(&mut self, data: u32) {
+        for k in (0..16).rev() {
+            let bit = (data >> (2 * k)) as u8 & 1;
+            self.cells.push((self.last | bit) ^ 1);
+            self.cells.push(bit);
+            self.last = bit;
+        }
+    }
+}
+
+/// Encodes a track in the Amiga trackdisk layout as `cells` cells: the sectors in the track's
+/// physical order, each with its sync words, header, label, checksums and odd/even data,
+/// then a gap to the end of the track. A sector flagged with a CRC error gets a bad data
+/// checksum so the error survives.
+pub fn encode_track(track: &Track, cells: usize) -> Vec<u8> {
+    let mut writer = CellWriter { cells: Vec::with_capacity(cells), last: 0 };
+    let count = track.sectors.len();
+    let number = track.cylinder.wrapping_mul(2).wrapping_add(track.head) as u32;
+    for (position, sector) in track.sectors.iter().enumerate() {
+        writer.long(0); // Two zero bytes before the sync words
+        writer.raw(SYNC);
+        writer.raw(SYNC);
+        let info = 0xFF00_0000 | number << 16 | (sector.id as u32) << 8 | (count - position) as u32;
+        let mut header = split(&[info]);
+        header.extend(split(&[0; 4])); // Sector label, unused by AmigaDOS
+        let mut data = sector.data.clone().unwrap_or_default();
+        data.resize(512, 0);
+        let longs: Vec<u32> = data.chunks_exact(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]])).collect();
+        let data = split(&longs);
+        let data_sum = checksum(&data) ^ if sector.crc_error { DATA_BITS } else { 0 };
+        for &long in header.iter().chain(&split(&[checksum(&header)])).chain(&split(&[data_sum])).chain(&data) {
+            writer.long(long);
+        }
+    }
+    while writer.cells.len() < cells {
+        writer.long(0);
+    }
+    writer.cells.truncate(cells.max(count * (SECTOR_LONGS + 2) * 32));
+    writer.cells
+}
+
+/// Finds and decodes every Amiga trackdisk sector in a bit stream produced by `flux::to_bits`.
+/// Each sector starts with two 0x4489 sync words, followed by the header, label, checksums
+/// and 512 data bytes, all stored as separate odd-bit and even-bit halves.
+pub fn decode_track(bits: &[u8]) -> DecodedTrack {
+    let mut result = DecodedTrack::default();
+    let mut window = 0u16;
+    let mut at = 0;
+    while at < bits.len() {
+        window = (window << 1) | bits[at] as u16;
+        at += 1;
+        if window != SYNC {
+            continue;
+        }
+        // Skip the second (and any further) sync word
+        while at + 16 <= bits.len() && bits[at..at + 16].iter().fold(0u16, |w, &b| (w << 1) | b as u16) == SYNC {
+            at += 16;
+        }
+        if at + SECTOR_LONGS * 32 > bits.len() {
+            break;
+        }
+        let raw: Vec<u32> = (0..SECTOR_LONGS).map(|i| raw_long(bits, at + i * 32)).collect();
+        let info = join(raw[0], raw[1]);
+        if join(raw[10], raw[11]) != checksum(&raw[0..10]) || info >> 24 != 0xFF {
+            result.bad_headers += 1;
+            continue;
+        }
+        let data: Vec<u8> = (0..128).flat_map(|i| join(raw[14 + i], raw[142 + i]).to_be_bytes()).collect();
+        let track = (info >> 16) as u8; // Cylinder * 2 + head
+        result.sectors.push(DecodedSector {
+            cylinder: track / 2,
+            head: track % 2,
+            sector: (info >> 8) as u8,
+            data: Some(data),
+            data_ok: join(raw[12], raw[13]) == checksum(&raw[14..SECTOR_LONGS]),
+        });
+        at += SECTOR_LONGS * 32;
+        window = 0;
+    }
+    result
+}
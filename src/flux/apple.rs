@@ -0,0 +1,233 @@
+use crate::disk::Disk;
+use crate::flux::{self, DecodedSector, DecodedTrack, TrackSector};
+
+/// Disk bytes for 6-bit values (6-and-2 encoding, 16-sector disks).
+const GCR62: [u8; 64] = [
+    0x96, 0x97, 0x9A, 0x9B, 0x9D, 0x9E, 0x9F, 0xA6, 0xA7, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF, 0xB2, 0xB3,
+    0xB4, 0xB5, 0xB6, 0xB7, 0xB9, 0xBA, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF, 0xCB, 0xCD, 0xCE, 0xCF, 0xD3,
+    0xD6, 0xD7, 0xD9, 0xDA, 0xDB, 0xDC, 0xDD, 0xDE, 0xDF, 0xE5, 0xE6, 0xE7, 0xE9, 0xEA, 0xEB, 0xEC,
+    0xED, 0xEE, 0xEF, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF9, 0xFA, 0xFB, 0xFC, 0xFD, 0xFE, 0xFF,
+];
+/// Disk bytes for 5-bit values (5-and-3 encoding, 13-sector DOS 3.2 disks).
+const GCR53: [u8; 32] = [
+    0xAB, 0xAD, 0xAE, 0xAF, 0xB5, 0xB6, 0xB7, 0xBA, 0xBB, 0xBD, 0xBE, 0xBF, 0xD6, 0xD7, 0xDA, 0xDB,
+    0xDD, 0xDE, 0xDF, 0xEA, 0xEB, 0xED, 0xEE, 0xEF, 0xF5, 0xF6, 0xF7, 0xFA, 0xFB, 0xFD, 0xFE, 0xFF,
+];
+const ADDRESS_16: [u8; 3] = [0xD5, 0xAA, 0x96];
+const ADDRESS_13: [u8; 3] = [0xD5, 0xAA, 0xB5];
+const DATA: [u8; 3] = [0xD5, 0xAA, 0xAD];
+const EPILOGUE: [u8; 3] = [0xDE, 0xAA, 0xEB];
+const SEARCH_LIMIT: usize = 64; // Nibbles between an address field and its data field
+
+/// Physical sector of each logical sector in a DOS 3.3 order (.do/.dsk) image.
+pub const DOS_ORDER: [u8; 16] = [0, 13, 11, 9, 7, 5, 3, 1, 14, 12, 10, 8, 6, 4, 2, 15];
+/// Physical sector of each 256-byte half-block in a ProDOS order (.po) image.
+pub const PRODOS_ORDER: [u8; 16] = [0, 2, 4, 6, 8, 10, 12, 14, 1, 3, 5, 7, 9, 11, 13, 15];
+
+/// Nominal bit cell width of a 5.25-inch Apple II drive.
+pub const CELL_NS: f64 = 4000.0;
+
+/// Reads disk bytes from a bit stream the way the disk controller does: leading 0 bits are
+/// skipped and a byte is complete once its high bit is set.
+pub fn nibbles(bits: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bits.len() / 8);
+    let mut value = 0u8;
+    for &bit in bits {
+        if value == 0 && bit == 0 {
+            continue;
+        }
+        value = (value << 1) | bit;
+        if value & 0x80 != 0 {
+            out.push(value);
+            value = 0;
+        }
+    }
+    out
+}
+
+fn odd_even(pair: &[u8]) -> u8 {
+    ((pair[0] << 1) | 1) & pair[1]
+}
+
+fn translate(table: &[u8], nibbles: &[u8]) -> Option<Vec<u8>> {
+    nibbles.iter().map(|&n| table.iter().position(|&c| c == n).map(|v| v as u8)).collect()
+}
+
+fn swap_low_bits(value: u8) -> u8 {
+    ((value & 0x01) << 1) | ((value & 0x02) >> 1)
+}
+
+/// Decodes a 6-and-2 data field (342 nibbles and a checksum): 86 nibbles holding the low two
+/// bits of each byte, then 256 holding the high six, each XORed with the previous value.
+fn decode_62(field: &[u8]) -> Option<(Vec<u8>, bool)> {
+    let values = translate(&GCR62, field.get(..343)?)?;
+    let mut previous = 0;
+    let decoded: Vec<u8> = values[..342].iter().map(|&v| {
+        previous ^= v;
+        previous
+    }).collect();
+    let data = (0..256).map(|i| (decoded[86 + i] << 2) | swap_low_bits((decoded[i % 86] >> (2 * (i / 86))) & 0x03)).collect();
+    Some((data, values[342] == previous))
+}
+
+/// Decodes a 5-and-3 data field (410 nibbles and a checksum) as written by DOS 3.2: 154
+/// nibbles holding the low three bits (stored in reverse), then 256 holding the high five.
+fn decode_53(field: &[u8]) -> Option<(Vec<u8>, bool)> {
+    const CHUNK: usize = 51;
+    let values = translate(&GCR53, field.get(..411)?)?;
+    let mut checksum = 0;
+    let mut three = [0u8; 154];
+    for (slot, &v) in three.iter_mut().rev().zip(&values[..154]) {
+        checksum ^= v;
+        *slot = checksum;
+    }
+    let mut base = [0u8; 256];
+    for (slot, &v) in base.iter_mut().zip(&values[154..410]) {
+        checksum ^= v;
+        *slot = checksum;
+    }
+    let mut data = Vec::with_capacity(256);
+    for i in (0..CHUNK).rev() {
+        let (t1, t2, t3) = (three[i], three[CHUNK + i], three[CHUNK * 2 + i]);
+        let t4 = ((t1 & 0x02) << 1) | (t2 & 0x02) | ((t3 & 0x02) >> 1);
+        let t5 = ((t1 & 0x01) << 2) | ((t2 & 0x01) << 1) | (t3 & 0x01);
+        data.push((base[i] << 3) | ((t1 >> 2) & 0x07));
+        data.push((base[CHUNK + i] << 3) | ((t2 >> 2) & 0x07));
+        data.push((base[CHUNK * 2 + i] << 3) | ((t3 >> 2) & 0x07));
+        data.push((base[CHUNK * 3 + i] << 3) | t4);
+        data.push((base[CHUNK * 4 + i] << 3) | t5);
+    }
+    data.push((base[255] << 3) | (three[CHUNK * 3] & 0x07));
+    Some((data, values[410] == checksum))
+}
+
+/// Finds and decodes every sector in a stream of disk bytes. Address fields (D5 AA 96 on
+/// 16-sector disks, D5 AA B5 on 13-sector ones) hold the volume, track, sector and checksum
+/// in 4-and-4 encoding; the data field that follows starts with D5 AA AD. Also returns the
+/// number of sectors per track the address fields imply.
+pub fn decode_nibbles(nibbles: &[u8]) -> (DecodedTrack, usize) {
+    let mut result = DecodedTrack::default();
+    let (mut found_16, mut found_13) = (false, false);
+    let mut at = 0;
+    while at + 14 <= nibbles.len() {
+        let prologue = &nibbles[at..at + 3];
+        let thirteen = prologue == ADDRESS_13;
+        if prologue != ADDRESS_16 && !thirteen {
+            at += 1;
+            continue;
+        }
+        let fields: Vec<u8> = nibbles[at + 3..at + 11].chunks_exact(2).map(odd_even).collect();
+        at += 11;
+        if fields[0] ^ fields[1] ^ fields[2] != fields[3] {
+            result.bad_headers += 1;
+            continue;
+        }
+        if thirteen { found_13 = true } else { found_16 = true }
+        let mut sector = DecodedSector { cylinder: fields[1], head: 0, sector: fields[2], data: None, data_ok: false };
+        let window = &nibbles[at..(at + SEARCH_LIMIT).min(nibbles.len())];
+        let data_at = window.windows(3).position(|w| w == DATA).filter(|&p| {
+            !window[..p + 2].windows(3).any(|w| w == ADDRESS_16 || w == ADDRESS_13)
+        });
+        if let Some(p) = data_at {
+            let field = &nibbles[at + p + 3..];
+            let decoded = if thirteen { decode_53(field) } else { decode_62(field) };
+            match decoded {
+                Some((data, ok)) => {
+                    sector.data = Some(data);
+                    sector.data_ok = ok;
+                    at += p + 3 + if thirteen { 411 } else { 343 };
+                }
+                // Truncated field or invalid disk bytes: treat as an unreadable data field
+                None if field.len() >= if thirteen { 411 } else { 343 } => {
+                    sector.data = Some(vec![0; 256]);
+                }
+                None => {}
+            }
+        }
+        result.sectors.push(sector);
+    }
+    (result, if found_13 && !found_16 { 13 } else { 16 })
+}
+
+/// Decodes a bit stream produced by `flux::to_bits` or read from a WOZ track.
+pub fn decode_track(bits: &[u8]) -> (DecodedTrack, usize) {
+    decode_nibbles(&nibbles(bits))
+}
+
+fn push_byte(bits: &mut Vec<u8>, byte: u8) {
+    bits.extend((0..8).rev().map(|i| (byte >> i) & 1));
+}
+
+/// Self-sync bytes: 0xFF followed by two 0 bits, so the controller falls into step.
+fn push_sync(bits: &mut Vec<u8>, count: usize) {
+    for _ in 0..count {
+        push_byte(bits, 0xFF);
+        bits.extend_from_slice(&[0, 0]);
+    }
+}
+
+/// Encodes 256 bytes as a 6-and-2 data field body (343 disk bytes, checksum last).
+fn encode_62(data: &[u8], bad_checksum: bool) -> Vec<u8> {
+    let mut values = vec![0u8; 342];
+    for (i, &byte) in data.iter().enumerate().take(256) {
+        values[i % 86] |= swap_low_bits(byte & 0x03) << (2 * (i / 86));
+        values[86 + i] = byte >> 2;
+    }
+    let mut previous = 0;
+    let mut out: Vec<u8> = values.iter().map(|&v| {
+        let disk_byte = GCR62[(v ^ previous) as usize];
+        previous = v;
+        disk_byte
+    }).collect();
+    out.push(GCR62[(if bad_checksum { previous ^ 0x3F } else { previous }) as usize]);
+    out
+}
+
+/// Builds the bit stream of a 16-sector track in DOS 3.3 layout, with 10-bit self-sync gaps.
+/// Sectors are written in the order given, so `sectors` should be in physical order.
+pub fn encode_track(volume: u8, track: u8, sectors: &[TrackSector]) -> Vec<u8> {
+    let mut bits = Vec::with_capacity(52_000);
+    push_sync(&mut bits, 48);
+    for s in sectors {
+        ADDRESS_16.iter().for_each(|&b| push_byte(&mut bits, b));
+        for value in [volume, track, s.sector, volume ^ track ^ s.sector] {
+            push_byte(&mut bits, (value >> 1) | 0xAA);
+            push_byte(&mut bits, value | 0xAA);
+        }
+        EPILOGUE.iter().for_each(|&b| push_byte(&mut bits, b));
+        push_sync(&mut bits, 6);
+        if let Some(data) = s.data {
+            DATA.iter().for_each(|&b| push_byte(&mut bits, b));
+            encode_62(data, s.bad_checksum).into_iter().for_each(|b| push_byte(&mut bits, b));
+            EPILOGUE.iter().for_each(|&b| push_byte(&mut bits, b));
+        }
+        push_sync(&mut bits, 20);
+    }
+    bits
+}
+
+/// Merges the passes over each track (indexed by track number) into a disk, with the summary
+/// line and a line per captured track with problems. Tracks 0-34 are always present so the
+/// result maps onto a sector image; later tracks are kept only if they hold sectors.
+pub fn build_disk(tracks: Vec<Option<Vec<(DecodedTrack, usize)>>>) -> (Disk, Vec<String>) {
+    let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+    let mut report = Vec::new();
+    let mut tally = flux::Tally::default();
+    for (number, passes) in tracks.into_iter().enumerate() {
+        let captured = passes.is_some();
+        let passes = passes.unwrap_or_default();
+        let spt = if passes.iter().any(|&(_, spt)| spt == 13) { 13 } else { 16 };
+        let (slots, bad_headers) = flux::merge(passes.into_iter().map(|(pass, _)| pass).collect(), spt);
+        if number >= 35 && slots.iter().all(|s| s.is_none()) {
+            continue;
+        }
+        if captured {
+            if let Some(problems) = flux::track_problems(&slots, bad_headers) {
+                report.push(format!("  Track {}: {}", number, problems));
+            }
+        }
+        disk.tracks.push(flux::build_track(number as u8, 0, 5, 256, slots, &mut tally));
+    }
+    report.insert(0, format!("Apple GCR Decode: {} sectors good, {} with bad data checksums, {} missing", tally.good, tally.bad, tally.missing));
+    (disk, report)
+}
@@ -0,0 +1,344 @@
+use crate::disk::{Disk, Sector, Track};
+use crate::formats::scp;
+use crate::flux::{self, DecodedTrack, ibm::{self, Encoding}};
+use std::collections::BTreeMap;
+
+/// One revolution of flux: the intervals between transitions in nanoseconds, starting at the
+/// index pulse, and the index-to-index time.
+pub struct Revolution {
+    pub duration_ns: u64,
+    pub flux: Vec<u32>,
+}
+
+/// Flux captured from a disk, whatever format it was stored in. Tracks are numbered as in
+/// SCP: cylinder * 2 + head, with cylinders counted in drive steps.
+#[derive(Default)]
+pub struct Capture {
+    pub disk_type: Option<u8>,       // SCP disk type, when the capture records one
+    pub tpi96: bool,                 // Captured on a 96 TPI drive
+    pub footer: Option<scp::Footer>, // SCP extension footer, kept when writing SCP again
+    pub tracks: BTreeMap<u8, Vec<Revolution>>,
+}
+
+/// Sector encodings that can be decoded from flux.
+#[derive(Clone, Copy)]
+pub enum Platform {
+    Cbm,               // 1541 GCR, zoned speeds, single-sided
+    Apple,             // Apple II 6-and-2 or 5-and-3 GCR, single-sided
+    Amiga { hd: bool }, // Amiga trackdisk MFM, double-sided
+    Ibm,               // IBM FM or MFM, any geometry
+}
+
+impl Platform {
+    /// Platform for an SCP disk type.
+    pub fn from_disk_type(disk_type: u8) -> Option<Platform> {
+        match disk_type >> 4 {
+            0x00 => Some(Platform::Cbm),
+            0x02 => Some(Platform::Apple),
+            0x03 => Some(Platform::Ibm),
+            0x04 => Some(Platform::Amiga { hd: disk_type & 0x0F == 0x01 }),
+            _ => None,
+        }
+    }
+
+    /// Platform for a capture: from its disk type, or detected from the flux.
+    pub fn of(capture: &Capture) -> Option<Platform> {
+        capture.disk_type.and_then(Platform::from_disk_type).or_else(|| Platform::detect(capture))
+    }
+
+    /// Guesses the platform from the first revolution of the first few tracks, for captures
+    /// that don't record a disk type.
+    pub fn detect(capture: &Capture) -> Option<Platform> {
+        for (&number, revolutions) in capture.tracks.iter().take(4) {
+            let Some(revolution) = revolutions.first() else { continue };
+            if ibm_pass(&revolution.flux).is_some() {
+                return Some(Platform::Ibm);
+            }
+            if let Some(cell) = flux::estimate_cell(&revolution.flux, 2) {
+                if !flux::amiga::decode_track(&flux::to_bits(&revolution.flux, cell)).sectors.is_empty() {
+                    return Some(Platform::Amiga { hd: cell < 1500.0 });
+                }
+            }
+            for platform in [Platform::Cbm, Platform::Apple] {
+                if !platform.pass(number / 2, &revolution.flux).0.sectors.is_empty() {
+                    return Some(platform);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Cbm => "CBM GCR",
+            Platform::Apple => "Apple GCR",
+            Platform::Amiga { .. } => "Amiga MFM",
+            Platform::Ibm => "IBM FM/MFM",
+        }
+    }
+
+    /// Cylinders and heads always present in the decoded disk. IBM disks have no fixed layout.
+    fn layout(self) -> (u8, u8) {
+        match self {
+            Platform::Cbm | Platform::Apple => (35, 1),
+            Platform::Amiga { .. } => (80, 2),
+            Platform::Ibm => (0, 2),
+        }
+    }
+
+    /// Whether a 48 TPI disk was captured on a 96 TPI drive, stepping twice per cylinder.
+    /// Commodore and Apple disks are always 48 TPI. An IBM disk is double-stepped when the
+    /// sector IDs on even drive cylinders give half the drive cylinder, since a 96 TPI drive
+    /// also reads 80-track disks. Amiga disks are 3.5-inch, so never double-stepped.
+    pub fn double_stepped(self, capture: &Capture) -> bool {
+        if !capture.tpi96 {
+            return false;
+        }
+        match self {
+            Platform::Cbm | Platform::Apple => true,
+            Platform::Amiga { .. } => false,
+            Platform::Ibm => {
+                let (mut half, mut full) = (0, 0);
+                let even = capture.tracks.iter().filter(|(&number, _)| number / 2 > 0 && number / 2 % 2 == 0);
+                for (&number, revolutions) in even.take(4) {
+                    let Some((_, _, (sectors, _))) = revolutions.first().and_then(|r| ibm_pass(&r.flux)) else { continue };
+                    half += sectors.iter().filter(|s| s.cylinder == number / 4).count();
+                    full += sectors.iter().filter(|s| s.cylinder == number / 2).count();
+                }
+                half > full
+            }
+        }
+    }
+
+    /// Cylinder and head of a capture track, or None for tracks the disk doesn't use: the
+    /// second side of single-sided disks, and the odd drive cylinders (between tracks) of a
+    /// double-stepped capture.
+    pub fn location(self, track_number: u8, double_stepped: bool) -> Option<(u8, u8)> {
+        let (cylinder, head) = (track_number / 2, track_number % 2);
+        if double_stepped && cylinder % 2 != 0 {
+            return None;
+        }
+        let cylinder = if double_stepped { cylinder / 2 } else { cylinder };
+        match self {
+            Platform::Amiga { .. } | Platform::Ibm => Some((cylinder, head)),
+            _ if head != 0 => None,
+            _ => Some((cylinder, 0)),
+        }
+    }
+
+    fn sectors_per_track(self, cylinder: u8) -> usize {
+        match self {
+            Platform::Cbm => flux::cbm::sectors_per_track(cylinder + 1) as usize,
+            Platform::Apple => 16,
+            Platform::Amiga { hd } => if hd { 22 } else { 11 },
+            Platform::Ibm => 0,
+        }
+    }
+
+    fn sector_size(self) -> usize {
+        match self {
+            Platform::Amiga { .. } | Platform::Ibm => 512,
+            _ => 256,
+        }
+    }
+
+    fn mode(self) -> u8 {
+        match self {
+            Platform::Amiga { hd: true } => 3,
+            _ => 5,
+        }
+    }
+
+    /// How tracks are named in the decode report: CBM tracks count from 1, Amiga tracks
+    /// interleave the two sides.
+    fn track_label(self, cylinder: u8, head: u8) -> String {
+        match self {
+            Platform::Cbm => format!("Track {}", cylinder + 1),
+            Platform::Apple => format!("Track {}", cylinder),
+            Platform::Amiga { .. } => format!("Track {} (Cyl {}, Head {})", cylinder as usize * 2 + head as usize, cylinder, head),
+            Platform::Ibm => format!("Cyl {}, Head {}", cylinder, head),
+        }
+    }
+
+    /// Decodes one revolution of a fixed-layout platform, with the sectors per track it holds.
+    fn pass(self, cylinder: u8, flux_ns: &[u32]) -> (DecodedTrack, usize) {
+        match self {
+            Platform::Cbm => (flux::cbm::decode_track(&flux::to_bits(flux_ns, flux::cbm::cell_ns(cylinder + 1))), self.sectors_per_track(cylinder)),
+            Platform::Apple => flux::apple::decode_track(&flux::to_bits(flux_ns, flux::apple::CELL_NS)),
+            Platform::Amiga { .. } => {
+                let pass = flux::estimate_cell(flux_ns, 2).map(|cell| flux::amiga::decode_track(&flux::to_bits(flux_ns, cell)));
+                (pass.unwrap_or_default(), self.sectors_per_track(cylinder))
+            }
+            Platform::Ibm => (DecodedTrack::default(), 0),
+        }
+    }
+}
+
+/// One sector as read from a single revolution.
+pub struct SectorRead {
+    pub id: u8,
+    pub data: Option<Vec<u8>>,
+    pub good: bool,                   // Data checksum matched
+    pub span: Option<(usize, usize)>, // Cells from the ID field to the end of the data (IBM only)
+}
+
+/// Everything read from one revolution, repeated sector IDs included, with the bit cell
+/// width used to read it.
+pub struct RevolutionRead {
+    pub cell_ns: f64,
+    pub sectors: Vec<SectorRead>,
+}
+
+impl Platform {
+    /// Reads the sectors of one revolution without merging anything, for checks that compare
+    /// revolutions or look at how a track is laid out. None if no bit cell could be found.
+    pub fn read(self, cylinder: u8, flux_ns: &[u32]) -> Option<RevolutionRead> {
+        let cell_ns = match self {
+            Platform::Ibm => {
+                let (cell_ns, found) = [(Encoding::Mfm, 2), (Encoding::Fm, 1)].into_iter().find_map(|(encoding, shortest)| {
+                    let cell = flux::estimate_cell(flux_ns, shortest)?;
+                    let (found, _) = ibm::scan_track(&flux::to_bits(flux_ns, cell), encoding);
+                    (!found.is_empty()).then_some((cell, found))
+                })?;
+                let sectors = found.into_iter().map(|(s, span)| SectorRead { id: s.id, good: s.data.is_some() && !s.crc_error, data: s.data, span: Some(span) }).collect();
+                return Some(RevolutionRead { cell_ns, sectors });
+            }
+            Platform::Cbm => flux::cbm::cell_ns(cylinder + 1),
+            Platform::Apple => flux::apple::CELL_NS,
+            Platform::Amiga { .. } => flux::estimate_cell(flux_ns, 2)?,
+        };
+        let sectors = self.pass(cylinder, flux_ns).0.sectors.into_iter().map(|s| SectorRead { id: s.sector, good: s.data_ok, data: s.data, span: None }).collect();
+        Some(RevolutionRead { cell_ns, sectors })
+    }
+}
+
+/// Decodes one revolution as IBM MFM, or as FM if no MFM sectors turn up. Returns the
+/// encoding, the cell rate (thousands of cells per second) and the sectors with the number of
+/// bad ID fields.
+fn ibm_pass(flux_ns: &[u32]) -> Option<(Encoding, usize, (Vec<Sector>, usize))> {
+    // The shortest MFM interval is two cells; FM has one-cell intervals wherever a bit is set
+    [(Encoding::Mfm, 2), (Encoding::Fm, 1)].into_iter().find_map(|(encoding, shortest)| {
+        let cell = flux::estimate_cell(flux_ns, shortest)?;
+        let decoded = ibm::decode_track(&flux::to_bits(flux_ns, cell), encoding);
+        (!decoded.0.is_empty()).then(|| (encoding, (1_000_000.0 / cell).round() as usize, decoded))
+    })
+}
+
+/// Describes bad sectors and ID fields on an IBM track, or None if it decoded cleanly.
+fn ibm_problems(sectors: &[Sector], bad_headers: usize) -> Option<String> {
+    let list = |pick: &dyn Fn(&Sector) -> bool| -> Vec<String> {
+        sectors.iter().filter(|s| pick(s)).map(|s| s.id.to_string()).collect()
+    };
+    let plural = |items: &[String]| if items.len() > 1 { "s" } else { "" };
+    let bad = list(&|s| s.crc_error);
+    let no_data = list(&|s| s.data.is_none());
+
+    let mut problems = Vec::new();
+    if !bad.is_empty() {
+        problems.push(format!("bad data checksum in sector{} {}", plural(&bad), bad.join(", ")));
+    }
+    if !no_data.is_empty() {
+        problems.push(format!("no data block for sector{} {}", plural(&no_data), no_data.join(", ")));
+    }
+    if bad_headers > 0 {
+        problems.push(format!("{} headers with bad checksums", bad_headers));
+    }
+    (!problems.is_empty()).then(|| problems.join("; "))
+}
+
+/// Decodes sectors from every track, keeping the best copy of each sector across revolutions.
+/// Also returns a summary and a line per track with problems for `display`.
+pub fn decode(capture: &Capture, platform: Platform) -> (Disk, Vec<String>) {
+    if let Platform::Ibm = platform {
+        return decode_ibm(capture);
+    }
+    let double_stepped = platform.double_stepped(capture);
+    let mut decoded: BTreeMap<(u8, u8), flux::MergedTrack> = BTreeMap::new();
+    for (&number, revolutions) in &capture.tracks {
+        let Some((cylinder, head)) = platform.location(number, double_stepped) else { continue };
+        let results: Vec<(DecodedTrack, usize)> = revolutions.iter().map(|r| platform.pass(cylinder, &r.flux)).collect();
+        // Apple disks are 13-sector if any revolution shows 5-and-3 address fields
+        let spt = match platform {
+            Platform::Apple => if results.iter().any(|&(_, spt)| spt == 13) { 13 } else { 16 },
+            _ => platform.sectors_per_track(cylinder),
+        };
+        decoded.insert((cylinder, head), flux::merge(results.into_iter().map(|(pass, _)| pass).collect(), spt));
+    }
+
+    // The standard tracks are always present so the result maps onto an ADF, D64 or
+    // Apple sector image; extra cylinders are kept only if they hold sectors.
+    let (cylinders, heads) = platform.layout();
+    let last = decoded.keys().map(|&(cylinder, _)| cylinder as usize + 1).max().unwrap_or(0).max(cylinders as usize);
+    let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+    let mut report = Vec::new();
+    let mut tally = flux::Tally::default();
+    for cylinder in 0..last as u8 {
+        for head in 0..heads {
+            let found = decoded.remove(&(cylinder, head));
+            let captured = found.is_some();
+            let (slots, bad_headers) = found.unwrap_or_else(|| (vec![None; platform.sectors_per_track(cylinder)], 0));
+            if cylinder >= cylinders && slots.iter().all(|s| s.is_none()) {
+                continue;
+            }
+            if captured {
+                if let Some(problems) = flux::track_problems(&slots, bad_headers) {
+                    report.push(format!("  {}: {}", platform.track_label(cylinder, head), problems));
+                }
+            }
+            disk.tracks.push(flux::build_track(cylinder, head, platform.mode(), platform.sector_size(), slots, &mut tally));
+        }
+    }
+    if double_stepped {
+        report.insert(0, "  48 TPI disk on a 96 TPI drive: odd drive cylinders skipped".to_string());
+    }
+    report.insert(0, format!("{} Decode: {} sectors good, {} with bad data checksums, {} missing", platform.name(), tally.good, tally.bad, tally.missing));
+    (disk, report)
+}
+
+/// IBM tracks keep the sector IDs, sizes and order found on the disk, and the mode follows
+/// the encoding and cell rate. Unformatted tracks are left out, and reported when a later
+/// track on the same side holds sectors.
+fn decode_ibm(capture: &Capture) -> (Disk, Vec<String>) {
+    let platform = Platform::Ibm;
+    let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+    let mut report = Vec::new();
+    let mut empty = Vec::new();
+    let mut tally = flux::Tally::default();
+    let double_stepped = platform.double_stepped(capture);
+    for (&number, revolutions) in &capture.tracks {
+        let Some((cylinder, head)) = platform.location(number, double_stepped) else { continue };
+        let mut passes: Vec<(Encoding, usize, (Vec<Sector>, usize))> = revolutions.iter().filter_map(|r| ibm_pass(&r.flux)).collect();
+        // The revolution with the most sectors gives the physical order; the others fill in
+        passes.sort_by_key(|(_, _, (sectors, _))| std::cmp::Reverse(sectors.len()));
+        let mut passes = passes.into_iter();
+        let Some((encoding, kcells, (mut sectors, mut bad_headers))) = passes.next() else {
+            empty.push((cylinder, head));
+            continue;
+        };
+        for (_, _, (more, bad)) in passes {
+            bad_headers += bad;
+            more.into_iter().for_each(|sector| ibm::add_sector(&mut sectors, sector));
+        }
+        if let Some(problems) = ibm_problems(&sectors, bad_headers) {
+            report.push(format!("  {}: {}", platform.track_label(cylinder, head), problems));
+        }
+        for sector in &sectors {
+            match (&sector.data, sector.crc_error) {
+                (None, _) => tally.missing += 1,
+                (Some(_), true) => tally.bad += 1,
+                (Some(_), false) => tally.good += 1,
+            }
+        }
+        disk.tracks.push(Track { mode: encoding.mode(kcells), cylinder, head, sectors, ..Default::default() });
+    }
+    for (cylinder, head) in empty {
+        if disk.tracks.iter().any(|t| t.head == head && t.cylinder > cylinder) {
+            report.push(format!("  {}: no sectors found", platform.track_label(cylinder, head)));
+        }
+    }
+    if double_stepped {
+        report.insert(0, "  48 TPI disk on a 96 TPI drive: odd drive cylinders skipped".to_string());
+    }
+    report.insert(0, format!("{} Decode: {} sectors good, {} with bad data checksums, {} missing", platform.name(), tally.good, tally.bad, tally.missing));
+    (disk, report)
+}
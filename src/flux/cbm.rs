@@ -0,0 +1,147 @@
+use crate::flux::{DecodedSector, DecodedTrack, TrackSector};
+
+/// 5-bit GCR code for each nibble, as written by the 1541.
+const GCR: [u8; 16] = [
+    0x0A, 0x0B, 0x12, 0x13, 0x0E, 0x0F, 0x16, 0x17,
+    0x09, 0x19, 0x1A, 0x1B, 0x0D, 0x1D, 0x1E, 0x15,
+];
+const HEADER_ID: u8 = 0x08;
+const DATA_ID: u8 = 0x07;
+const SYNC_BITS: usize = 10;
+
+/// Speed zone of a track (1-based): 3 is the fastest, used on the outer tracks.
+pub fn speed_zone(track: u8) -> usize {
+    match track {
+        0..=17 => 3,
+        18..=24 => 2,
+        25..=30 => 1,
+        _ => 0,
+    }
+}
+
+pub fn sectors_per_track(track: u8) -> u8 {
+    [17, 18, 19, 21][speed_zone(track)]
+}
+
+/// Nominal bit cell width for a track on a 300 RPM drive.
+pub fn cell_ns(track: u8) -> f64 {
+    [4000.0, 3750.0, 3500.0, 3250.0][speed_zone(track)]
+}
+
+/// Bytes of GCR that fit on a track at its zone's bit rate.
+pub fn track_length(track: u8) -> usize {
+    [6250, 6666, 7142, 7692][speed_zone(track)]
+}
+
+/// Decodes `count` bytes of GCR starting at bit `at`; the flag is false if any 5-bit group
+/// was not a valid code (those nibbles decode as 0).
+fn decode_gcr(bits: &[u8], at: usize, count: usize) -> Option<(Vec<u8>, bool)> {
+    let groups = bits.get(at..at + count * 10)?;
+    let mut valid = true;
+    let nibbles: Vec<u8> = groups.chunks_exact(5).map(|group| {
+        let code = group.iter().fold(0, |v, &b| (v << 1) | b);
+        GCR.iter().position(|&c| c == code).map(|n| n as u8).unwrap_or_else(|| {
+            valid = false;
+            0
+        })
+    }).collect();
+    Some((nibbles.chunks_exact(2).map(|pair| (pair[0] << 4) | pair[1]).collect(), valid))
+}
+
+/// Encodes bytes as packed GCR (every 4 bytes become 5).
+pub fn encode_gcr(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() * 5 / 4 + 1);
+    let (mut acc, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        for nibble in [byte >> 4, byte & 0x0F] {
+            acc = (acc << 5) | GCR[nibble as usize] as u32;
+            bits += 5;
+            while bits >= 8 {
+                bits -= 8;
+                out.push((acc >> bits) as u8);
+            }
+        }
+    }
+    if bits > 0 {
+        out.push((acc << (8 - bits)) as u8);
+    }
+    out
+}
+
+/// Finds and decodes every 1541 sector in a bit stream. A sector is a header block (0x08,
+/// checksum, sector, track, disk ID, 0x0F 0x0F) and a data block (0x07, 256 bytes,
+/// checksum), each GCR-encoded and preceded by a sync mark of at least ten 1 bits.
+pub fn decode_track(bits: &[u8]) -> DecodedTrack {
+    let mut result = DecodedTrack::default();
+    let mut pending: Option<DecodedSector> = None;
+    let mut ones = 0;
+    let mut at = 0;
+    while at < bits.len() {
+        if bits[at] == 1 {
+            ones += 1;
+            at += 1;
+            continue;
+        }
+        let synced = ones >= SYNC_BITS;
+        ones = 0;
+        if !synced {
+            at += 1;
+            continue;
+        }
+        match decode_gcr(bits, at, 1).map(|(b, _)| b[0]) {
+            Some(HEADER_ID) => {
+                let Some((header, valid)) = decode_gcr(bits, at, 8) else { break };
+                result.sectors.extend(pending.take());
+                if !valid || header[1] != header[2] ^ header[3] ^ header[4] ^ header[5] {
+                    result.bad_headers += 1;
+                } else {
+                    pending = Some(DecodedSector { cylinder: header[3], head: 0, sector: header[2], data: None, data_ok: false });
+                }
+                at += 80;
+            }
+            Some(DATA_ID) => {
+                let Some((block, valid)) = decode_gcr(bits, at, 260) else { break };
+                if let Some(mut sector) = pending.take() {
+                    let data = block[1..257].to_vec();
+                    sector.data_ok = valid && data.iter().fold(0, |x, &b| x ^ b) == block[257];
+                    sector.data = Some(data);
+                    result.sectors.push(sector);
+                }
+                at += 2600;
+            }
+            _ => at += 1,
+        }
+    }
+    result.sectors.extend(pending);
+    result
+}
+
+/// Builds the GCR bytes of a whole track in the 1541's layout, padded with 0x55 gap bytes to
+/// the zone's track length.
+pub fn encode_track(track: u8, disk_id: [u8; 2], sectors: &[TrackSector]) -> Vec<u8> {
+    const SYNC: [u8; 5] = [0xFF; 5];
+    let length = track_length(track);
+    let per_sector = 5 + 10 + 9 + 5 + 325;
+    let gap = length.saturating_sub(per_sector * sectors.len()) / sectors.len().max(1);
+    let mut out = Vec::with_capacity(length);
+    for s in sectors {
+        let checksum = s.sector ^ track ^ disk_id[1] ^ disk_id[0];
+        out.extend_from_slice(&SYNC);
+        out.extend(encode_gcr(&[HEADER_ID, checksum, s.sector, track, disk_id[1], disk_id[0], 0x0F, 0x0F]));
+        out.extend_from_slice(&[0x55; 9]);
+        match s.data {
+            Some(data) => {
+                let mut block = vec![DATA_ID];
+                block.extend_from_slice(data);
+                let checksum = data.iter().fold(0, |x, &b| x ^ b);
+                block.extend_from_slice(&[if s.bad_checksum { !checksum } else { checksum }, 0, 0]);
+                out.extend_from_slice(&SYNC);
+                out.extend(encode_gcr(&block));
+            }
+            None => out.extend(std::iter::repeat_n(0x55, 5 + 325)),
+        }
+        out.extend(std::iter::repeat_n(0x55, gap));
+    }
+    out.resize(out.len().max(length), 0x55);
+    out
+}
@@ -0,0 +1,284 @@
+use crate::disk::{Sector, Track};
+use anyhow::{Result, anyhow};
+
+const MFM_SYNC: u16 = 0x4489;       // A1 with a missing clock bit
+const MFM_INDEX_SYNC: u16 = 0x5224; // C2 with a missing clock bit
+const FM_ID_MARK: u16 = 0xF57E;      // FE with clock C7
+const FM_DATA_MARK: u16 = 0xF56F;    // FB with clock C7
+const FM_DELETED_MARK: u16 = 0xF56A; // F8 with clock C7
+const FM_INDEX_MARK: u16 = 0xF77A;   // FC with clock D7
+const ID_MARK: u8 = 0xFE;
+const DATA_MARK: u8 = 0xFB;
+const DELETED_MARK: u8 = 0xF8;
+const INDEX_MARK: u8 = 0xFC;
+const SEARCH_LIMIT: usize = 64; // Bytes between an ID field and its data mark
+
+/// IBM System/34 (MFM) or System/3740 (FM) recording.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Fm,
+    Mfm,
+}
+
+impl Encoding {
+    /// Encoding of an IMD mode (0-2 FM, 3-5 MFM).
+    pub fn for_mode(mode: u8) -> Encoding {
+        if mode <= 2 { Encoding::Fm } else { Encoding::Mfm }
+    }
+
+    /// IMD mode for a track of this encoding at a cell rate (thousands of cells per second).
+    pub fn mode(self, kcells: usize) -> u8 {
+        match (self, kcells) {
+            (Encoding::Mfm, ..=550) => 5,
+            (Encoding::Mfm, ..=700) => 4,
+            (Encoding::Mfm, _) => 3,
+            (Encoding::Fm, ..=275) => 2,
+            (Encoding::Fm, ..=350) => 1,
+            (Encoding::Fm, _) => 0,
+        }
+    }
+
+    fn gap_byte(self) -> u8 {
+        match self {
+            Encoding::Fm => 0xFF,
+            Encoding::Mfm => 0x4E,
+        }
+    }
+}
+
+/// CRC-16/CCITT as computed by the floppy controller, starting from 0xFFFF.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Data bits of `count` bytes starting at cell `at` (each byte is 16 cells, clock first).
+fn read_bytes(bits: &[u8], at: usize, count: usize) -> Option<Vec<u8>> {
+    let cells = bits.get(at..at + count * 16)?;
+    Some(cells.chunks_exact(16).map(|c| (0..8).fold(0, |byte, i| (byte << 1) | c[i * 2 + 1])).collect())
+}
+
+/// Finds the next address mark at or after cell `from`: the cell after it and the mark byte.
+fn next_mark(bits: &[u8], from: usize, encoding: Encoding) -> Option<(usize, u8)> {
+    let mut window = 0u16;
+    for at in from..bits.len() {
+        window = (window << 1) | bits[at] as u16;
+        match (encoding, window) {
+            (Encoding::Mfm, MFM_SYNC) => {
+                let mut pos = at + 1;
+                while read_raw(bits, pos) == Some(MFM_SYNC) {
+                    pos += 16;
+                }
+                return Some((pos + 16, read_bytes(bits, pos, 1)?[0]));
+            }
+            (Encoding::Fm, FM_ID_MARK) => return Some((at + 1, ID_MARK)),
+            (Encoding::Fm, FM_DATA_MARK) => return Some((at + 1, DATA_MARK)),
+            (Encoding::Fm, FM_DELETED_MARK) => return Some((at + 1, DELETED_MARK)),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn read_raw(bits: &[u8], at: usize) -> Option<u16> {
+    Some(bits.get(at..at + 16)?.iter().fold(0u16, |w, &b| (w << 1) | b as u16))
+}
+
+/// CRC of an address mark and the bytes after it; MFM marks are preceded by three A1 syncs.
+fn field_crc(encoding: Encoding, mark: u8, bytes: &[u8]) -> u16 {
+    let mut field = match encoding {
+        Encoding::Mfm => vec![0xA1, 0xA1, 0xA1, mark],
+        Encoding::Fm => vec![mark],
+    };
+    field.extend_from_slice(bytes);
+    crc16(&field)
+}
+
+/// Finds and decodes every IBM sector in one revolution of cells (one byte per cell, 1 = flux
+/// transition), in the order they pass the head. Returns the sectors and the number of ID
+/// fields with bad CRCs. An ID repeated on the track keeps its first copy with good data.
+pub fn decode_track(bits: &[u8], encoding: Encoding) -> (Vec<Sector>, usize) {
+    let (found, bad_headers) = scan_track(bits, encoding);
+    let mut sectors: Vec<Sector> = Vec::new();
+    found.into_iter().for_each(|(sector, _)| add_sector(&mut sectors, sector));
+    (sectors, bad_headers)
+}
+
+/// A sector as found on the track, with the cell offsets of its ID field and of the end of its
+/// data field (or ID field when there is no data).
+pub type Found = (Sector, (usize, usize));
+
+/// Every sector on a track as found, repeated IDs included.
+pub fn scan_track(bits: &[u8], encoding: Encoding) -> (Vec<Found>, usize) {
+    let mut sectors = Vec::new();
+    let mut bad_headers = 0;
+    let mut at = 0;
+    while let Some((pos, mark)) = next_mark(bits, at, encoding) {
+        at = pos;
+        if mark != ID_MARK {
+            continue;
+        }
+        let Some(id) = read_bytes(bits, pos, 6) else { break };
+        if field_crc(encoding, ID_MARK, &id[..4]) != u16::from_be_bytes([id[4], id[5]]) {
+            bad_headers += 1;
+            continue;
+        }
+        at = pos + 6 * 16;
+        let size = 128usize << (id[3] & 7);
+        let mut sector = Sector { id: id[2], cylinder: id[0], head: id[1], size, ..Default::default() };
+        let data_field = next_mark(bits, at, encoding)
+            .filter(|&(dpos, dmark)| dpos - at <= SEARCH_LIMIT * 16 && (dmark == DATA_MARK || dmark == DELETED_MARK))
+            .and_then(|(dpos, dmark)| Some((dpos, dmark, read_bytes(bits, dpos, size + 2)?)));
+        if let Some((dpos, dmark, field)) = data_field {
+            sector.deleted = dmark == DELETED_MARK;
+            sector.crc_error = field_crc(encoding, dmark, &field[..size]) != u16::from_be_bytes([field[size], field[size + 1]]);
+            sector.data = Some(field[..size].to_vec());
+            sector.compressed = sector.is_uniform();
+            at = dpos + (size + 2) * 16;
+        }
+        sectors.push((sector, (pos, at)));
+    }
+    (sectors, bad_headers)
+}
+
+/// Adds a decoded sector to a track, or if its ID is already there keeps whichever copy has
+/// good data.
+pub fn add_sector(sectors: &mut Vec<Sector>, sector: Sector) {
+    match sectors.iter_mut().find(|s| (s.id, s.cylinder, s.head) == (sector.id, sector.cylinder, sector.head)) {
+        Some(found) if (found.data.is_none() || found.crc_error) && sector.data.is_some() && !sector.crc_error => *found = sector,
+        Some(_) => {}
+        None => sectors.push(sector),
+    }
+}
+
+/// Builds cells a byte at a time, working out MFM clock bits from the neighbouring data bits.
+struct CellWriter {
+    encoding: Encoding,
+    cells: Vec<u8>,
+    last: u8, // Previous data bit
+}
+
+impl CellWriter {
+    fn bytes(&mut self, value: u8, count: usize) {
+        for _ in 0..count {
+            for i in (0..8).rev() {
+                let bit = (value >> i) & 1;
+                let clock = match self.encoding {
+                    Encoding::Fm => 1,
+                    Encoding::Mfm => (self.last | bit) ^ 1,
+                };
+                self.cells.extend([clock, bit]);
+                self.last = bit;
+            }
+        }
+    }
+
+    fn data(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|&b| self.bytes(b, 1));
+    }
+
+    /// A mark with missing clock bits, written as raw cells.
+    fn raw(&mut self, pattern: u16, count: usize) {
+        for _ in 0..count {
+            self.cells.extend((0..16).rev().map(|i| ((pattern >> i) & 1) as u8));
+        }
+        self.last = (pattern & 1) as u8;
+    }
+
+    /// Sync bytes and an address mark.
+    fn mark(&mut self, mark: u8) {
+        match self.encoding {
+            Encoding::Mfm => {
+                self.bytes(0x00, 12);
+                self.raw(MFM_SYNC, 3);
+                self.bytes(mark, 1);
+            }
+            Encoding::Fm => {
+                self.bytes(0x00, 6);
+                self.raw(match mark { ID_MARK => FM_ID_MARK, DELETED_MARK => FM_DELETED_MARK, _ => FM_DATA_MARK }, 1);
+            }
+        }
+    }
+}
+
+/// Encodes a track in the IBM layout (index mark, then ID and data fields per sector in the
+/// track's physical order) as `cells` cells, one byte per cell. GAP3 is sized to fill the
+/// track; when the sectors do not fit even with short gaps the track is left longer.
+pub fn encode_track(track: &Track, encoding: Encoding, cells: usize) -> Result<Vec<u8>> {
+    // Bytes before the first sector (GAP4a, sync, index mark, GAP1) and around each sector
+    let (preamble, per_sector, per_data, max_gap3) = match encoding {
+        Encoding::Mfm => (80 + 12 + 4 + 50, 12 + 3 + 1 + 4 + 2 + 22, 12 + 3 + 1 + 2, 84),
+        Encoding::Fm => (40 + 6 + 1 + 26, 6 + 1 + 4 + 2 + 11, 6 + 1 + 2, 58),
+    };
+    let mut used = 0;
+    for sector in &track.sectors {
+        if !sector.size.is_power_of_two() || !(128..=16384).contains(&sector.size) {
+            return Err(anyhow!(
+                "Cyl {}, Head {}, Sector {} is {} bytes; IBM sectors are 128 << N bytes",
+                track.cylinder, track.head, sector.id, sector.size
+            ));
+        }
+        used += per_sector + sector.data.as_ref().map_or(0, |_| per_data + sector.size);
+    }
+    let available = (cells / 16) as isize - used as isize;
+    let count = track.sectors.len().max(1) as isize;
+    let full = available - preamble as isize >= count * 3;
+    let gap3 = ((available - if full { preamble as isize } else { 16 }) / count).clamp(3, max_gap3) as usize;
+
+    let mut writer = CellWriter { encoding, cells: Vec::with_capacity(cells), last: 0 };
+    let gap = encoding.gap_byte();
+    if full {
+        match encoding {
+            Encoding::Mfm => {
+                writer.bytes(gap, 80);
+                writer.bytes(0x00, 12);
+                writer.raw(MFM_INDEX_SYNC, 3);
+                writer.bytes(INDEX_MARK, 1);
+                writer.bytes(gap, 50);
+            }
+            Encoding::Fm => {
+                writer.bytes(gap, 40);
+                writer.bytes(0x00, 6);
+                writer.raw(FM_INDEX_MARK, 1);
+                writer.bytes(gap, 26);
+            }
+        }
+    } else {
+        writer.bytes(gap, 16);
+    }
+    for sector in &track.sectors {
+        let n = sector.size.trailing_zeros() as u8 - 7;
+        let id = [sector.cylinder, sector.head, sector.id, n];
+        writer.mark(ID_MARK);
+        writer.data(&id);
+        writer.data(&field_crc(encoding, ID_MARK, &id).to_be_bytes());
+        writer.bytes(gap, if encoding == Encoding::Mfm { 22 } else { 11 });
+        if let Some(data) = &sector.data {
+            let mark = if sector.deleted { DELETED_MARK } else { DATA_MARK };
+            writer.mark(mark);
+            writer.data(data);
+            let crc = field_crc(encoding, mark, data);
+            writer.data(&(if sector.crc_error { !crc } else { crc }).to_be_bytes());
+        }
+        writer.bytes(gap, gap3);
+    }
+    if writer.cells.len() < cells {
+        writer.bytes(gap, (cells - writer.cells.len()).div_ceil(16));
+        writer.cells.truncate(cells);
+    }
+    Ok(writer.cells)
+}
+
+/// A track with no sectors: gap bytes only.
+pub fn blank_track(encoding: Encoding, cells: usize) -> Vec<u8> {
+    let mut writer = CellWriter { encoding, cells: Vec::with_capacity(cells), last: 0 };
+    writer.bytes(encoding.gap_byte(), cells.div_ceil(16));
+    writer.cells.truncate(cells);
+    writer.cells
+}
@@ -0,0 +1,232 @@
+use crate::disk::{Disk, Sector, Track};
+use anyhow::{Result, anyhow};
+use ibm::Encoding;
+
+pub mod amiga;
+pub mod apple;
+pub mod capture;
+pub mod cbm;
+pub mod ibm;
+pub mod stats;
+
+/// Estimates the bit cell width (ns) from flux intervals, given how many cells the shortest
+/// interval of the encoding spans (2 for MFM, 1 for GCR).
+pub fn estimate_cell(flux_ns: &[u32], shortest_cells: u32) -> Option<f64> {
+    let mut sorted: Vec<u32> = flux_ns.iter().copied().filter(|&t| t > 0).collect();
+    if sorted.len() < 100 {
+        return None;
+    }
+    sorted.sort_unstable();
+    // The 10th percentile falls inside the cluster of shortest intervals
+    let reference = sorted[sorted.len() / 10] as f64;
+    let cluster: Vec<f64> = sorted.iter().map(|&t| t as f64).filter(|&t| t > reference * 0.75 && t < reference * 1.25).collect();
+    let mean = cluster.iter().sum::<f64>() / cluster.len() as f64;
+    Some(mean / shortest_cells as f64)
+}
+
+/// Turns flux intervals into a bit stream (one byte per bit cell, 1 = flux transition) using
+/// a simple PLL that tracks drift in the cell width within 10% of `cell_ns`.
+pub fn to_bits(flux_ns: &[u32], cell_ns: f64) -> Vec<u8> {
+    let mut bits = Vec::with_capacity(flux_ns.len() * 3);
+    let mut clock = cell_ns;
+    for &interval in flux_ns {
+        let interval = interval as f64;
+        let cells = (interval / clock).round().max(1.0);
+        bits.extend(std::iter::repeat_n(0, cells as usize - 1));
+        bits.push(1);
+        clock += (interval / cells - clock) * 0.05;
+        clock = clock.clamp(cell_ns * 0.9, cell_ns * 1.1);
+    }
+    bits
+}
+
+/// A sector decoded from a bit stream, addressed as recorded in its header.
+#[derive(Clone)]
+pub struct DecodedSector {
+    pub cylinder: u8,
+    pub head: u8,
+    pub sector: u8,
+    pub data: Option<Vec<u8>>, // None if the header was found but not the data block
+    pub data_ok: bool,         // Data checksum matched
+}
+
+/// Sectors found in one pass over a track. Headers with bad checksums are only counted,
+/// since their addresses cannot be trusted.
+#[derive(Default)]
+pub struct DecodedTrack {
+    pub sectors: Vec<DecodedSector>,
+    pub bad_headers: usize,
+}
+
+/// The best copy of each sector on a track (indexed by sector number), with the number of
+/// headers that had bad checksums.
+pub type MergedTrack = (Vec<Option<DecodedSector>>, usize);
+
+/// Combines several decodes of the same track (e.g., revolutions), keeping the first copy of
+/// each sector with good data, or failing that the first copy with any data.
+pub fn merge(passes: Vec<DecodedTrack>, sectors_per_track: usize) -> MergedTrack {
+    let mut slots: Vec<Option<DecodedSector>> = vec![None; sectors_per_track];
+    let mut bad_headers = 0;
+    for pass in passes {
+        bad_headers += pass.bad_headers;
+        for sector in pass.sectors {
+            let Some(slot) = slots.get_mut(sector.sector as usize) else { continue };
+            let better = match slot {
+                None => true,
+                Some(found) => !found.data_ok && (sector.data_ok || found.data.is_none() && sector.data.is_some()),
+            };
+            if better {
+                *slot = Some(sector);
+            }
+        }
+    }
+    (slots, bad_headers)
+}
+
+/// Describes bad and missing sectors on a merged track, or None if it decoded cleanly.
+pub fn track_problems(slots: &[Option<DecodedSector>], bad_headers: usize) -> Option<String> {
+    let list = |pick: &dyn Fn(&Option<DecodedSector>) -> bool| -> Vec<String> {
+        slots.iter().enumerate().filter(|(_, s)| pick(s)).map(|(i, _)| i.to_string()).collect()
+    };
+    let plural = |items: &[String]| if items.len() > 1 { "s" } else { "" };
+    let bad = list(&|s| s.as_ref().is_some_and(|found| found.data.is_some() && !found.data_ok));
+    let no_data = list(&|s| s.as_ref().is_some_and(|found| found.data.is_none()));
+    let missing = list(&|s| s.is_none());
+
+    let mut problems = Vec::new();
+    if !bad.is_empty() {
+        problems.push(format!("bad data checksum in sector{} {}", plural(&bad), bad.join(", ")));
+    }
+    if !no_data.is_empty() {
+        problems.push(format!("no data block for sector{} {}", plural(&no_data), no_data.join(", ")));
+    }
+    if missing.len() == slots.len() {
+        problems.push("no sectors found".to_string());
+    } else if !missing.is_empty() {
+        problems.push(format!("sector{} {} missing", plural(&missing), missing.join(", ")));
+    }
+    if bad_headers > 0 {
+        problems.push(format!("{} headers with bad checksums", bad_headers));
+    }
+    (!problems.is_empty()).then(|| problems.join("; "))
+}
+
+/// Tally of sector outcomes across a decoded disk, for summaries.
+#[derive(Default)]
+pub struct Tally {
+    pub good: usize,
+    pub bad: usize,
+    pub missing: usize,
+}
+
+/// Builds a track from merged sectors, with placeholders for sectors that were not found so
+/// the track keeps its full layout.
+pub fn build_track(cylinder: u8, head: u8, mode: u8, size: usize, slots: Vec<Option<DecodedSector>>, tally: &mut Tally) -> Track {
+    let mut track = Track { mode, cylinder, head, ..Default::default() };
+    for (id, slot) in slots.into_iter().enumerate() {
+        let mut sector = Sector { id: id as u8, cylinder, head, size, ..Default::default() };
+        match slot {
+            Some(found) => {
+                sector.cylinder = found.cylinder;
+                sector.head = found.head;
+                sector.crc_error = found.data.is_some() && !found.data_ok;
+                match found.data {
+                    Some(data) => {
+                        sector.data = Some(data);
+                        sector.compressed = sector.is_uniform();
+                        if found.data_ok { tally.good += 1 } else { tally.bad += 1 }
+                    }
+                    None => tally.missing += 1,
+                }
+            }
+            None => tally.missing += 1,
+        }
+        track.sectors.push(sector);
+    }
+    track
+}
+
+/// A sector to write with an encoder's `encode_track`.
+pub struct TrackSector<'a> {
+    pub sector: u8,
+    pub data: Option<&'a [u8]>, // None writes the header without a data block
+    pub bad_checksum: bool,     // Write a deliberately wrong data checksum
+}
+
+/// Expands packed bytes into one byte per bit, most significant bit first.
+pub fn unpack_bits(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().flat_map(|&b| (0..8).rev().map(move |i| (b >> i) & 1)).collect()
+}
+
+/// Packs one byte per bit into bytes, most significant bit first, zero-padding the last byte.
+pub fn pack_bits(bits: &[u8]) -> Vec<u8> {
+    bits.chunks(8).map(|chunk| chunk.iter().enumerate().fold(0, |byte, (i, &bit)| byte | (bit << (7 - i)))).collect()
+}
+
+/// Cell rate (thousands of cells per second) an IMD mode is written at: the disk's own rate
+/// at 300 RPM, so 300 kbps modes (a 250 kbps disk in a high-density drive) use 250 kbps.
+fn cell_rate(mode: u8) -> Option<usize> {
+    [500, 250, 250, 1000, 500, 500].get(mode as usize).copied()
+}
+
+/// A sector disk laid out as cells for the cell- and flux-level writers (HFE, SCP): one
+/// stream per cylinder and side, one byte per cell, all at the fastest track's cell rate.
+pub struct CellDisk {
+    pub kcells: usize,             // Cell rate, thousands of cells per second
+    pub rpm: usize,
+    pub encoding: Encoding,        // FM only if every track is FM
+    pub amiga: bool,               // Written in the Amiga trackdisk layout
+    pub sides: usize,
+    pub tracks: Vec<[Vec<u8>; 2]>, // By cylinder; both sides the same length
+}
+
+impl CellDisk {
+    /// Encodes every track in the IBM layout, each in its own encoding; slower tracks (FM track
+    /// 0 on an MFM disk) have their cells stretched. Amiga disks (see `amiga::is_amiga`) are
+    /// encoded in the Amiga trackdisk layout instead, and missing tracks are left blank.
+    /// `format` names the output in errors (e.g., "hfe").
+    pub fn new(disk: &Disk, format: &str) -> Result<CellDisk> {
+        let cylinders = disk.tracks.iter().map(|t| t.cylinder as usize + 1).max()
+            .ok_or_else(|| anyhow!("Cannot write .{}: the disk has no tracks", format))?;
+        let sides = disk.tracks.iter().map(|t| t.head as usize + 1).max().unwrap_or(1);
+        if sides > 2 {
+            return Err(anyhow!("Cannot write .{}: the disk has {} heads; at most two sides can be written", format, sides));
+        }
+        let rate_of = |track: &Track| cell_rate(track.mode)
+            .ok_or_else(|| anyhow!("Cannot write .{}: Cyl {}, Head {} has unknown mode {}", format, track.cylinder, track.head, track.mode));
+        let mut kcells = 0;
+        for track in &disk.tracks {
+            kcells = kcells.max(rate_of(track)?);
+        }
+        let rpm = if disk.tracks.iter().any(|t| t.mode == 0) { 360 } else { 300 }; // 8-inch FM disks spin at 360 RPM
+        let cells = kcells * 1000 * 60 / rpm;
+        let all_fm = disk.tracks.iter().all(|t| Encoding::for_mode(t.mode) == Encoding::Fm);
+        let encoding = if all_fm { Encoding::Fm } else { Encoding::Mfm };
+        let is_amiga = amiga::is_amiga(disk);
+
+        let mut tracks: Vec<[Vec<u8>; 2]> = Vec::with_capacity(cylinders);
+        for cylinder in 0..cylinders as u8 {
+            let mut pair = [Vec::new(), Vec::new()];
+            for (head, side) in pair.iter_mut().enumerate().take(sides) {
+                *side = match disk.tracks.iter().find(|t| t.cylinder == cylinder && t.head == head as u8) {
+                    Some(track) if is_amiga => amiga::encode_track(track, cells),
+                    None if is_amiga => amiga::encode_track(&Track::default(), cells),
+                    Some(track) => {
+                        let stretch = kcells / rate_of(track)?;
+                        let own = ibm::encode_track(track, Encoding::for_mode(track.mode), cells / stretch)
+                            .map_err(|e| anyhow!("Cannot write .{}: {}", format, e))?;
+                        own.iter().flat_map(|&cell| std::iter::once(cell).chain(std::iter::repeat_n(0, stretch - 1))).collect()
+                    }
+                    None => ibm::blank_track(encoding, cells),
+                };
+            }
+            let length = pair.iter().map(Vec::len).max().unwrap_or(0);
+            for side in pair.iter_mut() {
+                let missing = length - side.len();
+                side.extend(ibm::blank_track(encoding, missing));
+            }
+            tracks.push(pair);
+        }
+        Ok(CellDisk { kcells, rpm, encoding, amiga: is_amiga, sides, tracks })
+    }
+}
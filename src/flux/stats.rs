@@ -0,0 +1,183 @@
+use crate::flux::capture::Revolution;
+
+const RANGE_NS: u32 = 64_000;  // Longer intervals all land in the last histogram bin
+const PEAK_TOLERANCE: f64 = 0.15; // Intervals within 15% of a peak count towards it
+
+/// How the interval peaks line up with the bit cell.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Coding {
+    Fm,  // One and two cells
+    Mfm, // Two, three and four cells
+    Gcr, // One, two, three (and more) cells, one data bit per cell
+}
+
+impl Coding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Coding::Fm => "FM",
+            Coding::Mfm => "MFM",
+            Coding::Gcr => "GCR",
+        }
+    }
+
+    /// Shortest and longest interval the encoding allows, in cells.
+    fn cells(self) -> (f64, f64) {
+        match self {
+            Coding::Fm => (1.0, 2.0),
+            Coding::Mfm => (2.0, 4.0),
+            Coding::Gcr => (1.0, 3.0),
+        }
+    }
+
+    /// Data rate in kbps for a cell width: FM and MFM spend two cells on each data bit.
+    pub fn kbps(self, cell_ns: f64) -> f64 {
+        match self {
+            Coding::Fm | Coding::Mfm => 1e6 / (2.0 * cell_ns),
+            Coding::Gcr => 1e6 / cell_ns,
+        }
+    }
+}
+
+/// Flux statistics over one or more revolutions.
+pub struct FluxStats {
+    pub intervals: usize,
+    pub rpm: Vec<f64>,               // Per revolution, from the index-to-index time
+    pub drift_ns: u64,               // Longest minus shortest index-to-index time
+    pub peaks: Vec<(f64, f64)>,      // Peak centres (ns) and the share of intervals near each
+    pub cell: Option<(f64, Coding)>, // Bit cell width (ns) the peaks are multiples of
+    pub outliers: f64,               // Share of intervals away from the peaks or whole cells
+    pub histogram: Vec<usize>,       // Interval counts per bin, from 0 ns
+}
+
+impl FluxStats {
+    pub fn new<'a>(revolutions: impl IntoIterator<Item = &'a Revolution>, bin_ns: u32) -> FluxStats {
+        let bins = RANGE_NS.div_ceil(bin_ns) as usize;
+        let mut histogram = vec![0; bins];
+        let mut durations = Vec::new();
+        let mut flux: Vec<u32> = Vec::new();
+        for revolution in revolutions {
+            durations.push(revolution.duration_ns);
+            flux.extend(&revolution.flux);
+        }
+        for &interval in &flux {
+            histogram[((interval / bin_ns) as usize).min(bins - 1)] += 1;
+        }
+        let peaks = peaks(&histogram, bin_ns, &flux);
+        let cell = cell(&peaks);
+        let outlier = |t: f64| match cell {
+            Some((cell, coding)) => {
+                let cells = (t / cell).round();
+                let (shortest, longest) = coding.cells();
+                let longest = peaks.iter().map(|&(centre, _)| (centre / cell).round()).fold(longest, f64::max);
+                cells < shortest || cells > longest || (t - cells * cell).abs() > cell / 4.0
+            }
+            None => !peaks.iter().any(|&(centre, _)| (t - centre).abs() <= centre * PEAK_TOLERANCE),
+        };
+        let outliers = flux.iter().filter(|&&t| outlier(t as f64)).count();
+        let timed: Vec<u64> = durations.iter().copied().filter(|&d| d > 0).collect();
+        FluxStats {
+            intervals: flux.len(),
+            rpm: timed.iter().map(|&d| 60e9 / d as f64).collect(),
+            drift_ns: timed.iter().max().zip(timed.iter().min()).map_or(0, |(max, min)| max - min),
+            peaks,
+            cell,
+            outliers: if flux.is_empty() { 0.0 } else { outliers as f64 / flux.len() as f64 },
+            histogram,
+        }
+    }
+
+    /// Summary lines: RPM and drift, peaks, cell and outliers.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if !self.rpm.is_empty() {
+            // Each revolution for a track, the range over a whole disk
+            let rpm = if self.rpm.len() <= 5 {
+                self.rpm.iter().map(|r| format!("{:.2}", r)).collect::<Vec<_>>().join(", ")
+            } else {
+                let (min, max) = self.rpm.iter().fold((f64::MAX, 0.0f64), |(min, max), &r| (min.min(r), max.max(r)));
+                format!("{:.2}-{:.2} over {} revolutions", min, max, self.rpm.len())
+            };
+            let mean_ns = 60e9 / (self.rpm.iter().sum::<f64>() / self.rpm.len() as f64);
+            lines.push(format!(
+                "RPM: {} (index-to-index drift {:.3} ms, {:.2}%)",
+                rpm, self.drift_ns as f64 / 1e6, self.drift_ns as f64 * 100.0 / mean_ns
+            ));
+        }
+        let peaks: Vec<String> = self.peaks.iter().map(|&(centre, share)| format!("{:.2} µs ({:.1}%)", centre / 1000.0, share * 100.0)).collect();
+        lines.push(format!("Peaks: {}", if peaks.is_empty() { "none".to_string() } else { peaks.join(", ") }));
+        lines.push(match self.cell {
+            Some((cell, coding)) => format!("Cell: {:.3} µs, {} at {:.0} kbps", cell / 1000.0, coding.name(), coding.kbps(cell)),
+            None => "Cell: not detected".to_string(),
+        });
+        lines.push(format!("Outliers: {:.2}%", self.outliers * 100.0));
+        lines
+    }
+
+    /// Bar chart of the histogram from the first to the last bin holding at least 0.1% of the
+    /// intervals.
+    pub fn histogram_lines(&self, bin_ns: u32) -> Vec<String> {
+        let total: usize = self.histogram.iter().sum();
+        let busy = |&(_, &count): &(usize, &usize)| count * 1000 >= total && count > 0;
+        let (Some((first, _)), Some((last, _))) = (
+            self.histogram.iter().enumerate().find(busy),
+            self.histogram.iter().enumerate().rfind(busy),
+        ) else {
+            return Vec::new();
+        };
+        let max = self.histogram[first..=last].iter().copied().max().unwrap_or(1).max(1);
+        (first..=last).map(|bin| {
+            let count = self.histogram[bin];
+            format!("  {:>6.2} µs |{:<50}| {}", (bin as u32 * bin_ns) as f64 / 1000.0, "#".repeat(count * 50 / max), count)
+        }).collect()
+    }
+}
+
+/// Local maxima of the histogram (smoothed over three bins) holding at least 1% of the
+/// largest, as the mean interval near each and the share of intervals within tolerance.
+fn peaks(histogram: &[usize], bin_ns: u32, flux: &[u32]) -> Vec<(f64, f64)> {
+    let smoothed: Vec<usize> = (0..histogram.len())
+        .map(|i| histogram[i.saturating_sub(1)..(i + 2).min(histogram.len())].iter().sum())
+        .collect();
+    let highest = smoothed.iter().copied().max().unwrap_or(0);
+    let mut peaks: Vec<(f64, f64)> = Vec::new();
+    // The last bin collects every long interval, so it is never a peak
+    for i in 1..smoothed.len().saturating_sub(2) {
+        if smoothed[i] * 100 < highest || smoothed[i] <= smoothed[i - 1] || smoothed[i] < smoothed[i + 1] {
+            continue;
+        }
+        let centre = (i as f64 + 0.5) * bin_ns as f64;
+        let near: Vec<f64> = flux.iter().map(|&t| t as f64).filter(|t| (t - centre).abs() <= centre * PEAK_TOLERANCE).collect();
+        let mean = near.iter().sum::<f64>() / near.len().max(1) as f64;
+        // Plateaus and jitter can make two maxima for one peak
+        if peaks.last().is_some_and(|&(previous, _)| mean - previous < previous * PEAK_TOLERANCE) {
+            continue;
+        }
+        peaks.push((mean, near.len() as f64 / flux.len().max(1) as f64));
+    }
+    peaks
+}
+
+/// Cell width the peaks are whole multiples of: one cell for the shortest peak (FM or GCR),
+/// or two (MFM). Needs at least two peaks.
+fn cell(peaks: &[(f64, f64)]) -> Option<(f64, Coding)> {
+    let &(shortest, _) = peaks.first()?;
+    if peaks.len() < 2 {
+        return None;
+    }
+    [1.0, 2.0].into_iter().find_map(|cells| {
+        let cell = shortest / cells;
+        let multiples: Vec<f64> = peaks.iter().map(|&(centre, _)| centre / cell).collect();
+        if multiples.iter().any(|m| (m - m.round()).abs() > PEAK_TOLERANCE) {
+            return None;
+        }
+        // Refine the cell from every peak rather than only the shortest
+        let cell = peaks.iter().map(|&(centre, _)| centre).sum::<f64>() / multiples.iter().map(|m| m.round()).sum::<f64>();
+        let longest = multiples.iter().fold(0.0f64, |a, m| a.max(m.round()));
+        match (cells as u8, longest as u8) {
+            (1, 2) => Some((cell, Coding::Fm)),
+            (1, _) => Some((cell, Coding::Gcr)),
+            (_, 3..=4) => Some((cell, Coding::Mfm)),
+            _ => None,
+        }
+    })
+}
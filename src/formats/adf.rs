@@ -0,0 +1,142 @@
+use crate::{FormatHandler, Geometry};
+use crate::disk::{Disk, Order, Placement, Sector, Track};
+use crate::fs::amiga::{AmigaDos, BootBlock};
+use crate::fs::logical_sectors;
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+const SECTOR_SIZE: usize = 512;
+const CYLINDERS: u8 = 80;
+const HEADS: u8 = 2;
+
+/// Amiga Disk File: the 512-byte sectors of an 80-cylinder, 2-head disk in track order,
+/// 11 sectors per track for double density (880K) or 22 for high density (1760K).
+pub struct ADFHandler {
+    data: Vec<u8>,
+}
+
+impl ADFHandler {
+    pub fn new(data: Vec<u8>) -> Self {
+        ADFHandler { data }
+    }
+
+    fn sectors_per_track(&self) -> Result<u8> {
+        match self.data.len() {
+            901_120 => Ok(11),
+            1_802_240 => Ok(22),
+            size => Err(anyhow!(
+                "Invalid .adf file: {} bytes. ADF images are 901120 bytes (880K, double density) or 1802240 bytes (1760K, high density).",
+                size
+            )),
+        }
+    }
+
+    /// IMD mode for the sector data rate: 250 kbps MFM for DD, 500 kbps MFM for HD.
+    fn mode(sectors_per_track: u8) -> u8 {
+        if sectors_per_track > 11 { 3 } else { 5 }
+    }
+}
+
+impl FormatHandler for ADFHandler {
+    fn display(&self, ascii: bool) -> Result<String> {
+        let spt = self.sectors_per_track()?;
+        let mut output = Vec::new();
+        output.push(format!("Amiga ADF: {} bytes ({})", self.data.len(), if spt > 11 { "high density" } else { "double density" }));
+        if !ascii {
+            output.push(format!(
+                "Detected Geometry: {} cylinders, {} heads, {} sectors/track, {} bytes/sector",
+                CYLINDERS, HEADS, spt, SECTOR_SIZE
+            ));
+            match BootBlock::parse(&self.data) {
+                Some(boot) => output.push(format!(
+                    "Bootblock: DOS\\{} ({}), {}",
+                    self.data[3], boot.describe(),
+                    if boot.bootable { "bootable (checksum valid)" } else { "not bootable" }
+                )),
+                None => output.push("Bootblock: not an AmigaDOS disk (no DOS signature)".to_string()),
+            }
+            if let Some(fs) = AmigaDos::open(logical_sectors(&self.disk()?)) {
+                output.push(format!("Volume: \"{}\"", fs.volume_name()));
+                output.push(match fs.free_blocks() {
+                    Some(free) => format!("Free Blocks: {} of {}", free, self.data.len() / SECTOR_SIZE - 2),
+                    None => "Free Blocks: unknown (bitmap marked invalid)".to_string(),
+                });
+            }
+        } else {
+            for (index, chunk) in self.data.chunks(SECTOR_SIZE).enumerate() {
+                let track = index / spt as usize;
+                let ascii_str: String = chunk.iter()
+                    .take(32)
+                    .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
+                    .collect();
+                output.push(format!(
+                    "Cyl {}, Head {}, Sector {}, Size {} bytes: {}",
+                    track / HEADS as usize, track % HEADS as usize, index % spt as usize, SECTOR_SIZE, ascii_str
+                ));
+            }
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        let disk = self.disk()?;
+        std::fs::write(output_path, target.encode(&disk)?)?;
+        if verbose {
+            println!("Wrote {} tracks of {} sectors", disk.tracks.len(), self.sectors_per_track()?);
+        }
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        let spt = self.sectors_per_track()?;
+        Ok(Some(Geometry::Manual { cylinders: CYLINDERS, heads: HEADS, sectors_per_track: spt, sector_size: SECTOR_SIZE as u16, mode: Self::mode(spt) }))
+    }
+
+    /// Amiga sectors are numbered from 0 on each track.
+    fn disk(&self) -> Result<Disk> {
+        let spt = self.sectors_per_track()?;
+        let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+        let mut chunks = self.data.chunks(SECTOR_SIZE);
+        for cylinder in 0..CYLINDERS {
+            for head in 0..HEADS {
+                let mut track = Track { mode: Self::mode(spt), cylinder, head, ..Default::default() };
+                for id in 0..spt {
+                    let chunk = chunks.next().unwrap_or_default();
+                    let mut sector = Sector { id, cylinder, head, size: SECTOR_SIZE, data: Some(chunk.to_vec()), ..Default::default() };
+                    sector.compressed = sector.is_uniform();
+                    track.sectors.push(sector);
+                }
+                disk.tracks.push(track);
+            }
+        }
+        Ok(disk)
+    }
+
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let sectors = disk.sectors(Order::Logical);
+        if let Some((track, sector)) = sectors.iter().find(|(_, s)| s.size != SECTOR_SIZE) {
+            return Err(anyhow!(
+                "Cannot write .adf: Cyl {}, Head {}, Sector {} is {} bytes; ADF needs 512-byte sectors",
+                track.cylinder, track.head, sector.id, sector.size
+            ));
+        }
+        if sectors.len() != 1760 && sectors.len() != 3520 {
+            return Err(anyhow!(
+                "Cannot write .adf: the disk has {} sectors; ADF needs 1760 (880K) or 3520 (1760K)",
+                sectors.len()
+            ));
+        }
+        let mut raw_data = Vec::with_capacity(sectors.len() * SECTOR_SIZE);
+        for (_, sector) in sectors {
+            match &sector.data {
+                Some(data) => raw_data.extend_from_slice(data),
+                None => raw_data.resize(raw_data.len() + SECTOR_SIZE, 0),
+            }
+        }
+        Ok(raw_data)
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{FormatHandler, Geometry};
+use crate::disk::{Disk, Order, Placement, Sector, Track};
+use crate::flux::{self, apple::{self, DOS_ORDER, PRODOS_ORDER}};
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+const SECTOR_SIZE: usize = 256;
+const SECTORS: usize = 16;
+const TRACK_SIZE: usize = SECTOR_SIZE * SECTORS;
+
+/// How the 16 sectors of each track are arranged in a sector image.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SectorOrder {
+    Dos,    // DOS 3.3 logical sector order (.do, most .dsk)
+    ProDos, // ProDOS block order (.po)
+}
+
+impl SectorOrder {
+    /// Physical sector for each position in the image's track.
+    pub fn table(self) -> &'static [u8; 16] {
+        match self {
+            SectorOrder::Dos => &DOS_ORDER,
+            SectorOrder::ProDos => &PRODOS_ORDER,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SectorOrder::Dos => "DOS 3.3 order",
+            SectorOrder::ProDos => "ProDOS order",
+        }
+    }
+}
+
+/// Volume number for address fields: the one in the DOS 3.3 VTOC (track 17, sector 0) if
+/// there is one, otherwise 254 as DOS formats by default.
+pub fn volume(disk: &Disk) -> u8 {
+    disk.tracks.iter().find(|t| t.cylinder == 17 && t.head == 0)
+        .and_then(|t| t.sectors.iter().find(|s| s.id == 0))
+        .and_then(|s| s.data.as_ref())
+        .filter(|d| d.len() == SECTOR_SIZE && d[1] == 17 && d[0x35] == SECTORS as u8)
+        .map_or(254, |d| d[6])
+}
+
+/// Bit streams of each track (by track number, empty if absent) in the DOS 3.3 16-sector
+/// layout, for the NIB and WOZ writers. `target` names the format in errors.
+pub fn encode_tracks(disk: &Disk, target: &str) -> Result<Vec<Vec<u8>>> {
+    let volume = volume(disk);
+    let mut out: Vec<Vec<u8>> = Vec::new();
+    for track in &disk.tracks {
+        if track.head != 0 {
+            return Err(anyhow!("Cannot write {}: the disk has a second side (Cyl {}, Head {})", target, track.cylinder, track.head));
+        }
+        if track.cylinder >= 40 {
+            return Err(anyhow!("Cannot write {}: track {} is beyond track 39", target, track.cylinder));
+        }
+        let mut sectors: Vec<&Sector> = track.sectors.iter().collect();
+        sectors.sort_by_key(|s| s.id);
+        if let Some(s) = sectors.iter().find(|s| s.size != SECTOR_SIZE || s.id as usize >= SECTORS) {
+            return Err(anyhow!(
+                "Cannot write {}: track {} sector {} ({} bytes) does not fit the 16 x 256-byte layout",
+                target, track.cylinder, s.id, s.size
+            ));
+        }
+        let layout: Vec<flux::TrackSector> = sectors.iter().map(|s| flux::TrackSector {
+            sector: s.id,
+            data: s.data.as_deref(),
+            bad_checksum: s.crc_error,
+        }).collect();
+        let index = track.cylinder as usize;
+        if out.len() <= index {
+            out.resize(index + 1, Vec::new());
+        }
+        out[index] = apple::encode_track(volume, track.cylinder, &layout);
+    }
+    Ok(out)
+}
+
+/// Apple II 5.25-inch sector image: 35 (or 40) tracks of sixteen 256-byte sectors, in DOS 3.3
+/// or ProDOS order.
+pub struct AppleHandler {
+    data: Vec<u8>,
+    order: SectorOrder,
+}
+
+impl AppleHandler {
+    /// With no order given (.dsk), the order is guessed from where the DOS 3.3 VTOC or ProDOS
+    /// volume directory turns up, defaulting to DOS 3.3.
+    pub fn new(data: Vec<u8>, order: Option<SectorOrder>) -> Self {
+        let order = order.unwrap_or_else(|| {
+            [SectorOrder::Dos, SectorOrder::ProDos].into_iter()
+                .find(|&o| Self::looks_like_filesystem(&data, o))
+                .unwrap_or(SectorOrder::Dos)
+        });
+        AppleHandler { data, order }
+    }
+
+    fn physical(data: &[u8], order: SectorOrder, track: usize, physical: u8) -> Option<&[u8]> {
+        let position = order.table().iter().position(|&p| p == physical)?;
+        let start = track * TRACK_SIZE + position * SECTOR_SIZE;
+        data.get(start..start + SECTOR_SIZE)
+    }
+
+    /// True if the DOS 3.3 catalog chain or the ProDOS volume directory key block (block 2,
+    /// track 0 physical sector 8) is where this order puts it. The VTOC itself is physical
+    /// sector 0 in both orders, so the DOS check follows the catalog from it instead.
+    fn looks_like_filesystem(data: &[u8], order: SectorOrder) -> bool {
+        let dos_sector = |logical: u8| DOS_ORDER.get(logical as usize).and_then(|&p| Self::physical(data, order, 17, p));
+        let catalog = Self::physical(data, order, 17, 0)
+            .filter(|vtoc| vtoc[1] == 17 && vtoc[0x27] == 122 && vtoc[0x35] == SECTORS as u8)
+            .and_then(|vtoc| dos_sector(vtoc[2]))
+            .filter(|first| first[1] == 17 && first[2] > 0)
+            .and_then(|first| Some((first[2], dos_sector(first[2])?)));
+        let dos = catalog.is_some_and(|(sector, next)| next[1] == 17 && next[2] == sector - 1);
+        let prodos = Self::physical(data, order, 0, 8).is_some_and(|s| s[0] == 0 && s[1] == 0 && s[4] >> 4 == 0x0F);
+        dos || prodos
+    }
+
+    fn tracks(&self) -> Result<usize> {
+        match self.data.len() {
+            143_360 => Ok(35),
+            163_840 => Ok(40),
+            size => Err(anyhow!(
+                "Invalid Apple II sector image: {} bytes. Expected 143360 bytes (35 tracks) or 163840 (40 tracks) of 16 sectors each.",
+                size
+            )),
+        }
+    }
+}
+
+impl FormatHandler for AppleHandler {
+    fn display(&self, ascii: bool) -> Result<String> {
+        let tracks = self.tracks()?;
+        let mut output = Vec::new();
+        output.push(format!("Apple II Sector Image: {} bytes ({})", self.data.len(), self.order.name()));
+        if !ascii {
+            output.push(format!(
+                "Detected Geometry: {} tracks, 1 head, {} sectors/track, {} bytes/sector",
+                tracks, SECTORS, SECTOR_SIZE
+            ));
+        } else {
+            for (track, sector) in self.disk()?.sectors(Order::Physical) {
+                let ascii_str: String = sector.data.as_deref().unwrap_or_default().iter()
+                    .take(32)
+                    .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
+                    .collect();
+                output.push(format!("Track {}, Sector {}, Size {} bytes: {}", track.cylinder, sector.id, sector.size, ascii_str));
+            }
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        Ok(Some(Geometry::Manual { cylinders: self.tracks()? as u8, heads: 1, sectors_per_track: SECTORS as u8, sector_size: SECTOR_SIZE as u16, mode: 5 }))
+    }
+
+    /// Sector IDs are the physical sector numbers from the address fields, so a disk read
+    /// from a .do and one read from a .po compare equal.
+    fn disk(&self) -> Result<Disk> {
+        let tracks = self.tracks()?;
+        let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+        for number in 0..tracks {
+            let cylinder = number as u8;
+            let mut track = Track { mode: 5, cylinder, head: 0, ..Default::default() };
+            for id in 0..SECTORS as u8 {
+                let data = Self::physical(&self.data, self.order, number, id).unwrap_or_default();
+                let mut sector = Sector { id, cylinder, head: 0, size: SECTOR_SIZE, data: Some(data.to_vec()), ..Default::default() };
+                sector.compressed = sector.is_uniform();
+                track.sectors.push(sector);
+            }
+            disk.tracks.push(track);
+        }
+        Ok(disk)
+    }
+
+    /// Writes each track's physical sectors in this image's order; missing sectors are
+    /// zero-filled.
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let mut tracks: Vec<&Track> = disk.tracks.iter().collect();
+        tracks.sort_by_key(|t| (t.cylinder, t.head));
+        if let Some(t) = tracks.iter().find(|t| t.head != 0) {
+            return Err(anyhow!("Cannot write an Apple II sector image: the disk has a second side (Cyl {}, Head {})", t.cylinder, t.head));
+        }
+        if tracks.len() != 35 && tracks.len() != 40 {
+            return Err(anyhow!("Cannot write an Apple II sector image: the disk has {} tracks; 35 or 40 are needed", tracks.len()));
+        }
+        let mut raw_data = Vec::with_capacity(tracks.len() * TRACK_SIZE);
+        for track in tracks {
+            if let Some(s) = track.sectors.iter().find(|s| s.size != SECTOR_SIZE || s.id as usize >= SECTORS) {
+                return Err(anyhow!(
+                    "Cannot write an Apple II sector image: Cyl {}, Sector {} ({} bytes) does not fit the 16 x 256-byte layout",
+                    track.cylinder, s.id, s.size
+                ));
+            }
+            for &physical in self.order.table() {
+                match track.sectors.iter().find(|s| s.id == physical).and_then(|s| s.data.as_ref()) {
+                    Some(data) => raw_data.extend_from_slice(data),
+                    None => raw_data.resize(raw_data.len() + SECTOR_SIZE, 0),
+                }
+            }
+        }
+        Ok(raw_data)
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{FormatHandler, Geometry};
+use crate::disk::{Disk, Order, Placement, Sector, Track};
+use crate::flux::cbm::sectors_per_track;
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+const SECTOR_SIZE: usize = 256;
+
+/// Number of sectors on a disk with this many tracks (35, 40 or 42).
+fn total_sectors(tracks: u8) -> usize {
+    (1..=tracks).map(|t| sectors_per_track(t) as usize).sum()
+}
+
+fn error_name(code: u8) -> &'static str {
+    match code {
+        0x00 | 0x01 => "OK",
+        0x02 => "header block not found",
+        0x03 => "no sync mark",
+        0x04 => "data block not found",
+        0x05 => "data checksum error",
+        0x06 => "write verify error",
+        0x07 => "write verify error",
+        0x08 => "write protected",
+        0x09 => "header checksum error",
+        0x0A => "write error",
+        0x0B => "disk ID mismatch",
+        0x0F => "drive not ready",
+        _ => "unknown error",
+    }
+}
+
+/// Commodore 1541 disk image: 256-byte sectors in track order (tracks numbered from 1, with
+/// 21/19/18/17 sectors in the four speed zones), optionally followed by one error code per
+/// sector.
+pub struct D64Handler {
+    data: Vec<u8>,
+}
+
+impl D64Handler {
+    pub fn new(data: Vec<u8>) -> Self {
+        D64Handler { data }
+    }
+
+    /// Track count and whether error bytes are present, from the file size.
+    fn layout(&self) -> Result<(u8, bool)> {
+        for tracks in [35, 40, 42] {
+            let sectors = total_sectors(tracks);
+            if self.data.len() == sectors * SECTOR_SIZE {
+                return Ok((tracks, false));
+            }
+            if self.data.len() == sectors * (SECTOR_SIZE + 1) {
+                return Ok((tracks, true));
+            }
+        }
+        Err(anyhow!(
+            "Invalid .d64 file: {} bytes. Expected 174848 bytes (35 tracks), 196608 (40 tracks) or 205312 (42 tracks), plus one error byte per sector if error info is included.",
+            self.data.len()
+        ))
+    }
+
+    fn error_bytes(&self) -> Result<Option<&[u8]>> {
+        let (tracks, errors) = self.layout()?;
+        let data_size = total_sectors(tracks) * SECTOR_SIZE;
+        Ok(errors.then(|| &self.data[data_size..]))
+    }
+}
+
+impl FormatHandler for D64Handler {
+    fn display(&self, ascii: bool) -> Result<String> {
+        let (tracks, _) = self.layout()?;
+        let errors = self.error_bytes()?;
+        let mut output = Vec::new();
+        output.push(format!(
+            "Commodore D64: {} bytes ({} tracks, {} sectors, {})",
+            self.data.len(), tracks, total_sectors(tracks), if errors.is_some() { "with error info" } else { "no error info" }
+        ));
+        if !ascii {
+            output.push(format!(
+                "Detected Geometry: {} tracks, 1 head, 17-21 sectors/track (zoned), {} bytes/sector",
+                tracks, SECTOR_SIZE
+            ));
+            if let Some(errors) = errors {
+                let disk = self.disk()?;
+                let flagged: Vec<String> = disk.sectors(Order::Logical).iter().zip(errors)
+                    .filter(|(_, &code)| code > 0x01)
+                    .map(|((track, sector), &code)| format!("Track {}, Sector {}: {:02X} {}", track.cylinder + 1, sector.id, code, error_name(code)))
+                    .collect();
+                output.push(format!("Sector Errors: {}", flagged.len()));
+                output.extend(flagged.iter().map(|line| format!("  {}", line)));
+            }
+        } else {
+            for (track, sector) in self.disk()?.sectors(Order::Logical) {
+                let ascii_str: String = sector.data.as_deref().unwrap_or_default().iter()
+                    .take(32)
+                    .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
+                    .collect();
+                output.push(format!("Track {}, Sector {}, Size {} bytes: {}", track.cylinder + 1, sector.id, sector.size, ascii_str));
+            }
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        Ok(None) // Zoned recording has no single sectors-per-track value
+    }
+
+    /// Track N is stored as cylinder N-1, with the 1541's track number in each sector's ID.
+    /// Error codes 02-04 mark the sector's data as missing, 05 and 09 as a CRC error.
+    fn disk(&self) -> Result<Disk> {
+        let (tracks, _) = self.layout()?;
+        let errors = self.error_bytes()?;
+        let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+        let mut chunks = self.data.chunks(SECTOR_SIZE);
+        let mut index = 0;
+        for number in 1..=tracks {
+            let mut track = Track { mode: 5, cylinder: number - 1, head: 0, ..Default::default() };
+            for id in 0..sectors_per_track(number) {
+                let chunk = chunks.next().unwrap_or_default();
+                let code = errors.map_or(0x01, |e| e[index]);
+                let mut sector = Sector { id, cylinder: number, head: 0, size: SECTOR_SIZE, ..Default::default() };
+                if !(0x02..=0x04).contains(&code) {
+                    sector.data = Some(chunk.to_vec());
+                    sector.compressed = sector.is_uniform();
+                }
+                sector.crc_error = code == 0x05 || code == 0x09;
+                track.sectors.push(sector);
+                index += 1;
+            }
+            disk.tracks.push(track);
+        }
+        Ok(disk)
+    }
+
+    /// Error bytes are appended only when some sector is missing data or has a CRC error.
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let sectors = disk.sectors(Order::Logical);
+        if let Some((track, sector)) = sectors.iter().find(|(_, s)| s.size != SECTOR_SIZE) {
+            return Err(anyhow!(
+                "Cannot write .d64: Cyl {}, Head {}, Sector {} is {} bytes; D64 needs 256-byte sectors",
+                track.cylinder, track.head, sector.id, sector.size
+            ));
+        }
+        if ![35, 40, 42].iter().any(|&t| total_sectors(t) == sectors.len()) {
+            return Err(anyhow!(
+                "Cannot write .d64: the disk has {} sectors; D64 needs 683 (35 tracks), 768 (40 tracks) or 802 (42 tracks)",
+                sectors.len()
+            ));
+        }
+        let mut raw_data = Vec::with_capacity(sectors.len() * (SECTOR_SIZE + 1));
+        let mut errors = Vec::with_capacity(sectors.len());
+        for (_, sector) in &sectors {
+            match &sector.data {
+                Some(data) => raw_data.extend_from_slice(data),
+                None => raw_data.resize(raw_data.len() + SECTOR_SIZE, 0),
+            }
+            errors.push(match (&sector.data, sector.crc_error) {
+                (None, _) => 0x04,
+                (Some(_), true) => 0x05,
+                _ => 0x01,
+            });
+        }
+        if errors.iter().any(|&code| code != 0x01) {
+            raw_data.extend(errors);
+        }
+        Ok(raw_data)
+    }
+}
@@ -0,0 +1,373 @@
+use crate::{FormatHandler, Geometry};
+use crate::formats::ParseError;
+use crate::disk::{Disk, Order, Placement, Sector, Track};
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+const STANDARD: &[u8] = b"MV - CPC";
+const EXTENDED: &[u8] = b"EXTENDED CPC DSK";
+const EXTENDED_HEADER: &[u8] = b"EXTENDED CPC DSK File\r\nDisk-Info\r\n";
+const TRACK_HEADER: &[u8] = b"Track-Info";
+const BLOCK_SIZE: usize = 0x100; // Disk and Track Information Blocks; track sizes are multiples of this
+const MAX_SECTORS: usize = 29; // Sector Information List entries that fit in a Track Information Block
+const GAP3: u8 = 0x4E;
+const FILLER: u8 = 0xE5;
+
+// FDC status bits recorded per sector
+const ST1_MISSING_ADDRESS: u8 = 0x01;
+const ST1_DATA_ERROR: u8 = 0x20;
+const ST2_MISSING_DATA: u8 = 0x01;
+const ST2_DATA_ERROR: u8 = 0x20;
+const ST2_DELETED: u8 = 0x40;
+
+/// True if the data starts with either DSK signature, so `.dsk` files can be told apart from
+/// Apple II sector images.
+pub fn is_dsk(data: &[u8]) -> bool {
+    data.starts_with(STANDARD) || data.starts_with(EXTENDED)
+}
+
+/// Disk Information Block fields shown by `display`.
+struct DiskInfo {
+    extended: bool,
+    creator: String,
+    tracks: u8,
+    sides: u8,
+}
+
+/// File offset and length of every sector's stored bytes, track by track.
+type StoredAt = Vec<Vec<(usize, usize)>>;
+
+/// IMD mode from the Extended DSK data rate (1 SD/DD, 2 HD, 3 ED) and recording mode (1 FM,
+/// 2 MFM); unknown values are taken as 250 kbps MFM.
+fn mode(rate: u8, recording: u8) -> u8 {
+    match (recording, rate) {
+        (1, 2 | 3) => 0,
+        (1, _) => 2,
+        (_, 2 | 3) => 3,
+        _ => 5,
+    }
+}
+
+/// Data rate and recording mode bytes for an IMD mode.
+fn rate_and_recording(mode: u8) -> (u8, u8) {
+    (if mode == 0 || mode == 3 { 2 } else { 1 }, if mode <= 2 { 1 } else { 2 })
+}
+
+/// FDC size code N for a sector size (128 << N).
+fn size_code(size: usize) -> Option<u8> {
+    (0..=8u8).find(|&n| 128 << n == size)
+}
+
+/// ST1/ST2 for a sector without recorded status, as a µPD765 would report its flags.
+fn derived_status(sector: &Sector) -> (u8, u8) {
+    let (mut st1, mut st2) = (0, 0);
+    if sector.data.is_none() {
+        st1 |= ST1_MISSING_ADDRESS;
+        st2 |= ST2_MISSING_DATA;
+    }
+    if sector.crc_error {
+        st1 |= ST1_DATA_ERROR;
+        st2 |= ST2_DATA_ERROR;
+    }
+    if sector.deleted {
+        st2 |= ST2_DELETED;
+    }
+    (st1, st2)
+}
+
+fn describe(sector: &Sector, st1: u8, st2: u8) -> String {
+    let mut notes = Vec::new();
+    if sector.data.is_none() {
+        notes.push("no data".to_string());
+    }
+    if (st1 | st2) & ST1_DATA_ERROR != 0 {
+        notes.push("data error".to_string());
+    }
+    if st2 & ST2_DELETED != 0 {
+        notes.push("deleted data".to_string());
+    }
+    if !sector.copies.is_empty() {
+        notes.push(format!("{} copies (weak)", sector.copies.len() + 1));
+    }
+    if notes.is_empty() {
+        notes.push("status bits only".to_string());
+    }
+    notes.join(", ")
+}
+
+/// Amstrad CPC / Spectrum +3 disk image, in the original "MV - CPC" layout (every track the
+/// same size) or "EXTENDED CPC DSK" (per-track sizes and per-sector data lengths). Each track
+/// lists its sectors' ID fields and FDC status registers ST1/ST2; an Extended DSK sector
+/// stored at a multiple of its size holds several reads of a weak sector.
+pub struct DSKHandler {
+    data: Vec<u8>,
+}
+
+impl DSKHandler {
+    pub fn new(data: Vec<u8>) -> Self {
+        DSKHandler { data }
+    }
+
+    /// The disk, with where each sector's stored bytes sit in the file, in the order of
+    /// `Disk::tracks`.
+    fn parse(&self) -> Result<(DiskInfo, Disk, StoredAt)> {
+        let data = &self.data;
+        if !is_dsk(data) || data.len() < BLOCK_SIZE {
+            return Err(ParseError::new("dsk", 0, format!(
+                "no \"MV - CPC\" or \"EXTENDED CPC DSK\" Disk Information Block ({} bytes). Apple II .dsk images are recognised by their size instead.",
+                data.len()
+            )).into());
+        }
+        let extended = data.starts_with(EXTENDED);
+        let info = DiskInfo {
+            extended,
+            creator: data[0x22..0x30].iter().take_while(|&&b| b != 0).map(|&b| b as char).collect::<String>().trim_end().to_string(),
+            tracks: data[0x30],
+            sides: data[0x31],
+        };
+        if !(1..=2).contains(&info.sides) {
+            return Err(ParseError::new("dsk", 0x31, format!("{} sides in the Disk Information Block; expected 1 or 2", info.sides)).into());
+        }
+        let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+        let mut stored_at = Vec::new();
+        let mut pos = BLOCK_SIZE;
+        for index in 0..info.tracks as usize * info.sides as usize {
+            let (cylinder, head) = ((index / info.sides as usize) as u8, (index % info.sides as usize) as u8);
+            let track_size = if extended {
+                data.get(0x34 + index).map_or(0, |&b| b as usize * BLOCK_SIZE)
+            } else {
+                u16::from_le_bytes([data[0x32], data[0x33]]) as usize
+            };
+            if track_size == 0 {
+                continue; // Unformatted track
+            }
+            let block = data.get(pos..pos + track_size).ok_or_else(|| ParseError::new("dsk", pos, format!(
+                "track: needs {} bytes but only {} remain", track_size, data.len() - pos.min(data.len())
+            )).track(cylinder, head))?;
+            if !block.starts_with(TRACK_HEADER) || block.len() < BLOCK_SIZE {
+                return Err(ParseError::new("dsk", pos, "no Track-Info block").track(cylinder, head).into());
+            }
+            let count = block[0x15] as usize;
+            if count > MAX_SECTORS {
+                return Err(ParseError::new("dsk", pos + 0x15, format!("{} sectors listed; at most {} fit the Track-Info block", count, MAX_SECTORS)).track(cylinder, head).into());
+            }
+            let mut track = Track { mode: mode(block[0x12], block[0x13]), cylinder, head, ..Default::default() };
+            let mut offset = BLOCK_SIZE;
+            let mut places = Vec::with_capacity(count);
+            for entry in block[0x18..0x18 + count * 8].chunks_exact(8) {
+                let (c, h, r, n, st1, st2) = (entry[0], entry[1], entry[2], entry[3], entry[4], entry[5]);
+                let size = 128 << n.min(8);
+                let stored = if extended { u16::from_le_bytes([entry[6], entry[7]]) as usize } else { 128 << block[0x14].min(8) };
+                let bytes = block.get(offset..offset + stored).ok_or_else(|| ParseError::new("dsk", pos + offset, format!(
+                    "sector data: needs {} bytes but only {} remain in the track block", stored, block.len() - offset.min(block.len())
+                )).track(cylinder, head).sector(r))?;
+                places.push((pos + offset, stored));
+                offset += stored;
+                // Every read is padded or cut to the sector size; only Extended DSK holds several
+                let mut reads: Vec<Vec<u8>> = if extended { bytes.chunks(size).map(<[u8]>::to_vec).collect() } else { vec![bytes[..stored.min(size)].to_vec()] };
+                reads.iter_mut().for_each(|read| read.resize(size, 0));
+                let mut reads = reads.into_iter();
+                let mut sector = Sector {
+                    id: r, cylinder: c, head: h, size,
+                    data: reads.next(),
+                    deleted: st2 & ST2_DELETED != 0,
+                    crc_error: (st1 & ST1_DATA_ERROR) | (st2 & ST2_DATA_ERROR) != 0,
+                    status: Some((st1, st2)),
+                    copies: reads.collect(),
+                    ..Default::default()
+                };
+                sector.compressed = sector.is_uniform() && sector.copies.is_empty();
+                track.sectors.push(sector);
+            }
+            disk.tracks.push(track);
+            stored_at.push(places);
+            pos += track_size;
+        }
+        Ok((info, disk, stored_at))
+    }
+
+    /// A copy of the loaded image with the sector data of `disk` written over it, or None if
+    /// the disk no longer fits the image's layout: other tracks or sectors, or data that
+    /// needs more or less room than is stored (a weak sector patched down to one read).
+    fn patched(&self, disk: &Disk) -> Result<Option<Vec<u8>>> {
+        let (info, original, stored_at) = self.parse()?;
+        if disk.tracks.len() != original.tracks.len() {
+            return Ok(None);
+        }
+        let mut out = self.data.clone();
+        for ((track, before), places) in disk.tracks.iter().zip(&original.tracks).zip(&stored_at) {
+            let same_track = track.cylinder == before.cylinder && track.head == before.head && track.sectors.len() == before.sectors.len();
+            if !same_track {
+                return Ok(None);
+            }
+            for ((sector, old), &(offset, stored)) in track.sectors.iter().zip(&before.sectors).zip(places) {
+                if sector.id != old.id || sector.size != old.size {
+                    return Ok(None);
+                }
+                let Some(data) = &sector.data else {
+                    if stored == 0 { continue } else { return Ok(None) }
+                };
+                let mut bytes: Vec<u8> = data.iter().chain(sector.copies.iter().flatten()).copied().collect();
+                // Reads were cut or zero-padded to the sector size when loaded; only that padding may go
+                if bytes.len() > stored {
+                    if bytes[stored..].iter().any(|&b| b != 0) {
+                        return Ok(None);
+                    }
+                    bytes.truncate(stored);
+                }
+                // A standard DSK slot larger than the sector keeps its filler
+                if bytes.len() < stored && !info.extended {
+                    bytes.extend_from_slice(&self.data[offset + bytes.len()..offset + stored]);
+                }
+                if bytes.len() != stored {
+                    return Ok(None);
+                }
+                out[offset..offset + stored].copy_from_slice(&bytes);
+            }
+        }
+        Ok(Some(out))
+    }
+}
+
+impl FormatHandler for DSKHandler {
+    fn display(&self, ascii: bool) -> Result<String> {
+        let (info, disk, _) = self.parse()?;
+        let mut output = Vec::new();
+        output.push(format!(
+            "Amstrad CPC DSK: {} bytes ({}, creator \"{}\")",
+            self.data.len(), if info.extended { "extended" } else { "standard" }, info.creator
+        ));
+        if !ascii {
+            let spt: Vec<usize> = disk.tracks.iter().map(|t| t.sectors.len()).collect();
+            let sizes: Vec<usize> = disk.tracks.iter().flat_map(|t| t.sectors.iter().map(|s| s.size)).collect();
+            let range = |values: &[usize]| match (values.iter().min(), values.iter().max()) {
+                (Some(min), Some(max)) if min != max => format!("{}-{}", min, max),
+                (Some(min), _) => min.to_string(),
+                _ => "0".to_string(),
+            };
+            output.push(format!(
+                "Detected Geometry: {} cylinders, {} heads, {} sectors/track, {} bytes/sector",
+                info.tracks, info.sides, range(&spt), range(&sizes)
+            ));
+            let unformatted = info.tracks as usize * info.sides as usize - disk.tracks.len();
+            if unformatted > 0 {
+                output.push(format!("Unformatted Tracks: {}", unformatted));
+            }
+            let flagged: Vec<String> = disk.sectors(Order::Physical).into_iter()
+                .filter_map(|(track, sector)| {
+                    let (st1, st2) = sector.status.unwrap_or_default();
+                    (st1 != 0 || st2 != 0 || !sector.copies.is_empty()).then(|| format!(
+                        "Cyl {}, Head {}, Sector ID {:02X} (C {}, H {}, N {}): ST1 {:02X} ST2 {:02X}, {}",
+                        track.cylinder, track.head, sector.id, sector.cylinder, sector.head,
+                        size_code(sector.size).unwrap_or(0), st1, st2, describe(sector, st1, st2)
+                    ))
+                })
+                .collect();
+            output.push(format!("Flagged Sectors: {}", flagged.len()));
+            output.extend(flagged.iter().map(|line| format!("  {}", line)));
+        } else {
+            for (track, sector) in disk.sectors(Order::Physical) {
+                let ascii_str: String = sector.data.as_deref().unwrap_or_default().iter()
+                    .take(32)
+                    .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
+                    .collect();
+                output.push(format!(
+                    "Cyl {}, Head {}, Sector {:02X}, Size {} bytes: {}",
+                    track.cylinder, track.head, sector.id, sector.size, ascii_str
+                ));
+            }
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        let (info, disk, _) = self.parse()?;
+        let Some(first) = disk.tracks.iter().find(|t| !t.sectors.is_empty()) else { return Ok(None) };
+        Ok(Some(Geometry::Manual {
+            cylinders: info.tracks,
+            heads: info.sides,
+            sectors_per_track: disk.tracks.iter().map(|t| t.sectors.len()).max().unwrap_or(0) as u8,
+            sector_size: first.sectors[0].size as u16,
+            mode: first.mode,
+        }))
+    }
+
+    /// Tracks are placed by their position in the file; sector IDs, cylinder and head come from
+    /// the Sector Information List. ST1/ST2 set the CRC error and deleted flags.
+    fn disk(&self) -> Result<Disk> {
+        Ok(self.parse()?.1)
+    }
+
+    /// Written back over the loaded image when the track layout is unchanged, so the variant
+    /// and every byte outside the sector data are kept. Otherwise writes an Extended DSK, which
+    /// can hold every track layout; sectors without recorded status get ST1/ST2 from their flags.
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        if is_dsk(&self.data) {
+            if let Some(out) = self.patched(disk)? {
+                return Ok(out);
+            }
+        }
+        let cylinders = disk.tracks.iter().map(|t| t.cylinder as usize + 1).max()
+            .ok_or_else(|| anyhow!("Cannot write .dsk: the disk has no tracks"))?;
+        let sides = disk.tracks.iter().map(|t| t.head as usize + 1).max().unwrap_or(1);
+        if sides > 2 || cylinders * sides > BLOCK_SIZE - 0x34 {
+            return Err(anyhow!(
+                "Cannot write .dsk: {} cylinders and {} heads do not fit the Disk Information Block (2 heads, {} tracks at most)",
+                cylinders, sides, BLOCK_SIZE - 0x34
+            ));
+        }
+        let mut out = vec![0u8; BLOCK_SIZE];
+        out[..EXTENDED_HEADER.len()].copy_from_slice(EXTENDED_HEADER);
+        let creator = b"floppytool";
+        out[0x22..0x22 + creator.len()].copy_from_slice(creator);
+        out[0x30] = cylinders as u8;
+        out[0x31] = sides as u8;
+        for index in 0..cylinders * sides {
+            let (cylinder, head) = ((index / sides) as u8, (index % sides) as u8);
+            let Some(track) = disk.tracks.iter().find(|t| t.cylinder == cylinder && t.head == head) else { continue };
+            if track.sectors.len() > MAX_SECTORS {
+                return Err(anyhow!(
+                    "Cannot write .dsk: Cyl {}, Head {} has {} sectors; a DSK track lists at most {}",
+                    cylinder, head, track.sectors.len(), MAX_SECTORS
+                ));
+            }
+            let mut block = vec![0u8; BLOCK_SIZE];
+            block[..12].copy_from_slice(b"Track-Info\r\n");
+            let (rate, recording) = rate_and_recording(track.mode);
+            block[0x10..0x18].copy_from_slice(&[cylinder, head, rate, recording, 0, track.sectors.len() as u8, GAP3, FILLER]);
+            for (i, sector) in track.sectors.iter().enumerate() {
+                let n = size_code(sector.size).ok_or_else(|| anyhow!(
+                    "Cannot write .dsk: Cyl {}, Head {}, Sector {} is {} bytes; DSK sizes are 128 << N",
+                    cylinder, head, sector.id, sector.size
+                ))?;
+                let (st1, st2) = sector.status.unwrap_or_else(|| derived_status(sector));
+                let start = block.len();
+                if let Some(data) = &sector.data {
+                    block.extend_from_slice(data);
+                    sector.copies.iter().for_each(|copy| block.extend_from_slice(copy));
+                }
+                let stored = (block.len() - start) as u16;
+                block[0x18 + i * 8..0x20 + i * 8].copy_from_slice(&[sector.cylinder, sector.head, sector.id, n, st1, st2, stored as u8, (stored >> 8) as u8]);
+                if i == 0 {
+                    block[0x14] = n;
+                }
+            }
+            block.resize(block.len().next_multiple_of(BLOCK_SIZE), 0);
+            if block.len() > 0xFF * BLOCK_SIZE {
+                return Err(anyhow!("Cannot write .dsk: Cyl {}, Head {} needs {} bytes; a DSK track holds at most {}", cylinder, head, block.len(), 0xFF * BLOCK_SIZE));
+            }
+            out[0x34 + index] = (block.len() / BLOCK_SIZE) as u8;
+            out.extend_from_slice(&block);
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,163 @@
+use crate::{FormatHandler, Geometry};
+use crate::formats::Reader;
+use crate::disk::{Disk, Placement, Sector};
+use crate::flux::{self, cbm};
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+const SIGNATURE: &[u8] = b"GCR-1541";
+const HALF_TRACKS: usize = 84;
+const MAX_TRACK_SIZE: usize = 7928;
+
+/// G64: the raw GCR bit stream of each 1541 half-track, with its speed zone.
+pub struct G64Handler {
+    data: Vec<u8>,
+}
+
+impl G64Handler {
+    pub fn new(data: Vec<u8>) -> Self {
+        G64Handler { data }
+    }
+
+    /// Header fields: version, number of half-track entries and maximum track size.
+    fn header(&self) -> Result<(u8, usize, usize)> {
+        let mut reader = Reader::new(&self.data, "g64", 0);
+        if reader.bytes(8, "signature")? != SIGNATURE {
+            return Err(reader.error_at(0, "missing 'GCR-1541' signature").into());
+        }
+        Ok((reader.u8("version")?, reader.u8("half-track count")? as usize, reader.u16("maximum track size")? as usize))
+    }
+
+    /// GCR bytes of every half-track with data, by half-track index (0 = track 1).
+    fn half_tracks(&self) -> Result<Vec<(usize, &[u8])>> {
+        let (_, count, _) = self.header()?;
+        let mut tracks = Vec::new();
+        let mut table = Reader::new(&self.data, "g64", 12);
+        for index in 0..count {
+            let offset = table.u32(&format!("half-track {} table entry", index))? as usize;
+            if offset == 0 {
+                continue;
+            }
+            let mut reader = Reader::new(&self.data, "g64", offset);
+            let length = reader.u16(&format!("half-track {} length", index))? as usize;
+            tracks.push((index, reader.bytes(length, &format!("half-track {} data", index))?));
+        }
+        Ok(tracks)
+    }
+
+    /// Decodes the sectors of every full track, plus a line per track with problems.
+    fn decode(&self) -> Result<(Disk, Vec<String>)> {
+        let mut found: Vec<Option<&[u8]>> = vec![None; HALF_TRACKS / 2];
+        for (index, bytes) in self.half_tracks()? {
+            if index % 2 == 0 && index / 2 < found.len() {
+                found[index / 2] = Some(bytes);
+            }
+        }
+        let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+        let mut report = Vec::new();
+        let mut tally = flux::Tally::default();
+        for (cylinder, bytes) in found.into_iter().enumerate() {
+            let number = cylinder as u8 + 1;
+            let spt = cbm::sectors_per_track(number) as usize;
+            // The track is a loop: decode it twice over so a sector spanning the end is found
+            let passes = bytes.map(|b| vec![cbm::decode_track(&flux::unpack_bits(&[b, b].concat()))]).unwrap_or_default();
+            let (slots, bad_headers) = flux::merge(passes, spt);
+            if number > 35 && slots.iter().all(|s| s.is_none()) {
+                continue;
+            }
+            if bytes.is_some() {
+                if let Some(problems) = flux::track_problems(&slots, bad_headers) {
+                    report.push(format!("  Track {}: {}", number, problems));
+                }
+            }
+            disk.tracks.push(flux::build_track(cylinder as u8, 0, 5, 256, slots, &mut tally));
+        }
+        report.insert(0, format!("CBM GCR Decode: {} sectors good, {} with bad data checksums, {} missing", tally.good, tally.bad, tally.missing));
+        Ok((disk, report))
+    }
+}
+
+impl FormatHandler for G64Handler {
+    fn display(&self, _ascii: bool) -> Result<String> {
+        let (version, count, max_size) = self.header()?;
+        let tracks = self.half_tracks()?;
+        let full = tracks.iter().filter(|(i, _)| i % 2 == 0).count();
+        let mut output = vec![
+            format!("Commodore G64: {} bytes, version {}", self.data.len(), version),
+            format!("Track Table: {} half-track entries, maximum track size {} bytes", count, max_size),
+            format!("Tracks with Data: {} full, {} half", full, tracks.len() - full),
+        ];
+        let zones: Vec<String> = tracks.iter().filter(|(i, _)| i % 2 == 0).map(|(i, _)| {
+            let zone = Reader::new(&self.data, "g64", 12 + count * 4 + i * 4).u32("speed zone entry").unwrap_or(0);
+            if zone > 3 { format!("{}:custom", i / 2 + 1) } else { format!("{}:{}", i / 2 + 1, zone) }
+        }).collect();
+        output.push(format!("Speed Zones (track:zone): {}", zones.join(" ")));
+        output.extend(self.decode()?.1);
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        Ok(None)
+    }
+
+    fn disk(&self) -> Result<Disk> {
+        Ok(self.decode()?.0)
+    }
+
+    /// Writes every track in the 1541 layout at its zone's length. The disk ID in the sector
+    /// headers comes from the BAM (track 18, sector 0).
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let bam = disk.tracks.iter().find(|t| t.cylinder == 17 && t.head == 0)
+            .and_then(|t| t.sectors.iter().find(|s| s.id == 0))
+            .and_then(|s| s.data.as_ref());
+        let disk_id = bam.filter(|d| d.len() >= 0xA4).map_or([b'0', b'0'], |d| [d[0xA2], d[0xA3]]);
+
+        let mut out = Vec::new();
+        out.extend_from_slice(SIGNATURE);
+        out.extend_from_slice(&[0, HALF_TRACKS as u8]);
+        out.extend_from_slice(&(MAX_TRACK_SIZE as u16).to_le_bytes());
+        let table = out.len();
+        out.resize(table + HALF_TRACKS * 8, 0);
+
+        for track in &disk.tracks {
+            if track.head != 0 {
+                return Err(anyhow!("Cannot write .g64: the disk has a second side (Cyl {}, Head {})", track.cylinder, track.head));
+            }
+            let number = track.cylinder.saturating_add(1);
+            let index = track.cylinder as usize * 2;
+            if index >= HALF_TRACKS {
+                return Err(anyhow!("Cannot write .g64: track {} is beyond track 42", number));
+            }
+            let mut sectors: Vec<&Sector> = track.sectors.iter().collect();
+            sectors.sort_by_key(|s| s.id);
+            if let Some(s) = sectors.iter().find(|s| s.size != 256) {
+                return Err(anyhow!("Cannot write .g64: track {} sector {} is {} bytes; the 1541 uses 256-byte sectors", number, s.id, s.size));
+            }
+            let layout: Vec<flux::TrackSector> = sectors.iter().map(|s| flux::TrackSector {
+                sector: s.id,
+                data: s.data.as_deref(),
+                bad_checksum: s.crc_error,
+            }).collect();
+            let mut gcr = cbm::encode_track(number, disk_id, &layout);
+            gcr.truncate(MAX_TRACK_SIZE);
+
+            let offset = out.len() as u32;
+            out[table + index * 4..table + index * 4 + 4].copy_from_slice(&offset.to_le_bytes());
+            let zone = cbm::speed_zone(number) as u32;
+            out[table + (HALF_TRACKS + index) * 4..table + (HALF_TRACKS + index) * 4 + 4].copy_from_slice(&zone.to_le_bytes());
+            out.extend_from_slice(&(gcr.len() as u16).to_le_bytes());
+            out.extend_from_slice(&gcr);
+            out.resize(out.len() + MAX_TRACK_SIZE - gcr.len(), 0);
+        }
+        Ok(out)
+    }
+}
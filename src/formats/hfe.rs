@@ -0,0 +1,354 @@
+use crate::{FormatHandler, Geometry};
+use crate::formats::{ParseError, Reader};
+use crate::disk::{Disk, Placement, Track};
+use crate::flux::{self, amiga, CellDisk, ibm::{self, Encoding}};
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+const SIGNATURE_V1: &[u8] = b"HXCPICFE";
+const SIGNATURE_V3: &[u8] = b"HXCHFEV3";
+const BLOCK_SIZE: usize = 512;
+const SIDE_CHUNK: usize = 256; // Each block holds 256 bytes of side 0, then 256 of side 1
+const MAX_SIDE_BYTES: usize = 0x7FFF; // Track length (both sides) is a 16-bit field
+
+// Track encodings
+const ISOIBM_MFM: u8 = 0;
+const AMIGA_MFM: u8 = 1;
+const ISOIBM_FM: u8 = 2;
+const EMU_FM: u8 = 3;
+
+// Interface modes
+const IBMPC_DD: u8 = 0;
+const IBMPC_HD: u8 = 1;
+const AMIGA_DD: u8 = 4;
+const AMIGA_HD: u8 = 5;
+const GENERIC_SHUGART_DD: u8 = 7;
+
+// HFEv3 opcodes as stored. The spec's 0xF0-0xF4 are written least significant bit first like
+// the cells, so the low nibble is all ones (never valid MFM) and the opcode is in the high nibble.
+// Operand bytes are bit-reversed the same way.
+const OPCODE_MASK: u8 = 0x0F;
+const NOP: u8 = 0x0F;
+const SET_INDEX: u8 = 0x8F;
+const SET_BITRATE: u8 = 0x4F;
+const SKIP_BITS: u8 = 0xCF;
+const RAND: u8 = 0x2F;
+
+fn encoding_name(encoding: u8) -> &'static str {
+    match encoding {
+        ISOIBM_MFM => "ISO IBM MFM",
+        AMIGA_MFM => "Amiga MFM",
+        ISOIBM_FM => "ISO IBM FM",
+        EMU_FM => "E-mu FM",
+        _ => "unknown",
+    }
+}
+
+fn interface_name(mode: u8) -> &'static str {
+    match mode {
+        0 => "IBM PC DD",
+        1 => "IBM PC HD",
+        2 => "Atari ST DD",
+        3 => "Atari ST HD",
+        4 => "Amiga DD",
+        5 => "Amiga HD",
+        6 => "CPC DD",
+        7 => "Generic Shugart DD",
+        8 => "IBM PC ED",
+        9 => "MSX2 DD",
+        10 => "C64 DD",
+        11 => "E-mu Shugart",
+        12 => "Akai S950 DD",
+        13 => "Akai S950 HD",
+        0xFE => "disabled",
+        _ => "unknown",
+    }
+}
+
+/// Header fields from block 0.
+struct Header {
+    v3: bool,
+    tracks: u8,
+    sides: u8,
+    encoding: u8,
+    bitrate: u16, // kbps; each stored bit is one cell at twice this rate
+    rpm: u16,
+    interface: u8,
+    track_list: usize, // Block holding the track offset table
+}
+
+/// HxC Floppy Emulator image (HFE v1, and v3 with opcodes), as used by HxC and Gotek
+/// (FlashFloppy) drives. Each track holds the raw cells of both sides, interleaved in 256-byte
+/// chunks and stored least significant bit first.
+pub struct HFEHandler {
+    data: Vec<u8>,
+}
+
+impl HFEHandler {
+    pub fn new(data: Vec<u8>) -> Self {
+        HFEHandler { data }
+    }
+
+    fn header(&self) -> Result<Header> {
+        let data = &self.data;
+        let v3 = data.starts_with(SIGNATURE_V3);
+        if !v3 && !data.starts_with(SIGNATURE_V1) || data.len() < BLOCK_SIZE {
+            return Err(ParseError::new("hfe", 0, format!("expected an \"HXCPICFE\" or \"HXCHFEV3\" header block ({} bytes)", data.len())).into());
+        }
+        let header = Header {
+            v3,
+            tracks: data[9],
+            sides: data[10],
+            encoding: data[11],
+            bitrate: u16::from_le_bytes([data[12], data[13]]),
+            rpm: u16::from_le_bytes([data[14], data[15]]),
+            interface: data[16],
+            track_list: u16::from_le_bytes([data[18], data[19]]) as usize,
+        };
+        if !(1..=2).contains(&header.sides) || header.bitrate == 0 {
+            return Err(ParseError::new("hfe", 10, format!("{} sides at {} kbps in the header", header.sides, header.bitrate)).into());
+        }
+        Ok(header)
+    }
+
+    /// Stored bytes of one side of a track, gathered from its 256-byte chunks.
+    fn side_bytes(&self, header: &Header, track: usize, side: usize) -> Result<Vec<u8>> {
+        let entry = header.track_list * BLOCK_SIZE + track * 4;
+        let mut lut = Reader::new(&self.data, "hfe", entry);
+        lut.track = Some((track as u8, side as u8));
+        let start = lut.u16("track table entry")? as usize * BLOCK_SIZE;
+        let length = lut.u16("track table entry")? as usize / 2;
+        let mut bytes = Vec::with_capacity(length);
+        for chunk in 0..length.div_ceil(SIDE_CHUNK) {
+            let from = start + chunk * BLOCK_SIZE + side * SIDE_CHUNK;
+            let take = (length - chunk * SIDE_CHUNK).min(SIDE_CHUNK);
+            let mut reader = Reader::new(&self.data, "hfe", from);
+            reader.track = lut.track;
+            bytes.extend_from_slice(reader.bytes(take, "track data")?);
+        }
+        Ok(bytes)
+    }
+
+    /// Cells of a side (one byte per cell) and their bit rate in kbps, expanding HFEv3 opcodes.
+    /// Random (weak) bytes get pseudo-random cells. Parts of the track after a SET_BITRATE are
+    /// resampled to the rate the track spends longest at; an error describes rate changes that
+    /// are not a whole-number ratio, since those cannot be resampled.
+    fn cells(header: &Header, bytes: &[u8]) -> Result<(Vec<u8>, usize), String> {
+        let mut parts: Vec<(usize, Vec<u8>)> = vec![(header.bitrate as usize, Vec::with_capacity(bytes.len() * 8))];
+        let push = |parts: &mut Vec<(usize, Vec<u8>)>, byte: u8, skip: usize| {
+            if let Some((_, cells)) = parts.last_mut() {
+                cells.extend((skip..8).map(|i| (byte >> i) & 1));
+            }
+        };
+        let mut random = 0x1234_5678u32;
+        let mut at = 0;
+        while at < bytes.len() {
+            let byte = bytes[at];
+            if !header.v3 || byte & OPCODE_MASK != OPCODE_MASK {
+                push(&mut parts, byte, 0);
+                at += 1;
+                continue;
+            }
+            match byte {
+                SET_BITRATE => {
+                    // The operand is the cell period in ticks of the emulator's 36 MHz clock
+                    let period = bytes.get(at + 1).map_or(0, |b| b.reverse_bits()) as usize;
+                    if period == 0 {
+                        return Err(format!("SET_BITRATE at byte {} has no valid rate", at));
+                    }
+                    parts.push((18_000 / period, Vec::new()));
+                    at += 2;
+                }
+                SKIP_BITS => {
+                    if let (Some(&skip), Some(&value)) = (bytes.get(at + 1), bytes.get(at + 2)) {
+                        push(&mut parts, value, (skip.reverse_bits() & 7) as usize);
+                    }
+                    at += 3;
+                }
+                RAND => {
+                    random = random.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    push(&mut parts, (random >> 16) as u8, 0);
+                    at += 1;
+                }
+                NOP | SET_INDEX => at += 1,
+                _ => at += 1,
+            }
+        }
+
+        parts.retain(|(_, cells)| !cells.is_empty());
+        let Some(&(rate, _)) = parts.iter().max_by_key(|(rate, cells)| cells.len() * 1000 / rate) else {
+            return Ok((Vec::new(), header.bitrate as usize));
+        };
+        let mut cells = Vec::with_capacity(parts.iter().map(|(_, c)| c.len()).sum());
+        for (part_rate, part) in parts {
+            if part_rate == rate {
+                cells.extend(part);
+            } else if rate.is_multiple_of(part_rate) {
+                // Slower part: each cell spans several cells at the track's rate
+                let stretch = rate / part_rate;
+                cells.extend(part.iter().flat_map(|&cell| std::iter::once(cell).chain(std::iter::repeat_n(0, stretch - 1))));
+            } else if part_rate.is_multiple_of(rate) {
+                // Faster part: a transition anywhere in a group of cells falls in one cell
+                cells.extend(part.chunks(part_rate / rate).map(|group| group.iter().fold(0, |a, &b| a | b)));
+            } else {
+                return Err(format!("bit rate changes between {} and {} kbps mid-track; only whole-number ratios can be decoded", rate, part_rate));
+            }
+        }
+        Ok((cells, rate))
+    }
+
+    /// Decodes every track into sectors, with a report of tracks that decoded badly.
+    fn decode(&self) -> Result<(Header, Disk, Vec<String>)> {
+        let header = self.header()?;
+        let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+        let mut report = Vec::new();
+        for cylinder in 0..header.tracks {
+            for head in 0..header.sides {
+                let (cells, kcells) = match Self::cells(&header, &self.side_bytes(&header, cylinder as usize, head as usize)?) {
+                    Ok((cells, rate)) => (cells, rate * 2),
+                    Err(e) => {
+                        report.push(format!("Cyl {}, Head {}: {}", cylinder, head, e));
+                        continue;
+                    }
+                };
+                if header.encoding == AMIGA_MFM {
+                    let spt = if kcells > 550 { 22 } else { 11 };
+                    let (slots, bad_headers) = flux::merge(vec![amiga::decode_track(&cells)], spt);
+                    if let Some(problem) = flux::track_problems(&slots, bad_headers) {
+                        report.push(format!("Cyl {}, Head {}: {}", cylinder, head, problem));
+                    }
+                    let mut tally = flux::Tally::default();
+                    disk.tracks.push(flux::build_track(cylinder, head, Encoding::Mfm.mode(kcells), 512, slots, &mut tally));
+                    continue;
+                }
+                // Try the header's encoding first; FM at half the cell rate shows up with every cell doubled
+                let native = if header.encoding == ISOIBM_FM || header.encoding == EMU_FM { Encoding::Fm } else { Encoding::Mfm };
+                let halved: Vec<u8> = cells.chunks(2).map(|pair| pair.iter().fold(0, |a, &b| a | b)).collect();
+                let attempts = [(native, &cells, kcells), (Encoding::Fm, &halved, kcells / 2), (Encoding::Fm, &cells, kcells), (Encoding::Mfm, &cells, kcells)];
+                let decoded = attempts.iter()
+                    .map(|&(encoding, cells, rate)| (ibm::decode_track(cells, encoding), encoding, rate))
+                    .find(|((sectors, _), _, _)| !sectors.is_empty());
+                let Some(((sectors, bad_headers), encoding, rate)) = decoded else {
+                    report.push(format!("Cyl {}, Head {}: no sectors found", cylinder, head));
+                    continue;
+                };
+                let bad = sectors.iter().filter(|s| s.crc_error).count();
+                let no_data = sectors.iter().filter(|s| s.data.is_none()).count();
+                if bad + no_data + bad_headers > 0 {
+                    report.push(format!(
+                        "Cyl {}, Head {}: {} sectors with CRC errors, {} without data, {} bad ID fields",
+                        cylinder, head, bad, no_data, bad_headers
+                    ));
+                }
+                disk.tracks.push(Track { mode: encoding.mode(rate), cylinder, head, sectors, ..Default::default() });
+            }
+        }
+        Ok((header, disk, report))
+    }
+}
+
+impl FormatHandler for HFEHandler {
+    fn display(&self, ascii: bool) -> Result<String> {
+        let (header, disk, report) = self.decode()?;
+        let mut output = Vec::new();
+        output.push(format!("HxC HFE: {} bytes ({})", self.data.len(), if header.v3 { "v3" } else { "v1" }));
+        if !ascii {
+            output.push(format!("Tracks: {}, Sides: {}", header.tracks, header.sides));
+            output.push(format!("Encoding: {}", encoding_name(header.encoding)));
+            output.push(format!("Bit Rate: {} kbps, {} RPM", header.bitrate, header.rpm));
+            output.push(format!("Interface Mode: {}", interface_name(header.interface)));
+            let sectors: usize = disk.tracks.iter().map(|t| t.sectors.len()).sum();
+            output.push(format!("Decoded: {} sectors on {} tracks", sectors, disk.tracks.len()));
+            output.extend(report.iter().map(|line| format!("  {}", line)));
+        } else {
+            for (track, sector) in disk.sectors(crate::disk::Order::Physical) {
+                let ascii_str: String = sector.data.as_deref().unwrap_or_default().iter()
+                    .take(32)
+                    .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
+                    .collect();
+                output.push(format!("Cyl {}, Head {}, Sector {}, Size {} bytes: {}", track.cylinder, track.head, sector.id, sector.size, ascii_str));
+            }
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        let (header, disk, _) = self.decode()?;
+        let Some(first) = disk.tracks.iter().find(|t| !t.sectors.is_empty()) else { return Ok(None) };
+        Ok(Some(Geometry::Manual {
+            cylinders: header.tracks,
+            heads: header.sides,
+            sectors_per_track: crate::fs::sectors_per_track(&disk) as u8,
+            sector_size: first.sectors[0].size as u16,
+            mode: first.mode,
+        }))
+    }
+
+    /// Tracks with no sectors found are left out.
+    fn disk(&self) -> Result<Disk> {
+        Ok(self.decode()?.1)
+    }
+
+    /// Writes HFE v1 from the cells of `CellDisk`: the IBM layout with each track in its own
+    /// encoding, or the Amiga trackdisk layout. The bit rate is the fastest track's.
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let cells = CellDisk::new(disk, "hfe")?;
+        let (cylinders, sides, max_rate, rpm, is_amiga) = (cells.tracks.len(), cells.sides, cells.kcells, cells.rpm, cells.amiga);
+        let all_fm = cells.encoding == Encoding::Fm;
+        let streams = cells.tracks;
+
+        let lut_blocks = (cylinders * 4).div_ceil(BLOCK_SIZE);
+        let mut out = vec![0xFFu8; BLOCK_SIZE * (1 + lut_blocks)];
+        out[..8].copy_from_slice(SIGNATURE_V1);
+        out[8] = 0; // Format revision
+        out[9] = cylinders as u8;
+        out[10] = sides as u8;
+        out[11] = if is_amiga { AMIGA_MFM } else if all_fm { ISOIBM_FM } else { ISOIBM_MFM };
+        out[12..14].copy_from_slice(&((max_rate / 2) as u16).to_le_bytes());
+        out[14..16].copy_from_slice(&(rpm as u16).to_le_bytes());
+        out[16] = match (all_fm, max_rate) {
+            _ if is_amiga => if max_rate > 500 { AMIGA_HD } else { AMIGA_DD },
+            (true, _) => GENERIC_SHUGART_DD,
+            (false, ..=600) => IBMPC_DD,
+            _ => IBMPC_HD,
+        };
+        out[17] = 0;
+        out[18..20].copy_from_slice(&1u16.to_le_bytes()); // Track table in block 1
+        out[20] = 0xFF; // Write allowed
+        out[21] = 0xFF; // Single step
+        for (cylinder, pair) in streams.iter().enumerate() {
+            let bytes: Vec<Vec<u8>> = pair.iter()
+                .map(|cells| cells.chunks(8).map(|chunk| chunk.iter().enumerate().fold(0, |byte, (i, &cell)| byte | (cell << i))).collect())
+                .collect();
+            let length = bytes[0].len().max(bytes[1].len());
+            if length > MAX_SIDE_BYTES {
+                return Err(anyhow!(
+                    "Cannot write .hfe: Cyl {} needs {} bytes per side; HFE tracks hold at most {}",
+                    cylinder, length, MAX_SIDE_BYTES
+                ));
+            }
+            let entry = BLOCK_SIZE + cylinder * 4;
+            let offset = (out.len() / BLOCK_SIZE) as u16;
+            out[entry..entry + 2].copy_from_slice(&offset.to_le_bytes());
+            out[entry + 2..entry + 4].copy_from_slice(&((length * 2) as u16).to_le_bytes());
+            for chunk in 0..length.div_ceil(SIDE_CHUNK) {
+                for side in &bytes {
+                    let part = side.get(chunk * SIDE_CHUNK..).unwrap_or_default();
+                    let part = &part[..part.len().min(SIDE_CHUNK)];
+                    out.extend_from_slice(part);
+                    out.resize(out.len() + SIDE_CHUNK - part.len(), 0);
+                }
+            }
+        }
+        Ok(out)
+    }
+}
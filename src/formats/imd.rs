@@ -1,8 +1,8 @@
 use crate::{FormatHandler, Geometry};
+use crate::formats::{ParseError, Reader};
+use crate::disk::{Disk, Placement, Sector, Track};
 use anyhow::{Result, anyhow};
-use byteorder::ReadBytesExt;
-use std::io::{Cursor, Read};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::Write;
 
@@ -13,57 +13,149 @@ pub struct IMDHandler {
 impl IMDHandler {
     pub fn new(data: Vec<u8>) -> Self { IMDHandler { data } }
 
+    /// Cylinders, heads, sectors per track, sector size and mode. Sectors per track and size
+    /// are the raw image layout; the mode is the first track's.
     fn analyze_geometry(&self) -> Result<(u8, u8, u8, u16, u8)> {
-        let header_end = self.data.iter().position(|&b| b == 0x1A)
-            .ok_or_else(|| anyhow!(
-                "Invalid .imd file: No header terminator (0x1A) found. The file may be corrupted or not in ImageDisk format."
-            ))?;
-        let mut cursor = Cursor::new(&self.data[header_end + 1..]);
-        let mut max_cyl = 0;
-        let mut max_head = 0;
-        let mut sectors_per_track = 0;
-        let mut sector_size = 0;
-        let mut mode = 0;
-
-        while cursor.position() < self.data.len() as u64 - header_end as u64 - 1 {
-            let track_mode = cursor.read_u8()?;
-            let cylinder = cursor.read_u8()?;
-            let head = cursor.read_u8()?;
-            let sector_count = cursor.read_u8()?;
-            let sector_size_code = cursor.read_u8()?;
-            let size = 128 << sector_size_code;
-
-            max_cyl = max_cyl.max(cylinder + 1);
-            max_head = max_head.max(head + 1);
-            if sectors_per_track == 0 { sectors_per_track = sector_count; }
-            if sector_size == 0 { sector_size = size; }
-            if mode == 0 { mode = track_mode; }
-
-            let skip_bytes = sector_count as u64
-                + if head & 0x80 != 0 { sector_count as u64 } else { 0 }
-                + if head & 0x40 != 0 { sector_count as u64 } else { 0 };
-            cursor.set_position(cursor.position() + skip_bytes);
-
-            for _ in 0..sector_count {
-                let type_byte = cursor.read_u8()?;
+        let disk = self.parse_disk()?;
+        let max_cyl = disk.tracks.iter().map(|t| t.cylinder.saturating_add(1)).max().unwrap_or(0);
+        let max_head = disk.tracks.iter().map(|t| t.head.saturating_add(1)).max().unwrap_or(0);
+        let layout = disk.layout();
+        Ok(match disk.tracks.first() {
+            Some(track) => (max_cyl, max_head, layout.slots as u8, layout.size as u16, track.mode),
+            None => (max_cyl, max_head, 0, 0, 0),
+        })
+    }
+
+    /// Offset of the 0x1A that ends the comment header.
+    fn header_end(&self) -> Result<usize, ParseError> {
+        self.data.iter().position(|&b| b == 0x1A).ok_or_else(|| ParseError::new(
+            "imd", 0, "no header terminator (0x1A) found. The file may be corrupted or not in ImageDisk format."
+        ))
+    }
+
+    fn parse_disk(&self) -> Result<Disk> {
+        let header_end = self.header_end()?;
+        let mut disk = Disk { comment: self.data[..header_end].to_vec(), tracks: Vec::new() };
+        let mut reader = Reader::new(&self.data, "imd", header_end + 1);
+
+        while reader.remaining() > 0 {
+            reader.track = None;
+            reader.sector = None;
+            let mode = reader.u8("track mode")?;
+            let cylinder = reader.u8("cylinder")?;
+            let head_flags = reader.u8("head")?;
+            let head = head_flags & 0x3F;
+            reader.track = Some((cylinder, head));
+            if mode > 5 {
+                return Err(reader.error_at(reader.pos - 3, format!("unknown track mode {}; expected 0-5", mode)).into());
+            }
+            let sector_count = reader.u8("sector count")? as usize;
+            let sector_size_code = reader.u8("sector size code")?;
+            if sector_size_code > 6 && sector_size_code != 0xFF {
+                return Err(reader.error_at(reader.pos - 1, format!("sector size code {}; expected 0-6 (128-8192 bytes) or 0xFF", sector_size_code)).into());
+            }
+
+            let ids = reader.bytes(sector_count, "sector numbering map")?.to_vec();
+            let cylinders = if head_flags & 0x80 != 0 { reader.bytes(sector_count, "sector cylinder map")?.to_vec() } else { vec![cylinder; sector_count] };
+            let heads = if head_flags & 0x40 != 0 { reader.bytes(sector_count, "sector head map")?.to_vec() } else { vec![head; sector_count] };
+            let sizes = if sector_size_code == 0xFF { // Per-sector size table (IMD 1.18)
+                let mut sizes = Vec::with_capacity(sector_count);
+                for &id in &ids {
+                    let size = reader.u16("sector size table")? as usize;
+                    if !(1..=8192).contains(&size) {
+                        return Err(reader.error_at(reader.pos - 2, format!("sector ID {} has size {} in the size table; expected 1-8192 bytes", id, size)).into());
+                    }
+                    sizes.push(size);
+                }
+                sizes
+            } else {
+                vec![128usize << sector_size_code; sector_count]
+            };
+
+            let mut track = Track {
+                mode,
+                cylinder,
+                head,
+                sectors: Vec::with_capacity(sector_count),
+                cylinder_map: head_flags & 0x80 != 0,
+                head_map: head_flags & 0x40 != 0,
+            };
+            for i in 0..sector_count {
+                reader.sector = Some(ids[i]);
+                let type_byte = reader.u8("sector data record type")?;
+                let mut sector = Sector { id: ids[i], cylinder: cylinders[i], head: heads[i], size: sizes[i], ..Default::default() };
                 match type_byte {
-                    1 => cursor.set_position(cursor.position() + size as u64),
-                    2 => cursor.set_position(cursor.position() + 1),
-                    _ => return Err(anyhow!("Unsupported sector type: {}", type_byte)),
+                    0 => {}
+                    1..=8 => {
+                        let flags = type_byte - 1;
+                        sector.compressed = flags & 0x01 != 0;
+                        sector.deleted = flags & 0x02 != 0;
+                        sector.crc_error = flags & 0x04 != 0;
+                        sector.data = Some(if sector.compressed {
+                            vec![reader.u8("compressed sector fill byte")?; sizes[i]]
+                        } else {
+                            reader.bytes(sizes[i], "sector data")?.to_vec()
+                        });
+                    }
+                    _ => return Err(reader.error_at(reader.pos - 1, format!("unsupported sector data record type {}; expected 0-8", type_byte)).into()),
                 }
+                track.sectors.push(sector);
             }
+            disk.tracks.push(track);
         }
-        Ok((max_cyl, max_head, sectors_per_track, sector_size, mode))
+        Ok(disk)
+    }
+
+    fn write_disk(disk: &Disk) -> Vec<u8> {
+        let mut out = disk.comment.clone();
+        out.push(0x1A);
+        for track in &disk.tracks {
+            let cylinder_map = track.cylinder_map || track.sectors.iter().any(|s| s.cylinder != track.cylinder);
+            let head_map = track.head_map || track.sectors.iter().any(|s| s.head != track.head);
+            let size = track.sectors.first().map_or(512, |s| s.size);
+            let fixed_size = track.sectors.iter().all(|s| s.size == size) && size.is_power_of_two() && (128..=8192).contains(&size);
+
+            out.push(track.mode);
+            out.push(track.cylinder);
+            out.push(track.head | if cylinder_map { 0x80 } else { 0 } | if head_map { 0x40 } else { 0 });
+            out.push(track.sectors.len() as u8);
+            out.push(if fixed_size { (size / 128).trailing_zeros() as u8 } else { 0xFF });
+            out.extend(track.sectors.iter().map(|s| s.id));
+            if cylinder_map {
+                out.extend(track.sectors.iter().map(|s| s.cylinder));
+            }
+            if head_map {
+                out.extend(track.sectors.iter().map(|s| s.head));
+            }
+            if !fixed_size {
+                for s in &track.sectors {
+                    out.extend_from_slice(&(s.size as u16).to_le_bytes());
+                }
+            }
+
+            for sector in &track.sectors {
+                match &sector.data {
+                    None => out.push(0),
+                    Some(data) => {
+                        let compressed = sector.compressed && sector.is_uniform();
+                        out.push(1 + compressed as u8 + if sector.deleted { 2 } else { 0 } + if sector.crc_error { 4 } else { 0 });
+                        if compressed {
+                            out.push(data[0]);
+                        } else {
+                            out.extend_from_slice(data);
+                        }
+                    }
+                }
+            }
+        }
+        out
     }
 }
 
 impl FormatHandler for IMDHandler {
     fn display(&self, ascii: bool) -> Result<String> {
         let mut output = Vec::new();
-        let header_end = self.data.iter().position(|&b| b == 0x1A)
-            .ok_or_else(|| anyhow!(
-                "Invalid .imd file: No header terminator (0x1A) found. The file may be corrupted or not in ImageDisk format."
-            ))?;
+        let header_end = self.header_end()?;
         let header = String::from_utf8_lossy(&self.data[..header_end]);
         output.push(format!("Header: {}", header));
 
@@ -76,42 +168,27 @@ impl FormatHandler for IMDHandler {
                 "Detected Geometry: {} cylinders, {} heads, {} sectors/track, {} bytes/sector, mode {}",
                 cylinders, heads, sectors_per_track, sector_size, mode
             ));
+            let disk = self.parse_disk()?;
+            output.push(match disk.interleave() {
+                Some((interleave, 0)) => format!("Interleave: {}:1", interleave),
+                Some((interleave, differing)) => format!("Interleave: {}:1 ({} tracks differ)", interleave, differing),
+                None => "Interleave: n/a".to_string(),
+            });
+            output.push(match disk.skew() {
+                Some(skew) => format!("Track-to-track Skew: {} sectors", skew),
+                None => "Track-to-track Skew: n/a".to_string(),
+            });
         } else {
-            let mut cursor = Cursor::new(&self.data[header_end + 1..]);
-            while cursor.position() < self.data.len() as u64 - header_end as u64 - 1 {
-                let mode = cursor.read_u8()?;
-                let cylinder = cursor.read_u8()?;
-                let head = cursor.read_u8()?;
-                let sector_count = cursor.read_u8()?;
-                let sector_size_code = cursor.read_u8()?;
-                let sector_size = 128 << sector_size_code;
-
-                let skip_bytes = sector_count as u64
-                    + if head & 0x80 != 0 { sector_count as u64 } else { 0 }
-                    + if head & 0x40 != 0 { sector_count as u64 } else { 0 };
-                cursor.set_position(cursor.position() + skip_bytes);
-
-                for sector in 1..=sector_count {
-                    let type_byte = cursor.read_u8()?;
-                    let mut sector_data = Vec::new();
-                    match type_byte {
-                        1 => {
-                            sector_data.resize(sector_size as usize, 0);
-                            cursor.read_exact(&mut sector_data)?;
-                        }
-                        2 => {
-                            let value = cursor.read_u8()?;
-                            sector_data = vec![value; sector_size as usize];
-                        }
-                        _ => return Err(anyhow!("Unsupported sector type: {}", type_byte)),
-                    }
-                    let ascii_str: String = sector_data.iter()
+            let disk = self.parse_disk()?;
+            for track in &disk.tracks {
+                for (position, sector) in track.sectors.iter().enumerate() {
+                    let ascii_str: String = sector.data.as_deref().unwrap_or_default().iter()
                         .take(32)
-                        .map(|&b| if b >= 32 && b <= 126 { b as char } else { '.' })
+                        .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
                         .collect();
                     output.push(format!(
                         "Cyl {}, Head {}, Sector {}, Size {} bytes, Mode {}: {}",
-                        cylinder, head, sector, sector_size, mode, ascii_str
+                        track.cylinder, track.head, position + 1, sector.size, track.mode, ascii_str
                     ));
                 }
             }
@@ -119,78 +196,43 @@ impl FormatHandler for IMDHandler {
         Ok(output.join("\n"))
     }
 
-    fn convert(&self, target: &dyn FormatHandler, output_path: &PathBuf, input_path: &PathBuf, meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, verbose: bool, _validate: bool) -> Result<()> {
-        if target.data().len() == 0 { // IMG conversion
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, input_path: &Path, meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, verbose: bool, _validate: bool, placement: Placement) -> Result<()> {
+        if target.data().is_empty() { // IMG conversion
+            let disk = self.parse_disk()?;
+            let layout = disk.layout();
             let mut raw_data = Vec::new();
-            let header_end = self.data.iter().position(|&b| b == 0x1A).unwrap();
-            let header = &self.data[..header_end + 1]; // Include 0x1A
-            let mut cursor = Cursor::new(&self.data[header_end + 1..]);
             let mut total_sectors = 0;
             let mut total_compressed = 0;
-            let mut sector_size = 0;
-            let mut sector_ids_map = Vec::new();
-
-            while cursor.position() < self.data.len() as u64 - header_end as u64 - 1 {
-                let mode = cursor.read_u8()?;
-                let cylinder = cursor.read_u8()?;
-                let head = cursor.read_u8()?;
-                let sector_count = cursor.read_u8()?;
-                let sector_size_code = cursor.read_u8()?;
-                let current_sector_size = 128 << sector_size_code;
-                if sector_size == 0 { sector_size = current_sector_size; }
-
-                // Read sector ID map
-                let mut sector_ids = Vec::new();
-                for _ in 0..sector_count {
-                    sector_ids.push(cursor.read_u8()?);
-                }
-                sector_ids_map.push((cylinder, head, sector_ids.clone()));
-                let skip_bytes = if head & 0x80 != 0 { sector_count as u64 } else { 0 }
-                    + if head & 0x40 != 0 { sector_count as u64 } else { 0 };
-                cursor.set_position(cursor.position() + skip_bytes);
-
-                // Store sector data in correct order
-                let mut track_data = vec![vec![0u8; current_sector_size as usize]; sector_count as usize];
-                let mut normal_sectors = 0;
-                let mut compressed_sectors = 0;
-
-                for i in 0..sector_count {
-                    let type_byte = cursor.read_u8()?;
-                    let sector_idx = (sector_ids[i as usize] - 1) as usize; // 1-based to 0-based
-                    match type_byte {
-                        1 => {
-                            cursor.read_exact(&mut track_data[sector_idx])?;
-                            normal_sectors += 1;
-                        }
-                        2 => {
-                            let value = cursor.read_u8()?;
-                            track_data[sector_idx].fill(value);
-                            compressed_sectors += 1;
-                        }
-                        _ => {
-                            if verbose {
-                                println!("Skipping unsupported sector type {} in Cyl {}, Head {}", type_byte, cylinder, head);
-                            }
-                            cursor.set_position(cursor.position() + current_sector_size as u64);
-                        }
-                    }
-                }
+            let mut noted_tracks = 0;
 
-                // Append in sequential order for .img
-                for sector_data in track_data {
-                    raw_data.extend_from_slice(&sector_data); // Fixed typo here
+            for track in &disk.tracks {
+                let (data, notes) = track.raw(placement, layout);
+                raw_data.extend_from_slice(&data);
+                // Anything that doesn't fit one slot per sector is reported, verbose or not
+                for note in &notes {
+                    println!("Cyl {}, Head {}: {}", track.cylinder, track.head, note);
                 }
+                noted_tracks += !notes.is_empty() as usize;
 
-                total_sectors += sector_count as usize;
+                let compressed_sectors = track.sectors.iter().filter(|s| s.compressed).count();
+                total_sectors += track.sectors.len();
                 total_compressed += compressed_sectors;
 
                 if verbose {
                     println!(
                         "Processing Cyl {}, Head {}: {} sectors ({} normal, {} compressed), size {} bytes, mode {}",
-                        cylinder, head, sector_count, normal_sectors, compressed_sectors, current_sector_size, mode
+                        track.cylinder, track.head, track.sectors.len(), track.sectors.len() - compressed_sectors, compressed_sectors,
+                        track.sectors.first().map_or(layout.size, |s| s.size), track.mode
                     );
                 }
             }
+            if noted_tracks > 0 {
+                println!("Sector layout ({}): {} tracks did not map one sector per slot; see the notes above", match placement {
+                    Placement::ById => "by ID".to_string(),
+                    Placement::Physical => "physical order".to_string(),
+                    Placement::Base(base) => format!("base ID {}", base),
+                }, noted_tracks);
+            }
 
             let mut file = File::create(output_path)?;
             file.write_all(&raw_data)?;
@@ -199,10 +241,11 @@ impl FormatHandler for IMDHandler {
             let default_meta_path = input_path.with_extension("imd.meta");
             let meta_path = meta_path.unwrap_or(&default_meta_path);
             let mut meta_file = File::create(meta_path)?;
-            meta_file.write_all(header)?;
-            for (cyl, head, ids) in sector_ids_map {
-                meta_file.write_all(&[cyl, head, ids.len() as u8])?;
-                meta_file.write_all(&ids)?;
+            meta_file.write_all(&disk.comment)?;
+            meta_file.write_all(&[0x1A])?;
+            for track in &disk.tracks {
+                meta_file.write_all(&[track.cylinder, track.head, track.sectors.len() as u8])?;
+                meta_file.write_all(&track.sectors.iter().map(|s| s.id).collect::<Vec<u8>>())?;
             }
 
             if verbose {
@@ -222,4 +265,8 @@ impl FormatHandler for IMDHandler {
     }
 
     fn data(&self) -> &[u8] { &self.data }
+
+    fn disk(&self) -> Result<Disk> { self.parse_disk() }
+
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> { Ok(Self::write_disk(disk)) }
 }
@@ -1,62 +1,126 @@
 use crate::{FormatHandler, Geometry};
+use crate::disk::{Disk, Order, Placement, Sector, Track};
+use crate::formats::{ParseError, Reader};
+use crate::fs::fat::Bpb;
 use anyhow::{Result, anyhow};
 use std::fs::File;
-use std::io::{Read, Write, Cursor};
-use byteorder::ReadBytesExt;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Cylinders, heads, sectors per track, sector size and IMD mode.
+type RawGeometry = (u8, u8, u8, u16, u8);
 
 pub struct IMGHandler {
     data: Vec<u8>,
+    geometry: Option<RawGeometry>, // From --geometry; inferred when None
 }
 
 impl IMGHandler {
     pub fn new(data: Vec<u8>) -> Self {
-        IMGHandler { data }
+        IMGHandler { data, geometry: None }
     }
 
-    fn infer_geometry(&self) -> Result<(u8, u8, u8, u16, u8)> {
+    /// Uses a --geometry given on the command line instead of inferring one, so that every
+    /// command can open images of non-standard sizes.
+    pub fn with_geometry(mut self, geometry: &Geometry) -> Self {
+        if let Geometry::Manual { cylinders, heads, sectors_per_track, sector_size, mode } = *geometry {
+            self.geometry = Some((cylinders, heads, sectors_per_track, sector_size, mode));
+        }
+        self
+    }
+
+    fn infer_geometry(&self) -> Result<RawGeometry> {
+        self.detect_geometry().map(|(geometry, _)| geometry)
+    }
+
+    /// Works out the geometry from --geometry if given, else from the boot sector BPB when it
+    /// describes this image exactly, otherwise from the file size. Also returns a description
+    /// of the source used.
+    fn detect_geometry(&self) -> Result<(RawGeometry, &'static str)> {
         let size = self.data.len();
 
+        if let Some(geometry @ (cylinders, heads, sectors_per_track, sector_size, _)) = self.geometry {
+            let expected_size = cylinders as usize * heads as usize * sectors_per_track as usize * sector_size as usize;
+            if expected_size != size {
+                return Err(anyhow!(
+                    "Geometry {}x{}x{}x{} ({} bytes) does not match file size ({} bytes)",
+                    cylinders, heads, sectors_per_track, sector_size, expected_size, size
+                ));
+            }
+            return Ok((geometry, "--geometry"));
+        }
+
+        if let Some(bpb) = self.data.get(..512).and_then(Bpb::parse) {
+            let sector_size = bpb.bytes_per_sector as usize;
+            let track_sectors = bpb.sectors_per_track as usize * bpb.heads as usize;
+            let plausible = (1..=2).contains(&bpb.heads)
+                && (1..=63).contains(&bpb.sectors_per_track)
+                && bpb.total_sectors as usize * sector_size == size
+                && (bpb.total_sectors as usize).is_multiple_of(track_sectors)
+                && bpb.total_sectors as usize / track_sectors <= 255;
+            if plausible {
+                let cylinders = (bpb.total_sectors as usize / track_sectors) as u8;
+                let spt = bpb.sectors_per_track as u8;
+                return Ok(((cylinders, bpb.heads as u8, spt, bpb.bytes_per_sector, mode_for(cylinders, spt, bpb.bytes_per_sector)), "boot sector BPB"));
+            }
+        }
+
         let formats = [
-            (360_000, 40, 2, 9, 5),
-            (720_000, 80, 2, 9, 5),
-            (1_228_800, 80, 2, 15, 4),
-            (1_474_560, 80, 2, 18, 5),
+            (163_840, 40, 1, 8),
+            (184_320, 40, 1, 9),
+            (327_680, 40, 2, 8),
+            (368_640, 40, 2, 9),
+            (655_360, 80, 2, 8),
+            (737_280, 80, 2, 9),
+            (1_228_800, 80, 2, 15),
+            (1_474_560, 80, 2, 18),
+            (2_949_120, 80, 2, 36),
         ];
 
-        for &(expected_size, cyl, heads, spt, mode) in &formats {
+        for &(expected_size, cyl, heads, spt) in &formats {
             if size == expected_size {
-                return Ok((cyl, heads, spt, 512, mode));
+                return Ok(((cyl, heads, spt, 512, mode_for(cyl, spt, 512)), "file size (standard PC format)"));
             }
         }
 
-        if size == 368_640 {
-            return Ok((40, 2, 9, 512, 5));
-        }
-
-        if size % 512 == 0 {
+        if size.is_multiple_of(512) {
+            // Prefer double-sided disks and the usual cylinder counts over odd shapes
             let total_sectors = size / 512;
-            for cyl in (40..=80).rev() {
-                for heads in (1..=2).rev() {
+            for heads in [2, 1] {
+                for cyl in [80, 40, 77, 81, 82, 83, 84, 35] {
                     let spt = total_sectors / (cyl * heads);
-                    if spt * cyl * heads == total_sectors && spt <= 36 {
-                        return Ok((cyl as u8, heads as u8, spt as u8, 512, 5));
+                    if spt * cyl * heads == total_sectors && (8..=36).contains(&spt) {
+                        return Ok(((cyl as u8, heads as u8, spt as u8, 512, mode_for(cyl as u8, spt as u8, 512)), "file size (best guess; check with --geometry)"));
                     }
                 }
             }
         }
 
         Err(anyhow!(
-            "No suitable geometry found for file size {} bytes. Specify with --geometry (e.g., '40,2,9,512,5' for 360KB, '80,2,18,512,5' for 1.44MB). Common sizes: 360KB, 720KB, 1.2MB, 1.44MB.",
+            "No suitable geometry found for file size {} bytes. Specify with --geometry (e.g., '40,2,9,512,4' for 360KB, '80,2,18,512,3' for 1.44MB). Common sizes: 360KB, 720KB, 1.2MB, 1.44MB.",
             size
         ))
     }
 }
 
+/// IMD recording mode for a geometry: high-density tracks need 500 kbps MFM, 40-track
+/// double-density disks are assumed to be read in a high-density 5.25" drive (300 kbps),
+/// other double-density disks use 250 kbps. 128-byte sectors are taken to be FM.
+fn mode_for(cylinders: u8, sectors_per_track: u8, sector_size: u16) -> u8 {
+    let track_bytes = sectors_per_track as usize * sector_size as usize;
+    match (sector_size, cylinders) {
+        (128, _) if sectors_per_track >= 26 => 0, // 8" single density, 500 kbps FM
+        (128, _) => 2,                            // 250 kbps FM
+        _ if track_bytes >= 15 * 512 => 3,
+        (_, 0..=42) => 4,
+        _ => 5,
+    }
+}
+
 impl FormatHandler for IMGHandler {
     fn display(&self, ascii: bool) -> Result<String> {
         let size = self.data.len();
-        let (cylinders, heads, sectors_per_track, sector_size, mode) = self.infer_geometry()?;
+        let ((cylinders, heads, sectors_per_track, sector_size, mode), source) = self.detect_geometry()?;
         let mut output = Vec::new();
 
         output.push(format!("Raw IMG: {} bytes", size));
@@ -65,8 +129,9 @@ impl FormatHandler for IMGHandler {
                 "Detected Geometry: {} cylinders, {} heads, {} sectors/track, {} bytes/sector",
                 cylinders, heads, sectors_per_track, sector_size
             ));
+            output.push(format!("Geometry Source: {}", source));
             output.push(format!(
-                "Note: Mode is not stored in .img files; inferred mode {} (common modes for this size: 4 or 5)",
+                "Note: Mode is not stored in .img files; inferred mode {} from the geometry",
                 mode
             ));
         } else {
@@ -77,7 +142,7 @@ impl FormatHandler for IMGHandler {
                         let chunk = &self.data[pos..pos + sector_size as usize];
                         let ascii_str: String = chunk.iter()
                             .take(32)
-                            .map(|&b| if b >= 32 && b <= 126 { b as char } else { '.' })
+                            .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
                             .collect();
                         output.push(format!(
                             "Cyl {}, Head {}, Sector {}, Size {} bytes, Mode {}: {}",
@@ -91,8 +156,8 @@ impl FormatHandler for IMGHandler {
         Ok(output.join("\n"))
     }
 
-    fn convert(&self, target: &dyn FormatHandler, output_path: &PathBuf, input_path: &PathBuf, meta_path: Option<&PathBuf>, geometry: Option<Geometry>, verbose: bool, validate: bool) -> Result<()> {
-        if target.data().len() == 0 { // Conversion to IMD
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, input_path: &Path, meta_path: Option<&PathBuf>, geometry: Option<Geometry>, verbose: bool, validate: bool, _placement: Placement) -> Result<()> {
+        if target.data().is_empty() { // Conversion to IMD
             let (cylinders, heads, sectors_per_track, sector_size, mode) = match geometry {
                 Some(Geometry::Manual { cylinders, heads, sectors_per_track, sector_size, mode }) => {
                     (cylinders, heads, sectors_per_track, sector_size, mode)
@@ -118,19 +183,18 @@ impl FormatHandler for IMGHandler {
                 let mut meta_file = File::open(meta_path)?;
                 let mut meta_data = Vec::new();
                 meta_file.read_to_end(&mut meta_data)?;
-                let header_end = meta_data.iter().position(|&b| b == 0x1A).unwrap();
+                let header_end = meta_data.iter().position(|&b| b == 0x1A)
+                    .ok_or_else(|| ParseError::new("imd.meta", 0, "no header terminator (0x1A) found"))?;
                 raw_data.extend_from_slice(&meta_data[..header_end + 1]);
-                
-                let mut cursor = Cursor::new(&meta_data[header_end + 1..]);
-                while cursor.position() < meta_data.len() as u64 - header_end as u64 - 1 {
-                    let cyl = cursor.read_u8()?;
-                    let head = cursor.read_u8()?;
-                    let count = cursor.read_u8()?;
-                    let mut ids = Vec::new();
-                    for _ in 0..count {
-                        ids.push(cursor.read_u8()?);
-                    }
-                    sector_ids_map.push((cyl, head, ids));
+
+                let mut reader = Reader::new(&meta_data, "imd.meta", header_end + 1);
+                while reader.remaining() > 0 {
+                    reader.track = None;
+                    let cyl = reader.u8("cylinder")?;
+                    let head = reader.u8("head")?;
+                    reader.track = Some((cyl, head));
+                    let count = reader.u8("sector count")? as usize;
+                    sector_ids_map.push((cyl, head, reader.bytes(count, "sector IDs")?.to_vec()));
                 }
                 if verbose {
                     println!("Loaded metadata from {}", meta_path.display());
@@ -223,4 +287,34 @@ impl FormatHandler for IMGHandler {
     fn data(&self) -> &[u8] {
         &self.data
     }
+
+    fn disk(&self) -> Result<Disk> {
+        let (cylinders, heads, sectors_per_track, sector_size, mode) = self.infer_geometry()?;
+        let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+        let mut chunks = self.data.chunks(sector_size as usize);
+        for cylinder in 0..cylinders {
+            for head in 0..heads {
+                let mut track = Track { mode, cylinder, head, ..Default::default() };
+                for id in 1..=sectors_per_track {
+                    let chunk = chunks.next().unwrap_or_default();
+                    let mut sector = Sector { id, cylinder, head, size: sector_size as usize, data: Some(chunk.to_vec()), ..Default::default() };
+                    sector.compressed = sector.is_uniform();
+                    track.sectors.push(sector);
+                }
+                disk.tracks.push(track);
+            }
+        }
+        Ok(disk)
+    }
+
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let mut raw_data = Vec::new();
+        for (_, sector) in disk.sectors(Order::Logical) {
+            match &sector.data {
+                Some(data) => raw_data.extend_from_slice(data),
+                None => raw_data.resize(raw_data.len() + sector.size, 0),
+            }
+        }
+        Ok(raw_data)
+    }
 }
@@ -0,0 +1,270 @@
+use crate::{FormatHandler, Geometry};
+use crate::formats::ParseError;
+use crate::disk::{Disk, Placement};
+use crate::flux::capture::{self, Capture, Platform, Revolution};
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+// Stream block headers
+const FLUX2_MAX: u8 = 0x07; // 0x00-0x07: two-byte flux value
+const NOP1: u8 = 0x08;
+const NOP2: u8 = 0x09;
+const NOP3: u8 = 0x0A;
+const OVL16: u8 = 0x0B; // Adds 0x10000 to the next flux value
+const FLUX3: u8 = 0x0C;
+const OOB: u8 = 0x0D; // Out-of-band block; 0x0E-0xFF are one-byte flux values
+
+// Out-of-band block types
+const STREAM_INFO: u8 = 0x01;
+const INDEX: u8 = 0x02;
+const STREAM_END: u8 = 0x03;
+const KF_INFO: u8 = 0x04;
+const EOF: u8 = 0x0D;
+
+// Default clocks, derived from the board's 48 MHz master clock
+const MCK: f64 = 18_432_000.0 * 73.0 / 14.0 / 2.0;
+const SCK: f64 = MCK / 2.0;
+const ICK: f64 = MCK / 16.0;
+
+/// An index pulse: the stream position of the flux cell it fell in, the sample clocks from
+/// the start of that cell, and the index clock at the pulse.
+struct Index {
+    position: usize,
+    sample_counter: u32,
+    index_counter: u32,
+}
+
+/// One parsed stream file (a single track).
+struct Stream {
+    flux: Vec<(u32, usize)>, // Sample clocks and the stream position after the value
+    indexes: Vec<Index>,
+    info: Vec<String>,     // KFInfo strings
+    problems: Vec<String>, // Stream positions that don't match and end-of-stream errors
+    sck: f64,
+    ick: f64,
+}
+
+impl Stream {
+    fn parse(data: &[u8], name: &str, cylinder: u8, head: u8) -> Result<Stream> {
+        let mut stream = Stream { flux: Vec::new(), indexes: Vec::new(), info: Vec::new(), problems: Vec::new(), sck: SCK, ick: ICK };
+        let truncated = |at: usize| ParseError::new("raw", at, format!("block in stream {} runs past the end of the file ({} bytes)", name, data.len())).track(cylinder, head);
+        let mut at = 0;
+        let mut position = 0; // Stream position: bytes of flux data, not counting OOB blocks
+        let mut overflow = 0u32;
+        while at < data.len() {
+            let header = data[at];
+            let (value, size) = match header {
+                0x00..=FLUX2_MAX => (Some(((header as u32) << 8) | *data.get(at + 1).ok_or_else(|| truncated(at))? as u32), 2),
+                NOP1 | NOP2 | NOP3 => (None, (header - NOP1 + 1) as usize),
+                OVL16 => {
+                    overflow = overflow.saturating_add(0x10000);
+                    (None, 1)
+                }
+                FLUX3 => {
+                    let bytes = data.get(at + 1..at + 3).ok_or_else(|| truncated(at))?;
+                    (Some(u16::from_be_bytes([bytes[0], bytes[1]]) as u32), 3)
+                }
+                OOB => {
+                    let block = data.get(at + 1..at + 4).ok_or_else(|| truncated(at))?;
+                    let (kind, length) = (block[0], u16::from_le_bytes([block[1], block[2]]) as usize);
+                    if kind == EOF {
+                        break;
+                    }
+                    let body = data.get(at + 4..at + 4 + length).ok_or_else(|| truncated(at))?;
+                    let word = |i: usize| body.get(i * 4..i * 4 + 4).map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+                    match kind {
+                        STREAM_INFO | STREAM_END if word(0) as usize != position => stream.problems.push(format!(
+                            "stream position {} recorded at offset {}, but {} bytes were read", word(0), at, position
+                        )),
+                        _ => {}
+                    }
+                    match kind {
+                        INDEX => stream.indexes.push(Index { position: word(0) as usize, sample_counter: word(1), index_counter: word(2) }),
+                        STREAM_END if word(1) != 0 => stream.problems.push(match word(1) {
+                            1 => "capture ended with a buffering error (data lost)".to_string(),
+                            2 => "capture ended with no index pulse detected".to_string(),
+                            code => format!("capture ended with error code {}", code),
+                        }),
+                        KF_INFO => {
+                            let text = String::from_utf8_lossy(body).trim_end_matches('\0').to_string();
+                            for (key, value) in text.split(',').filter_map(|pair| pair.trim().split_once('=')) {
+                                match (key, value.parse::<f64>()) {
+                                    ("sck", Ok(clock)) => stream.sck = clock,
+                                    ("ick", Ok(clock)) => stream.ick = clock,
+                                    _ => {}
+                                }
+                            }
+                            stream.info.push(text);
+                        }
+                        _ => {}
+                    }
+                    at += 4 + length;
+                    continue;
+                }
+                _ => (Some(header as u32), 1),
+            };
+            if data.len() < at + size {
+                return Err(truncated(at).into());
+            }
+            at += size;
+            position += size;
+            if let Some(value) = value {
+                stream.flux.push((overflow.saturating_add(value), position));
+                overflow = 0;
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Splits the flux into revolutions at the index pulses. Each revolution starts with the
+    /// rest of the cell the index fell in; flux before the first and after the last index is
+    /// dropped.
+    fn revolutions(&self) -> Vec<Revolution> {
+        let ns = 1e9 / self.sck;
+        let cells: Vec<(usize, &Index)> = self.indexes.iter()
+            .filter_map(|index| self.flux.iter().position(|&(_, end)| end > index.position).map(|cell| (cell, index)))
+            .collect();
+        cells.windows(2).map(|pair| {
+            let ((start, from), (end, to)) = (pair[0], pair[1]);
+            let first = self.flux[start].0.saturating_sub(from.sample_counter);
+            let flux = std::iter::once(first).filter(|&t| t > 0)
+                .chain(self.flux[start + 1..end].iter().map(|&(t, _)| t))
+                .map(|t| (t as f64 * ns).round() as u32)
+                .collect();
+            let duration_ns = (to.index_counter.wrapping_sub(from.index_counter) as f64 * 1e9 / self.ick).round() as u64;
+            Revolution { duration_ns, flux }
+        }).collect()
+    }
+}
+
+/// Splits a stream file name like "track00.0.raw" into its prefix, cylinder and head.
+fn stream_name(name: &str) -> Option<(&str, u8, u8)> {
+    let (rest, head) = name.strip_suffix(".raw")?.rsplit_once('.')?;
+    let head = head.parse().ok().filter(|&h| h <= 1)?;
+    let prefix = rest.trim_end_matches(|c: char| c.is_ascii_digit());
+    let cylinder = rest[prefix.len()..].parse().ok()?;
+    Some((prefix, cylinder, head))
+}
+
+/// KryoFlux raw stream files, one per track (`trackCC.H.raw`), opened as a set from a
+/// directory or any one of its files.
+pub struct KryoFluxHandler {
+    source: String,                         // Directory and file name pattern, for display
+    streams: Vec<(u8, u8, String, Stream)>, // Cylinder, head, file name and parsed stream
+}
+
+impl KryoFluxHandler {
+    pub fn open(path: &Path) -> Result<Self> {
+        let (dir, wanted) = if path.is_dir() {
+            (path.to_path_buf(), None)
+        } else {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            let (prefix, _, _) = stream_name(name).ok_or_else(|| anyhow!(
+                "'{}' is not named like a KryoFlux stream file (e.g., 'track00.0.raw')", path.display()
+            ))?;
+            (path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf(), Some(prefix.to_string()))
+        };
+        let mut files: Vec<(String, u8, u8, PathBuf)> = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some((prefix, cylinder, head)) = stream_name(&name) {
+                files.push((prefix.to_string(), cylinder, head, entry.path()));
+            }
+        }
+        // Without a file to go by, use the prefix most files share
+        let prefix = wanted.or_else(|| {
+            let mut prefixes: Vec<&String> = files.iter().map(|(p, ..)| p).collect();
+            prefixes.sort();
+            prefixes.chunk_by(|a, b| a == b).max_by_key(|run| run.len()).map(|run| run[0].clone())
+        }).ok_or_else(|| anyhow!("No KryoFlux stream files (e.g., 'track00.0.raw') found in '{}'", dir.display()))?;
+        files.retain(|(p, ..)| *p == prefix);
+        files.sort_by_key(|&(_, cylinder, head, _)| (cylinder, head));
+
+        let mut streams = Vec::new();
+        for (_, cylinder, head, path) in files {
+            let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            let stream = Stream::parse(&std::fs::read(&path)?, &name, cylinder, head)?;
+            streams.push((cylinder, head, name, stream));
+        }
+        Ok(KryoFluxHandler { source: format!("{}/{}NN.H.raw", dir.display(), prefix), streams })
+    }
+
+    fn capture(&self) -> Capture {
+        let mut capture = Capture::default();
+        for (cylinder, head, _, stream) in &self.streams {
+            capture.tracks.insert(cylinder.saturating_mul(2).saturating_add(*head), stream.revolutions());
+        }
+        capture
+    }
+
+    fn decode_sectors(&self) -> Result<(Disk, Vec<String>)> {
+        let capture = self.capture();
+        let platform = Platform::detect(&capture).ok_or_else(|| anyhow!(
+            "No IBM, Amiga, Commodore or Apple II sectors were found on the first tracks of the KryoFlux streams."
+        ))?;
+        Ok(capture::decode(&capture, platform))
+    }
+}
+
+impl FormatHandler for KryoFluxHandler {
+    fn display(&self, _ascii: bool) -> Result<String> {
+        let mut output = Vec::new();
+        output.push(format!("KryoFlux Stream Files: {} tracks from {}", self.streams.len(), self.source));
+        let mut info: Vec<&String> = self.streams.iter().flat_map(|(.., s)| &s.info).collect();
+        info.sort();
+        info.dedup();
+        output.extend(info.iter().map(|text| format!("KFInfo: {}", text)));
+        if let Some((.., stream)) = self.streams.first() {
+            output.push(format!("Sample Clock: {:.3} MHz, Index Clock: {:.3} MHz", stream.sck / 1e6, stream.ick / 1e6));
+        }
+
+        let capture = self.capture();
+        let counts: Vec<usize> = capture.tracks.values().map(Vec::len).collect();
+        let durations: Vec<u64> = capture.tracks.values().flatten().map(|r| r.duration_ns).filter(|&d| d > 0).collect();
+        if let (Some(min), Some(max)) = (counts.iter().min(), counts.iter().max()) {
+            output.push(format!("Revolutions: {}{} per track", min, if min != max { format!("-{}", max) } else { String::new() }));
+        }
+        if !durations.is_empty() {
+            let average = durations.iter().sum::<u64>() as f64 / durations.len() as f64;
+            output.push(format!("Average RPM: {:.1}", 60e9 / average));
+        }
+        for (_, _, name, stream) in &self.streams {
+            output.extend(stream.problems.iter().map(|problem| format!("  {}: {}", name, problem)));
+        }
+        if Platform::detect(&capture).is_some() {
+            output.extend(self.decode_sectors()?.1);
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    /// Stream sets are spread over several files, so there is no single image to return.
+    fn data(&self) -> &[u8] {
+        &[]
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        let disk = self.disk()?;
+        let Some(first) = disk.tracks.iter().find(|t| !t.sectors.is_empty()) else { return Ok(None) };
+        Ok(Some(Geometry::Manual {
+            cylinders: disk.tracks.iter().map(|t| t.cylinder.saturating_add(1)).max().unwrap_or(0),
+            heads: disk.tracks.iter().map(|t| t.head.saturating_add(1)).max().unwrap_or(0),
+            sectors_per_track: crate::fs::sectors_per_track(&disk) as u8,
+            sector_size: first.sectors[0].size as u16,
+            mode: first.mode,
+        }))
+    }
+
+    fn disk(&self) -> Result<Disk> {
+        Ok(self.decode_sectors()?.0)
+    }
+
+    fn flux(&self) -> Result<Option<Capture>> {
+        Ok(Some(self.capture()))
+    }
+}
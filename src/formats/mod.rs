@@ -1,3 +1,118 @@
+use std::fmt;
+
+pub mod adf;
+pub mod apple;
+pub mod d64;
+pub mod dsk;
+pub mod g64;
+pub mod hfe;
 pub mod imd;
 pub mod img;
+pub mod kryoflux;
+pub mod msa;
+pub mod nib;
 pub mod scp;
+pub mod st;
+pub mod td0;
+pub mod woz;
+
+/// A malformed or truncated image, with where the problem is. Parsers return it inside
+/// `anyhow::Error`, so callers that scan many files can `downcast_ref` it and report the
+/// offset, track and sector without matching on the message text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub format: &'static str,    // File extension, e.g. "imd"
+    pub offset: usize,           // Byte offset into the file
+    pub track: Option<(u8, u8)>, // Cylinder and head being read
+    pub sector: Option<u8>,      // Sector ID being read
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(format: &'static str, offset: usize, message: impl Into<String>) -> Self {
+        ParseError { format, offset, track: None, sector: None, message: message.into() }
+    }
+
+    pub fn track(mut self, cylinder: u8, head: u8) -> Self {
+        self.track = Some((cylinder, head));
+        self
+    }
+
+    pub fn sector(mut self, id: u8) -> Self {
+        self.sector = Some(id);
+        self
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid .{} file at offset {} (0x{:X})", self.format, self.offset, self.offset)?;
+        if let Some((cylinder, head)) = self.track {
+            write!(f, ", Cyl {}, Head {}", cylinder, head)?;
+        }
+        if let Some(id) = self.sector {
+            write!(f, ", Sector ID {}", id)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Bounds-checked little-endian reads through an image. Every failure is a `ParseError` at
+/// the offset of the field being read, carrying the current track and sector.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    format: &'static str,
+    pub pos: usize,
+    pub track: Option<(u8, u8)>,
+    pub sector: Option<u8>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8], format: &'static str, pos: usize) -> Self {
+        Reader { data, format, pos, track: None, sector: None }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.pos)
+    }
+
+    /// An error at the current offset, with the current track and sector.
+    pub fn error(&self, message: impl Into<String>) -> ParseError {
+        self.error_at(self.pos, message)
+    }
+
+    pub fn error_at(&self, offset: usize, message: impl Into<String>) -> ParseError {
+        ParseError { format: self.format, offset, track: self.track, sector: self.sector, message: message.into() }
+    }
+
+    /// The next `count` bytes of `what` (e.g. "sector data").
+    pub fn bytes(&mut self, count: usize, what: &str) -> Result<&'a [u8], ParseError> {
+        let bytes = self.data.get(self.pos..).and_then(|rest| rest.get(..count)).ok_or_else(|| self.error(format!(
+            "{}: needs {} bytes but only {} remain", what, count, self.remaining()
+        )))?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self, what: &str) -> Result<u8, ParseError> {
+        Ok(self.bytes(1, what)?[0])
+    }
+
+    pub fn u16(&mut self, what: &str) -> Result<u16, ParseError> {
+        let b = self.bytes(2, what)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self, what: &str) -> Result<u32, ParseError> {
+        let b = self.bytes(4, what)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self, what: &str) -> Result<u64, ParseError> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.bytes(8, what)?);
+        Ok(u64::from_le_bytes(b))
+    }
+}
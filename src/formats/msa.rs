@@ -0,0 +1,182 @@
+use crate::{FormatHandler, Geometry};
+use crate::formats::ParseError;
+use crate::disk::{Disk, Placement};
+use crate::formats::st::{self, SECTOR_SIZE, StGeometry};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+const MAGIC: u16 = 0x0E0F;
+const HEADER_SIZE: usize = 10;
+const RLE_MARKER: u8 = 0xE5;
+
+/// A parsed MSA file: geometry, the first and last track stored, and each stored track's
+/// sector data (decompressed) with whether it was compressed.
+struct Msa {
+    spt: u8,
+    sides: u8,
+    start: u8,
+    end: u8,
+    tracks: Vec<(Vec<u8>, bool)>,
+}
+
+fn word(data: &[u8], at: usize) -> Option<u16> {
+    data.get(at..at + 2).map(|w| u16::from_be_bytes([w[0], w[1]]))
+}
+
+/// Expands a track's run-length encoding: E5 <byte> <count> repeats the byte `count` times;
+/// any other byte stands for itself.
+fn decompress(packed: &[u8], size: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 0;
+    while pos < packed.len() {
+        if packed[pos] == RLE_MARKER {
+            let value = *packed.get(pos + 1)?;
+            let count = word(packed, pos + 2)? as usize;
+            out.resize(out.len() + count, value);
+            pos += 4;
+        } else {
+            out.push(packed[pos]);
+            pos += 1;
+        }
+        if out.len() > size {
+            return None;
+        }
+    }
+    (out.len() == size).then_some(out)
+}
+
+/// Run-length encodes a track, or returns None if that would not make it smaller. Runs of
+/// four or more bytes are packed, and so is every E5 byte since it would read as a marker.
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    while pos < data.len() {
+        let value = data[pos];
+        let run = data[pos..].iter().take(u16::MAX as usize).take_while(|&&b| b == value).count();
+        if run >= 4 || value == RLE_MARKER {
+            out.push(RLE_MARKER);
+            out.push(value);
+            out.extend_from_slice(&(run as u16).to_be_bytes());
+            pos += run;
+        } else {
+            out.push(value);
+            pos += 1;
+        }
+    }
+    (out.len() < data.len()).then_some(out)
+}
+
+impl Msa {
+    fn parse(data: &[u8]) -> Result<Msa> {
+        let header = |at: usize| word(data, at).ok_or_else(|| ParseError::new("msa", at, format!("{} bytes is too short for the 10-byte header", data.len())));
+        if header(0)? != MAGIC {
+            return Err(ParseError::new("msa", 0, format!("expected signature 0E 0F, found {:04X}", header(0)?)).into());
+        }
+        let (spt, sides, start, end) = (header(2)?, header(4)?.saturating_add(1), header(6)?, header(8)?);
+        if !(1..=36).contains(&spt) || sides > 2 || start > end || end > 255 {
+            return Err(ParseError::new("msa", 2, format!(
+                "header gives {} sectors/track, {} sides, tracks {}-{}. Expected 1-36 sectors, 1 or 2 sides and start <= end <= 255.",
+                spt, sides, start, end
+            )).into());
+        }
+        let size = spt as usize * SECTOR_SIZE;
+        let mut tracks = Vec::new();
+        let mut pos = HEADER_SIZE;
+        for cylinder in start..=end {
+            for side in 0..sides {
+                let length = word(data, pos)
+                    .ok_or_else(|| ParseError::new("msa", pos, "track is missing").track(cylinder as u8, side as u8))? as usize;
+                let packed = data.get(pos + 2..pos + 2 + length).ok_or_else(|| ParseError::new("msa", pos + 2, format!(
+                    "track: needs {} bytes but only {} remain", length, data.len().saturating_sub(pos + 2)
+                )).track(cylinder as u8, side as u8))?;
+                tracks.push(if length == size {
+                    (packed.to_vec(), false)
+                } else {
+                    let unpacked = decompress(packed, size)
+                        .ok_or_else(|| ParseError::new("msa", pos + 2, format!("compressed track does not expand to {} bytes", size)).track(cylinder as u8, side as u8))?;
+                    (unpacked, true)
+                });
+                pos += 2 + length;
+            }
+        }
+        Ok(Msa { spt: spt as u8, sides: sides as u8, start: start as u8, end: end as u8, tracks })
+    }
+
+    fn geometry(&self) -> StGeometry {
+        (self.end + 1, self.sides, self.spt)
+    }
+}
+
+/// Magic Shadow Archiver image of an Atari ST disk: a header giving the geometry and track
+/// range, then each track's sectors, run-length compressed when that saves space.
+pub struct MSAHandler {
+    data: Vec<u8>,
+}
+
+impl MSAHandler {
+    pub fn new(data: Vec<u8>) -> Self {
+        MSAHandler { data }
+    }
+}
+
+impl FormatHandler for MSAHandler {
+    fn display(&self, ascii: bool) -> Result<String> {
+        let msa = Msa::parse(&self.data)?;
+        let mut output = Vec::new();
+        output.push(format!("Atari MSA Image: {} bytes", self.data.len()));
+        if !ascii {
+            let (cylinders, sides, spt) = msa.geometry();
+            output.push(format!(
+                "Detected Geometry: {} cylinders, {} heads, {} sectors/track, {} bytes/sector",
+                cylinders, sides, spt, SECTOR_SIZE
+            ));
+            output.push(format!(
+                "Tracks: {}-{}, {} of {} compressed",
+                msa.start, msa.end, msa.tracks.iter().filter(|(_, packed)| *packed).count(), msa.tracks.len()
+            ));
+            if msa.start == 0 {
+                output.extend(st::boot_info(&msa.tracks[0].0));
+            }
+        } else {
+            output.extend(st::ascii_lines(&self.disk()?));
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        let (cylinders, heads, sectors_per_track) = Msa::parse(&self.data)?.geometry();
+        Ok(Some(Geometry::Manual { cylinders, heads, sectors_per_track, sector_size: SECTOR_SIZE as u16, mode: st::mode(sectors_per_track) }))
+    }
+
+    /// Only the stored track range is present; tracks before the first one are absent.
+    fn disk(&self) -> Result<Disk> {
+        let msa = Msa::parse(&self.data)?;
+        let data: Vec<u8> = msa.tracks.iter().flat_map(|(track, _)| track.iter().copied()).collect();
+        Ok(st::raw_disk(&data, msa.geometry(), msa.start))
+    }
+
+    /// Writes the tracks from the first cylinder present, so a partial MSA keeps its range.
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let (cylinders, sides, spt) = st::layout(disk, ".msa")?;
+        let start = disk.tracks.iter().map(|t| t.cylinder).min().unwrap_or(0);
+        let mut out = Vec::new();
+        for value in [MAGIC, spt as u16, sides as u16 - 1, start as u16, cylinders as u16 - 1] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        for track in st::track_data(disk, (cylinders, sides, spt), start) {
+            let packed = compress(&track).unwrap_or(track);
+            out.extend_from_slice(&(packed.len() as u16).to_be_bytes());
+            out.extend_from_slice(&packed);
+        }
+        Ok(out)
+    }
+}
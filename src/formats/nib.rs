@@ -0,0 +1,79 @@
+use crate::{FormatHandler, Geometry};
+use crate::disk::{Disk, Placement};
+use crate::flux::apple;
+use crate::formats::apple::encode_tracks;
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+const TRACK_SIZE: usize = 6656;
+
+/// Apple II NIB: the disk bytes ("nibbles") of each track as the controller reads them,
+/// 6656 per track, without sync or timing information.
+pub struct NIBHandler {
+    data: Vec<u8>,
+}
+
+impl NIBHandler {
+    pub fn new(data: Vec<u8>) -> Self {
+        NIBHandler { data }
+    }
+
+    fn tracks(&self) -> Result<usize> {
+        match self.data.len() {
+            232_960 => Ok(35),
+            266_240 => Ok(40),
+            size => Err(anyhow!(
+                "Invalid .nib file: {} bytes. Expected 232960 bytes (35 tracks) or 266240 (40 tracks) of 6656 disk bytes each.",
+                size
+            )),
+        }
+    }
+
+    fn decode(&self) -> Result<(Disk, Vec<String>)> {
+        self.tracks()?;
+        // Each track is a loop: decode it twice over so a sector spanning the end is found
+        let tracks = self.data.chunks(TRACK_SIZE)
+            .map(|nibbles| Some(vec![apple::decode_nibbles(&[nibbles, nibbles].concat())]))
+            .collect();
+        Ok(apple::build_disk(tracks))
+    }
+}
+
+impl FormatHandler for NIBHandler {
+    fn display(&self, _ascii: bool) -> Result<String> {
+        let tracks = self.tracks()?;
+        let mut output = vec![format!("Apple II NIB: {} bytes ({} tracks of {} disk bytes)", self.data.len(), tracks, TRACK_SIZE)];
+        output.extend(self.decode()?.1);
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        Ok(Some(Geometry::Manual { cylinders: self.tracks()? as u8, heads: 1, sectors_per_track: 16, sector_size: 256, mode: 5 }))
+    }
+
+    fn disk(&self) -> Result<Disk> {
+        Ok(self.decode()?.0)
+    }
+
+    /// Writes every track in the DOS 3.3 16-sector layout, padded with sync bytes.
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let tracks = encode_tracks(disk, ".nib")?;
+        let count = tracks.len().max(35);
+        let mut out = Vec::with_capacity(count * TRACK_SIZE);
+        for number in 0..count {
+            let mut nibbles = tracks.get(number).map(|bits| apple::nibbles(bits)).unwrap_or_default();
+            nibbles.resize(TRACK_SIZE, 0xFF);
+            out.extend_from_slice(&nibbles);
+        }
+        Ok(out)
+    }
+}
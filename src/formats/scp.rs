@@ -1,7 +1,9 @@
 use crate::{FormatHandler, Geometry};
+use crate::disk::{Disk, Placement};
+use crate::formats::Reader;
+use crate::flux::CellDisk;
+use crate::flux::capture::{self, Capture, Platform, Revolution};
 use anyhow::{Result, anyhow};
-use std::io::{Cursor, Read};
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 
 pub struct SCPHandler {
     data: Vec<u8>,
@@ -13,32 +15,27 @@ impl SCPHandler {
     }
 
     fn parse_header(&self) -> Result<SCPHeader> {
-        if self.data.len() < 16 {
-            return Err(anyhow!("File too short: {} bytes. Expected at least 16 bytes for header.", self.data.len()));
-        }
-        let mut cursor = Cursor::new(&self.data);
-        let mut magic = [0u8; 3];
-        cursor.read_exact(&mut magic)?;
-        if magic != *b"SCP" {
-            return Err(anyhow!("Invalid .scp file: Magic bytes not 'SCP'"));
-        }
-
-        let version = cursor.read_u8()?;
-        let disk_type = cursor.read_u8()?;
-        let revolutions = cursor.read_u8()?;
-        let start_track = cursor.read_u8()?;
-        let end_track = cursor.read_u8()?;
-        let flags = cursor.read_u8()?;
-        let bit_cell_width = cursor.read_u8()?;
-        let heads = cursor.read_u8()?;
-        let resolution = cursor.read_u8()?;
-        let checksum = cursor.read_u32::<LittleEndian>()?;
-
-        if revolutions < 1 || revolutions > 5 {
-            return Err(anyhow!("Invalid revolutions: {}. Must be 1-5.", revolutions));
+        let mut reader = Reader::new(&self.data, "scp", 0);
+        if reader.bytes(3, "signature")? != b"SCP" {
+            return Err(reader.error_at(0, "signature is not 'SCP'").into());
+        }
+
+        let version = reader.u8("version")?;
+        let disk_type = reader.u8("disk type")?;
+        let revolutions = reader.u8("revolution count")?;
+        let start_track = reader.u8("start track")?;
+        let end_track = reader.u8("end track")?;
+        let flags = reader.u8("flags")?;
+        let bit_cell_width = reader.u8("bit cell width")?;
+        let heads = reader.u8("heads")?;
+        let resolution = reader.u8("resolution")?;
+        let checksum = reader.u32("checksum")?;
+
+        if !(1..=5).contains(&revolutions) {
+            return Err(reader.error_at(5, format!("{} revolutions; expected 1-5", revolutions)).into());
         }
         if start_track > end_track || end_track > 167 {
-            return Err(anyhow!("Invalid track range: start {} to end {}. Max 167.", start_track, end_track));
+            return Err(reader.error_at(6, format!("track range {} to {}; expected start <= end <= 167", start_track, end_track)).into());
         }
 
         Ok(SCPHeader {
@@ -59,9 +56,24 @@ impl SCPHandler {
         let manufacturer = disk_type >> 4;
         let subclass = disk_type & 0x0F;
         match manufacturer {
-            0x00 => format!("CBM, subclass {}", subclass),
+            0x00 => match subclass {
+                0x00 => "C64".to_string(),
+                0x01 => "Commodore Plus/4".to_string(),
+                0x02 => "Commodore VIC-20".to_string(),
+                0x03 => "Commodore PET".to_string(),
+                _ => format!("CBM, subclass {}", subclass),
+            },
             0x01 => format!("Atari, subclass {}", subclass),
-            0x02 => format!("Apple, subclass {}", subclass),
+            0x02 => match subclass {
+                0x00 => "Apple II".to_string(),
+                0x01 => "Apple II Pro".to_string(),
+                _ => format!("Apple, subclass {}", subclass),
+            },
+            0x04 => match subclass {
+                0x00 => "Amiga".to_string(),
+                0x01 => "Amiga HD".to_string(),
+                _ => format!("Amiga, subclass {}", subclass),
+            },
             0x03 => match subclass {
                 0x03 => "PC 1.44M".to_string(),
                 0x05 => "PC 1.44M".to_string(), // Add 0x35 for v2.5 Index Mode
@@ -78,59 +90,375 @@ impl SCPHandler {
     fn parse_track_headers(&self) -> Result<Vec<TrackInfo>> {
         let header = self.parse_header()?;
         let mut tracks = Vec::new();
-        let mut cursor = Cursor::new(&self.data);
 
-        // Skip header (16 bytes) to TDH offset table
-        cursor.set_position(16);
-        let track_count = (header.end_track as usize - header.start_track as usize + 1) as u64;
-        if self.data.len() < (16 + track_count * 4) as usize {
-            return Err(anyhow!("File too short for TDH table: {} bytes, need {}", self.data.len(), 16 + track_count * 4));
+        // The TDH offset table follows the header, indexed by track number
+        let mut table = Reader::new(&self.data, "scp", header.table_offset() as usize + header.start_track as usize * 4);
+        for track_num in header.start_track..=header.end_track {
+            let offset = table.u32(&format!("track {} offset table entry", track_num))?;
+            if offset == 0 {
+                continue; // No data for this track
+            }
+            // "TRK", the track number, then index time, flux count and data offset per revolution
+            let mut reader = Reader::new(&self.data, "scp", offset as usize);
+            if reader.bytes(3, &format!("track {} header", track_num))? != b"TRK" {
+                return Err(reader.error_at(offset as usize, format!("track {} header does not start with 'TRK'", track_num)).into());
+            }
+            let track_number = reader.u8("track number")?;
+            let mut revolutions = Vec::with_capacity(header.revolutions as usize);
+            for revolution in 0..header.revolutions {
+                let what = format!("track {} revolution {} entry", track_number, revolution);
+                revolutions.push(RevolutionInfo {
+                    index_time: reader.u32(&what)?,
+                    count: reader.u32(&what)?,
+                    data_offset: reader.u32(&what)?,
+                });
+            }
+            tracks.push(TrackInfo { track_number, offset, revolutions });
         }
+        Ok(tracks)
+    }
 
-        for _track_num in header.start_track as usize..=header.end_track as usize {
-            let offset = cursor.read_u32::<LittleEndian>()?;
+    /// The extension footer, when the header flags one and the file ends with "FPCS".
+    fn parse_footer(&self, header: &SCPHeader) -> Result<Option<Footer>> {
+        if header.flags & FLAG_FOOTER == 0 || self.data.len() < 16 + FOOTER_SIZE || !self.data.ends_with(b"FPCS") {
+            return Ok(None);
+        }
+        let mut reader = Reader::new(&self.data, "scp", self.data.len() - FOOTER_SIZE);
+        let mut strings = [None, None, None, None, None, None];
+        for string in &mut strings {
+            let offset = reader.u32("footer string offset")? as usize;
             if offset == 0 {
-                continue; // No data for this track
+                continue;
             }
-            if offset as usize + 12 > self.data.len() {
-                return Err(anyhow!("Track offset 0x{:08X} exceeds file size {}", offset, self.data.len()));
+            // A 16-bit length, the text and a terminating zero
+            let mut text = Reader::new(&self.data, "scp", offset);
+            let length = text.u16("footer string length")? as usize;
+            *string = Some(String::from_utf8_lossy(text.bytes(length, "footer string")?).trim_end_matches('\0').to_string());
+        }
+        let [manufacturer, model, serial, creator, application, comments] = strings;
+        Ok(Some(Footer {
+            manufacturer,
+            model,
+            serial,
+            creator,
+            application,
+            comments,
+            created: reader.u64("footer creation time")?,
+            modified: reader.u64("footer modification time")?,
+            application_version: reader.u8("application version")?,
+            hardware_version: reader.u8("hardware version")?,
+            firmware_version: reader.u8("firmware version")?,
+            format_revision: reader.u8("format revision")?,
+        }))
+    }
+
+    /// Flux transition intervals in nanoseconds for each revolution of a track, with the
+    /// index-to-index times.
+    fn track_flux(&self, header: &SCPHeader, track: &TrackInfo) -> Result<Vec<Revolution>> {
+        let tick_ns = header.tick_ns();
+        let mut revolutions = Vec::new();
+        for (revolution, info) in track.revolutions.iter().enumerate() {
+            let start = track.offset as usize + info.data_offset as usize;
+            let bytes = Reader::new(&self.data, "scp", start)
+                .bytes(info.count as usize * 2, &format!("track {} revolution {} flux data", track.track_number, revolution))?;
+            let mut intervals = Vec::with_capacity(info.count as usize);
+            let mut carry = 0u32;
+            for pair in bytes.chunks_exact(2) {
+                let value = u16::from_be_bytes([pair[0], pair[1]]) as u32;
+                if value == 0 {
+                    carry = carry.saturating_add(0x10000); // Overflow: add to the next interval
+                } else {
+                    intervals.push(carry.saturating_add(value).saturating_mul(tick_ns));
+                    carry = 0;
+                }
             }
-            let mut track_cursor = Cursor::new(&self.data);
-            track_cursor.set_position(offset as u64);
-            let mut trk = [0u8; 3];
-            track_cursor.read_exact(&mut trk)?;
-            if trk != *b"TRK" {
-                return Err(anyhow!("Invalid track header at offset 0x{:08X}: Expected 'TRK'", offset));
+            revolutions.push(Revolution { duration_ns: info.index_time as u64 * tick_ns as u64, flux: intervals });
+        }
+        Ok(revolutions)
+    }
+
+    fn capture(&self) -> Result<Capture> {
+        let header = self.parse_header()?;
+        let mut capture = Capture {
+            disk_type: Some(header.disk_type),
+            tpi96: header.flags & 0x02 != 0,
+            footer: self.parse_footer(&header)?,
+            ..Default::default()
+        };
+        let tracks = self.parse_track_headers()?;
+        let numbering = Numbering::of(&header, &tracks);
+        for info in &tracks {
+            capture.tracks.insert(numbering.capture_track(info.track_number)?, self.track_flux(&header, info)?);
+        }
+        Ok(capture)
+    }
+
+    /// Decodes sectors from every track (see `Platform` for the encodings), with a summary
+    /// and a line per track with problems for `display`. The platform comes from the disk
+    /// type, or is detected from the flux when the type has no known encoding.
+    fn decode_sectors(&self) -> Result<(Disk, Vec<String>)> {
+        let capture = self.capture()?;
+        let platform = Platform::of(&capture).ok_or_else(|| anyhow!(
+            "Sector decoding from .scp needs a Commodore (disk type 0x00-0x0F), Apple II (0x20-0x2F), PC (0x30-0x3F) or Amiga (0x40 or 0x41) disk, and no known encoding was found on the first tracks."
+        ))?;
+        Ok(capture::decode(&capture, platform))
+    }
+
+    fn is_amiga(&self) -> Result<bool> {
+        Ok(self.parse_header()?.disk_type >> 4 == 0x04)
+    }
+}
+
+const TRACK_SLOTS: usize = 168; // Entries in the TDH offset table
+const TICK_NS: f64 = 25.0;      // Resolution 0
+const FLAG_FOOTER: u8 = 0x20;   // Extension footer at the end of the file
+const FLAG_EXTENDED: u8 = 0x40; // Extended mode: the TDH offset table starts at 0x80
+const FOOTER_SIZE: usize = 0x30;
+
+/// The SCP extension footer: drive and image details, and the versions of the software and
+/// hardware that made the capture. Strings are stored elsewhere in the file and located by
+/// offset; timestamps are seconds since 1970 (UTC).
+#[derive(Clone, Default)]
+pub struct Footer {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub creator: Option<String>,
+    pub application: Option<String>,
+    pub comments: Option<String>,
+    pub created: u64,
+    pub modified: u64,
+    pub application_version: u8, // Major and minor version in the high and low nibbles
+    pub hardware_version: u8,
+    pub firmware_version: u8,
+    pub format_revision: u8,
+}
+
+impl Footer {
+    fn lines(&self) -> Vec<String> {
+        let version = |v: u8| if v == 0 { "unknown".to_string() } else { format!("{}.{}", v >> 4, v & 0x0F) };
+        let mut lines = Vec::new();
+        for (label, text) in [
+            ("Drive Manufacturer", &self.manufacturer),
+            ("Drive Model", &self.model),
+            ("Drive Serial", &self.serial),
+            ("Creator", &self.creator),
+            ("Application", &self.application),
+            ("Comments", &self.comments),
+        ] {
+            if let Some(text) = text {
+                lines.push(format!("  {}: {}", label, text));
             }
-            let track_number = track_cursor.read_u8()?;
-            let duration_total = track_cursor.read_u32::<BigEndian>()?; // Total duration in resolution units
-            // println!("Track {} duration_total: {}", track_number, duration_total); // Debug print (commented out)
-            let length_bytes = [
-                track_cursor.read_u8()?,
-                track_cursor.read_u8()?,
-                track_cursor.read_u8()?,
-            ];
-            let length = ((length_bytes[0] as u32) << 16) | ((length_bytes[1] as u32) << 8) | (length_bytes[2] as u32); // 3 bytes, big-endian
-            let effective_length = std::cmp::min(length, (self.data.len() - offset as usize) as u32); // Cap length
-            track_cursor.read_u8()?; // Skip extra byte (flux data start)
-            if offset as usize + effective_length as usize > self.data.len() {
-                let track_start = offset as usize;
-                let track_end = std::cmp::min(track_start + 12, self.data.len());
-                let track_hex: Vec<String> = self.data[track_start..track_end].iter().map(|b| format!("{:02X}", b)).collect();
-                println!("Warning: Track {} length {} bytes at offset 0x{:08X} exceeds file size {}. Track header bytes: {}", 
-                         track_number, effective_length, offset, self.data.len(), track_hex.join(" "));
+        }
+        lines.push(format!("  Created: {}", utc(self.created)));
+        lines.push(format!("  Modified: {}", utc(self.modified)));
+        lines.push(format!(
+            "  Application Version: {}, Hardware: {}, Firmware: {}, Format Revision: {}",
+            version(self.application_version), version(self.hardware_version), version(self.firmware_version), version(self.format_revision)
+        ));
+        lines
+    }
+
+    /// Strings followed by the footer itself, for a footer that will start at file offset `at`.
+    fn encode(&self, at: usize) -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut offsets = Vec::new();
+        for text in [&self.manufacturer, &self.model, &self.serial, &self.creator, &self.application, &self.comments] {
+            match text {
+                Some(text) => {
+                    offsets.push((at + strings.len()) as u32);
+                    strings.extend_from_slice(&(text.len() as u16).to_le_bytes());
+                    strings.extend_from_slice(text.as_bytes());
+                    strings.push(0);
+                }
+                None => offsets.push(0),
             }
-            tracks.push(TrackInfo {
-                track_number,
-                duration_total,
-                length: effective_length,
-                offset,
-            });
         }
-        Ok(tracks)
+        let mut out = strings;
+        for offset in offsets {
+            out.extend_from_slice(&offset.to_le_bytes());
+        }
+        out.extend_from_slice(&self.created.to_le_bytes());
+        out.extend_from_slice(&self.modified.to_le_bytes());
+        out.extend_from_slice(&[self.application_version, self.hardware_version, self.firmware_version, self.format_revision]);
+        out.extend_from_slice(b"FPCS");
+        out
+    }
+}
+
+/// Seconds since 1970 as a UTC date and time, or "not set" for zero.
+fn utc(seconds: u64) -> String {
+    if seconds == 0 {
+        return "not set".to_string();
+    }
+    // Days to a civil date, counting from 1 March 0000 so leap days fall at the end of a year
+    let days = (seconds / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let time = seconds % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// How TDH entries map to drive cylinders and heads. Normally entry N is cylinder N / 2,
+/// head N % 2, whatever the heads field says. Some software numbers the tracks of a
+/// single-sided capture consecutively instead, which shows as entries on the other side.
+enum Numbering {
+    Interleaved,
+    Consecutive { head: u8 },
+}
+
+impl Numbering {
+    fn of(header: &SCPHeader, tracks: &[TrackInfo]) -> Numbering {
+        match header.heads {
+            1 | 2 if tracks.iter().any(|t| t.track_number % 2 != header.heads - 1) => Numbering::Consecutive { head: header.heads - 1 },
+            _ => Numbering::Interleaved,
+        }
+    }
+
+    /// Drive cylinder and head of a TDH entry.
+    fn location(&self, track_number: u8) -> (u16, u8) {
+        match *self {
+            Numbering::Interleaved => (track_number as u16 / 2, track_number % 2),
+            Numbering::Consecutive { head } => (track_number as u16, head),
+        }
+    }
+
+    /// Capture track number (cylinder * 2 + head) of a TDH entry.
+    fn capture_track(&self, track_number: u8) -> Result<u8> {
+        let (cylinder, head) = self.location(track_number);
+        u8::try_from(cylinder * 2 + head as u16).map_err(|_| anyhow!(
+            "Track {} of the single-sided capture is cylinder {}; cylinders above 127 are not supported", track_number, cylinder
+        ))
     }
 }
 
+/// SCP disk type to record for a capture that has none: the detected platform's, or "other".
+fn disk_type(capture: &Capture) -> u8 {
+    match Platform::detect(capture) {
+        Some(Platform::Cbm) => 0x00,
+        Some(Platform::Apple) => 0x20,
+        Some(Platform::Ibm) => 0x30,
+        Some(Platform::Amiga { hd }) => if hd { 0x41 } else { 0x40 },
+        None => 0x80,
+    }
+}
+
+/// Writes a flux capture as SCP with 25 ns resolution. Every track gets the same number of
+/// revolutions, the fewest any track has (at most five), each with its index-to-index time.
+pub fn encode_capture(capture: &Capture) -> Result<Vec<u8>> {
+    let (Some(&first), Some(&last)) = (capture.tracks.keys().next(), capture.tracks.keys().next_back()) else {
+        return Err(anyhow!("Cannot write .scp: the capture has no tracks"));
+    };
+    if last as usize >= TRACK_SLOTS {
+        return Err(anyhow!("Cannot write .scp: track {} is past the last SCP track ({})", last, TRACK_SLOTS - 1));
+    }
+    let revolutions = capture.tracks.values().map(Vec::len).min().unwrap_or(0).min(5);
+    if revolutions == 0 {
+        return Err(anyhow!("Cannot write .scp: some tracks have no complete revolution between index pulses"));
+    }
+    let ticks = |ns: f64| (ns / TICK_NS).round().max(1.0) as u32;
+    let durations: Vec<u64> = capture.tracks.values().flat_map(|revs| revs[..revolutions].iter().map(|r| r.duration_ns)).collect();
+    let average_ns = durations.iter().sum::<u64>() / durations.len() as u64;
+    let sides = capture.tracks.keys().fold(0u8, |sides, &number| sides | (1 << (number % 2)));
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"SCP");
+    out.push(0x24); // Version 2.4
+    out.push(capture.disk_type.unwrap_or_else(|| disk_type(capture)));
+    out.push(revolutions as u8);
+    out.push(first);
+    out.push(last);
+    // Index-aligned, and 360 RPM when a revolution is nearer 166.7 ms than 200 ms
+    out.push(
+        0x01 | if capture.tpi96 { 0x02 } else { 0 } | if average_ns < 183_000_000 { 0x04 } else { 0 }
+            | if capture.footer.is_some() { FLAG_FOOTER } else { 0 }
+    );
+    out.push(0); // 16-bit flux values
+    out.push(match sides { 1 => 1, 2 => 2, _ => 0 });
+    out.push(0); // Resolution: 25 ns
+    out.extend_from_slice(&[0; 4]); // Checksum, filled in below
+    out.resize(16 + TRACK_SLOTS * 4, 0);
+
+    for (&number, revs) in &capture.tracks {
+        let offset = out.len();
+        out[16 + number as usize * 4..20 + number as usize * 4].copy_from_slice(&(offset as u32).to_le_bytes());
+        out.extend_from_slice(b"TRK");
+        out.push(number);
+        let mut data: Vec<u8> = Vec::new();
+        let mut entries = Vec::new();
+        let table_end = 4 + revolutions * 12;
+        for revolution in &revs[..revolutions] {
+            let start = table_end + data.len();
+            let mut count = 0u32;
+            for &interval in &revolution.flux {
+                let mut value = ticks(interval as f64);
+                // A zero word carries 65536 ticks into the next value, which must not be zero itself
+                while value > 0xFFFF {
+                    data.extend_from_slice(&[0, 0]);
+                    value -= 0x10000;
+                    count += 1;
+                }
+                data.extend_from_slice(&(value.max(1) as u16).to_be_bytes());
+                count += 1;
+            }
+            entries.push((ticks(revolution.duration_ns as f64), count, start as u32));
+        }
+        for (index_time, count, start) in entries {
+            out.extend_from_slice(&index_time.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            out.extend_from_slice(&start.to_le_bytes());
+        }
+        out.extend_from_slice(&data);
+    }
+    if let Some(footer) = &capture.footer {
+        // Kept from the source image, apart from the modification time and format revision
+        let modified = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let footer = Footer { modified, format_revision: 0x24, ..footer.clone() };
+        let at = out.len();
+        out.extend(footer.encode(at));
+    }
+    let checksum = out[16..].iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+    out[12..16].copy_from_slice(&checksum.to_le_bytes());
+    Ok(out)
+}
+
+/// Flux for a sector disk, made from the cells of `CellDisk` with every transition exactly on
+/// its cell: one revolution per track, numbered cylinder * 2 + head.
+pub fn synthesize(disk: &Disk) -> Result<Capture> {
+    let cells = CellDisk::new(disk, "scp")?;
+    let cell_ns = 1_000_000 / cells.kcells as u32;
+    let disk_type = match (cells.amiga, cells.kcells) {
+        (true, kcells) => if kcells > 550 { 0x41 } else { 0x40 },
+        (false, 1000..) => if cells.rpm == 360 { 0x32 } else { 0x33 }, // PC 1.2M or 1.44M
+        (false, _) => if cells.tracks.len() > 42 { 0x31 } else { 0x30 }, // PC 720K or 360K
+    };
+    let mut capture = Capture { disk_type: Some(disk_type), ..Default::default() };
+    for (cylinder, pair) in cells.tracks.iter().enumerate() {
+        for (head, stream) in pair.iter().enumerate().take(cells.sides) {
+            let number = u8::try_from(cylinder * 2 + head)
+                .map_err(|_| anyhow!("Cannot write .scp: cylinder {} is past the last SCP track", cylinder))?;
+            let mut flux = Vec::new();
+            let mut run = 0;
+            for &cell in stream {
+                run += 1;
+                if cell == 1 {
+                    flux.push(run * cell_ns);
+                    run = 0;
+                }
+            }
+            let duration_ns = stream.len() as u64 * cell_ns as u64;
+            capture.tracks.insert(number, vec![Revolution { duration_ns, flux }]);
+        }
+    }
+    Ok(capture)
+}
+
 struct SCPHeader {
     version: u8,
     disk_type: u8,
@@ -144,34 +472,52 @@ struct SCPHeader {
     checksum: u32,
 }
 
+impl SCPHeader {
+    /// File offset of the TDH offset table.
+    fn table_offset(&self) -> u64 {
+        if self.flags & FLAG_EXTENDED != 0 { 0x80 } else { 16 }
+    }
+
+    /// Length of one flux and index time unit: 25 ns times (resolution + 1).
+    fn tick_ns(&self) -> u32 {
+        25 * (self.resolution as u32 + 1)
+    }
+}
+
 struct TrackInfo {
     track_number: u8,
-    duration_total: u32, // Total in resolution units across all revolutions
-    length: u32,         // in bytes (capped)
-    offset: u32,         // file offset
+    offset: u32,                     // File offset of the track data header
+    revolutions: Vec<RevolutionInfo>,
+}
+
+struct RevolutionInfo {
+    index_time: u32,  // Index-to-index time in ticks
+    count: u32,       // Flux values (16-bit words, including overflow words)
+    data_offset: u32, // From the start of the track data header
 }
 
 impl FormatHandler for SCPHandler {
     fn display(&self, _ascii: bool) -> Result<String> {
         let header = self.parse_header()?;
         let tracks = self.parse_track_headers()?;
+        let capture = self.capture()?;
         let mut output = Vec::new();
 
-        // Debug: Dump first 16 bytes
-        let header_hex: Vec<String> = self.data[..16].iter().map(|b| format!("{:02X}", b)).collect();
-        output.push(format!("Header Hex: {}", header_hex.join(" ")));
-
-        output.push(format!("SuperCard Pro Image (.scp)"));
+        output.push("SuperCard Pro Image (.scp)".to_string());
         output.push(format!("File Size: {} bytes", self.data.len()));
         output.push(format!("Version: {}.{}", header.version >> 4, header.version & 0x0F));
         output.push(format!("Disk Type: {} (0x{:02X})", self.disk_type_to_string(header.disk_type), header.disk_type));
         output.push(format!("Revolutions: {}", header.revolutions));
         output.push(format!("Track Range: {} to {}", header.start_track, header.end_track));
-        output.push(format!("Flags: 0x{:02X} (Index: {}, TPI: {}, Flux at Index: {})",
+        output.push(format!("Flags: 0x{:02X} (Index: {}, TPI: {}, RPM: {}, Normalized: {}, Read/Write: {}, Footer: {}, Extended: {})",
             header.flags,
             header.flags & 0x01 != 0,
             if header.flags & 0x02 != 0 { "96" } else { "48" },
-            header.flags & 0x20 != 0
+            if header.flags & 0x04 != 0 { "360" } else { "300" },
+            header.flags & 0x08 != 0,
+            header.flags & 0x10 != 0,
+            header.flags & FLAG_FOOTER != 0,
+            header.flags & FLAG_EXTENDED != 0
         ));
         output.push(format!(
             "Bit Cell Width: {}",
@@ -186,42 +532,68 @@ impl FormatHandler for SCPHandler {
                 _ => format!("Invalid: {}", header.heads),
             }
         ));
-        let resolution_ns = if header.resolution == 0 { 25 } else { header.resolution as u32 * 25 }; // Default 25ns
-        let inferred_resolution_ns = if !tracks.is_empty() && header.revolutions > 0 {
-            let duration_units = tracks[0].duration_total as f64 / header.revolutions as f64;
-            if duration_units > 0.0 {
-                (200_000.0 / (duration_units / 1_000_000.0)) as u32 // Assume 1µs units, convert to ns
-            } else {
-                resolution_ns
-            }
-        } else {
-            resolution_ns
-        }; // Infer from first track if possible
-        output.push(format!(
-            "Resolution: {}ns (inferred: {}ns){}",
-            resolution_ns,
-            inferred_resolution_ns,
-            if header.resolution == 0 { " (assumed default for v2.5)" } else { "" }
-        ));
+        let tick_ns = header.tick_ns();
+        output.push(format!("Resolution: {} ns", tick_ns));
         output.push(format!("Checksum: 0x{:08X}", header.checksum));
 
         // Track data
         output.push(format!("Tracks ({}):", tracks.len()));
-        let resolution_us = inferred_resolution_ns as f64 / 1_000_000.0; // Use inferred resolution in µs
-        for track in tracks {
-            let duration_ms_total = (track.duration_total as f64 * resolution_us) / 1_000.0; // Total µs to ms
-            let duration_ms_per_rev = duration_ms_total / header.revolutions as f64; // Per revolution
+        let mut rpms = Vec::new();
+        let numbering = Numbering::of(&header, &tracks);
+        if let Numbering::Consecutive { head } = numbering {
+            output.push(format!("  Single-sided tracks numbered consecutively: track N is cylinder N, head {}", head));
+        }
+        for track in &tracks {
+            let rpm: Vec<String> = track.revolutions.iter().map(|r| {
+                if r.index_time == 0 {
+                    return "-".to_string();
+                }
+                let rpm = 60e9 / (r.index_time as f64 * tick_ns as f64);
+                rpms.push(rpm);
+                format!("{:.2}", rpm)
+            }).collect();
+            let (cylinder, head) = numbering.location(track.track_number);
             output.push(format!(
-                "  Track {}: Duration {:.2}ms/rev (Total {:.2}ms), Length {} bytes, Offset 0x{:08X}",
-                track.track_number, duration_ms_per_rev, duration_ms_total, track.length, track.offset
+                "  Track {} (Cyl {}, Head {}): RPM {}, {} flux values, Offset 0x{:08X}",
+                track.track_number, cylinder, head, rpm.join(", "), track.revolutions.iter().map(|r| r.count as u64).sum::<u64>(), track.offset
             ));
         }
+        if !rpms.is_empty() {
+            output.push(format!("Average RPM: {:.2}", rpms.iter().sum::<f64>() / rpms.len() as f64));
+        }
+
+        match &capture.footer {
+            Some(footer) => {
+                output.push("Footer:".to_string());
+                output.extend(footer.lines());
+            }
+            None if header.flags & FLAG_FOOTER != 0 => output.push("Footer: flagged in the header but no FPCS signature at the end of the file".to_string()),
+            None => {}
+        }
+
+        if let Some(platform) = Platform::of(&capture) {
+            output.extend(capture::decode(&capture, platform).1);
+        }
 
         Ok(output.join("\n"))
     }
 
-    fn convert(&self, _target: &dyn FormatHandler, _output_path: &std::path::PathBuf, _input_path: &std::path::PathBuf, _meta_path: Option<&std::path::PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool) -> Result<()> {
-        Err(anyhow!("Conversion from .scp not yet implemented"))
+    fn convert(&self, target: &dyn FormatHandler, output_path: &std::path::Path, _input_path: &std::path::Path, _meta_path: Option<&std::path::PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn disk(&self) -> Result<Disk> {
+        Ok(self.decode_sectors()?.0)
+    }
+
+    fn flux(&self) -> Result<Option<Capture>> {
+        Ok(Some(self.capture()?))
+    }
+
+    /// Sector images are written as flux synthesized from their cells (see `synthesize`).
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        encode_capture(&synthesize(disk)?)
     }
 
     fn data(&self) -> &[u8] {
@@ -238,6 +610,9 @@ impl FormatHandler for SCPHandler {
                 sector_size: 512,
                 mode: 5, // Common MFM mode for 1.44M
             }))
+        } else if self.is_amiga()? {
+            let hd = header.disk_type & 0x0F == 0x01;
+            Ok(Some(Geometry::Manual { cylinders: 80, heads: 2, sectors_per_track: if hd { 22 } else { 11 }, sector_size: 512, mode: if hd { 3 } else { 5 } }))
         } else {
             Ok(None) // Geometry inference not implemented for other types yet
         }
@@ -0,0 +1,191 @@
+use crate::{FormatHandler, Geometry};
+use crate::disk::{Disk, Order, Placement, Sector, Track};
+use crate::fs::fat::Bpb;
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Cylinders, sides and sectors per track of an ST disk.
+pub type StGeometry = (u8, u8, u8);
+
+/// IMD mode for the sector count: 250 kbps MFM for double density (up to 11 sectors per
+/// track), 500 kbps MFM for high density.
+pub fn mode(sectors_per_track: u8) -> u8 {
+    if sectors_per_track > 11 { 3 } else { 5 }
+}
+
+/// Works out the geometry from the ST boot sector when it describes an image of this size,
+/// otherwise from the size alone. Also returns a description of the source used.
+pub fn detect_geometry(data: &[u8]) -> Result<(StGeometry, &'static str)> {
+    let total = data.len() / SECTOR_SIZE;
+    if let Some(bpb) = data.get(..SECTOR_SIZE).and_then(Bpb::parse) {
+        let track_sectors = bpb.sectors_per_track as usize * bpb.heads as usize;
+        let plausible = bpb.bytes_per_sector as usize == SECTOR_SIZE
+            && (1..=2).contains(&bpb.heads)
+            && (1..=36).contains(&bpb.sectors_per_track)
+            && bpb.total_sectors as usize * SECTOR_SIZE == data.len()
+            && total.is_multiple_of(track_sectors)
+            && total / track_sectors <= 255;
+        if plausible {
+            return Ok((((total / track_sectors) as u8, bpb.heads as u8, bpb.sectors_per_track as u8), "boot sector"));
+        }
+    }
+    if data.len().is_multiple_of(SECTOR_SIZE) {
+        // ST disks are usually 80-84 tracks of 9-11 sectors, one- or two-sided
+        for cylinders in [80, 81, 82, 83, 84, 79, 40, 41, 42] {
+            for sides in [2, 1] {
+                let spt = total / (cylinders * sides);
+                if spt * cylinders * sides == total && ((9..=11).contains(&spt) || spt == 18) {
+                    return Ok(((cylinders as u8, sides as u8, spt as u8), "file size (best guess)"));
+                }
+            }
+        }
+    }
+    Err(anyhow!(
+        "No Atari ST geometry fits {} bytes and the boot sector does not describe the disk. ST images are 80-84 tracks of 9, 10 or 11 512-byte sectors on one or two sides (e.g., 368640 bytes for 80 x 1 x 9, 819200 for 80 x 2 x 10).",
+        data.len()
+    ))
+}
+
+/// Boot sector details: whether TOS would execute it (the big-endian words sum to 0x1234) and
+/// the serial number written when the disk was formatted.
+pub fn boot_info(boot: &[u8]) -> Vec<String> {
+    if boot.len() < SECTOR_SIZE {
+        return Vec::new();
+    }
+    let sum = boot[..SECTOR_SIZE].chunks_exact(2).fold(0u16, |sum, w| sum.wrapping_add(u16::from_be_bytes([w[0], w[1]])));
+    vec![
+        format!("Boot Sector: {}", if sum == 0x1234 { "executable (checksum 0x1234)" } else { "not executable" }),
+        format!("Serial Number: {:02X}{:02X}{:02X}", boot[8], boot[9], boot[10]),
+    ]
+}
+
+/// Builds the disk from track-ordered sector data, sectors numbered from 1 on each track.
+/// Tracks before `first_cylinder` are left out.
+pub fn raw_disk(data: &[u8], (cylinders, sides, spt): StGeometry, first_cylinder: u8) -> Disk {
+    let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+    let mut chunks = data.chunks(SECTOR_SIZE);
+    for cylinder in first_cylinder..cylinders {
+        for head in 0..sides {
+            let mut track = Track { mode: mode(spt), cylinder, head, ..Default::default() };
+            for id in 1..=spt {
+                let chunk = chunks.next().unwrap_or_default();
+                let mut sector = Sector { id, cylinder, head, size: SECTOR_SIZE, data: Some(chunk.to_vec()), ..Default::default() };
+                sector.compressed = sector.is_uniform();
+                track.sectors.push(sector);
+            }
+            disk.tracks.push(track);
+        }
+    }
+    disk
+}
+
+/// Geometry for writing the disk as an ST layout, checking that every sector is 512 bytes,
+/// numbered from 1 and on one of two sides. `target` names the format in errors.
+pub fn layout(disk: &Disk, target: &str) -> Result<StGeometry> {
+    let (mut cylinders, mut sides, mut spt) = (0usize, 0u8, 0u8);
+    for track in &disk.tracks {
+        if track.head > 1 {
+            return Err(anyhow!("Cannot write {}: Cyl {} has head {}; ST disks have at most two sides", target, track.cylinder, track.head));
+        }
+        if let Some(s) = track.sectors.iter().find(|s| s.size != SECTOR_SIZE || s.id == 0) {
+            return Err(anyhow!(
+                "Cannot write {}: Cyl {}, Head {}, Sector {} ({} bytes) does not fit the ST layout of 512-byte sectors numbered from 1",
+                target, track.cylinder, track.head, s.id, s.size
+            ));
+        }
+        cylinders = cylinders.max(track.cylinder as usize + 1);
+        sides = sides.max(track.head.saturating_add(1));
+        spt = spt.max(track.sectors.iter().map(|s| s.id).max().unwrap_or(0));
+    }
+    if spt == 0 {
+        return Err(anyhow!("Cannot write {}: the disk has no sectors", target));
+    }
+    Ok((cylinders as u8, sides, spt))
+}
+
+/// Sector data of each track from `first_cylinder` on, in cylinder then side order, with
+/// missing sectors zero-filled.
+pub fn track_data(disk: &Disk, (cylinders, sides, spt): StGeometry, first_cylinder: u8) -> Vec<Vec<u8>> {
+    let mut tracks = Vec::new();
+    for cylinder in first_cylinder..cylinders {
+        for head in 0..sides {
+            let track = disk.tracks.iter().find(|t| t.cylinder == cylinder && t.head == head);
+            let mut data = Vec::with_capacity(spt as usize * SECTOR_SIZE);
+            for id in 1..=spt {
+                match track.and_then(|t| t.sectors.iter().find(|s| s.id == id)).and_then(|s| s.data.as_ref()) {
+                    Some(sector) => data.extend_from_slice(sector),
+                    None => data.resize(data.len() + SECTOR_SIZE, 0),
+                }
+            }
+            tracks.push(data);
+        }
+    }
+    tracks
+}
+
+/// Lines listing each sector's first bytes for `display --ascii`.
+pub fn ascii_lines(disk: &Disk) -> Vec<String> {
+    disk.sectors(Order::Physical).into_iter().map(|(track, sector)| {
+        let ascii_str: String = sector.data.as_deref().unwrap_or_default().iter()
+            .take(32)
+            .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
+            .collect();
+        format!("Cyl {}, Head {}, Sector {}, Size {} bytes: {}", track.cylinder, track.head, sector.id, sector.size, ascii_str)
+    }).collect()
+}
+
+/// Atari ST raw image: the 512-byte sectors of each track in cylinder then side order, with
+/// the geometry given only by the boot sector (9, 10 or 11 sectors per track, often one-sided).
+pub struct STHandler {
+    data: Vec<u8>,
+}
+
+impl STHandler {
+    pub fn new(data: Vec<u8>) -> Self {
+        STHandler { data }
+    }
+}
+
+impl FormatHandler for STHandler {
+    fn display(&self, ascii: bool) -> Result<String> {
+        let ((cylinders, sides, spt), source) = detect_geometry(&self.data)?;
+        let mut output = Vec::new();
+        output.push(format!("Atari ST Image: {} bytes", self.data.len()));
+        if !ascii {
+            output.push(format!(
+                "Detected Geometry: {} cylinders, {} heads, {} sectors/track, {} bytes/sector",
+                cylinders, sides, spt, SECTOR_SIZE
+            ));
+            output.push(format!("Geometry Source: {}", source));
+            output.extend(boot_info(&self.data));
+        } else {
+            output.extend(ascii_lines(&self.disk()?));
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        let ((cylinders, heads, sectors_per_track), _) = detect_geometry(&self.data)?;
+        Ok(Some(Geometry::Manual { cylinders, heads, sectors_per_track, sector_size: SECTOR_SIZE as u16, mode: mode(sectors_per_track) }))
+    }
+
+    fn disk(&self) -> Result<Disk> {
+        let (geometry, _) = detect_geometry(&self.data)?;
+        Ok(raw_disk(&self.data, geometry, 0))
+    }
+
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        Ok(track_data(disk, layout(disk, ".st")?, 0).concat())
+    }
+}
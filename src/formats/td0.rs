@@ -0,0 +1,356 @@
+use crate::{FormatHandler, Geometry};
+use crate::formats::{ParseError, Reader};
+use crate::disk::{Disk, Order, Placement, Sector, Track};
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+const SIGNATURE: &[u8] = b"TD";
+const SIGNATURE_ADVANCED: &[u8] = b"td"; // LZHUF-compressed after the header
+const VERSION: u8 = 21; // Teledisk 2.1
+const HEADER_SIZE: usize = 12;
+const COMMENT_HEADER_SIZE: usize = 10;
+const END_OF_IMAGE: u8 = 0xFF; // Sector count of the track header that ends the image
+
+// Header flags
+const RATE_FM: u8 = 0x80;    // Data rate byte: every track is FM
+const HAS_COMMENT: u8 = 0x80; // Stepping byte: a comment block follows the header
+const HEAD_FM: u8 = 0x80;    // Track header head byte: this track is FM
+
+// Sector flags
+const DUPLICATE: u8 = 0x01;
+const CRC_ERROR: u8 = 0x02;
+const DELETED: u8 = 0x04;
+const SKIPPED: u8 = 0x10; // Not allocated by DOS; no data stored
+const NO_DATA: u8 = 0x20; // ID field without a data field
+
+// Sector data encodings
+const RAW: u8 = 0;
+const REPEATED: u8 = 1; // A 2-byte pattern repeated
+const RLE: u8 = 2;      // Literal runs and repeated patterns
+
+/// CRC-16 with polynomial 0xA097 from 0, as Teledisk uses for its headers and sector data.
+fn crc(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0xA097 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Data rate code (0 250 kbps, 1 300 kbps, 2 500 kbps) of an IMD mode.
+fn rate(mode: u8) -> u8 {
+    match mode % 3 {
+        0 => 2,
+        1 => 1,
+        _ => 0,
+    }
+}
+
+/// IMD mode for a data rate code and encoding.
+fn mode(rate: u8, fm: bool) -> u8 {
+    let base = if fm { 0 } else { 3 };
+    base + match rate & 0x03 {
+        2 => 0,
+        1 => 1,
+        _ => 2,
+    }
+}
+
+/// Header fields shown by `display`.
+struct Header {
+    version: u8,
+    rate: u8,
+    drive: u8,
+    sides: u8,
+    comment: Option<String>,
+}
+
+fn drive_name(drive: u8) -> &'static str {
+    match drive {
+        1 => "5.25\" 360K",
+        2 => "5.25\" 1.2M",
+        3 => "3.5\" 720K",
+        4 => "3.5\" 1.44M",
+        5 => "8\"",
+        6 => "3.5\" 2.88M",
+        _ => "unknown",
+    }
+}
+
+/// Sydex Teledisk image. Only uncompressed ("TD") images are read; those with advanced
+/// compression ("td") must be expanded first. Each track lists its sectors in physical order
+/// with their ID fields, and sector data is stored raw, as a repeated pattern or run-length
+/// encoded.
+pub struct TD0Handler {
+    data: Vec<u8>,
+}
+
+impl TD0Handler {
+    pub fn new(data: Vec<u8>) -> Self {
+        TD0Handler { data }
+    }
+
+    fn parse(&self) -> Result<(Header, Disk)> {
+        let mut reader = Reader::new(&self.data, "td0", 0);
+        let header = reader.bytes(HEADER_SIZE, "header")?;
+        if header.starts_with(SIGNATURE_ADVANCED) {
+            return Err(reader.error_at(0, "advanced compression (\"td\") is not supported; expand the image with Teledisk or td02imd first").into());
+        }
+        if !header.starts_with(SIGNATURE) {
+            return Err(reader.error_at(0, "signature is not \"TD\"").into());
+        }
+        if crc(&header[..10]) != u16::from_le_bytes([header[10], header[11]]) {
+            return Err(reader.error_at(10, "header CRC does not match").into());
+        }
+        let (version, rate, drive, stepping, sides) = (header[4], header[5], header[6], header[7], header[9]);
+        let mut comment = None;
+        if stepping & HAS_COMMENT != 0 {
+            let block = reader.bytes(COMMENT_HEADER_SIZE, "comment header")?;
+            let length = u16::from_le_bytes([block[2], block[3]]) as usize;
+            let text = reader.bytes(length, "comment")?;
+            let lines: Vec<String> = text.split(|&b| b == 0).filter(|line| !line.is_empty()).map(|line| String::from_utf8_lossy(line).into_owned()).collect();
+            comment = Some(lines.join("\n"));
+        }
+
+        let mut disk = Disk { comment: b"IMD 1.18 - floppytool\n".to_vec(), tracks: Vec::new() };
+        if let Some(text) = &comment {
+            disk.comment.extend_from_slice(text.as_bytes());
+        }
+        loop {
+            reader.track = None;
+            reader.sector = None;
+            let count = reader.u8("track sector count")?;
+            if count == END_OF_IMAGE {
+                break;
+            }
+            let cylinder = reader.u8("cylinder")?;
+            let head_flags = reader.u8("head")?;
+            reader.u8("track header CRC")?;
+            let head = head_flags & 0x7F;
+            reader.track = Some((cylinder, head));
+            let mut track = Track { mode: mode(rate, (rate | head_flags) & HEAD_FM != 0), cylinder, head, ..Default::default() };
+            for _ in 0..count {
+                let id = reader.bytes(6, "sector header")?;
+                let (c, h, r, n, flags) = (id[0], id[1], id[2], id[3], id[4]);
+                reader.sector = Some(r);
+                if n > 6 {
+                    return Err(reader.error_at(reader.pos - 3, format!("sector size code {}; expected 0-6 (128-8192 bytes)", n)).into());
+                }
+                let size = 128usize << n;
+                let mut sector = Sector {
+                    id: r, cylinder: c, head: h, size,
+                    deleted: flags & DELETED != 0,
+                    crc_error: flags & CRC_ERROR != 0,
+                    ..Default::default()
+                };
+                if flags & (SKIPPED | NO_DATA) == 0 {
+                    let start = reader.pos;
+                    let length = reader.u16("sector data length")? as usize;
+                    let block = reader.bytes(length, "sector data")?;
+                    let data = Self::expand(block, size).map_err(|message| ParseError { offset: start, ..reader.error(message) })?;
+                    sector.data = Some(data);
+                    sector.compressed = sector.is_uniform();
+                }
+                track.sectors.push(sector);
+            }
+            disk.tracks.push(track);
+        }
+        Ok((Header { version, rate, drive, sides, comment }, disk))
+    }
+
+    /// Sector data from a data block (encoding byte, then the encoded bytes), cut or
+    /// zero-padded to the sector size.
+    fn expand(block: &[u8], size: usize) -> Result<Vec<u8>, String> {
+        let (&encoding, body) = block.split_first().ok_or("empty sector data block")?;
+        let truncated = || format!("sector data block ends early ({} bytes)", block.len());
+        let mut data = Vec::with_capacity(size);
+        match encoding {
+            RAW => data.extend_from_slice(body),
+            REPEATED => {
+                let mut at = 0;
+                while at < body.len() && data.len() < size {
+                    let run = body.get(at..at + 4).ok_or_else(truncated)?;
+                    let count = u16::from_le_bytes([run[0], run[1]]) as usize;
+                    (0..count).for_each(|_| data.extend_from_slice(&run[2..4]));
+                    at += 4;
+                }
+            }
+            RLE => {
+                let mut at = 0;
+                while at < body.len() && data.len() < size {
+                    let (kind, count) = (body[at], *body.get(at + 1).ok_or_else(truncated)? as usize);
+                    at += 2;
+                    if kind == 0 {
+                        data.extend_from_slice(body.get(at..at + count).ok_or_else(truncated)?);
+                        at += count;
+                    } else {
+                        let length = 1usize << kind.min(8);
+                        let pattern = body.get(at..at + length).ok_or_else(truncated)?;
+                        (0..count).for_each(|_| data.extend_from_slice(pattern));
+                        at += length;
+                    }
+                }
+            }
+            _ => return Err(format!("unknown sector data encoding {}; expected 0-2", encoding)),
+        }
+        data.resize(size, 0);
+        Ok(data)
+    }
+}
+
+impl FormatHandler for TD0Handler {
+    fn display(&self, ascii: bool) -> Result<String> {
+        let (header, disk) = self.parse()?;
+        let mut output = Vec::new();
+        output.push(format!("Teledisk: {} bytes (version {}.{}, uncompressed)", self.data.len(), header.version / 10, header.version % 10));
+        if !ascii {
+            if let Some(comment) = &header.comment {
+                output.push(format!("Comment: {}", comment.replace('\n', " / ")));
+            }
+            output.push(format!("Source Drive: {}, {} sides, {} kbps", drive_name(header.drive), header.sides, [250, 300, 500, 250][(header.rate & 0x03) as usize]));
+            let cylinders = disk.tracks.iter().map(|t| t.cylinder as usize + 1).max().unwrap_or(0);
+            let heads = disk.tracks.iter().map(|t| t.head as usize + 1).max().unwrap_or(0);
+            let layout = disk.layout();
+            output.push(format!(
+                "Detected Geometry: {} cylinders, {} heads, {} sectors/track, {} bytes/sector, mode {}",
+                cylinders, heads, layout.slots, layout.size, disk.tracks.first().map_or(0, |t| t.mode)
+            ));
+            output.push(match disk.interleave() {
+                Some((interleave, 0)) => format!("Interleave: {}:1", interleave),
+                Some((interleave, differing)) => format!("Interleave: {}:1 ({} tracks differ)", interleave, differing),
+                None => "Interleave: n/a".to_string(),
+            });
+            output.push(match disk.skew() {
+                Some(skew) => format!("Track-to-track Skew: {} sectors", skew),
+                None => "Track-to-track Skew: n/a".to_string(),
+            });
+        } else {
+            for (track, sector) in disk.sectors(Order::Physical) {
+                let ascii_str: String = sector.data.as_deref().unwrap_or_default().iter()
+                    .take(32)
+                    .map(|&b| if (32..=126).contains(&b) { b as char } else { '.' })
+                    .collect();
+                output.push(format!(
+                    "Cyl {}, Head {}, Sector {}, Size {} bytes, Mode {}: {}",
+                    track.cylinder, track.head, sector.id, sector.size, track.mode, ascii_str
+                ));
+            }
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        let (_, disk) = self.parse()?;
+        let Some(first) = disk.tracks.iter().find(|t| !t.sectors.is_empty()) else { return Ok(None) };
+        let layout = disk.layout();
+        Ok(Some(Geometry::Manual {
+            cylinders: disk.tracks.iter().map(|t| t.cylinder.saturating_add(1)).max().unwrap_or(0),
+            heads: disk.tracks.iter().map(|t| t.head.saturating_add(1)).max().unwrap_or(0),
+            sectors_per_track: layout.slots as u8,
+            sector_size: layout.size as u16,
+            mode: first.mode,
+        }))
+    }
+
+    fn disk(&self) -> Result<Disk> {
+        Ok(self.parse()?.1)
+    }
+
+    /// Writes an uncompressed ("TD") image. Uniform sectors are stored as a repeated pattern,
+    /// others raw. The comment after the IMD header line becomes the Teledisk comment. Teledisk
+    /// records one data rate for the whole disk, so every track must share it.
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let first = disk.tracks.first().ok_or_else(|| anyhow!("Cannot write .td0: the disk has no tracks"))?;
+        if let Some(track) = disk.tracks.iter().find(|t| rate(t.mode) != rate(first.mode)) {
+            return Err(anyhow!(
+                "Cannot write .td0: Cyl {}, Head {} is mode {} but Cyl {}, Head {} is mode {}; Teledisk records one data rate per disk",
+                track.cylinder, track.head, track.mode, first.cylinder, first.head, first.mode
+            ));
+        }
+        let cylinders = disk.tracks.iter().map(|t| t.cylinder as usize + 1).max().unwrap_or(0);
+        let sides = disk.tracks.iter().map(|t| t.head as usize + 1).max().unwrap_or(1);
+        let all_fm = disk.tracks.iter().all(|t| t.mode <= 2);
+        let spt = disk.tracks.iter().map(|t| t.sectors.len()).max().unwrap_or(0);
+        let drive = match rate(first.mode) {
+            2 if spt >= 18 => 4,
+            2 => 2,
+            _ if cylinders > 42 => 3,
+            _ => 1,
+        };
+        let text: Vec<u8> = match disk.comment.iter().position(|&b| b == b'\n') {
+            Some(end) if disk.comment.starts_with(b"IMD ") => disk.comment[end + 1..].to_vec(),
+            _ => disk.comment.clone(),
+        };
+        let lines: Vec<&[u8]> = text.split(|&b| b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line)).filter(|line| !line.is_empty()).collect();
+
+        let mut out = Vec::new();
+        out.extend_from_slice(SIGNATURE);
+        out.extend_from_slice(&[0, 0, VERSION, rate(first.mode) | if all_fm { RATE_FM } else { 0 }, drive]);
+        out.push(if lines.is_empty() { 0 } else { HAS_COMMENT });
+        out.push(0); // Every sector stored, not only those DOS allocated
+        out.push(sides as u8);
+        out.extend_from_slice(&crc(&out).to_le_bytes());
+        if !lines.is_empty() {
+            let mut body = vec![0u8; 8]; // Length, then a creation date left unset
+            for line in &lines {
+                body.extend_from_slice(line);
+                body.push(0);
+            }
+            let length = (body.len() - 8) as u16;
+            body[..2].copy_from_slice(&length.to_le_bytes());
+            out.extend_from_slice(&crc(&body).to_le_bytes());
+            out.extend_from_slice(&body);
+        }
+
+        for track in &disk.tracks {
+            if track.sectors.len() >= END_OF_IMAGE as usize {
+                return Err(anyhow!("Cannot write .td0: Cyl {}, Head {} has {} sectors; a track holds at most 254", track.cylinder, track.head, track.sectors.len()));
+            }
+            let header = [track.sectors.len() as u8, track.cylinder, track.head | if track.mode <= 2 { HEAD_FM } else { 0 }];
+            out.extend_from_slice(&header);
+            out.push(crc(&header) as u8);
+            for (i, sector) in track.sectors.iter().enumerate() {
+                let n = (0..=6u8).find(|&n| 128 << n == sector.size).ok_or_else(|| anyhow!(
+                    "Cannot write .td0: Cyl {}, Head {}, Sector {} is {} bytes; Teledisk sizes are 128 << N up to 8192",
+                    track.cylinder, track.head, sector.id, sector.size
+                ))?;
+                let mut flags = 0;
+                if track.sectors[..i].iter().any(|s| s.id == sector.id) { flags |= DUPLICATE; }
+                if sector.crc_error { flags |= CRC_ERROR; }
+                if sector.deleted { flags |= DELETED; }
+                if sector.data.is_none() { flags |= NO_DATA; }
+                let check = sector.data.as_deref().map_or(0, |data| crc(data) as u8);
+                out.extend_from_slice(&[sector.cylinder, sector.head, sector.id, n, flags, check]);
+                if let Some(data) = &sector.data {
+                    let block = if sector.is_uniform() {
+                        let count = (data.len() / 2) as u16;
+                        let mut block = vec![REPEATED];
+                        block.extend_from_slice(&count.to_le_bytes());
+                        block.extend_from_slice(&[data[0], data[0]]);
+                        block
+                    } else {
+                        let mut block = vec![RAW];
+                        block.extend_from_slice(data);
+                        block
+                    };
+                    out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+                    out.extend_from_slice(&block);
+                }
+            }
+        }
+        out.push(END_OF_IMAGE);
+        Ok(out)
+    }
+}
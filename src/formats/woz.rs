@@ -0,0 +1,300 @@
+use crate::{FormatHandler, Geometry};
+use crate::formats::ParseError;
+use crate::boot::crc32;
+use crate::disk::{Disk, Placement};
+use crate::flux::{self, apple};
+use crate::formats::apple::encode_tracks;
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+
+const SIGNATURE: &[u8] = b"WOZ2\xFF\x0A\x0D\x0A";
+const QUARTER_TRACKS: usize = 160;
+const BLOCK: usize = 512;
+const TRACK_DATA_START: usize = 1536; // Block 3, after the header, INFO, TMAP and TRK entries
+const TICK_NS: u32 = 125;
+
+/// Fields of the INFO chunk.
+struct Info {
+    version: u8,
+    disk_type: u8, // 1 = 5.25-inch, 2 = 3.5-inch
+    write_protected: bool,
+    synchronized: bool,
+    cleaned: bool,
+    creator: String,
+    sides: u8,
+    boot_format: u8,    // 0 unknown, 1 16-sector, 2 13-sector, 3 both
+    bit_timing: u8,     // Bit cell width in 125 ns units
+    largest_track: u16, // In 512-byte blocks
+}
+
+/// A track's data: a bit stream, or flux intervals (WOZ 2.1 FLUX chunk) in 125 ns ticks.
+enum TrackData<'a> {
+    Bits(&'a [u8], usize),
+    Flux(&'a [u8]),
+}
+
+/// WOZ 2: an Apple II disk as the bit stream (or flux) of each quarter track, mapped through
+/// TMAP/FLUX tables, with INFO and optional META chunks.
+pub struct WOZHandler {
+    data: Vec<u8>,
+}
+
+impl WOZHandler {
+    pub fn new(data: Vec<u8>) -> Self {
+        WOZHandler { data }
+    }
+
+    /// Chunks by four-character ID, with their data.
+    fn chunks(&self) -> Result<Vec<([u8; 4], &[u8])>> {
+        if self.data.len() < 12 || &self.data[..8] != SIGNATURE {
+            return Err(ParseError::new("woz", 0, format!(
+                "missing 'WOZ2' signature{}",
+                if self.data.starts_with(b"WOZ1") { " (WOZ 1 images are not supported; convert them to WOZ 2 first)" } else { "" }
+            )).into());
+        }
+        let mut chunks = Vec::new();
+        let mut at = 12;
+        while at + 8 <= self.data.len() {
+            let id = [self.data[at], self.data[at + 1], self.data[at + 2], self.data[at + 3]];
+            let size = u32::from_le_bytes([self.data[at + 4], self.data[at + 5], self.data[at + 6], self.data[at + 7]]) as usize;
+            let body = self.data.get(at + 8..at + 8 + size).ok_or_else(|| ParseError::new("woz", at, format!(
+                "{} chunk of {} bytes runs past the end of the file", String::from_utf8_lossy(&id), size
+            )))?;
+            chunks.push((id, body));
+            at += 8 + size;
+        }
+        Ok(chunks)
+    }
+
+    fn chunk(&self, id: &[u8; 4]) -> Result<Option<&[u8]>> {
+        Ok(self.chunks()?.into_iter().find(|(i, _)| i == id).map(|(_, body)| body))
+    }
+
+    /// A chunk that must be present.
+    fn required(&self, id: &[u8; 4], min_size: usize) -> Result<&[u8]> {
+        Ok(self.chunk(id)?.filter(|c| c.len() >= min_size).ok_or_else(|| ParseError::new(
+            "woz", 12, format!("missing {} chunk", String::from_utf8_lossy(id))
+        ))?)
+    }
+
+    /// File offset of a slice of `data`, for error locations.
+    fn offset_of(&self, part: &[u8]) -> usize {
+        part.as_ptr() as usize - self.data.as_ptr() as usize
+    }
+
+    fn info(&self) -> Result<Info> {
+        let c = self.required(b"INFO", 46)?;
+        Ok(Info {
+            version: c[0],
+            disk_type: c[1],
+            write_protected: c[2] != 0,
+            synchronized: c[3] != 0,
+            cleaned: c[4] != 0,
+            creator: String::from_utf8_lossy(&c[5..37]).trim_end().to_string(),
+            sides: c[37],
+            boot_format: c[38],
+            bit_timing: c[39],
+            largest_track: u16::from_le_bytes([c[44], c[45]]),
+        })
+    }
+
+    /// META chunk entries (tab-separated key and value per line).
+    fn meta(&self) -> Result<Vec<(String, String)>> {
+        let Some(body) = self.chunk(b"META")? else { return Ok(Vec::new()) };
+        Ok(String::from_utf8_lossy(body).lines().filter_map(|line| {
+            line.split_once('\t').map(|(k, v)| (k.to_string(), v.to_string()))
+        }).collect())
+    }
+
+    /// Data of the whole track `number`: flux if the FLUX chunk maps its quarter track,
+    /// otherwise the bit stream from TMAP.
+    fn track(&self, number: usize) -> Result<Option<TrackData<'_>>> {
+        let trks = self.required(b"TRKS", 0)?;
+        let entry = |index: u8| -> Result<Option<(&[u8], usize)>> {
+            if index == 0xFF {
+                return Ok(None);
+            }
+            let e = trks.get(index as usize * 8..index as usize * 8 + 8).ok_or_else(|| ParseError::new(
+                "woz", self.offset_of(trks), format!("track map points at TRK entry {}, past the end of the TRKS chunk", index)
+            ))?;
+            let start = u16::from_le_bytes([e[0], e[1]]) as usize * BLOCK;
+            let blocks = u16::from_le_bytes([e[2], e[3]]) as usize;
+            let count = u32::from_le_bytes([e[4], e[5], e[6], e[7]]) as usize;
+            let bytes = self.data.get(start..start + blocks * BLOCK).ok_or_else(|| ParseError::new(
+                "woz", self.offset_of(e), format!("TRK entry {} points at {} blocks from offset {}, past the end of the file", index, blocks, start)
+            ))?;
+            Ok(Some((bytes, count)))
+        };
+        let quarter = number * 4;
+        if let Some(map) = self.chunk(b"FLUX")? {
+            if let Some((bytes, count)) = entry(map.get(quarter).copied().unwrap_or(0xFF))? {
+                return Ok(Some(TrackData::Flux(&bytes[..count.min(bytes.len())])));
+            }
+        }
+        let tmap = self.required(b"TMAP", 0)?;
+        Ok(entry(tmap.get(quarter).copied().unwrap_or(0xFF))?.map(|(bytes, bits)| TrackData::Bits(bytes, bits)))
+    }
+
+    fn decode(&self) -> Result<(Disk, Vec<String>)> {
+        let info = self.info()?;
+        if info.disk_type != 1 {
+            return Err(anyhow!("Sector decoding from .woz is only supported for 5.25-inch disks (this image is 3.5-inch)."));
+        }
+        let cell = info.bit_timing.max(1) as f64 * TICK_NS as f64;
+        let mut tracks = Vec::new();
+        for number in 0..QUARTER_TRACKS / 4 {
+            // Each track is a loop: decode it twice over so a sector spanning the end is found
+            let bits = match self.track(number)? {
+                Some(TrackData::Bits(bytes, count)) => {
+                    let bits = &flux::unpack_bits(bytes)[..count.min(bytes.len() * 8)];
+                    Some([bits, bits].concat())
+                }
+                Some(TrackData::Flux(bytes)) => {
+                    let intervals = flux_intervals(bytes);
+                    Some(flux::to_bits(&[intervals.as_slice(), intervals.as_slice()].concat(), cell))
+                }
+                None => None,
+            };
+            tracks.push(bits.map(|bits| vec![apple::decode_track(&bits)]));
+        }
+        Ok(apple::build_disk(tracks))
+    }
+}
+
+/// Flux intervals in ns from WOZ flux bytes, where 255 carries into the next byte.
+fn flux_intervals(bytes: &[u8]) -> Vec<u32> {
+    let mut intervals = Vec::with_capacity(bytes.len());
+    let mut carry = 0;
+    for &b in bytes {
+        carry += b as u32;
+        if b != 0xFF {
+            intervals.push(carry * TICK_NS);
+            carry = 0;
+        }
+    }
+    intervals
+}
+
+impl FormatHandler for WOZHandler {
+    fn display(&self, _ascii: bool) -> Result<String> {
+        let info = self.info()?;
+        let stored = u32::from_le_bytes([self.data[8], self.data[9], self.data[10], self.data[11]]);
+        let crc = if stored == 0 {
+            "not set".to_string()
+        } else if stored == crc32(&self.data[12..]) {
+            format!("0x{:08X} (valid)", stored)
+        } else {
+            format!("0x{:08X} (mismatch: computed 0x{:08X})", stored, crc32(&self.data[12..]))
+        };
+        let mut output = vec![
+            format!("WOZ 2 Image: {} bytes, CRC32 {}", self.data.len(), crc),
+            format!(
+                "Info: version {}, {}, {} side{}, write protected: {}, synchronized: {}, cleaned: {}",
+                info.version, if info.disk_type == 2 { "3.5-inch" } else { "5.25-inch" },
+                info.sides, if info.sides == 1 { "" } else { "s" }, info.write_protected, info.synchronized, info.cleaned
+            ),
+            format!("Creator: {}", info.creator),
+            format!("Boot Sector Format: {}", match info.boot_format {
+                1 => "16-sector",
+                2 => "13-sector",
+                3 => "16-sector and 13-sector",
+                _ => "unknown",
+            }),
+            format!("Optimal Bit Timing: {} ns", info.bit_timing as u32 * TICK_NS),
+        ];
+        let (mut bits, mut fluxes) = (0, 0);
+        for number in 0..QUARTER_TRACKS / 4 {
+            match self.track(number)? {
+                Some(TrackData::Bits(..)) => bits += 1,
+                Some(TrackData::Flux(_)) => fluxes += 1,
+                None => {}
+            }
+        }
+        output.push(format!("Tracks: {} bit stream, {} flux (largest {} blocks)", bits, fluxes, info.largest_track));
+        let meta = self.meta()?;
+        if !meta.is_empty() {
+            output.push("Metadata:".to_string());
+            output.extend(meta.iter().filter(|(_, v)| !v.is_empty()).map(|(k, v)| format!("  {}: {}", k, v)));
+        }
+        if info.disk_type == 1 {
+            output.extend(self.decode()?.1);
+        }
+        Ok(output.join("\n"))
+    }
+
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, _input_path: &Path, _meta_path: Option<&PathBuf>, _geometry: Option<Geometry>, _verbose: bool, _validate: bool, _placement: Placement) -> Result<()> {
+        std::fs::write(output_path, target.encode(&self.disk()?)?)?;
+        Ok(())
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn geometry(&self) -> Result<Option<Geometry>> {
+        Ok(None)
+    }
+
+    fn disk(&self) -> Result<Disk> {
+        Ok(self.decode()?.0)
+    }
+
+    /// Writes a 5.25-inch WOZ 2 image with a bit stream per track in the DOS 3.3 layout, each
+    /// mapped to its quarter track and the ones either side, plus a META chunk.
+    fn encode(&self, disk: &Disk) -> Result<Vec<u8>> {
+        let tracks = encode_tracks(disk, ".woz")?;
+        let creator = format!("floppytool {}", env!("CARGO_PKG_VERSION"));
+
+        let mut tmap = [0xFFu8; QUARTER_TRACKS];
+        let mut entries = vec![0u8; QUARTER_TRACKS * 8];
+        let mut track_data = Vec::new();
+        let mut largest = 0;
+        for (index, bits) in tracks.iter().enumerate().filter(|(_, bits)| !bits.is_empty()) {
+            let quarter = index * 4;
+            for q in [quarter.checked_sub(1), Some(quarter), Some(quarter + 1)].into_iter().flatten() {
+                if let Some(slot) = tmap.get_mut(q) {
+                    *slot = index as u8;
+                }
+            }
+            let mut bytes = flux::pack_bits(bits);
+            let blocks = bytes.len().div_ceil(BLOCK);
+            bytes.resize(blocks * BLOCK, 0);
+            let start = (TRACK_DATA_START + track_data.len()) / BLOCK;
+            entries[index * 8..index * 8 + 2].copy_from_slice(&(start as u16).to_le_bytes());
+            entries[index * 8 + 2..index * 8 + 4].copy_from_slice(&(blocks as u16).to_le_bytes());
+            entries[index * 8 + 4..index * 8 + 8].copy_from_slice(&(bits.len() as u32).to_le_bytes());
+            largest = largest.max(blocks);
+            track_data.extend(bytes);
+        }
+
+        let mut info = vec![0u8; 60];
+        info[0] = 2; // INFO version
+        info[1] = 1; // 5.25-inch
+        info[4] = 1; // Cleaned: no stray bits between tracks
+        info[5..37].copy_from_slice(format!("{:<32}", creator).as_bytes());
+        info[37] = 1; // Sides
+        info[38] = 1; // 16-sector boot format
+        info[39] = 32; // 4 us bit cells
+        info[44..46].copy_from_slice(&(largest as u16).to_le_bytes());
+        let meta = format!("title\t\nsubtitle\t\npublisher\t\ndeveloper\t\ncopyright\t\nversion\t\nlanguage\t\nrequires_ram\t\nrequires_machine\t\nnotes\tWritten by {}\nside\t\nside_name\t\ncontributor\t\nimage_date\t\n", creator);
+
+        let mut out = Vec::with_capacity(TRACK_DATA_START + track_data.len() + meta.len() + 8);
+        out.extend_from_slice(SIGNATURE);
+        out.extend_from_slice(&[0; 4]); // CRC32, filled in below
+        for (id, body) in [(b"INFO", info.as_slice()), (b"TMAP", tmap.as_slice())] {
+            out.extend_from_slice(id);
+            out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            out.extend_from_slice(body);
+        }
+        out.extend_from_slice(b"TRKS");
+        out.extend_from_slice(&((entries.len() + track_data.len()) as u32).to_le_bytes());
+        out.extend_from_slice(&entries);
+        out.extend_from_slice(&track_data);
+        out.extend_from_slice(b"META");
+        out.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+        out.extend_from_slice(meta.as_bytes());
+        let crc = crc32(&out[12..]);
+        out[8..12].copy_from_slice(&crc.to_le_bytes());
+        Ok(out)
+    }
+}
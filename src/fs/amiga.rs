@@ -0,0 +1,239 @@
+use crate::fs::{FileEntry, Filesystem};
+use anyhow::{Result, anyhow};
+
+const BLOCK_SIZE: usize = 512;
+const HASH_SIZE: usize = BLOCK_SIZE / 4 - 56;
+const T_HEADER: u32 = 2;
+const T_LIST: u32 = 16;
+const ST_ROOT: i32 = 1;
+const ST_USERDIR: i32 = 2;
+const ST_FILE: i32 = -3;
+
+// Offsets within header blocks, counted from the end as in the AmigaDOS documentation
+const HIGH_SEQ: usize = 8;
+const TABLE: usize = 24;
+const BITMAP_FLAG: usize = BLOCK_SIZE - 200;
+const BITMAP_PAGES: usize = BLOCK_SIZE - 196;
+const PROTECT: usize = BLOCK_SIZE - 192;
+const BYTE_SIZE: usize = BLOCK_SIZE - 188;
+const NAME: usize = BLOCK_SIZE - 80;
+const HASH_CHAIN: usize = BLOCK_SIZE - 16;
+const EXTENSION: usize = BLOCK_SIZE - 8;
+const SEC_TYPE: usize = BLOCK_SIZE - 4;
+
+fn long(block: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([block[offset], block[offset + 1], block[offset + 2], block[offset + 3]])
+}
+
+/// Standard block checksum: all longwords of the block sum to zero.
+fn checksum_ok(block: &[u8]) -> bool {
+    block.chunks_exact(4).fold(0u32, |sum, l| sum.wrapping_add(long(l, 0))) == 0
+}
+
+/// The first two blocks of an AmigaDOS disk.
+#[derive(Debug, Clone)]
+pub struct BootBlock {
+    pub ffs: bool,
+    pub international: bool,
+    pub dircache: bool,
+    pub bootable: bool, // Kickstart only runs the boot code if the checksum is valid
+}
+
+impl BootBlock {
+    /// Parses the 1024-byte bootblock; None unless it starts with "DOS".
+    pub fn parse(data: &[u8]) -> Option<BootBlock> {
+        if data.len() < 2 * BLOCK_SIZE || &data[..3] != b"DOS" {
+            return None;
+        }
+        let flags = data[3];
+        // Sum with end-around carry; a valid bootblock sums to 0xFFFFFFFF
+        let sum = data[..2 * BLOCK_SIZE].chunks_exact(4).fold(0u32, |sum, l| {
+            let (next, carry) = sum.overflowing_add(long(l, 0));
+            next + carry as u32
+        });
+        Some(BootBlock {
+            ffs: flags & 1 != 0,
+            international: flags & 2 != 0,
+            dircache: flags & 4 != 0,
+            bootable: sum == 0xFFFF_FFFF,
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        let mut text = String::from(if self.ffs { "FFS" } else { "OFS" });
+        if self.international {
+            text.push_str(", international");
+        }
+        if self.dircache {
+            text.push_str(", directory cache");
+        }
+        text
+    }
+}
+
+/// Read-only AmigaDOS (OFS or FFS) filesystem over 512-byte logical sectors.
+pub struct AmigaDos {
+    pub boot: BootBlock,
+    root: usize,
+    sectors: Vec<Vec<u8>>,
+}
+
+impl AmigaDos {
+    /// Opens the filesystem if the bootblock says "DOS" and the root block in the middle of
+    /// the disk is a valid root header.
+    pub fn open(sectors: Vec<Vec<u8>>) -> Option<AmigaDos> {
+        if sectors.len() < 4 || sectors.iter().any(|s| s.len() != BLOCK_SIZE) {
+            return None;
+        }
+        let boot = BootBlock::parse(&[sectors[0].as_slice(), sectors[1].as_slice()].concat())?;
+        let root = sectors.len() / 2;
+        let block = &sectors[root];
+        if long(block, 0) != T_HEADER || long(block, SEC_TYPE) as i32 != ST_ROOT || !checksum_ok(block) {
+            return None;
+        }
+        Some(AmigaDos { boot, root, sectors })
+    }
+
+    fn block(&self, n: usize) -> Result<&[u8]> {
+        self.sectors.get(n).map(|b| b.as_slice()).ok_or_else(|| anyhow!("Block {} lies beyond the end of the disk", n))
+    }
+
+    fn name_of(block: &[u8]) -> String {
+        let len = (block[NAME] as usize).min(30);
+        block[NAME + 1..NAME + 1 + len].iter().map(|&b| b as char).collect() // ISO-8859-1
+    }
+
+    pub fn volume_name(&self) -> String {
+        Self::name_of(&self.sectors[self.root])
+    }
+
+    /// Free blocks according to the bitmap, or None if the bitmap is marked invalid.
+    pub fn free_blocks(&self) -> Option<usize> {
+        let root = &self.sectors[self.root];
+        if long(root, BITMAP_FLAG) != 0xFFFF_FFFF {
+            return None;
+        }
+        let mut free = 0;
+        let mut block = 2; // The bootblock is not mapped
+        for page in 0..25 {
+            let pointer = long(root, BITMAP_PAGES + page * 4) as usize;
+            if pointer == 0 {
+                break;
+            }
+            let bitmap = self.sectors.get(pointer)?;
+            for offset in (4..BLOCK_SIZE).step_by(4) {
+                let bits = long(bitmap, offset);
+                for bit in 0..32 {
+                    if block < self.sectors.len() && bits & (1 << bit) != 0 {
+                        free += 1;
+                    }
+                    block += 1;
+                }
+            }
+        }
+        Some(free)
+    }
+
+    /// Data blocks of a file, following the header's extension blocks.
+    fn file_blocks(&self, header: usize) -> Result<Vec<usize>> {
+        let mut blocks = Vec::new();
+        let mut current = header;
+        for _ in 0..self.sectors.len() {
+            let block = self.block(current)?;
+            let count = (long(block, HIGH_SEQ) as usize).min(HASH_SIZE);
+            for i in 0..count {
+                // The table is filled from its last entry backwards
+                blocks.push(long(block, TABLE + (HASH_SIZE - 1 - i) * 4) as usize);
+            }
+            current = long(block, EXTENSION) as usize;
+            if current == 0 {
+                return Ok(blocks);
+            }
+            if long(self.block(current)?, 0) != T_LIST {
+                return Err(anyhow!("File extension block {} has the wrong type", current));
+            }
+        }
+        Err(anyhow!("File header {} has an extension chain that loops", header))
+    }
+
+    fn read_dir(&self, dir: usize, prefix: &str, depth: usize, files: &mut Vec<FileEntry>) -> Result<()> {
+        if depth > 16 {
+            return Err(anyhow!("Directory nesting too deep under '{}'", prefix));
+        }
+        let table = self.block(dir)?;
+        let mut entries = Vec::new();
+        for slot in 0..HASH_SIZE {
+            let mut next = long(table, TABLE + slot * 4) as usize;
+            let mut steps = 0;
+            while next != 0 {
+                steps += 1;
+                if steps > self.sectors.len() {
+                    return Err(anyhow!("Hash chain loops in directory '{}'", prefix));
+                }
+                let block = self.block(next)?;
+                if long(block, 0) != T_HEADER {
+                    return Err(anyhow!("Directory entry at block {} is not a header block", next));
+                }
+                entries.push(next);
+                next = long(block, HASH_CHAIN) as usize;
+            }
+        }
+        entries.sort_by_key(|&n| Self::name_of(&self.sectors[n]).to_uppercase());
+
+        for n in entries {
+            let block = &self.sectors[n];
+            let name = format!("{}{}", prefix, Self::name_of(block));
+            match long(block, SEC_TYPE) as i32 {
+                ST_USERDIR => self.read_dir(n, &format!("{}/", name), depth + 1, files)?,
+                ST_FILE => {
+                    let size = long(block, BYTE_SIZE) as usize;
+                    files.push(FileEntry { name, size, sectors: self.file_blocks(n)?, detail: protection(long(block, PROTECT)) });
+                }
+                _ => {} // Hard and soft links
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Protection bits in `list` style ("----rwed"); the low four bits are set when denied.
+fn protection(bits: u32) -> String {
+    "hsparwed".chars().enumerate().map(|(i, c)| {
+        let bit = 7 - i;
+        let allowed = if bit < 4 { bits & (1 << bit) == 0 } else { bits & (1 << bit) != 0 };
+        if allowed { c } else { '-' }
+    }).collect()
+}
+
+impl Filesystem for AmigaDos {
+    fn name(&self) -> String {
+        format!("AmigaDOS {}", if self.boot.ffs { "FFS" } else { "OFS" })
+    }
+
+    fn files(&self) -> Result<Vec<FileEntry>> {
+        let mut files = Vec::new();
+        self.read_dir(self.root, "", 0, &mut files)?;
+        Ok(files)
+    }
+
+    fn sector_data(&self) -> &[Vec<u8>] {
+        &self.sectors
+    }
+
+    /// FFS data blocks hold plain data; OFS data blocks start with a 24-byte header giving
+    /// the number of bytes used.
+    fn read_file(&self, file: &FileEntry) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(file.size);
+        for &n in &file.sectors {
+            let block = self.block(n)?;
+            if self.boot.ffs {
+                data.extend_from_slice(block);
+            } else {
+                let used = (long(block, 12) as usize).min(BLOCK_SIZE - 24);
+                data.extend_from_slice(&block[24..24 + used]);
+            }
+        }
+        data.truncate(file.size);
+        Ok(data)
+    }
+}
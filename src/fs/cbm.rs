@@ -0,0 +1,135 @@
+use crate::flux::cbm::sectors_per_track;
+use crate::fs::{FileEntry, Filesystem};
+use anyhow::{Result, anyhow};
+
+const DIRECTORY_TRACK: u8 = 18;
+const FILE_TYPES: [&str; 5] = ["DEL", "SEQ", "PRG", "USR", "REL"];
+
+/// PETSCII to ASCII for names: letters in either case map to upper case, other characters
+/// outside the printable range become '?'.
+fn petscii(bytes: &[u8]) -> String {
+    bytes.iter().take_while(|&&b| b != 0xA0).map(|&b| match b {
+        0x20..=0x5F => b as char,
+        0xC1..=0xDA => (b - 0x80) as char,
+        _ => '?',
+    }).collect()
+}
+
+/// Read-only CBM DOS 2.6 filesystem (1541) over 256-byte logical sectors.
+pub struct CbmDos {
+    sectors: Vec<Vec<u8>>,
+    tracks: u8,
+}
+
+impl CbmDos {
+    /// Opens the filesystem if the sector count matches a 35/40/42-track disk and the BAM on
+    /// track 18 points at the directory.
+    pub fn open(sectors: Vec<Vec<u8>>) -> Option<CbmDos> {
+        let tracks = [35, 40, 42].into_iter().find(|&t| (1..=t).map(|n| sectors_per_track(n) as usize).sum::<usize>() == sectors.len())?;
+        if sectors.iter().any(|s| s.len() != 256) {
+            return None;
+        }
+        let fs = CbmDos { sectors, tracks };
+        let bam = fs.sector(DIRECTORY_TRACK, 0)?;
+        (bam[0] == DIRECTORY_TRACK && bam[2] == b'A').then_some(fs)
+    }
+
+    fn lba(&self, track: u8, sector: u8) -> Option<usize> {
+        if track == 0 || track > self.tracks || sector >= sectors_per_track(track) {
+            return None;
+        }
+        Some((1..track).map(|t| sectors_per_track(t) as usize).sum::<usize>() + sector as usize)
+    }
+
+    fn sector(&self, track: u8, sector: u8) -> Option<&[u8]> {
+        self.lba(track, sector).map(|lba| self.sectors[lba].as_slice())
+    }
+
+    fn bam(&self) -> &[u8] {
+        self.sector(DIRECTORY_TRACK, 0).unwrap_or_default()
+    }
+
+    /// Disk name and the ID/DOS type shown in a directory listing header (e.g. "FT 2A").
+    pub fn label(&self) -> (String, String) {
+        let bam = self.bam();
+        // The ID and DOS type are separated by a shifted space, shown as a plain one
+        let id: Vec<u8> = bam[0xA2..0xA7].iter().map(|&b| if b == 0xA0 { b' ' } else { b }).collect();
+        (petscii(&bam[0x90..0xA0]), petscii(&id))
+    }
+
+    /// Free blocks as DOS reports them, excluding the directory track.
+    pub fn blocks_free(&self) -> usize {
+        let bam = self.bam();
+        (1..=35u8).filter(|&t| t != DIRECTORY_TRACK).map(|t| bam[4 + (t as usize - 1) * 4] as usize).sum()
+    }
+
+    /// Logical sectors of a track/sector chain and the number of bytes used in the last one.
+    fn chain(&self, track: u8, sector: u8) -> Result<(Vec<usize>, usize)> {
+        let (mut track, mut sector) = (track, sector);
+        let mut lbas = Vec::new();
+        loop {
+            let lba = self.lba(track, sector).ok_or_else(|| anyhow!("Chain points at track {} sector {}, which does not exist", track, sector))?;
+            if lbas.len() > self.sectors.len() {
+                return Err(anyhow!("Sector chain loops at track {} sector {}", track, sector));
+            }
+            lbas.push(lba);
+            let block = &self.sectors[lba];
+            if block[0] == 0 {
+                return Ok((lbas, (block[1] as usize).saturating_sub(1)));
+            }
+            (track, sector) = (block[0], block[1]);
+        }
+    }
+}
+
+impl Filesystem for CbmDos {
+    fn name(&self) -> String {
+        "CBM DOS".to_string()
+    }
+
+    fn files(&self) -> Result<Vec<FileEntry>> {
+        let bam = self.bam();
+        let (directory, _) = self.chain(bam[0], bam[1])?;
+        let mut files = Vec::new();
+        for lba in directory {
+            for entry in self.sectors[lba].chunks_exact(32) {
+                let kind = entry[2];
+                if kind & 0x07 == 0 && kind & 0x80 == 0 {
+                    continue; // Scratched or unused
+                }
+                let name = petscii(&entry[5..21]);
+                let blocks = u16::from_le_bytes([entry[30], entry[31]]);
+                let (sectors, size) = if entry[3] == 0 {
+                    (Vec::new(), 0)
+                } else {
+                    let (chain, last) = self.chain(entry[3], entry[4])?;
+                    let size = (chain.len() - 1) * 254 + last;
+                    (chain, size)
+                };
+                let detail = format!(
+                    "{}{}{} {} blocks",
+                    if kind & 0x80 == 0 { "*" } else { "" },
+                    FILE_TYPES.get((kind & 0x07) as usize).unwrap_or(&"???"),
+                    if kind & 0x40 != 0 { "<" } else { "" },
+                    blocks
+                );
+                files.push(FileEntry { name, size, sectors, detail });
+            }
+        }
+        Ok(files)
+    }
+
+    fn sector_data(&self) -> &[Vec<u8>] {
+        &self.sectors
+    }
+
+    /// Each block starts with a link to the next, so only bytes 2 onwards are file data.
+    fn read_file(&self, file: &FileEntry) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(file.size);
+        for &lba in &file.sectors {
+            data.extend_from_slice(&self.sectors[lba][2..]);
+        }
+        data.truncate(file.size);
+        Ok(data)
+    }
+}
@@ -0,0 +1,603 @@
+use crate::disk::Disk;
+use crate::fs::{FileEntry, Filesystem};
+use anyhow::{Result, anyhow};
+
+/// Common formats in cpmtools `diskdefs` syntax. Tracks count both sides of double-sided disks.
+pub const BUILTIN_DISKDEFS: &str = "\
+diskdef ibm-3740
+  seclen 128
+  tracks 77
+  sectrk 26
+  blocksize 1024
+  maxdir 64
+  skew 6
+  boottrk 2
+  os 2.2
+end
+
+diskdef kpii
+  seclen 512
+  tracks 40
+  sectrk 10
+  blocksize 1024
+  maxdir 64
+  skew 0
+  boottrk 1
+  os 2.2
+end
+
+diskdef kpiv
+  seclen 512
+  tracks 80
+  sectrk 10
+  blocksize 2048
+  maxdir 64
+  skew 0
+  boottrk 1
+  os 2.2
+end
+
+diskdef osborne1
+  seclen 1024
+  tracks 40
+  sectrk 5
+  blocksize 1024
+  maxdir 64
+  skew 0
+  boottrk 3
+  os 2.2
+end
+
+diskdef osborne1sd
+  seclen 256
+  tracks 40
+  sectrk 10
+  blocksize 2048
+  maxdir 64
+  skew 2
+  boottrk 3
+  os 2.2
+end
+
+diskdef qx10
+  seclen 256
+  tracks 80
+  sectrk 16
+  blocksize 2048
+  maxdir 128
+  skew 0
+  boottrk 2
+  os 2.2
+end
+
+diskdef pcw
+  seclen 512
+  tracks 40
+  sectrk 9
+  blocksize 1024
+  maxdir 64
+  skew 1
+  boottrk 1
+  os 3
+end
+
+diskdef cpcsys
+  seclen 512
+  tracks 40
+  sectrk 9
+  blocksize 1024
+  maxdir 64
+  skew 1
+  boottrk 2
+  os 2.2
+end
+
+diskdef cpcdata
+  seclen 512
+  tracks 40
+  sectrk 9
+  blocksize 1024
+  maxdir 64
+  skew 1
+  boottrk 0
+  os 2.2
+end
+";
+
+/// A cpmtools disk definition.
+#[derive(Debug, Clone)]
+pub struct DiskDef {
+    pub name: String,
+    pub seclen: usize,
+    pub tracks: usize,
+    pub sectrk: usize,
+    pub blocksize: usize,
+    pub maxdir: usize,
+    pub skew: usize,
+    pub skewtab: Option<Vec<usize>>,
+    pub boottrk: usize,
+    pub offset: usize, // Tracks to skip before the filesystem starts
+    pub os3: bool,
+}
+
+impl DiskDef {
+    /// Parses every `diskdef NAME ... end` block in cpmtools syntax. Unknown keys are ignored.
+    pub fn parse_all(text: &str) -> Result<Vec<DiskDef>> {
+        let mut defs = Vec::new();
+        let mut current: Option<DiskDef> = None;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut words = line.split_whitespace();
+            let (Some(key), value) = (words.next(), words.next()) else { continue };
+            let number_value = || -> Result<usize> {
+                let value = value.ok_or_else(|| anyhow!("diskdefs line {}: '{}' needs a value", number + 1, key))?;
+                parse_number(value).ok_or_else(|| anyhow!("diskdefs line {}: invalid number '{}' for '{}'", number + 1, value, key))
+            };
+            match (key, current.as_mut()) {
+                ("diskdef", None) => {
+                    let name = value.ok_or_else(|| anyhow!("diskdefs line {}: diskdef needs a name", number + 1))?;
+                    current = Some(DiskDef {
+                        name: name.to_string(), seclen: 128, tracks: 0, sectrk: 0, blocksize: 1024, maxdir: 64,
+                        skew: 0, skewtab: None, boottrk: 0, offset: 0, os3: false,
+                    });
+                }
+                ("end", Some(_)) => {
+                    if let Some(def) = current.take() {
+                        def.validate()?;
+                        defs.push(def);
+                    }
+                }
+                ("diskdef", Some(_)) | ("end", None) => return Err(anyhow!("diskdefs line {}: unbalanced diskdef/end", number + 1)),
+                (_, None) => {}
+                (_, Some(def)) => match key {
+                    "seclen" => def.seclen = number_value()?,
+                    "tracks" => def.tracks = number_value()?,
+                    "sectrk" => def.sectrk = number_value()?,
+                    "blocksize" => def.blocksize = number_value()?,
+                    "maxdir" => def.maxdir = number_value()?,
+                    "skew" => def.skew = number_value()?,
+                    "boottrk" => def.boottrk = number_value()?,
+                    "offset" => def.offset = number_value()?,
+                    "os" => def.os3 = value.is_some_and(|v| v.starts_with('3')),
+                    "skewtab" => {
+                        let table = value.unwrap_or("").split(',').map(|v| v.trim().parse::<usize>())
+                            .collect::<std::result::Result<Vec<_>, _>>()
+                            .map_err(|_| anyhow!("diskdefs line {}: invalid skewtab", number + 1))?;
+                        def.skewtab = Some(table);
+                    }
+                    _ => {}
+                },
+            }
+        }
+        if current.is_some() {
+            return Err(anyhow!("diskdefs: missing 'end' after the last diskdef"));
+        }
+        Ok(defs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.seclen == 0 || self.tracks == 0 || self.sectrk == 0 || self.maxdir == 0 {
+            return Err(anyhow!("diskdef '{}' needs non-zero seclen, tracks, sectrk and maxdir", self.name));
+        }
+        if self.tracks <= self.boottrk + self.offset {
+            return Err(anyhow!(
+                "diskdef '{}': {} tracks leave no data area after {} boot and {} offset tracks",
+                self.name, self.tracks, self.boottrk, self.offset
+            ));
+        }
+        if self.blocksize < 1024 || !self.blocksize.is_power_of_two() || !self.blocksize.is_multiple_of(self.seclen) {
+            return Err(anyhow!("diskdef '{}': blocksize {} must be a power of two >= 1024 and a multiple of seclen", self.name, self.blocksize));
+        }
+        if let Some(table) = &self.skewtab {
+            if table.len() != self.sectrk || table.iter().any(|&s| s >= self.sectrk) {
+                return Err(anyhow!("diskdef '{}': skewtab must list {} sectors numbered from 0", self.name, self.sectrk));
+            }
+        }
+        if self.blocks() <= self.directory_blocks() {
+            return Err(anyhow!(
+                "diskdef '{}': {} data blocks cannot hold a {}-block directory and any files",
+                self.name, self.blocks(), self.directory_blocks()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Finds a definition by name in the built-in set plus any extra definitions.
+    pub fn find(name: &str, extra: &[DiskDef]) -> Result<DiskDef> {
+        let builtin = Self::parse_all(BUILTIN_DISKDEFS)?;
+        extra.iter().chain(builtin.iter()).find(|d| d.name.eq_ignore_ascii_case(name)).cloned()
+            .ok_or_else(|| anyhow!(
+                "Unknown diskdef '{}'. Built-in definitions: {}. Use --diskdefs to load a cpmtools diskdefs file.",
+                name, builtin.iter().map(|d| d.name.as_str()).collect::<Vec<_>>().join(", ")
+            ))
+    }
+
+    /// Total data blocks (DSM + 1).
+    pub fn blocks(&self) -> usize {
+        (self.tracks.saturating_sub(self.boottrk + self.offset)) * self.sectrk * self.seclen / self.blocksize
+    }
+
+    fn wide_pointers(&self) -> bool {
+        self.blocks() > 256
+    }
+
+    fn pointers_per_entry(&self) -> usize {
+        if self.wide_pointers() { 8 } else { 16 }
+    }
+
+    /// Extent mask: how many 16K logical extents one directory entry covers, minus one.
+    fn extent_mask(&self) -> usize {
+        (self.pointers_per_entry() * self.blocksize / 16384).max(1) - 1
+    }
+
+    fn directory_blocks(&self) -> usize {
+        (self.maxdir * 32).div_ceil(self.blocksize)
+    }
+
+    /// Logical-to-physical sector translation within a track.
+    pub fn skew_table(&self) -> Vec<usize> {
+        if let Some(table) = &self.skewtab {
+            return table.clone();
+        }
+        if self.skew <= 1 {
+            return (0..self.sectrk).collect();
+        }
+        let mut used = vec![false; self.sectrk];
+        let mut table = Vec::with_capacity(self.sectrk);
+        for i in 0..self.sectrk {
+            let mut j = (i * self.skew) % self.sectrk;
+            while used[j] {
+                j = (j + 1) % self.sectrk;
+            }
+            used[j] = true;
+            table.push(j);
+        }
+        table
+    }
+}
+
+fn parse_number(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// One 32-byte directory entry.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub user: u8,
+    pub name: String, // "NAME.EXT" with attribute bits stripped
+    pub read_only: bool,
+    pub system: bool,
+    pub extent: usize, // EX + 32 * S2
+    pub records: usize, // RC
+    pub blocks: Vec<usize>,
+}
+
+/// CP/M 2.2/3 filesystem over logical sectors.
+pub struct Cpm {
+    def: DiskDef,
+    sectors: Vec<Vec<u8>>,
+    skew: Vec<usize>,
+    sectors_per_track: usize, // Sectors per track in the image (sorted by ID)
+}
+
+impl Cpm {
+    pub fn open(def: DiskDef, sectors: Vec<Vec<u8>>, sectors_per_track: usize) -> Result<Cpm> {
+        if let Some(sector) = sectors.iter().find(|s| s.len() != def.seclen) {
+            return Err(anyhow!(
+                "diskdef '{}' expects {}-byte sectors but the image has {}-byte sectors",
+                def.name, def.seclen, sector.len()
+            ));
+        }
+        if sectors_per_track != def.sectrk {
+            return Err(anyhow!("diskdef '{}' expects {} sectors per track but the image has {}", def.name, def.sectrk, sectors_per_track));
+        }
+        // Every block the definition can allocate must lie inside the image
+        if sectors.len() < def.tracks * def.sectrk {
+            return Err(anyhow!(
+                "Image is too small for diskdef '{}': it needs {} tracks of {} sectors ({} sectors) but the image has {} sectors",
+                def.name, def.tracks, def.sectrk, def.tracks * def.sectrk, sectors.len()
+            ));
+        }
+        let skew = def.skew_table();
+        Ok(Cpm { def, sectors, skew, sectors_per_track })
+    }
+
+    /// Logical sector numbers (image order) holding a block.
+    fn block_sectors(&self, block: usize) -> Vec<usize> {
+        let per_block = self.def.blocksize / self.def.seclen;
+        (0..per_block).map(|i| {
+            let relative = block * per_block + i;
+            let track = self.def.offset + self.def.boottrk + relative / self.def.sectrk;
+            track * self.sectors_per_track + self.skew[relative % self.def.sectrk]
+        }).collect()
+    }
+
+    fn directory_sectors(&self) -> Vec<usize> {
+        (0..self.def.directory_blocks()).flat_map(|b| self.block_sectors(b)).collect()
+    }
+
+    fn directory(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = self.directory_sectors().iter()
+            .flat_map(|&s| self.sectors.get(s).cloned().unwrap_or_default())
+            .collect();
+        data.truncate(self.def.maxdir * 32);
+        data
+    }
+
+    /// All directory entries that describe file extents (user areas 0-15).
+    pub fn entries(&self) -> Vec<DirEntry> {
+        let wide = self.def.wide_pointers();
+        self.directory().chunks_exact(32).filter(|e| e[0] <= 15).map(|e| {
+            let name: String = e[1..9].iter().map(|&b| (b & 0x7F) as char).collect::<String>().trim_end().to_string();
+            let ext: String = e[9..12].iter().map(|&b| (b & 0x7F) as char).collect::<String>().trim_end().to_string();
+            let blocks = if wide {
+                e[16..32].chunks_exact(2).map(|p| u16::from_le_bytes([p[0], p[1]]) as usize).filter(|&b| b != 0).collect()
+            } else {
+                e[16..32].iter().map(|&b| b as usize).filter(|&b| b != 0).collect()
+            };
+            DirEntry {
+                user: e[0],
+                name: if ext.is_empty() { name } else { format!("{}.{}", name, ext) },
+                read_only: e[9] & 0x80 != 0,
+                system: e[10] & 0x80 != 0,
+                extent: (e[12] as usize & 0x1F) + 32 * (e[14] as usize & 0x3F),
+                records: e[15] as usize,
+                blocks,
+            }
+        }).collect()
+    }
+
+    fn display_name(user: u8, name: &str) -> String {
+        if user == 0 { name.to_string() } else { format!("{}:{}", user, name) }
+    }
+
+    fn block_is_valid(&self, block: usize) -> bool {
+        block >= self.def.directory_blocks() && block < self.def.blocks()
+    }
+
+    fn used_blocks(&self) -> Vec<bool> {
+        let mut used = vec![false; self.def.blocks()];
+        for block in 0..self.def.directory_blocks().min(used.len()) {
+            used[block] = true;
+        }
+        for entry in self.entries() {
+            for &block in &entry.blocks {
+                if let Some(slot) = used.get_mut(block) {
+                    *slot = true;
+                }
+            }
+        }
+        used
+    }
+
+    /// Writes a file into the given user area, replacing no existing file.
+    pub fn inject(&mut self, user: u8, name: &str, data: &[u8]) -> Result<()> {
+        let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+        let base = base.to_ascii_uppercase();
+        let ext = ext.to_ascii_uppercase();
+        if base.is_empty() || base.len() > 8 || ext.len() > 3 || user > 15
+            || !base.chars().chain(ext.chars()).all(|c| c.is_ascii_graphic() && !"<>.,;:=?*[]".contains(c)) {
+            return Err(anyhow!("'{}' is not a valid CP/M 8.3 file name (user 0-15)", name));
+        }
+        let full = if ext.is_empty() { base.clone() } else { format!("{}.{}", base, ext) };
+        if self.entries().iter().any(|e| e.user == user && e.name == full) {
+            return Err(anyhow!("{} already exists in user area {}", full, user));
+        }
+
+        let records = data.len().div_ceil(128);
+        let blocks_needed = (records * 128).div_ceil(self.def.blocksize);
+        let mut used = self.used_blocks();
+        let free_blocks: Vec<usize> = (0..used.len()).filter(|&b| !used[b]).take(blocks_needed).collect();
+        if free_blocks.len() < blocks_needed {
+            return Err(anyhow!("Not enough free space: {} blocks needed, {} free", blocks_needed, used.iter().filter(|u| !**u).count()));
+        }
+        let per_entry = self.def.pointers_per_entry();
+        let entries_needed = blocks_needed.div_ceil(per_entry).max(1);
+        let directory = self.directory();
+        let free_entries: Vec<usize> = directory.chunks_exact(32).enumerate().filter(|(_, e)| e[0] == 0xE5).map(|(i, _)| i).take(entries_needed).collect();
+        if free_entries.len() < entries_needed {
+            return Err(anyhow!("Directory full: {} entries needed, {} free", entries_needed, free_entries.len()));
+        }
+
+        // Data, padded with ^Z to whole records and zeros to whole blocks
+        let mut padded = data.to_vec();
+        padded.resize(records * 128, 0x1A);
+        padded.resize(blocks_needed * self.def.blocksize, 0);
+        for (chunk, &block) in padded.chunks(self.def.blocksize).zip(&free_blocks) {
+            used[block] = true;
+            for (sector_data, sector) in chunk.chunks(self.def.seclen).zip(self.block_sectors(block)) {
+                self.store(sector, sector_data)?;
+            }
+        }
+
+        // Directory entries: each covers `per_entry` blocks, i.e. (extent_mask + 1) logical extents
+        let records_per_entry = per_entry * self.def.blocksize / 128;
+        let mut directory = directory;
+        for (n, &slot) in free_entries.iter().enumerate() {
+            let entry_blocks = &free_blocks[(n * per_entry).min(free_blocks.len())..((n + 1) * per_entry).min(free_blocks.len())];
+            let entry_records = records.saturating_sub(n * records_per_entry).min(records_per_entry);
+            // Extent number of the last logical extent used by this entry
+            let extent = n * (self.def.extent_mask() + 1) + entry_records.saturating_sub(1) / 128;
+            let rc = if entry_records == 0 { 0 } else { entry_records - (entry_records - 1) / 128 * 128 };
+            let e = &mut directory[slot * 32..slot * 32 + 32];
+            e.fill(0);
+            e[0] = user;
+            e[1..9].copy_from_slice(format!("{:<8}", base).as_bytes());
+            e[9..12].copy_from_slice(format!("{:<3}", ext).as_bytes());
+            e[12] = (extent & 0x1F) as u8;
+            e[14] = (extent >> 5) as u8;
+            e[15] = rc as u8;
+            if self.def.wide_pointers() {
+                for (i, &block) in entry_blocks.iter().enumerate() {
+                    e[16 + i * 2..18 + i * 2].copy_from_slice(&(block as u16).to_le_bytes());
+                }
+            } else {
+                for (i, &block) in entry_blocks.iter().enumerate() {
+                    e[16 + i] = block as u8;
+                }
+            }
+        }
+        directory.resize(self.directory_sectors().len() * self.def.seclen, 0xE5);
+        for (chunk, sector) in directory.chunks(self.def.seclen).zip(self.directory_sectors()) {
+            self.store(sector, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces one logical sector, which must exist and have the diskdef's sector size.
+    fn store(&mut self, sector: usize, data: &[u8]) -> Result<()> {
+        let count = self.sectors.len();
+        match self.sectors.get_mut(sector) {
+            Some(target) if target.len() == data.len() => {
+                target.copy_from_slice(data);
+                Ok(())
+            }
+            Some(target) => Err(anyhow!("Logical sector {} has {} bytes but diskdef '{}' writes {}", sector, target.len(), self.def.name, data.len())),
+            None => Err(anyhow!("Logical sector {} is past the end of the image ({} sectors)", sector, count)),
+        }
+    }
+}
+
+/// How well a diskdef fits an image.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub def: DiskDef,
+    pub score: i32,
+    pub valid: usize,   // Directory entries that look like file extents
+    pub invalid: usize, // Directory entries that cannot be CP/M entries under this diskdef
+}
+
+impl Cpm {
+    /// Scores the directory as seen through this diskdef: plausible user numbers, 8.3 names,
+    /// extent counters and block pointers, unique block ownership, and used entries packed
+    /// together (a wrong skew scatters them or reads data sectors as directory).
+    fn score(&self) -> Candidate {
+        let wide = self.def.wide_pointers();
+        let mut valid = 0;
+        let mut invalid = 0;
+        let mut gaps = 0;
+        let mut seen_empty = false;
+        let mut owned = vec![false; self.def.blocks()];
+        for e in self.directory().chunks_exact(32) {
+            let ok = match e[0] {
+                0xE5 => {
+                    seen_empty = true;
+                    continue;
+                }
+                0..=15 => {
+                    let name_ok = e[1..12].iter().all(|&b| (0x20..0x7F).contains(&(b & 0x7F)) && !(b & 0x7F).is_ascii_lowercase())
+                        && e[1] & 0x7F != b' ';
+                    let counters_ok = e[12] <= 31 && e[14] <= 15 && e[15] <= 0x80;
+                    let pointers: Vec<usize> = if wide {
+                        e[16..32].chunks_exact(2).map(|p| u16::from_le_bytes([p[0], p[1]]) as usize).collect()
+                    } else {
+                        e[16..32].iter().map(|&b| b as usize).collect()
+                    };
+                    let mut pointers_ok = true;
+                    for &block in pointers.iter().filter(|&&b| b != 0) {
+                        if !self.block_is_valid(block) || owned[block] {
+                            pointers_ok = false;
+                        } else {
+                            owned[block] = true;
+                        }
+                    }
+                    name_ok && counters_ok && pointers_ok
+                }
+                0x20 | 0x21 => self.def.os3, // Disc label and date stamps (CP/M 3)
+                16..=31 => self.def.os3,     // Password entries (CP/M 3)
+                _ => false,
+            };
+            if ok {
+                valid += 1;
+                if seen_empty {
+                    gaps += 1;
+                }
+            } else {
+                invalid += 1;
+            }
+        }
+        let score = valid as i32 * 10 - invalid as i32 * 15 - gaps * 2;
+        Candidate { def: self.def.clone(), score, valid, invalid }
+    }
+}
+
+/// Ranks diskdefs (built-in plus `extra`) against the disk, best first. Definitions whose
+/// sector size or sectors per track do not match the image are skipped; a definition using
+/// exactly the image's track count is preferred over one that uses only part of it.
+pub fn rank(disk: &Disk, extra: &[DiskDef]) -> Result<Vec<Candidate>> {
+    let sectors = crate::fs::logical_sectors(disk);
+    let spt = crate::fs::sectors_per_track(disk);
+    let track_count = disk.tracks.len();
+    let mut defs = DiskDef::parse_all(BUILTIN_DISKDEFS)?;
+    defs.extend(extra.iter().cloned());
+
+    let mut candidates = Vec::new();
+    for def in defs {
+        if def.tracks > track_count {
+            continue;
+        }
+        let tracks = def.tracks;
+        let Ok(cpm) = Cpm::open(def, sectors.clone(), spt) else { continue };
+        let mut candidate = cpm.score();
+        candidate.score += if tracks == track_count { 20 } else { -20 };
+        candidates.push(candidate);
+    }
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.score));
+    Ok(candidates)
+}
+
+impl Filesystem for Cpm {
+    fn name(&self) -> String {
+        format!("CP/M {} (diskdef {})", if self.def.os3 { "3" } else { "2.2" }, self.def.name)
+    }
+
+    fn files(&self) -> Result<Vec<FileEntry>> {
+        let mut entries = self.entries();
+        entries.sort_by(|a, b| (a.user, &a.name, a.extent).cmp(&(b.user, &b.name, b.extent)));
+        let mut files: Vec<FileEntry> = Vec::new();
+        let mut last: Option<(u8, String)> = None;
+        for entry in entries {
+            let key = (entry.user, entry.name.clone());
+            if last.as_ref() != Some(&key) {
+                let mut flags = vec![format!("user {}", entry.user)];
+                if entry.read_only { flags.push("R/O".to_string()); }
+                if entry.system { flags.push("SYS".to_string()); }
+                files.push(FileEntry {
+                    name: Self::display_name(entry.user, &entry.name),
+                    size: 0,
+                    sectors: Vec::new(),
+                    detail: flags.join(" "),
+                });
+                last = Some(key);
+            }
+            let Some(file) = files.last_mut() else { continue };
+            // Size comes from the highest extent: 128 records per logical extent plus RC
+            file.size = file.size.max((entry.extent * 128 + entry.records) * 128);
+            for &block in &entry.blocks {
+                if !self.block_is_valid(block) {
+                    return Err(anyhow!("{} references block {} outside the data area (0-{})", file.name, block, self.def.blocks() - 1));
+                }
+                file.sectors.extend(self.block_sectors(block));
+            }
+        }
+        for file in &mut files {
+            file.sectors.truncate(file.size.div_ceil(self.def.seclen));
+        }
+        Ok(files)
+    }
+
+    fn sector_data(&self) -> &[Vec<u8>] {
+        &self.sectors
+    }
+
+    fn write_file(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let (user, name) = match name.split_once(':') {
+            Some((user, name)) => (user.parse().map_err(|_| anyhow!("Invalid user area in '{}'", name))?, name),
+            None => (0, name),
+        };
+        self.inject(user, name, data)
+    }
+}
@@ -0,0 +1,188 @@
+use crate::flux::apple::DOS_ORDER;
+use crate::fs::{FileEntry, Filesystem};
+use anyhow::{Result, anyhow};
+
+const SECTORS: usize = 16;
+const VTOC_TRACK: usize = 17;
+const ENTRY_SIZE: usize = 35;
+const TS_PAIRS: usize = 122; // Track/sector pairs in one T/S list sector
+
+/// A catalog entry, before the file's sectors are followed.
+struct CatalogEntry {
+    name: String,
+    kind: u8, // File type byte, including the locked bit
+    ts_list: (u8, u8),
+    length: u16, // Sector count recorded in the catalog
+}
+
+/// Read-only Apple DOS 3.3 filesystem over 256-byte sectors in physical order, 16 per track
+/// (as `logical_sectors` gives them for an Apple disk).
+pub struct Dos33 {
+    sectors: Vec<Vec<u8>>,
+    tracks: usize,
+}
+
+impl Dos33 {
+    /// Opens the filesystem if the VTOC on track 17 describes a 16-sector disk of this size.
+    pub fn open(sectors: Vec<Vec<u8>>) -> Option<Dos33> {
+        if !sectors.len().is_multiple_of(SECTORS) || !(35..=40).contains(&(sectors.len() / SECTORS)) || sectors.iter().any(|s| s.len() != 256) {
+            return None;
+        }
+        let fs = Dos33 { tracks: sectors.len() / SECTORS, sectors };
+        let vtoc = fs.sector(VTOC_TRACK as u8, 0)?;
+        let valid = (vtoc[1] as usize) < fs.tracks && (vtoc[2] as usize) < SECTORS
+            && vtoc[0x27] == TS_PAIRS as u8 && vtoc[0x35] == SECTORS as u8 && vtoc[0x36] == 0 && vtoc[0x37] == 1;
+        valid.then_some(fs)
+    }
+
+    /// Index into `sectors` of a DOS logical sector.
+    fn index(&self, track: u8, sector: u8) -> Option<usize> {
+        if track as usize >= self.tracks || sector as usize >= SECTORS {
+            return None;
+        }
+        Some(track as usize * SECTORS + DOS_ORDER[sector as usize] as usize)
+    }
+
+    fn sector(&self, track: u8, sector: u8) -> Option<&[u8]> {
+        self.index(track, sector).map(|i| self.sectors[i].as_slice())
+    }
+
+    fn vtoc(&self) -> &[u8] {
+        self.sector(VTOC_TRACK as u8, 0).unwrap_or_default()
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.vtoc()[6]
+    }
+
+    /// Free sectors according to the VTOC bitmaps (a set bit is free).
+    pub fn free_sectors(&self) -> usize {
+        let vtoc = self.vtoc();
+        let tracks = (vtoc[0x34] as usize).clamp(1, self.tracks).min(50);
+        (0..tracks).map(|t| u16::from_be_bytes([vtoc[0x38 + t * 4], vtoc[0x39 + t * 4]]).count_ones() as usize).sum()
+    }
+
+    fn catalog(&self) -> Result<Vec<CatalogEntry>> {
+        let vtoc = self.vtoc();
+        let (mut track, mut sector) = (vtoc[1], vtoc[2]);
+        let mut entries = Vec::new();
+        let mut visited = 0;
+        while track != 0 {
+            visited += 1;
+            if visited > self.sectors.len() {
+                return Err(anyhow!("Catalog chain loops at track {} sector {}", track, sector));
+            }
+            let block = self.sector(track, sector)
+                .ok_or_else(|| anyhow!("Catalog chain points at track {} sector {}, which does not exist", track, sector))?;
+            for entry in block[0x0B..].chunks_exact(ENTRY_SIZE) {
+                match entry[0] {
+                    0x00 => return Ok(entries), // Never used: the end of the catalog
+                    0xFF => continue,           // Deleted
+                    _ => {}
+                }
+                let name: String = entry[3..33].iter().map(|&b| (b & 0x7F) as char).collect();
+                entries.push(CatalogEntry {
+                    name: name.trim_end().to_string(),
+                    kind: entry[2],
+                    ts_list: (entry[0], entry[1]),
+                    length: u16::from_le_bytes([entry[33], entry[34]]),
+                });
+            }
+            (track, sector) = (block[1], block[2]);
+        }
+        Ok(entries)
+    }
+
+    /// Data sectors of a file (indices into `sectors`), following its track/sector lists.
+    /// Unallocated entries (sparse random-access files) are skipped.
+    fn file_sectors(&self, entry: &CatalogEntry) -> Result<Vec<usize>> {
+        let (mut track, mut sector) = entry.ts_list;
+        let mut sectors = Vec::new();
+        let mut visited = 0;
+        while track != 0 {
+            visited += 1;
+            if visited > self.sectors.len() {
+                return Err(anyhow!("{}: track/sector list chain loops", entry.name));
+            }
+            let list = self.sector(track, sector)
+                .ok_or_else(|| anyhow!("{}: track/sector list at track {} sector {} does not exist", entry.name, track, sector))?;
+            for pair in list[0x0C..0x0C + TS_PAIRS * 2].chunks_exact(2) {
+                if pair[0] == 0 && pair[1] == 0 {
+                    continue;
+                }
+                sectors.push(self.index(pair[0], pair[1])
+                    .ok_or_else(|| anyhow!("{}: data at track {} sector {} does not exist", entry.name, pair[0], pair[1]))?);
+            }
+            (track, sector) = (list[1], list[2]);
+        }
+        Ok(sectors)
+    }
+
+    /// Bytes of the file's sectors, concatenated.
+    fn raw(&self, sectors: &[usize]) -> Vec<u8> {
+        sectors.iter().flat_map(|&i| self.sectors[i].iter().copied()).collect()
+    }
+}
+
+fn type_letter(kind: u8) -> &'static str {
+    match kind & 0x7F {
+        0x00 => "T",
+        0x01 => "I",
+        0x02 => "A",
+        0x04 => "B",
+        0x08 => "S",
+        0x10 => "R",
+        0x20 => "AA",
+        0x40 => "BB",
+        _ => "?",
+    }
+}
+
+/// Offset and length of the contents within a file's sectors. Binary files start with a load
+/// address and length, BASIC programs with a length; text files end at the first zero byte.
+fn contents(kind: u8, raw: &[u8]) -> (usize, usize, String) {
+    let word = |at: usize| raw.get(at..at + 2).map_or(0, |w| u16::from_le_bytes([w[0], w[1]]) as usize);
+    match kind & 0x7F {
+        0x04 => {
+            let length = word(2).min(raw.len().saturating_sub(4));
+            (4, length, format!(" ${:04X}", word(0)))
+        }
+        0x01 | 0x02 => (2, word(0).min(raw.len().saturating_sub(2)), String::new()),
+        0x00 => (0, raw.iter().position(|&b| b == 0).unwrap_or(raw.len()), String::new()),
+        _ => (0, raw.len(), String::new()),
+    }
+}
+
+impl Filesystem for Dos33 {
+    fn name(&self) -> String {
+        "Apple DOS 3.3".to_string()
+    }
+
+    fn files(&self) -> Result<Vec<FileEntry>> {
+        let mut files = Vec::new();
+        for entry in self.catalog()? {
+            let sectors = self.file_sectors(&entry)?;
+            let (_, size, address) = contents(entry.kind, &self.raw(&sectors));
+            let detail = format!(
+                "{}{}{} {} sectors",
+                if entry.kind & 0x80 != 0 { "*" } else { " " },
+                type_letter(entry.kind), address, entry.length
+            );
+            files.push(FileEntry { name: entry.name, size, sectors, detail });
+        }
+        Ok(files)
+    }
+
+    fn sector_data(&self) -> &[Vec<u8>] {
+        &self.sectors
+    }
+
+    /// Extracts the contents without the DOS header (load address and length).
+    fn read_file(&self, file: &FileEntry) -> Result<Vec<u8>> {
+        let entry = self.catalog()?.into_iter().find(|e| e.name == file.name)
+            .ok_or_else(|| anyhow!("File '{}' not found in the catalog", file.name))?;
+        let raw = self.raw(&file.sectors);
+        let (offset, length, _) = contents(entry.kind, &raw);
+        Ok(raw[offset..offset + length].to_vec())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::fs::{FileEntry, Filesystem};
+use anyhow::{Result, anyhow};
+
+/// Extended BPB fields written by DOS 4.0 and later (boot signature 0x29).
+#[derive(Debug, Clone)]
+pub struct ExtendedBpb {
+    pub drive_number: u8,
+    pub serial: u32,
+    pub label: String,
+    pub fs_type: String,
+}
+
+/// BIOS Parameter Block fields from a DOS 2.0+ boot sector.
+#[derive(Debug, Clone)]
+pub struct Bpb {
+    pub oem: String,
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fat_count: u8,
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    pub media: u8,
+    pub sectors_per_fat: u16,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    pub hidden_sectors: u32,
+    pub extended: Option<ExtendedBpb>,
+}
+
+impl Bpb {
+    /// Parses and sanity-checks the BPB in a boot sector; None if it is not a plausible FAT BPB.
+    pub fn parse(boot: &[u8]) -> Option<Bpb> {
+        if boot.len() < 36 {
+            return None;
+        }
+        let u16_at = |o: usize| u16::from_le_bytes([boot[o], boot[o + 1]]);
+        let u32_at = |o: usize| u32::from_le_bytes([boot[o], boot[o + 1], boot[o + 2], boot[o + 3]]);
+        let text = |range: std::ops::Range<usize>| String::from_utf8_lossy(&boot[range]).trim_end().to_string();
+        let total16 = u16_at(0x13);
+        let bpb = Bpb {
+            oem: text(0x03..0x0B),
+            bytes_per_sector: u16_at(0x0B),
+            sectors_per_cluster: boot[0x0D],
+            reserved_sectors: u16_at(0x0E),
+            fat_count: boot[0x10],
+            root_entries: u16_at(0x11),
+            total_sectors: if total16 != 0 { total16 as u32 } else { u32_at(0x20) },
+            media: boot[0x15],
+            sectors_per_fat: u16_at(0x16),
+            sectors_per_track: u16_at(0x18),
+            heads: u16_at(0x1A),
+            hidden_sectors: u32_at(0x1C),
+            extended: (boot.len() >= 0x3E && boot[0x26] == 0x29).then(|| ExtendedBpb {
+                drive_number: boot[0x24],
+                serial: u32_at(0x27),
+                label: text(0x2B..0x36),
+                fs_type: text(0x36..0x3E),
+            }),
+        };
+        let valid = bpb.bytes_per_sector.is_power_of_two()
+            && (128..=4096).contains(&bpb.bytes_per_sector)
+            && bpb.sectors_per_cluster.is_power_of_two()
+            && bpb.reserved_sectors >= 1
+            && (1..=2).contains(&bpb.fat_count)
+            && bpb.root_entries > 0
+            && bpb.total_sectors > 0
+            && (bpb.media >= 0xF8 || bpb.media == 0xF0)
+            && bpb.sectors_per_fat > 0;
+        valid.then_some(bpb)
+    }
+
+    pub fn root_dir_start(&self) -> usize {
+        self.reserved_sectors as usize + self.fat_count as usize * self.sectors_per_fat as usize
+    }
+
+    pub fn data_start(&self) -> usize {
+        let root_sectors = (self.root_entries as usize * 32).div_ceil(self.bytes_per_sector as usize);
+        self.root_dir_start() + root_sectors
+    }
+}
+
+/// Read-only FAT12 filesystem over logical sectors.
+pub struct Fat {
+    bpb: Bpb,
+    sectors: Vec<Vec<u8>>,
+    fat: Vec<u8>,
+}
+
+impl Fat {
+    pub fn open(sectors: Vec<Vec<u8>>) -> Option<Fat> {
+        let bpb = Bpb::parse(sectors.first()?)?;
+        if sectors.first()?.len() != bpb.bytes_per_sector as usize || bpb.data_start() >= sectors.len() {
+            return None;
+        }
+        let fat_start = bpb.reserved_sectors as usize;
+        let fat: Vec<u8> = sectors.get(fat_start..fat_start + bpb.sectors_per_fat as usize)?.concat();
+        if fat.first() != Some(&bpb.media) {
+            return None; // First FAT entry repeats the media descriptor
+        }
+        Some(Fat { bpb, sectors, fat })
+    }
+
+    fn entry(&self, cluster: usize) -> Option<usize> {
+        let offset = cluster * 3 / 2;
+        let value = u16::from_le_bytes([*self.fat.get(offset)?, *self.fat.get(offset + 1)?]) as usize;
+        Some(if cluster & 1 == 0 { value & 0xFFF } else { value >> 4 })
+    }
+
+    fn cluster_sectors(&self, cluster: usize) -> std::ops::Range<usize> {
+        let spc = self.bpb.sectors_per_cluster as usize;
+        let start = self.bpb.data_start() + (cluster - 2) * spc;
+        start..start + spc
+    }
+
+    /// Logical sectors of the cluster chain starting at `cluster`.
+    fn chain(&self, mut cluster: usize) -> Result<Vec<usize>> {
+        let max_clusters = self.sectors.len();
+        let mut result = Vec::new();
+        let mut visited = 0;
+        while (2..0xFF0).contains(&cluster) {
+            visited += 1;
+            if visited > max_clusters {
+                return Err(anyhow!("FAT cluster chain loops at cluster {}", cluster));
+            }
+            result.extend(self.cluster_sectors(cluster).filter(|&s| s < self.sectors.len()));
+            cluster = self.entry(cluster).ok_or_else(|| anyhow!("Cluster {} lies beyond the FAT", cluster))?;
+        }
+        Ok(result)
+    }
+
+    fn read_dir(&self, sectors: &[usize], prefix: &str, depth: usize, files: &mut Vec<FileEntry>) -> Result<()> {
+        if depth > 16 {
+            return Err(anyhow!("Directory nesting too deep under '{}'", prefix));
+        }
+        let data: Vec<u8> = sectors.iter().flat_map(|&s| self.sectors[s].iter().copied()).collect();
+        for entry in data.chunks_exact(32) {
+            match entry[0] {
+                0x00 => break,
+                0xE5 => continue,
+                _ => {}
+            }
+            let attributes = entry[0x0B];
+            if attributes & 0x08 != 0 {
+                continue; // Volume label or long file name
+            }
+            let base = String::from_utf8_lossy(&entry[0..8]).trim_end().to_string();
+            let ext = String::from_utf8_lossy(&entry[8..11]).trim_end().to_string();
+            if base == "." || base == ".." {
+                continue;
+            }
+            let name = if ext.is_empty() { format!("{}{}", prefix, base) } else { format!("{}{}.{}", prefix, base, ext) };
+            let cluster = u16::from_le_bytes([entry[0x1A], entry[0x1B]]) as usize;
+            let size = u32::from_le_bytes([entry[0x1C], entry[0x1D], entry[0x1E], entry[0x1F]]) as usize;
+            if attributes & 0x10 != 0 {
+                let chain = self.chain(cluster)?;
+                self.read_dir(&chain, &format!("{}/", name), depth + 1, files)?;
+            } else {
+                let mut chain = self.chain(cluster)?;
+                chain.truncate(size.div_ceil(self.bpb.bytes_per_sector as usize));
+                let flags: Vec<&str> = [(0x01, "R/O"), (0x02, "HID"), (0x04, "SYS"), (0x20, "ARC")].iter()
+                    .filter(|(bit, _)| attributes & bit != 0).map(|(_, flag)| *flag).collect();
+                files.push(FileEntry { name, size, sectors: chain, detail: flags.join(" ") });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for Fat {
+    fn name(&self) -> String {
+        "FAT12".to_string()
+    }
+
+    fn files(&self) -> Result<Vec<FileEntry>> {
+        let root_start = self.bpb.root_dir_start();
+        let root: Vec<usize> = (root_start..self.bpb.data_start()).collect();
+        let mut files = Vec::new();
+        self.read_dir(&root, "", 0, &mut files)?;
+        Ok(files)
+    }
+
+    fn sector_data(&self) -> &[Vec<u8>] {
+        &self.sectors
+    }
+}
@@ -0,0 +1,172 @@
+use crate::disk::{Disk, Order};
+use anyhow::{Result, anyhow};
+use std::path::PathBuf;
+
+pub mod amiga;
+pub mod cbm;
+pub mod cpm;
+pub mod dos33;
+pub mod fat;
+pub mod prodos;
+
+/// A file found in a filesystem, with the logical sectors that hold its data (in file order).
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: usize,
+    pub sectors: Vec<usize>,
+    pub detail: String, // Filesystem-specific attributes shown by `ls`
+}
+
+impl FileEntry {
+    /// Relative path for extraction: directories are kept and a CP/M "N:" user prefix
+    /// becomes a "userN" directory.
+    pub fn host_path(&self) -> PathBuf {
+        match self.name.split_once(':') {
+            Some((user, name)) => PathBuf::from(format!("user{}", user)).join(name),
+            None => PathBuf::from(&self.name),
+        }
+    }
+}
+
+pub trait Filesystem {
+    /// Short description of the detected filesystem (e.g., "FAT12").
+    fn name(&self) -> String;
+    fn files(&self) -> Result<Vec<FileEntry>>;
+    /// Current contents of every logical sector, including changes made by `write_file`.
+    fn sector_data(&self) -> &[Vec<u8>];
+
+    fn read_file(&self, file: &FileEntry) -> Result<Vec<u8>> {
+        let sectors = self.sector_data();
+        let mut data = Vec::with_capacity(file.size);
+        for &s in &file.sectors {
+            data.extend_from_slice(sectors.get(s).ok_or_else(|| anyhow!("{} references sector {} beyond the disk", file.name, s))?);
+        }
+        data.truncate(file.size);
+        Ok(data)
+    }
+
+    fn write_file(&mut self, _name: &str, _data: &[u8]) -> Result<()> {
+        Err(anyhow!("Writing files is not supported for {}", self.name()))
+    }
+
+    /// Looks up a file by name, case-insensitively.
+    fn find(&self, name: &str) -> Result<FileEntry> {
+        self.files()?.into_iter().find(|f| f.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("File '{}' not found. Use 'ls' to list the files on the disk.", name))
+    }
+}
+
+/// Sector data in logical order (see `Disk::sectors`), with missing sectors zero-filled.
+pub fn logical_sectors(disk: &Disk) -> Vec<Vec<u8>> {
+    disk.sectors(Order::Logical).into_iter()
+        .map(|(_, s)| s.data.clone().unwrap_or_else(|| vec![0; s.size]))
+        .collect()
+}
+
+/// Copies changed logical sectors back into the disk, leaving unchanged sectors untouched.
+pub fn store_logical_sectors(disk: &mut Disk, sectors: &[Vec<u8>]) -> Result<()> {
+    for ((t, s), data) in disk.sector_positions(Order::Logical).into_iter().zip(sectors) {
+        let sector = &mut disk.tracks[t].sectors[s];
+        if sector.data.as_ref() != Some(data) {
+            sector.patch(data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Most common number of sectors per track, used to map filesystem tracks onto the image.
+pub fn sectors_per_track(disk: &Disk) -> usize {
+    let mut counts: Vec<(usize, usize)> = Vec::new();
+    for track in &disk.tracks {
+        match counts.iter_mut().find(|(n, _)| *n == track.sectors.len()) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((track.sectors.len(), 1)),
+        }
+    }
+    counts.into_iter().max_by_key(|&(_, count)| count).map_or(0, |(n, _)| n)
+}
+
+/// Opens the filesystem on the disk: CP/M when a diskdef is given, otherwise whatever is detected.
+pub fn open(disk: &Disk, diskdef: Option<cpm::DiskDef>) -> Result<Box<dyn Filesystem>> {
+    if let Some(def) = diskdef {
+        return Ok(Box::new(cpm::Cpm::open(def, logical_sectors(disk), sectors_per_track(disk))?));
+    }
+    detect(disk).ok_or_else(|| anyhow!(
+        "No supported filesystem detected. For CP/M disks, choose a format with --diskdef (e.g., '--diskdef kpii')."
+    ))
+}
+
+/// Detects a supported filesystem on the disk (FAT12, AmigaDOS, CBM DOS, Apple DOS 3.3, then ProDOS). CP/M is only chosen automatically when the
+/// best diskdef finds file entries and no invalid ones.
+pub fn detect(disk: &Disk) -> Option<Box<dyn Filesystem>> {
+    let sectors = logical_sectors(disk);
+    if let Some(fat) = fat::Fat::open(sectors.clone()) {
+        return Some(Box::new(fat));
+    }
+    if let Some(amiga) = amiga::AmigaDos::open(sectors.clone()) {
+        return Some(Box::new(amiga));
+    }
+    if let Some(cbm) = cbm::CbmDos::open(sectors.clone()) {
+        return Some(Box::new(cbm));
+    }
+    if let Some(dos) = dos33::Dos33::open(sectors.clone()) {
+        return Some(Box::new(dos));
+    }
+    if let Some(prodos) = prodos::ProDos::open(sectors.clone()) {
+        return Some(Box::new(prodos));
+    }
+    let best = cpm::rank(disk, &[]).ok()?.into_iter().next()?;
+    if best.valid > 0 && best.invalid == 0 {
+        return cpm::Cpm::open(best.def, sectors, sectors_per_track(disk)).ok().map(|c| Box::new(c) as Box<dyn Filesystem>);
+    }
+    None
+}
+
+/// Filesystem lines for `display`: the detected filesystem or, failing that, the most
+/// likely CP/M formats. Never fails, so a problem here cannot hide the rest of the display.
+pub fn report(disk: &Disk) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(fat) = fat::Fat::open(logical_sectors(disk)) {
+        lines.push(format!("Filesystem: {}", fat.name()));
+        return lines;
+    }
+    if let Some(amiga) = amiga::AmigaDos::open(logical_sectors(disk)) {
+        lines.push(format!("Filesystem: {} (volume \"{}\")", amiga.name(), amiga.volume_name()));
+        return lines;
+    }
+    if let Some(cbm) = cbm::CbmDos::open(logical_sectors(disk)) {
+        let (name, id) = cbm.label();
+        lines.push(format!("Filesystem: {} (disk \"{}\", id \"{}\", {} blocks free)", cbm.name(), name, id, cbm.blocks_free()));
+        return lines;
+    }
+    if let Some(dos) = dos33::Dos33::open(logical_sectors(disk)) {
+        lines.push(format!("Filesystem: {} (volume {}, {} sectors free)", dos.name(), dos.volume(), dos.free_sectors()));
+        return lines;
+    }
+    if let Some(prodos) = prodos::ProDos::open(logical_sectors(disk)) {
+        lines.push(format!("Filesystem: {} (volume \"/{}\", {} blocks free)", prodos.name(), prodos.volume_name(), prodos.free_blocks()));
+        return lines;
+    }
+    let candidates: Vec<cpm::Candidate> = match cpm::rank(disk, &[]) {
+        Ok(ranked) => ranked.into_iter().filter(|c| c.valid > 0).collect(),
+        Err(e) => {
+            lines.push(format!("Filesystem: cannot check for CP/M ({})", e));
+            return lines;
+        }
+    };
+    match candidates.first() {
+        Some(best) => {
+            lines.push(format!(
+                "Likely CP/M Format: {} (score {}: {} directory entries valid, {} invalid)",
+                best.def.name, best.score, best.valid, best.invalid
+            ));
+            let others: Vec<String> = candidates[1..].iter().take(3).map(|c| format!("{} ({})", c.def.name, c.score)).collect();
+            if !others.is_empty() {
+                lines.push(format!("  Alternatives: {}", others.join(", ")));
+            }
+        }
+        None => lines.push("Filesystem: not recognised".to_string()),
+    }
+    lines
+}
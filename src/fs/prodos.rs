@@ -0,0 +1,217 @@
+use crate::flux::apple::PRODOS_ORDER;
+use crate::fs::{FileEntry, Filesystem};
+use anyhow::{Result, anyhow};
+
+const SECTORS: usize = 16;
+const BLOCK_SIZE: usize = 512;
+const VOLUME_DIRECTORY: usize = 2;
+const ENTRY_LENGTH: usize = 0x27;
+const SEEDLING: u8 = 0x1;
+const SAPLING: u8 = 0x2;
+const TREE: u8 = 0x3;
+const SUBDIRECTORY: u8 = 0xD;
+
+/// Read-only ProDOS filesystem on a 5.25-inch disk: 512-byte blocks made of two 256-byte
+/// sectors, over sectors in physical order, 16 per track.
+pub struct ProDos {
+    sectors: Vec<Vec<u8>>,
+}
+
+fn word(data: &[u8], at: usize) -> usize {
+    u16::from_le_bytes([data[at], data[at + 1]]) as usize
+}
+
+/// Name from an entry or header whose first byte holds the storage type and name length.
+fn entry_name(entry: &[u8]) -> String {
+    let len = (entry[0] & 0x0F) as usize;
+    entry[1..1 + len].iter().map(|&b| b as char).collect()
+}
+
+fn type_name(kind: u8) -> String {
+    match kind {
+        0x00 => "NON".to_string(),
+        0x04 => "TXT".to_string(),
+        0x06 => "BIN".to_string(),
+        0x0F => "DIR".to_string(),
+        0xFA => "INT".to_string(),
+        0xFC => "BAS".to_string(),
+        0xFD => "VAR".to_string(),
+        0xFE => "REL".to_string(),
+        0xFF => "SYS".to_string(),
+        _ => format!("${:02X}", kind),
+    }
+}
+
+impl ProDos {
+    /// Opens the filesystem if block 2 holds a volume directory header.
+    pub fn open(sectors: Vec<Vec<u8>>) -> Option<ProDos> {
+        if !sectors.len().is_multiple_of(SECTORS) || !(35..=40).contains(&(sectors.len() / SECTORS)) || sectors.iter().any(|s| s.len() != 256) {
+            return None;
+        }
+        let fs = ProDos { sectors };
+        let key = fs.block(VOLUME_DIRECTORY).ok()?;
+        let valid = word(&key, 0) == 0 && key[4] >> 4 == 0xF && key[4] & 0x0F > 0
+            && key[0x23] as usize == ENTRY_LENGTH && key[0x24] == 0x0D;
+        valid.then_some(fs)
+    }
+
+    fn blocks(&self) -> usize {
+        self.sectors.len() / 2
+    }
+
+    /// The two sectors (indices into `sectors`) that make up a block.
+    fn block_sectors(&self, block: usize) -> Result<[usize; 2]> {
+        if block >= self.blocks() {
+            return Err(anyhow!("Block {} is beyond the end of the {}-block volume", block, self.blocks()));
+        }
+        let (track, half) = (block / 8, (block % 8) * 2);
+        Ok([track * SECTORS + PRODOS_ORDER[half] as usize, track * SECTORS + PRODOS_ORDER[half + 1] as usize])
+    }
+
+    fn block(&self, block: usize) -> Result<Vec<u8>> {
+        Ok(self.block_sectors(block)?.iter().flat_map(|&s| self.sectors[s].iter().copied()).collect())
+    }
+
+    pub fn volume_name(&self) -> String {
+        self.block(VOLUME_DIRECTORY).map(|key| entry_name(&key[4..])).unwrap_or_default()
+    }
+
+    /// Free blocks according to the volume bitmap (a set bit is free).
+    pub fn free_blocks(&self) -> usize {
+        let Ok(key) = self.block(VOLUME_DIRECTORY) else { return 0 };
+        let (bitmap, total) = (word(&key, 0x27), word(&key, 0x29).min(self.blocks()));
+        (0..total.div_ceil(BLOCK_SIZE * 8))
+            .filter_map(|i| self.block(bitmap + i).ok())
+            .flatten()
+            .flat_map(|byte| (0..8).rev().map(move |bit| (byte >> bit) & 1))
+            .take(total)
+            .filter(|&bit| bit == 1)
+            .count()
+    }
+
+    /// Data blocks of a file in order, with None for sparse (unallocated) blocks.
+    fn data_blocks(&self, storage: u8, key: usize, eof: usize) -> Result<Vec<Option<usize>>> {
+        let count = eof.div_ceil(BLOCK_SIZE);
+        let index = |block: usize| -> Result<Vec<Option<usize>>> {
+            let data = self.block(block)?;
+            Ok((0..256).map(|i| Some(data[i] as usize | (data[256 + i] as usize) << 8).filter(|&b| b != 0)).collect())
+        };
+        let mut blocks = match storage {
+            SEEDLING => vec![Some(key)],
+            SAPLING => index(key)?,
+            TREE => {
+                let mut blocks = Vec::new();
+                for entry in index(key)?.into_iter().take(count.div_ceil(256)) {
+                    match entry {
+                        Some(block) => blocks.extend(index(block)?),
+                        None => blocks.extend([None; 256]),
+                    }
+                }
+                blocks
+            }
+            _ => return Err(anyhow!("Unsupported storage type {}", storage)),
+        };
+        blocks.resize(count, None);
+        Ok(blocks)
+    }
+
+    fn read_dir(&self, key: usize, prefix: &str, depth: usize, files: &mut Vec<FileEntry>) -> Result<()> {
+        if depth > 16 {
+            return Err(anyhow!("Directory nesting too deep under '{}'", prefix));
+        }
+        let mut block = key;
+        let mut visited = 0;
+        while block != 0 {
+            visited += 1;
+            if visited > self.blocks() {
+                return Err(anyhow!("Directory block chain loops in '{}'", prefix));
+            }
+            let data = self.block(block)?;
+            for (i, entry) in data[4..].chunks_exact(ENTRY_LENGTH).take(13).enumerate() {
+                let storage = entry[0] >> 4;
+                if (block == key && i == 0) || storage == 0 {
+                    continue; // Directory header, or a deleted entry
+                }
+                let name = format!("{}{}", prefix, entry_name(entry));
+                let (kind, pointer, blocks_used) = (entry[0x10], word(entry, 0x11), word(entry, 0x13));
+                let eof = word(entry, 0x15) | (entry[0x17] as usize) << 16;
+                match storage {
+                    SUBDIRECTORY => self.read_dir(pointer, &format!("{}/", name), depth + 1, files)?,
+                    SEEDLING | SAPLING | TREE => {
+                        let sectors = self.data_blocks(storage, pointer, eof)?.into_iter().flatten()
+                            .map(|b| self.block_sectors(b))
+                            .collect::<Result<Vec<_>>>()?
+                            .concat();
+                        let detail = format!(
+                            "{}{} ${:04X} {} blocks",
+                            if entry[0x1E] & 0xC2 == 0 { "*" } else { " " }, // Locked: not writable, renamable or destroyable
+                            type_name(kind), word(entry, 0x1F), blocks_used
+                        );
+                        files.push(FileEntry { name, size: eof, sectors, detail });
+                    }
+                    _ => {} // Extended (forked) files and anything unknown
+                }
+            }
+            block = word(&data, 2);
+        }
+        Ok(())
+    }
+
+    /// Storage type, key block and EOF of the file entry at `path`.
+    fn lookup(&self, path: &str) -> Result<(u8, usize, usize)> {
+        let mut key = VOLUME_DIRECTORY;
+        let parts: Vec<&str> = path.split('/').collect();
+        'parts: for (n, part) in parts.iter().enumerate() {
+            let mut block = key;
+            let mut visited = 0;
+            while block != 0 && visited <= self.blocks() {
+                visited += 1;
+                let data = self.block(block)?;
+                for (i, entry) in data[4..].chunks_exact(ENTRY_LENGTH).take(13).enumerate() {
+                    if (block == key && i == 0) || entry[0] >> 4 == 0 || !entry_name(entry).eq_ignore_ascii_case(part) {
+                        continue;
+                    }
+                    if n + 1 == parts.len() {
+                        return Ok((entry[0] >> 4, word(entry, 0x11), word(entry, 0x15) | (entry[0x17] as usize) << 16));
+                    }
+                    key = word(entry, 0x11);
+                    continue 'parts;
+                }
+                block = word(&data, 2);
+            }
+            break;
+        }
+        Err(anyhow!("File '{}' not found in the directory", path))
+    }
+}
+
+impl Filesystem for ProDos {
+    fn name(&self) -> String {
+        "ProDOS".to_string()
+    }
+
+    fn files(&self) -> Result<Vec<FileEntry>> {
+        let mut files = Vec::new();
+        self.read_dir(VOLUME_DIRECTORY, "", 0, &mut files)?;
+        Ok(files)
+    }
+
+    fn sector_data(&self) -> &[Vec<u8>] {
+        &self.sectors
+    }
+
+    /// Sparse blocks read as zeros, so the file is rebuilt from its index blocks rather than
+    /// from the allocated sectors alone.
+    fn read_file(&self, file: &FileEntry) -> Result<Vec<u8>> {
+        let (storage, key, eof) = self.lookup(&file.name)?;
+        let mut data = Vec::with_capacity(eof.next_multiple_of(BLOCK_SIZE));
+        for block in self.data_blocks(storage, key, eof)? {
+            match block {
+                Some(b) => data.extend(self.block(b)?),
+                None => data.resize(data.len() + BLOCK_SIZE, 0),
+            }
+        }
+        data.truncate(eof);
+        Ok(data)
+    }
+}
@@ -1,22 +1,35 @@
 use anyhow::{Result, anyhow};
 use clap::{Parser, Subcommand};
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 trait FormatHandler: Send + Sync {
     fn display(&self, ascii: bool) -> Result<String>;
-    fn convert(&self, target: &dyn FormatHandler, output_path: &PathBuf, input_path: &PathBuf, meta_path: Option<&PathBuf>, geometry: Option<Geometry>, verbose: bool, validate: bool) -> Result<()>;
+    #[allow(clippy::too_many_arguments)]
+    fn convert(&self, target: &dyn FormatHandler, output_path: &Path, input_path: &Path, meta_path: Option<&PathBuf>, geometry: Option<Geometry>, verbose: bool, validate: bool, placement: disk::Placement) -> Result<()>;
     fn data(&self) -> &[u8];
     fn geometry(&self) -> Result<Option<Geometry>>;
+    fn disk(&self) -> Result<disk::Disk> {
+        Err(anyhow!("Sector-level access is not supported for this format yet."))
+    }
+    fn encode(&self, _disk: &disk::Disk) -> Result<Vec<u8>> {
+        Err(anyhow!("Writing this format from sector data is not supported yet."))
+    }
+    fn flux(&self) -> Result<Option<flux::capture::Capture>> {
+        Ok(None)
+    }
 }
 
-fn load_handler(file_path: &PathBuf) -> Result<Box<dyn FormatHandler>> {
+fn load_handler(file_path: &PathBuf, geometry: &Geometry) -> Result<Box<dyn FormatHandler>> {
+    if file_path.is_dir() {
+        return Ok(Box::new(formats::kryoflux::KryoFluxHandler::open(file_path)?));
+    }
     let ext = file_path
         .extension()
         .and_then(|s| s.to_str())
         .map(|s| s.to_lowercase())
-        .ok_or_else(|| anyhow!("No file extension found for '{}'. Supported formats: .img, .imd, .scp.", file_path.display()))?;
+        .ok_or_else(|| anyhow!("No file extension found for '{}'. Supported formats: .img, .imd, .adf, .d64, .g64, .do, .po, .dsk, .nib, .woz, .scp, .st, .msa, .hfe, .td0, KryoFlux .raw (or a directory of them).", file_path.display()))?;
 
     let mut file = File::open(file_path)?;
     let mut data = Vec::new();
@@ -24,15 +37,53 @@ fn load_handler(file_path: &PathBuf) -> Result<Box<dyn FormatHandler>> {
 
     match ext.as_str() {
         "imd" => Ok(Box::new(formats::imd::IMDHandler::new(data))),
-        "img" => Ok(Box::new(formats::img::IMGHandler::new(data))),
+        "img" => Ok(Box::new(formats::img::IMGHandler::new(data).with_geometry(geometry))),
+        "adf" => Ok(Box::new(formats::adf::ADFHandler::new(data))),
+        "d64" => Ok(Box::new(formats::d64::D64Handler::new(data))),
+        "g64" => Ok(Box::new(formats::g64::G64Handler::new(data))),
+        "do" => Ok(Box::new(formats::apple::AppleHandler::new(data, Some(formats::apple::SectorOrder::Dos)))),
+        "po" => Ok(Box::new(formats::apple::AppleHandler::new(data, Some(formats::apple::SectorOrder::ProDos)))),
+        "dsk" if formats::dsk::is_dsk(&data) => Ok(Box::new(formats::dsk::DSKHandler::new(data))),
+        "dsk" => Ok(Box::new(formats::apple::AppleHandler::new(data, None))),
+        "nib" => Ok(Box::new(formats::nib::NIBHandler::new(data))),
+        "woz" => Ok(Box::new(formats::woz::WOZHandler::new(data))),
         "scp" => Ok(Box::new(formats::scp::SCPHandler::new(data))),
+        "st" => Ok(Box::new(formats::st::STHandler::new(data))),
+        "msa" => Ok(Box::new(formats::msa::MSAHandler::new(data))),
+        "hfe" => Ok(Box::new(formats::hfe::HFEHandler::new(data))),
+        "td0" => Ok(Box::new(formats::td0::TD0Handler::new(data))),
+        "raw" => Ok(Box::new(formats::kryoflux::KryoFluxHandler::open(file_path)?)),
         _ => Err(anyhow!(
-            "Unsupported format '{}'. Supported formats are .img, .imd, and .scp. Use --input with a valid file (e.g., 'disk.img', 'disk.imd', 'disk.scp').",
+            "Unsupported format '{}'. Supported formats are .img, .imd, .adf, .d64, .g64, .do, .po, .dsk, .nib, .woz, .scp, .st, .msa, .hfe, .td0 and KryoFlux .raw stream files. Use --input with a valid file (e.g., 'disk.img', 'disk.imd', 'disk.adf', 'disk.d64', 'disk.scp').",
             ext
         )),
     }
 }
 
+fn target_handler(format: &str) -> Result<Box<dyn FormatHandler>> {
+    match format {
+        "img" => Ok(Box::new(formats::img::IMGHandler::new(Vec::new()))),
+        "imd" => Ok(Box::new(formats::imd::IMDHandler::new(Vec::new()))),
+        "adf" => Ok(Box::new(formats::adf::ADFHandler::new(Vec::new()))),
+        "d64" => Ok(Box::new(formats::d64::D64Handler::new(Vec::new()))),
+        "g64" => Ok(Box::new(formats::g64::G64Handler::new(Vec::new()))),
+        "do" => Ok(Box::new(formats::apple::AppleHandler::new(Vec::new(), Some(formats::apple::SectorOrder::Dos)))),
+        "po" => Ok(Box::new(formats::apple::AppleHandler::new(Vec::new(), Some(formats::apple::SectorOrder::ProDos)))),
+        "nib" => Ok(Box::new(formats::nib::NIBHandler::new(Vec::new()))),
+        "woz" => Ok(Box::new(formats::woz::WOZHandler::new(Vec::new()))),
+        "dsk" => Ok(Box::new(formats::dsk::DSKHandler::new(Vec::new()))),
+        "st" => Ok(Box::new(formats::st::STHandler::new(Vec::new()))),
+        "msa" => Ok(Box::new(formats::msa::MSAHandler::new(Vec::new()))),
+        "hfe" => Ok(Box::new(formats::hfe::HFEHandler::new(Vec::new()))),
+        "scp" => Ok(Box::new(formats::scp::SCPHandler::new(Vec::new()))),
+        "td0" => Ok(Box::new(formats::td0::TD0Handler::new(Vec::new()))),
+        _ => Err(anyhow!(
+            "Unknown target format '{}'. Use --format with 'img', 'imd', 'adf', 'd64', 'g64', 'do', 'po', 'nib', 'woz', 'dsk', 'st', 'msa', 'hfe', 'scp' or 'td0' (e.g., 'floppytool --input file.imd convert --format img --output out.img').",
+            format
+        )),
+    }
+}
+
 #[derive(Parser)]
 #[command(
     about = "A utility for displaying and converting floppy disk image formats",
@@ -41,14 +92,43 @@ fn load_handler(file_path: &PathBuf) -> Result<Box<dyn FormatHandler>> {
     after_help = "Additional options are available under subcommands. For display options, see `floppytool display --help` (e.g., --ascii). For conversion options, see `floppytool convert --help` (e.g., --format, --output, --geometry, --verbose, --validate, --imdmeta)."
 )]
 struct Cli {
-    /// Input floppy disk image file (e.g., file.img, file.imd)
+    /// Input floppy disk image file (e.g., file.img, file.imd, file.adf, file.d64, file.woz), or a directory of KryoFlux stream files
     #[arg(short, long)]
     input: PathBuf,
 
+    /// Geometry of a raw .img input as 'cylinders,heads,sectors,size,mode' (e.g., '80,2,15,512,4') or 'auto' for inference
+    #[arg(long, global = true, value_parser = parse_geometry, default_value = "auto")]
+    geometry: Geometry,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Filesystem options shared by the file-level commands
+#[derive(clap::Args)]
+struct FsArgs {
+    /// CP/M disk definition to use, built-in or from --diskdefs (e.g., 'kpii', 'osborne1', 'ibm-3740')
+    #[arg(long)]
+    diskdef: Option<String>,
+
+    /// cpmtools-style diskdefs file with additional CP/M definitions
+    #[arg(long)]
+    diskdefs: Option<PathBuf>,
+}
+
+impl FsArgs {
+    fn diskdef(&self) -> Result<Option<fs::cpm::DiskDef>> {
+        let extra = match &self.diskdefs {
+            Some(path) => fs::cpm::DiskDef::parse_all(&std::fs::read_to_string(path)?)?,
+            None => Vec::new(),
+        };
+        match &self.diskdef {
+            Some(name) => Ok(Some(fs::cpm::DiskDef::find(name, &extra)?)),
+            None => Ok(None),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Display details of the input floppy image
@@ -56,10 +136,14 @@ enum Commands {
         /// Show sector data as ASCII characters (instead of geometry summary)
         #[arg(long, default_value_t = false)]
         ascii: bool,
+
+        /// File of extra boot code hashes, one 'loader CRC32 name' or 'virus CRC32 name' per line
+        #[arg(long)]
+        fingerprints: Option<PathBuf>,
     },
     /// Convert the input floppy image to another format
     Convert {
-        /// Target format for conversion (e.g., 'img', 'imd')
+        /// Target format for conversion (e.g., 'img', 'imd', 'adf', 'd64', 'g64', 'do', 'po', 'nib', 'woz', 'dsk', 'st', 'msa', 'hfe', 'scp', 'td0')
         #[arg(long)]
         format: String,
 
@@ -67,10 +151,6 @@ enum Commands {
         #[arg(long)]
         output: PathBuf,
 
-        /// Specify geometry as 'cylinders,heads,sectors,size,mode' (e.g., '80,2,15,512,4') or 'auto' for inference
-        #[arg(long, value_parser = parse_geometry, default_value = "auto")]
-        geometry: Geometry,
-
         /// Print detailed conversion progress
         #[arg(long, default_value_t = false)]
         verbose: bool,
@@ -82,7 +162,198 @@ enum Commands {
         /// Optional path to an .imd.meta file from a previous conversion (overrides default)
         #[arg(long)]
         imdmeta: Option<PathBuf>,
+
+        /// Sector layout for .imd to .img: 'id' (sorted by ID), 'physical' (recorded order, repeated IDs kept) or 'base=N' (ID N is the first sector, e.g. 'base=0x41')
+        #[arg(long, value_parser = parse_placement, default_value = "id")]
+        sector_order: disk::Placement,
+    },
+    /// Rewrite the physical sector order of every track and save the result
+    Reinterleave {
+        /// Interleave factor (1 = sequential, 2 = 2:1, etc.)
+        #[arg(long)]
+        interleave: u8,
+
+        /// Skew in sectors from one cylinder to the next (both heads of a cylinder start together)
+        #[arg(long, default_value_t = 0)]
+        skew: u8,
+
+        /// Output file path; the format is taken from its extension and must keep sector order:
+        /// .imd, .td0, .dsk, .hfe or .scp (e.g., 'fast.imd')
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Read sectors and print a hex dump (or write the raw bytes)
+    Read {
+        /// Start address as 'cylinder/head/sector' using the sector ID (e.g., '0/0/1')
+        #[arg(long, value_parser = parse_chs, required_unless_present = "lba", conflicts_with = "lba")]
+        chs: Option<(u8, u8, u8)>,
+
+        /// Start at this 0-based logical sector number instead of a CHS address
+        #[arg(long)]
+        lba: Option<usize>,
+
+        /// Address sectors by 0-based physical position on the track rather than by sector ID
+        #[arg(long, default_value_t = false)]
+        physical: bool,
+
+        /// Number of consecutive sectors to read
+        #[arg(long, default_value_t = 1)]
+        count: usize,
+
+        /// Output raw sector bytes instead of a hex dump
+        #[arg(long, default_value_t = false)]
+        raw: bool,
+
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Overwrite sectors in place with data from a file or hex string
+    Write {
+        /// Start address as 'cylinder/head/sector' using the sector ID (e.g., '0/0/1')
+        #[arg(long, value_parser = parse_chs, required_unless_present = "lba", conflicts_with = "lba")]
+        chs: Option<(u8, u8, u8)>,
+
+        /// Start at this 0-based logical sector number instead of a CHS address
+        #[arg(long)]
+        lba: Option<usize>,
+
+        /// Address sectors by 0-based physical position on the track rather than by sector ID
+        #[arg(long, default_value_t = false)]
+        physical: bool,
+
+        /// File whose contents are written from the start sector onwards
+        #[arg(long, required_unless_present = "hex", conflicts_with = "hex")]
+        from: Option<PathBuf>,
+
+        /// Hex bytes to write from the start of the sector (e.g., 'eb 3c 90')
+        #[arg(long)]
+        hex: Option<String>,
+
+        /// Save the patched image to this path instead of overwriting the input
+        #[arg(long)]
+        output: Option<PathBuf>,
     },
+    /// Search sector data for a byte pattern or string across the whole disk
+    #[command(group(clap::ArgGroup::new("pattern").required(true).args(["hex", "text", "regex", "ebcdic"])))]
+    Search {
+        /// Hex byte pattern (e.g., 'eb 3c 90')
+        #[arg(long)]
+        hex: Option<String>,
+
+        /// ASCII text to find
+        #[arg(long)]
+        text: Option<String>,
+
+        /// Regular expression matched against raw bytes (e.g., 'SN[0-9]{6}')
+        #[arg(long)]
+        regex: Option<String>,
+
+        /// Text to find encoded as EBCDIC (code page 037)
+        #[arg(long)]
+        ebcdic: Option<String>,
+
+        /// Match letters case-insensitively (text, regex and EBCDIC patterns)
+        #[arg(long, default_value_t = false)]
+        ignore_case: bool,
+
+        #[command(flatten)]
+        fs: FsArgs,
+    },
+    /// List the files in the disk's filesystem
+    Ls {
+        #[command(flatten)]
+        fs: FsArgs,
+    },
+    /// Extract files from the disk's filesystem
+    Extract {
+        /// File to extract (repeatable); all files when omitted. CP/M user areas use 'N:NAME.EXT'
+        #[arg(long)]
+        file: Vec<String>,
+
+        /// Directory to extract into
+        #[arg(long, default_value = ".")]
+        output: PathBuf,
+
+        #[command(flatten)]
+        fs: FsArgs,
+    },
+    /// Copy a host file into the disk's filesystem
+    Inject {
+        /// Host file to copy onto the disk
+        #[arg(long)]
+        from: PathBuf,
+
+        /// Name on the disk (defaults to the host file name). CP/M user areas use 'N:NAME.EXT'
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Save the modified image to this path instead of overwriting the input
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        fs: FsArgs,
+    },
+    /// Show flux interval histograms, peaks, cell width and RPM for a flux image (.scp or KryoFlux)
+    FluxStats {
+        /// Only this track (numbered cylinder * 2 + head, as in SCP), with its histogram
+        #[arg(long)]
+        track: Option<u8>,
+
+        /// Histogram bin width in nanoseconds
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..=10_000))]
+        bin: u32,
+
+        /// Write the histogram of every shown track to this CSV file
+        #[arg(long)]
+        csv: Option<PathBuf>,
+    },
+    /// Look for copy-protection markers (odd sector IDs and sizes, bad, deleted and weak sectors, long tracks, weak bits) and known schemes
+    AnalyzeProtection,
+    /// Browse the track/sector map and sector contents in a full-screen viewer
+    Browse {
+        /// Allow editing sectors and saving changes back to the input file
+        #[arg(long, default_value_t = false)]
+        edit: bool,
+    },
+}
+
+fn parse_chs(s: &str) -> Result<(u8, u8, u8), String> {
+    let parts: Vec<&str> = s.split('/').collect();
+    if parts.len() != 3 {
+        return Err("Address must be 'cylinder/head/sector' (e.g., '0/0/1')".to_string());
+    }
+    Ok((
+        parts[0].parse().map_err(|e| format!("Invalid cylinder: {}", e))?,
+        parts[1].parse().map_err(|e| format!("Invalid head: {}", e))?,
+        parts[2].parse().map_err(|e| format!("Invalid sector: {}", e))?,
+    ))
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let digits: String = s.chars().filter(|c| !c.is_whitespace() && *c != ':' && *c != ',').collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(anyhow!("Hex data '{}' must contain an even number of hex digits (e.g., 'eb 3c 90')", s));
+    }
+    (0..digits.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| anyhow!("Invalid hex byte '{}' in '{}'", &digits[i..i + 2], s)))
+        .collect()
+}
+
+fn output_format(path: &Path) -> String {
+    path.extension().and_then(|s| s.to_str()).map(|s| s.to_lowercase()).unwrap_or_default()
+}
+
+/// Resolves a --chs or --lba start address to an index into `disk.sector_positions(order)`.
+fn start_sector(disk: &disk::Disk, chs: Option<(u8, u8, u8)>, lba: Option<usize>, order: disk::Order) -> Result<usize> {
+    let total = disk.sector_positions(order).len();
+    match (chs, lba) {
+        (Some((c, h, s)), _) => disk.find_sector(c, h, s, order),
+        (None, Some(lba)) if lba < total => Ok(lba),
+        (None, Some(lba)) => Err(anyhow!("Logical sector {} is beyond the end of the disk ({} sectors)", lba, total)),
+        (None, None) => Err(anyhow!("Specify a start address with --chs or --lba")),
+    }
 }
 
 fn parse_geometry(s: &str) -> Result<Geometry, String> {
@@ -103,6 +374,22 @@ fn parse_geometry(s: &str) -> Result<Geometry, String> {
     }
 }
 
+fn parse_placement(s: &str) -> Result<disk::Placement, String> {
+    match s {
+        "id" => Ok(disk::Placement::ById),
+        "physical" => Ok(disk::Placement::Physical),
+        _ => {
+            let base = s.strip_prefix("base=")
+                .ok_or_else(|| "Sector order must be 'id', 'physical' or 'base=N' (e.g., 'base=0x41')".to_string())?;
+            let parsed = match base.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => base.parse(),
+            };
+            parsed.map(disk::Placement::Base).map_err(|e| format!("Invalid base sector ID '{}': {}", base, e))
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 enum Geometry {
     Auto,
@@ -127,37 +414,75 @@ impl Geometry {
     }
 }
 
+mod boot;
+mod browse;
+mod disk;
+mod flux;
 mod formats;
+mod fs;
+mod protection;
+mod search;
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let handler = load_handler(&cli.input)?;
+    let handler = load_handler(&cli.input, &cli.geometry)?;
 
     match cli.command {
-        Commands::Display { ascii } => println!("{}", handler.display(ascii)?),
-        Commands::Convert { format, output, geometry, verbose, validate, imdmeta } => {
-            let target: Box<dyn FormatHandler> = match format.as_str() {
-                "img" => Box::new(formats::img::IMGHandler::new(Vec::new())),
-                "imd" => Box::new(formats::imd::IMDHandler::new(Vec::new())),
-                _ => return Err(anyhow!(
-                    "Unknown target format '{}'. Use --format with 'img' or 'imd' (e.g., 'floppytool --input file.imd convert --format img --output out.img').",
-                    format
-                )),
+        Commands::Display { ascii, fingerprints } => {
+            let extra = match &fingerprints {
+                Some(path) => boot::parse_fingerprints(&std::fs::read_to_string(path)?)?,
+                None => Vec::new(),
             };
-            let effective_geometry = match geometry.clone() {
+            println!("{}", handler.display(ascii)?);
+            if !ascii {
+                if let Ok(disk) = handler.disk() {
+                    for line in boot::report(&disk, &extra).into_iter().chain(fs::report(&disk)) {
+                        println!("{}", line);
+                    }
+                }
+            }
+        }
+        Commands::Convert { format, output, verbose, validate, imdmeta, sector_order } => {
+            let target = target_handler(&format)?;
+            let effective_geometry = match cli.geometry.clone() {
                 Geometry::Auto => handler.geometry()?.unwrap_or(Geometry::Manual {
                     cylinders: 40, heads: 2, sectors_per_track: 9, sector_size: 512, mode: 5
                 }),
                 g => g,
             };
-            handler.convert(&*target, &output, &cli.input, imdmeta.as_ref(), Some(effective_geometry.clone()), verbose, validate)?;
+            if matches!(format.as_str(), "img" | "imd") {
+                handler.convert(&*target, &output, &cli.input, imdmeta.as_ref(), Some(effective_geometry.clone()), verbose, validate, sector_order)?;
+            } else if let (true, Some(capture)) = (format == "scp", handler.flux()?) {
+                // Flux sources are copied as captured rather than decoded; sector images are synthesized
+                std::fs::write(&output, formats::scp::encode_capture(&capture)?)?;
+            } else {
+                // Other targets are written from the shared sector model
+                std::fs::write(&output, target.encode(&handler.disk()?)?)?;
+            }
             if format == "img" {
+                // A raw image keeps only sector data, so protected disks come out broken
+                if let Ok(disk) = handler.disk() {
+                    let markers = protection::sector_markers(&disk, handler.flux()?.as_ref());
+                    if !markers.is_empty() {
+                        println!("Warning: {} copy-protection markers (odd sector IDs or sizes, deleted, bad or weak sectors) cannot be kept in .img; see analyze-protection", markers.len());
+                    }
+                }
                 if let Some(Geometry::Manual { cylinders, heads, sectors_per_track, sector_size, mode }) = handler.geometry()? {
-                    println!("Geometry for reverse conversion: {},{},{},{},{}", cylinders, heads, sectors_per_track, sector_size, mode);
+                    // Extra or larger sectors kept by the layout make the image bigger than the geometry says
+                    let expected = cylinders as u64 * heads as u64 * sectors_per_track as u64 * sector_size as u64;
+                    let written = std::fs::metadata(&output)?.len();
+                    if written == expected {
+                        println!("Geometry for reverse conversion: {},{},{},{},{}", cylinders, heads, sectors_per_track, sector_size, mode);
+                    } else {
+                        println!(
+                            "Warning: {} is {} bytes, not the {} bytes of {} cylinders x {} heads x {} sectors x {} bytes; no --geometry describes it, so convert the original .imd rather than this image",
+                            output.display(), written, expected, cylinders, heads, sectors_per_track, sector_size
+                        );
+                    }
                 }
             }
             if validate {
-                let output_handler = load_handler(&output)?;
+                let output_handler = load_handler(&output, &effective_geometry)?;
                 let output_data = output_handler.data();
                 let input_data = handler.data();
                 if format == "img" {
@@ -182,15 +507,307 @@ fn main() -> Result<()> {
                         println!("Warning: Output size {} differs from input size {} due to compression in source .imd", output_data.len(), input_data.len());
                     }
                     println!("Validation passed: Output size matches expected geometry");
-                } else {
+                } else if format == "imd" {
                     if output_data.len() != input_data.len() {
                         println!("Warning: Output size {} differs from input size {} due to compression in output .imd", output_data.len(), input_data.len());
                     }
                     println!("Validation passed: IMD file written successfully");
+                } else {
+                    println!("Note: --validate only checks .img and .imd output; the .{} file was not read back", format);
                 }
             }
             println!("Converted to {}", output.display());
         }
+        Commands::Reinterleave { interleave, skew, output } => {
+            let format = output_format(&output);
+            if !matches!(format.as_str(), "imd" | "td0" | "dsk" | "hfe" | "scp") {
+                return Err(anyhow!(
+                    "Cannot reinterleave to '.{}': only .imd, .td0, .dsk, .hfe and .scp record the physical sector order.", format
+                ));
+            }
+            let target = target_handler(&format)?;
+            let mut disk = handler.disk()?;
+            disk.reinterleave(interleave, skew)?;
+            std::fs::write(&output, target.encode(&disk)?)?;
+            println!("Reinterleaved {} tracks to {}:1 with skew {}", disk.tracks.len(), interleave, skew);
+            println!("Converted to {}", output.display());
+        }
+        Commands::Read { chs, lba, physical, count, raw, output } => {
+            let disk = handler.disk()?;
+            let order = if physical { disk::Order::Physical } else { disk::Order::Logical };
+            let sectors = disk.sectors(order);
+            let start = start_sector(&disk, chs, lba, order)?;
+            if start + count > sectors.len() {
+                return Err(anyhow!("Reading {} sectors from sector {} runs past the end of the disk ({} sectors)", count, start, sectors.len()));
+            }
+
+            let mut bytes = Vec::new();
+            let mut dump = Vec::new();
+            for (index, (track, sector)) in sectors[start..start + count].iter().enumerate() {
+                let position = track.sectors.iter().position(|s| std::ptr::eq(s, *sector)).unwrap_or(0);
+                let mut flags = String::new();
+                if sector.deleted { flags.push_str(", deleted"); }
+                if sector.crc_error { flags.push_str(", CRC error"); }
+                dump.push(format!(
+                    "LBA {}: Cyl {}, Head {}, Sector {} (physical {}), Size {} bytes{}",
+                    start + index, track.cylinder, track.head, sector.id, position, sector.size, flags
+                ));
+                match &sector.data {
+                    Some(data) => {
+                        dump.push(disk::hex_dump(data));
+                        bytes.extend_from_slice(data);
+                    }
+                    None => {
+                        dump.push("(no data recorded)".to_string());
+                        if raw {
+                            eprintln!("Warning: Cyl {}, Head {}, Sector {} has no data; writing zeros", track.cylinder, track.head, sector.id);
+                        }
+                        bytes.resize(bytes.len() + sector.size, 0);
+                    }
+                }
+            }
+            let contents = if raw { bytes } else { (dump.join("\n") + "\n").into_bytes() };
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, contents)?;
+                    println!("Wrote {} sectors to {}", count, path.display());
+                }
+                None => std::io::stdout().write_all(&contents)?,
+            }
+        }
+        Commands::Write { chs, lba, physical, from, hex, output } => {
+            let mut disk = handler.disk()?;
+            let order = if physical { disk::Order::Physical } else { disk::Order::Logical };
+            let bytes = match (from, hex) {
+                (Some(path), _) => std::fs::read(&path)?,
+                (None, Some(hex)) => parse_hex(&hex)?,
+                (None, None) => return Err(anyhow!("Specify the data to write with --from or --hex")),
+            };
+            if bytes.is_empty() {
+                return Err(anyhow!("Nothing to write: the input data is empty"));
+            }
+            let positions = disk.sector_positions(order);
+            let start = start_sector(&disk, chs, lba, order)?;
+            let capacity: usize = positions[start..].iter().map(|&(t, s)| disk.tracks[t].sectors[s].size).sum();
+            if bytes.len() > capacity {
+                return Err(anyhow!("Data runs {} bytes past the end of the disk; nothing was written", bytes.len() - capacity));
+            }
+
+            let mut remaining = &bytes[..];
+            for &(t, s) in &positions[start..] {
+                if remaining.is_empty() {
+                    break;
+                }
+                let track = &mut disk.tracks[t];
+                let (cylinder, head) = (track.cylinder, track.head);
+                let sector = &mut track.sectors[s];
+                let n = remaining.len().min(sector.size);
+                let recorded = sector.data.as_ref().map(Vec::len);
+                sector.patch(&remaining[..n]).map_err(|e| anyhow!("Cyl {}, Head {}: {}; nothing was written", cylinder, head, e))?;
+                remaining = &remaining[n..];
+                let short = match recorded {
+                    Some(len) if len < sector.size => format!(" (only {} of {} bytes were recorded; zero-padded)", len, sector.size),
+                    _ => String::new(),
+                };
+                println!(
+                    "Wrote {} bytes to Cyl {}, Head {}, Sector {}{}{}",
+                    n, cylinder, head, sector.id, if sector.compressed { " (uniform, stored compressed)" } else { "" }, short
+                );
+            }
+
+            let output = output.unwrap_or_else(|| cli.input.clone());
+            let fresh;
+            let target = if output_format(&output) == output_format(&cli.input) {
+                &*handler // Saved by the input's own handler, so the rest of the image is kept
+            } else {
+                fresh = target_handler(&output_format(&output))?;
+                &*fresh
+            };
+            std::fs::write(&output, target.encode(&disk)?)?;
+            println!("Saved {}", output.display());
+        }
+        Commands::Search { hex, text, regex, ebcdic, ignore_case, fs } => {
+            let pattern = match (hex, text, regex, ebcdic) {
+                (Some(hex), ..) => search::Pattern::Hex(parse_hex(&hex)?),
+                (_, Some(text), ..) => search::Pattern::Text(text),
+                (_, _, Some(regex), _) => search::Pattern::Regex(regex),
+                (.., Some(ebcdic)) => search::Pattern::Ebcdic(ebcdic),
+                _ => return Err(anyhow!("Specify a pattern with --hex, --text, --regex or --ebcdic")),
+            };
+            let regex = search::compile(&pattern, ignore_case)?;
+            let disk = handler.disk()?;
+            let sectors = disk.sectors(disk::Order::Logical);
+
+            // Map logical sectors to the files that own them when a filesystem is recognised
+            let mut owners = std::collections::HashMap::new();
+            let filesystem = match fs.diskdef()? {
+                Some(def) => Some(fs::open(&disk, Some(def))?),
+                None => fs::detect(&disk),
+            };
+            if let Some(fs) = filesystem {
+                println!("Filesystem: {}", fs.name());
+                // Damaged disks are what searches are most often for, so a bad directory only loses the file names
+                match fs.files() {
+                    Ok(files) => for file in files {
+                        for &lba in &file.sectors {
+                            owners.insert(lba, file.name.clone());
+                        }
+                    },
+                    Err(e) => println!("Warning: cannot map sectors to files ({}); matches are shown without file names", e),
+                }
+            }
+
+            let hits = search::search(&disk, &pattern, &regex);
+            for hit in &hits {
+                let (track, sector) = sectors[hit.lba];
+                let shown: String = hit.bytes.iter().take(32).map(|&b| if (32..=126).contains(&b) { b as char } else { '.' }).collect();
+                println!(
+                    "Cyl {}, Head {}, Sector {}, offset {} (LBA {}): \"{}\"{}{}",
+                    track.cylinder, track.head, sector.id, hit.offset, hit.lba, shown,
+                    if hit.spans > 1 { format!(" (spans {} sectors)", hit.spans) } else { String::new() },
+                    owners.get(&hit.lba).map(|name| format!(" [{}]", name)).unwrap_or_default()
+                );
+            }
+            println!("{} match{} found", hits.len(), if hits.len() == 1 { "" } else { "es" });
+        }
+        Commands::Ls { fs } => {
+            let disk = handler.disk()?;
+            let filesystem = fs::open(&disk, fs.diskdef()?)?;
+            let files = filesystem.files()?;
+            println!("Filesystem: {}", filesystem.name());
+            for file in &files {
+                println!("  {:<24} {:>8}  {}", file.name, file.size, file.detail);
+            }
+            println!("{} files, {} bytes", files.len(), files.iter().map(|f| f.size).sum::<usize>());
+        }
+        Commands::Extract { file, output, fs } => {
+            let disk = handler.disk()?;
+            let filesystem = fs::open(&disk, fs.diskdef()?)?;
+            let files = if file.is_empty() {
+                filesystem.files()?
+            } else {
+                file.iter().map(|name| filesystem.find(name)).collect::<Result<Vec<_>>>()?
+            };
+            for entry in &files {
+                let path = output.join(entry.host_path());
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&path, filesystem.read_file(entry)?)?;
+                println!("Extracted {} ({} bytes) to {}", entry.name, entry.size, path.display());
+            }
+        }
+        Commands::Inject { from, name, output, fs } => {
+            let mut disk = handler.disk()?;
+            let mut filesystem = fs::open(&disk, fs.diskdef()?)?;
+            let name = match name {
+                Some(name) => name,
+                None => from.file_name().and_then(|n| n.to_str()).map(|n| n.to_string())
+                    .ok_or_else(|| anyhow!("Cannot derive a disk file name from '{}'; use --name", from.display()))?,
+            };
+            let data = std::fs::read(&from)?;
+            filesystem.write_file(&name, &data)?;
+            fs::store_logical_sectors(&mut disk, filesystem.sector_data())?;
+
+            let output = output.unwrap_or_else(|| cli.input.clone());
+            let fresh;
+            let target = if output_format(&output) == output_format(&cli.input) {
+                &*handler
+            } else {
+                fresh = target_handler(&output_format(&output))?;
+                &*fresh
+            };
+            std::fs::write(&output, target.encode(&disk)?)?;
+            println!("Injected {} ({} bytes) into {}", name, data.len(), output.display());
+        }
+        Commands::FluxStats { track, bin, csv } => {
+            let capture = handler.flux()?.ok_or_else(|| anyhow!(
+                "flux-stats needs a flux image (.scp or KryoFlux stream files); this image holds decoded sectors."
+            ))?;
+            let selected: Vec<_> = capture.tracks.iter().filter(|(&number, _)| track.is_none_or(|t| t == number)).collect();
+            if selected.is_empty() {
+                return Err(anyhow!(
+                    "Track {} is not in the capture, which holds tracks {}",
+                    track.unwrap_or(0),
+                    capture.tracks.keys().map(|n| n.to_string()).collect::<Vec<_>>().join(", ")
+                ));
+            }
+            // Cylinder and head on the disk, as for sector decoding; None for drive tracks it doesn't use
+            let platform = flux::capture::Platform::of(&capture);
+            let double_stepped = platform.is_some_and(|p| p.double_stepped(&capture));
+            if double_stepped {
+                println!("48 TPI disk on a 96 TPI drive: odd drive cylinders skipped");
+            }
+            let location = |number: u8| match platform {
+                Some(platform) => platform.location(number, double_stepped),
+                None => Some((number / 2, number % 2)),
+            };
+            let mut rows = vec!["track,cylinder,head,bin_start_ns,bin_end_ns,count".to_string()];
+            for &(&number, revolutions) in &selected {
+                let stats = flux::stats::FluxStats::new(revolutions, bin);
+                let place = match location(number) {
+                    Some((cylinder, head)) => format!("Cyl {}, Head {}", cylinder, head),
+                    None => "unused by the disk".to_string(),
+                };
+                println!("Track {} ({}): {} revolutions, {} intervals", number, place, revolutions.len(), stats.intervals);
+                for line in stats.lines() {
+                    println!("  {}", line);
+                }
+                if track.is_some() {
+                    println!("  Histogram ({} ns bins):", bin);
+                    for line in stats.histogram_lines(bin) {
+                        println!("  {}", line);
+                    }
+                }
+                for (i, &count) in stats.histogram.iter().enumerate().filter(|&(_, &count)| count > 0) {
+                    let (cylinder, head) = location(number).map_or((String::new(), String::new()), |(c, h)| (c.to_string(), h.to_string()));
+                    rows.push(format!("{},{},{},{},{},{}", number, cylinder, head, i as u32 * bin, (i as u32 + 1) * bin, count));
+                }
+            }
+            if selected.len() > 1 {
+                let stats = flux::stats::FluxStats::new(selected.iter().flat_map(|&(_, revolutions)| revolutions), bin);
+                println!("All Tracks: {} intervals", stats.intervals);
+                for line in stats.lines() {
+                    println!("  {}", line);
+                }
+                println!("  Histogram ({} ns bins):", bin);
+                for line in stats.histogram_lines(bin) {
+                    println!("  {}", line);
+                }
+            }
+            if let Some(path) = csv {
+                std::fs::write(&path, rows.join("\n") + "\n")?;
+                println!("Histogram written to {}", path.display());
+            }
+        }
+        Commands::AnalyzeProtection => {
+            let capture = handler.flux()?;
+            let disk = match handler.disk() {
+                Ok(disk) => Some(disk),
+                Err(_) if capture.is_some() => None, // Flux in an encoding we cannot decode
+                Err(e) => return Err(e),
+            };
+            for line in protection::analyze(disk.as_ref(), capture.as_ref()).lines() {
+                println!("{}", line);
+            }
+        }
+        Commands::Browse { edit } => {
+            let disk = handler.disk()?;
+            if edit {
+                // Refuse up front rather than after the user has made edits that cannot be saved
+                if handler.flux()?.is_some() {
+                    return Err(anyhow!(
+                        "Cannot edit '{}': it holds captured flux, which saving the edited sectors would replace. Convert it to .imd or .hfe and edit that instead.",
+                        cli.input.display()
+                    ));
+                }
+                handler.encode(&disk).map_err(|e| anyhow!(
+                    "Cannot edit '{}': {} Browse without --edit, or convert it to .imd first.", cli.input.display(), e
+                ))?;
+            }
+            let save = edit.then(|| (cli.input.clone(), &*handler));
+            browse::Browser::new(disk, save).run()?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,357 @@
+use crate::boot::contains;
+use crate::disk::{most_common, Disk, Order, Track};
+use crate::flux::{self, capture::{Capture, Platform, RevolutionRead, SectorRead}};
+use crate::fs::fat::Bpb;
+
+/// Text that protection schemes leave in their loaders, searched for in every sector.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"SPEEDLOCK", "Speedlock"),
+    (b"PROLOK", "Prolok"),
+    (b"Rob Northen Comp", "Copylock"),
+    (b"COPYLOCK", "Copylock"),
+];
+
+/// Sync words that replace 0x4489 on the eleven sectors of an Amiga Copylock track.
+const COPYLOCK_SYNCS: [u16; 11] = [0x8A91, 0x8A44, 0x8A45, 0x8A51, 0x8912, 0x8911, 0x8914, 0x8915, 0x8944, 0x8945, 0x8951];
+
+/// Cells per revolution of standard IBM and Amiga tracks, for FM and MFM at each standard
+/// data rate and drive speed.
+const STANDARD_CELLS: [f64; 6] = [50_000.0, 83_333.0, 100_000.0, 166_667.0, 200_000.0, 400_000.0];
+
+const LENGTH_TOLERANCE: f64 = 0.03; // Drives run within about 1.5% of their nominal speed
+const RATE_SEGMENTS: usize = 16;    // Parts of a revolution compared for bit rate changes
+const RATE_TOLERANCE: f64 = 0.06;
+const NO_FLUX_CELLS: f64 = 16.0;    // Four times the longest MFM interval
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    DuplicateId,
+    IdRange,
+    IdMismatch,
+    Size,
+    SectorCount,
+    Deleted,
+    CrcError,
+    NoData,
+    WeakSector,
+    LongTrack,
+    ShortTrack,
+    RateChange,
+    WeakBits,
+    Gap,
+    NoFlux,
+}
+
+/// Something on a track that a plain sector copy would not reproduce.
+pub struct Marker {
+    pub cylinder: u8,
+    pub head: u8,
+    pub kind: Kind,
+    pub detail: String,
+}
+
+/// A protection scheme recognised from loader text or from its markers.
+pub struct Scheme {
+    pub name: &'static str,
+    pub likely: bool, // Loader text or a unique layout, rather than markers it shares with others
+    pub evidence: Vec<String>,
+}
+
+pub struct Analysis {
+    pub markers: Vec<Marker>,
+    pub schemes: Vec<Scheme>,
+    pub notes: Vec<String>,
+}
+
+impl Analysis {
+    fn scheme(&mut self, name: &'static str, likely: bool, evidence: String) {
+        match self.schemes.iter_mut().find(|s| s.name == name) {
+            Some(scheme) => {
+                scheme.likely |= likely;
+                scheme.evidence.push(evidence);
+            }
+            None => self.schemes.push(Scheme { name, likely, evidence: vec![evidence] }),
+        }
+    }
+
+    /// Report lines for `analyze-protection`.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = self.notes.clone();
+        if self.markers.is_empty() {
+            lines.push("No copy-protection markers found.".to_string());
+        } else {
+            lines.push(format!("Copy-Protection Markers: {}", self.markers.len()));
+            for marker in &self.markers {
+                lines.push(format!("  Cyl {}, Head {}: {}", marker.cylinder, marker.head, marker.detail));
+            }
+        }
+        if self.schemes.is_empty() {
+            lines.push("Known Schemes: none identified".to_string());
+        } else {
+            lines.push("Known Schemes:".to_string());
+            for scheme in &self.schemes {
+                lines.push(format!("  {} ({}): {}", scheme.name, if scheme.likely { "likely" } else { "possible" }, scheme.evidence.join("; ")));
+            }
+        }
+        lines
+    }
+}
+
+/// Looks for copy-protection markers in the sectors of a disk and, for flux images, in the
+/// flux itself, and names the schemes they point to.
+pub fn analyze(disk: Option<&Disk>, capture: Option<&Capture>) -> Analysis {
+    let mut analysis = Analysis { markers: Vec::new(), schemes: Vec::new(), notes: Vec::new() };
+    if let Some(disk) = disk {
+        analysis.markers = sector_markers(disk, capture);
+    }
+    let platform = capture.and_then(Platform::of);
+    match (capture, platform) {
+        (Some(capture), Some(platform)) => flux_markers(capture, platform, &mut analysis),
+        (Some(_), None) => analysis.notes.push("Flux checks skipped: no known encoding on the first tracks".to_string()),
+        _ => {}
+    }
+    if let Some(disk) = disk {
+        identify(disk, platform, &mut analysis);
+    }
+    analysis.markers.sort_by_key(|m| (m.cylinder, m.head));
+    analysis
+}
+
+/// Sector-level markers: repeated or unusual sector IDs, ID fields naming another track, odd
+/// sizes and counts, deleted data, CRC errors, missing data and weak sectors. For a disk
+/// decoded from flux, only tracks present in the capture are checked.
+pub fn sector_markers(disk: &Disk, capture: Option<&Capture>) -> Vec<Marker> {
+    let platform = capture.and_then(Platform::of);
+    let locations: Option<Vec<(u8, u8)>> = capture.zip(platform).map(|(capture, platform)| {
+        let double_stepped = platform.double_stepped(capture);
+        capture.tracks.keys().filter_map(|&n| platform.location(n, double_stepped)).collect()
+    });
+    // Other platforms decoded from flux always have their standard layout
+    let layout = platform.is_none_or(|p| matches!(p, Platform::Ibm));
+    let captured = |track: &Track| locations.as_ref().is_none_or(|l| l.contains(&(track.cylinder, track.head)));
+    let tracks: Vec<&Track> = disk.tracks.iter().filter(|t| !t.sectors.is_empty() && captured(t)).collect();
+    // What most tracks look like; CBM zones make inner tracks shorter, so only extra sectors count
+    let usual_count = most_common(tracks.iter().map(|t| t.sectors.len())).unwrap_or(0);
+    let usual_size = most_common(tracks.iter().flat_map(|t| t.sectors.iter().map(|s| s.size))).unwrap_or(0);
+    let first_id = most_common(tracks.iter().filter_map(|t| t.sectors.iter().map(|s| s.id as usize).min())).unwrap_or(1);
+    // Some formats put head 0 in the ID fields of both sides
+    let side1: Vec<&&Track> = tracks.iter().filter(|t| t.head == 1).collect();
+    let head_ids_differ = side1.iter().filter(|t| t.sectors.iter().any(|s| s.head != 1)).count() * 2 > side1.len();
+    // and some number tracks from 1
+    let offset = most_common(tracks.iter().flat_map(|t| t.sectors.iter().map(|s| s.cylinder.wrapping_sub(t.cylinder) as usize))).unwrap_or(0) as u8;
+
+    let mut markers = Vec::new();
+    for track in tracks {
+        let mut add = |kind: Kind, detail: String| markers.push(Marker { cylinder: track.cylinder, head: track.head, kind, detail });
+        let ids = |pick: &dyn Fn(&crate::disk::Sector) -> bool| -> Vec<String> {
+            track.sectors.iter().filter(|s| pick(s)).map(|s| s.id.to_string()).collect()
+        };
+
+        let mut repeated: Vec<(u8, usize)> = Vec::new();
+        for sector in &track.sectors {
+            match repeated.iter_mut().find(|(id, _)| *id == sector.id) {
+                Some((_, count)) => *count += 1,
+                None => repeated.push((sector.id, 1)),
+            }
+        }
+        for (id, count) in repeated.into_iter().filter(|&(_, count)| count > 1) {
+            add(Kind::DuplicateId, format!("sector ID {} appears {} times", id, count));
+        }
+        if layout && track.sectors.len() > usual_count {
+            add(Kind::SectorCount, format!("{} sectors where most tracks have {}", track.sectors.len(), usual_count));
+        }
+        let outside = ids(&|s| (s.id as usize) < first_id || s.id as usize >= first_id + usual_count);
+        if layout && !outside.is_empty() {
+            add(Kind::IdRange, format!("sector IDs outside {}-{}: {}", first_id, first_id + usual_count - 1, outside.join(", ")));
+        }
+        for sector in track.sectors.iter().filter(|s| s.cylinder.wrapping_sub(track.cylinder) != offset || (s.head != track.head && !head_ids_differ)) {
+            add(Kind::IdMismatch, format!("sector {} has the ID field of Cyl {}, Head {}", sector.id, sector.cylinder, sector.head));
+        }
+        for sector in track.sectors.iter().filter(|s| layout && s.size != usual_size) {
+            let kind = if sector.size > usual_size { "oversized" } else { "undersized" };
+            add(Kind::Size, format!("{} sector {}: {} bytes where the disk uses {}", kind, sector.id, sector.size, usual_size));
+        }
+        for (kind, label, list) in [
+            (Kind::Deleted, "deleted data in sectors", ids(&|s| s.deleted)),
+            (Kind::CrcError, "CRC errors in sectors", ids(&|s| s.crc_error)),
+            (Kind::NoData, "no data field for sectors", ids(&|s| s.data.is_none())),
+        ] {
+            if !list.is_empty() {
+                add(kind, format!("{} {}", label, list.join(", ")));
+            }
+        }
+        for sector in &track.sectors {
+            let reads = sector.copies.iter().filter(|c| Some(*c) != sector.data.as_ref()).count();
+            if reads > 0 {
+                add(Kind::WeakSector, format!("weak sector {}: {} reads differ", sector.id, reads));
+            }
+        }
+    }
+    markers
+}
+
+/// Flux-level markers: long and short tracks, bit rate changes within a track, sectors
+/// whose data changes between revolutions (weak bits), unusual gaps between sectors, areas
+/// without flux and IDs repeated within one revolution. Also fingerprints Copylock tracks.
+fn flux_markers(capture: &Capture, platform: Platform, analysis: &mut Analysis) {
+    let double_stepped = platform.double_stepped(capture);
+    struct Read {
+        cylinder: u8,
+        head: u8,
+        revolutions: Vec<(RevolutionRead, u64, Vec<u32>)>, // Read, index-to-index time and flux
+    }
+    let mut tracks = Vec::new();
+    for (&number, revolutions) in &capture.tracks {
+        let Some((cylinder, head)) = platform.location(number, double_stepped) else { continue };
+        let revolutions: Vec<_> = revolutions.iter()
+            .filter_map(|r| Some((platform.read(cylinder, &r.flux)?, r.duration_ns, r.flux.clone())))
+            .filter(|(read, ..)| !read.sectors.is_empty())
+            .collect();
+        if !revolutions.is_empty() {
+            tracks.push(Read { cylinder, head, revolutions });
+        }
+    }
+
+    // Gaps between consecutive sectors (IBM), in bytes of 16 cells
+    let gaps_of = |read: &RevolutionRead| -> Vec<(u8, usize)> {
+        read.sectors.windows(2).filter_map(|w| Some((w[0].id, w[1].span?.0.saturating_sub(w[0].span?.1) / 16))).collect()
+    };
+    // Jitter moves gaps by a byte or so, so they are compared in 8-byte steps
+    let usual_gap = most_common(tracks.iter().flat_map(|t| gaps_of(&t.revolutions[0].0)).map(|(_, gap)| gap / 8 * 8)).map(|g| g + 4);
+
+    for track in &tracks {
+        let mut add = |kind: Kind, detail: String| {
+            analysis.markers.push(Marker { cylinder: track.cylinder, head: track.head, kind, detail })
+        };
+        let (best, duration, flux_ns) = track.revolutions.iter().max_by_key(|(read, ..)| read.sectors.len()).unwrap();
+
+        if *duration > 0 {
+            let cells = *duration as f64 / best.cell_ns;
+            let standard = match platform {
+                Platform::Cbm => 200e6 / flux::cbm::cell_ns(track.cylinder + 1),
+                Platform::Apple => 200e6 / flux::apple::CELL_NS,
+                _ => STANDARD_CELLS.into_iter().min_by(|a, b| (cells / a).ln().abs().total_cmp(&(cells / b).ln().abs())).unwrap(),
+            };
+            let excess = cells / standard - 1.0;
+            if excess.abs() > LENGTH_TOLERANCE {
+                let kind = if excess > 0.0 { Kind::LongTrack } else { Kind::ShortTrack };
+                let label = if excess > 0.0 { "long" } else { "short" };
+                add(kind, format!("{} track: {:.0} cells per revolution, {:+.1}% against the standard {:.0}", label, cells, excess * 100.0, standard));
+            }
+        }
+
+        // The shortest-interval cluster in each part of the revolution tracks the bit rate
+        let total: u64 = flux_ns.iter().map(|&t| t as u64).sum();
+        let mut segments: Vec<Vec<u32>> = vec![Vec::new(); RATE_SEGMENTS];
+        let mut elapsed = 0u64;
+        for &interval in flux_ns {
+            segments[((elapsed * RATE_SEGMENTS as u64) / total.max(1)) as usize % RATE_SEGMENTS].push(interval);
+            elapsed += interval as u64;
+        }
+        // A part holding only gap bytes may have no one-cell intervals, so count in whole cells
+        let rates: Vec<f64> = segments.iter()
+            .filter_map(|s| flux::estimate_cell(s, 1))
+            .map(|shortest| shortest / (shortest / best.cell_ns).round().max(1.0))
+            .collect();
+        if let (Some(min), Some(max)) = (rates.iter().copied().reduce(f64::min), rates.iter().copied().reduce(f64::max)) {
+            if max / min - 1.0 > RATE_TOLERANCE {
+                add(Kind::RateChange, format!("bit rate changes within the track: cells of {:.0}-{:.0} ns ({:.1}%)", min, max, (max / min - 1.0) * 100.0));
+            }
+        }
+
+        let mut seen: Vec<(u8, usize)> = Vec::new();
+        for sector in &best.sectors {
+            match seen.iter_mut().find(|(id, _)| *id == sector.id) {
+                Some((_, count)) => *count += 1,
+                None => seen.push((sector.id, 1)),
+            }
+        }
+        for (id, count) in seen.into_iter().filter(|&(_, count)| count > 1) {
+            add(Kind::DuplicateId, format!("sector ID {} appears {} times in one revolution", id, count));
+        }
+
+        if track.revolutions.len() > 1 {
+            let mut weak = Vec::new();
+            for (position, sector) in best.sectors.iter().enumerate() {
+                // The same sector in other revolutions: same ID, same occurrence if repeated
+                let occurrence = best.sectors[..position].iter().filter(|s| s.id == sector.id).count();
+                let reads: Vec<&SectorRead> = track.revolutions.iter()
+                    .filter_map(|(read, ..)| read.sectors.iter().filter(|s| s.id == sector.id).nth(occurrence))
+                    .collect();
+                // A good read in any revolution makes it a read error rather than weak bits
+                if !reads.iter().any(|r| r.good) && reads.windows(2).any(|w| w[0].data != w[1].data) {
+                    weak.push(sector.id.to_string());
+                }
+            }
+            if !weak.is_empty() {
+                add(Kind::WeakBits, format!("weak bits: sectors {} read differently between revolutions", weak.join(", ")));
+            }
+        }
+
+        if let Some(usual) = usual_gap {
+            for (id, gap) in gaps_of(best) {
+                if gap.abs_diff(usual) > 32 && (gap > usual * 2 || gap * 2 < usual) {
+                    add(Kind::Gap, format!("gap of {} bytes after sector {} where the disk uses about {}", gap, id, usual));
+                }
+            }
+        }
+
+        let limit = (best.cell_ns * NO_FLUX_CELLS) as u32;
+        let empty: Vec<u32> = flux_ns.iter().copied().filter(|&t| t > limit).collect();
+        if let Some(&longest) = empty.iter().max() {
+            add(Kind::NoFlux, format!(
+                "{} area{} without flux, the longest {:.0} µs", empty.len(), if empty.len() > 1 { "s" } else { "" }, longest as f64 / 1000.0
+            ));
+        }
+
+        if let Platform::Amiga { .. } = platform {
+            let bits = flux::to_bits(flux_ns, best.cell_ns);
+            let found = COPYLOCK_SYNCS.iter().filter(|&&sync| {
+                bits.windows(16).any(|w| w.iter().fold(0u16, |word, &bit| word << 1 | bit as u16) == sync)
+            }).count();
+            if found >= 6 {
+                analysis.scheme("Copylock", true, format!("Cyl {}, Head {}: {} of the 11 Copylock sync words", track.cylinder, track.head, found));
+            }
+        }
+    }
+}
+
+/// Names schemes from loader text and from the pattern of markers.
+fn identify(disk: &Disk, platform: Option<Platform>, analysis: &mut Analysis) {
+    for (track, sector) in disk.sectors(Order::Physical) {
+        for data in sector.data.iter().chain(&sector.copies) {
+            for &(text, name) in SIGNATURES {
+                let evidence = format!("\"{}\" in Cyl {}, Head {}, sector {}", String::from_utf8_lossy(text), track.cylinder, track.head, sector.id);
+                if contains(data, text) && !analysis.schemes.iter().any(|s| s.evidence.contains(&evidence)) {
+                    analysis.scheme(name, true, evidence);
+                }
+            }
+        }
+    }
+
+    let pc = disk.sectors(Order::Logical).first().and_then(|(_, s)| s.data.as_deref()).and_then(Bpb::parse).is_some();
+
+    // CPC and Spectrum +3 Speedlock reads a weak sector on the first track
+    if let Some(marker) = analysis.markers.iter().find(|m| m.cylinder == 0 && matches!(m.kind, Kind::WeakSector | Kind::WeakBits)) {
+        if !pc {
+            let evidence = format!("weak sector on Cyl 0, Head {}", marker.head);
+            analysis.scheme("Speedlock", false, evidence);
+        }
+    }
+    // Amiga Speedlock writes part of a track with longer cells and part with shorter ones
+    if let (Some(Platform::Amiga { .. }), Some(marker)) = (platform, analysis.markers.iter().find(|m| m.kind == Kind::RateChange)) {
+        let evidence = format!("bit rate changes within Cyl {}, Head {}", marker.cylinder, marker.head);
+        analysis.scheme("Speedlock", false, evidence);
+    }
+    // Prolok burns a hole through the disk, so one track reads with errors. Plenty of disks
+    // are simply damaged, so this only backs up the loader text.
+    let mut bad: Vec<(u8, u8)> = analysis.markers.iter()
+        .filter(|m| matches!(m.kind, Kind::CrcError | Kind::NoData | Kind::WeakBits))
+        .map(|m| (m.cylinder, m.head))
+        .collect();
+    bad.sort();
+    bad.dedup();
+    if pc && bad.len() == 1 && analysis.schemes.iter().any(|s| s.name == "Prolok") {
+        analysis.scheme("Prolok", true, format!("damaged area: bad sectors only on Cyl {}, Head {}", bad[0].0, bad[0].1));
+    }
+}
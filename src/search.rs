@@ -0,0 +1,114 @@
+use crate::disk::{Disk, Order};
+use anyhow::{Result, anyhow};
+use regex::bytes::{Regex, RegexBuilder};
+
+/// What kind of pattern the user supplied.
+pub enum Pattern {
+    Hex(Vec<u8>),
+    Text(String),
+    Regex(String),
+    Ebcdic(String),
+}
+
+/// A match in the logical sector stream.
+pub struct Hit {
+    pub lba: usize,    // Logical sector where the match starts
+    pub offset: usize, // Byte offset within that sector
+    pub bytes: Vec<u8>,
+    pub spans: usize,  // Number of sectors the match touches
+}
+
+/// Translates printable ASCII to EBCDIC (code page 037).
+fn to_ebcdic(c: char) -> Result<u8> {
+    const PUNCTUATION: &[(char, u8)] = &[
+        (' ', 0x40), ('!', 0x5A), ('"', 0x7F), ('#', 0x7B), ('$', 0x5B), ('%', 0x6C), ('&', 0x50), ('\'', 0x7D),
+        ('(', 0x4D), (')', 0x5D), ('*', 0x5C), ('+', 0x4E), (',', 0x6B), ('-', 0x60), ('.', 0x4B), ('/', 0x61),
+        (':', 0x7A), (';', 0x5E), ('<', 0x4C), ('=', 0x7E), ('>', 0x6E), ('?', 0x6F), ('@', 0x7C), ('[', 0xBA),
+        ('\\', 0xE0), (']', 0xBB), ('^', 0xB0), ('_', 0x6D), ('`', 0x79), ('{', 0xC0), ('|', 0x4F), ('}', 0xD0),
+        ('~', 0xA1),
+    ];
+    // Letters come in three runs per case: A-I, J-R, S-Z
+    let letter = |c: u8, base: u8| match c {
+        0..=8 => base + 1 + c,
+        9..=17 => base + 0x10 + 1 + (c - 9),
+        _ => base + 0x20 + 2 + (c - 18),
+    };
+    match c {
+        '0'..='9' => Ok(0xF0 + (c as u8 - b'0')),
+        'A'..='Z' => Ok(letter(c as u8 - b'A', 0xC0)),
+        'a'..='z' => Ok(letter(c as u8 - b'a', 0x80)),
+        _ => PUNCTUATION.iter().find(|(p, _)| *p == c).map(|(_, e)| *e)
+            .ok_or_else(|| anyhow!("Character '{}' has no EBCDIC equivalent in code page 037", c)),
+    }
+}
+
+fn byte_class(bytes: &[u8]) -> String {
+    match bytes {
+        [b] => format!("\\x{:02X}", b),
+        _ => format!("[{}]", bytes.iter().map(|b| format!("\\x{:02X}", b)).collect::<String>()),
+    }
+}
+
+/// Builds a byte regex for the pattern. Hex, text and EBCDIC patterns match literally.
+pub fn compile(pattern: &Pattern, ignore_case: bool) -> Result<Regex> {
+    let source = match pattern {
+        Pattern::Hex(bytes) => bytes.iter().map(|&b| byte_class(&[b])).collect(),
+        Pattern::Text(text) => regex::escape(text),
+        Pattern::Regex(regex) => regex.clone(),
+        Pattern::Ebcdic(text) => text.chars().map(|c| {
+            Ok(if ignore_case && c.is_ascii_alphabetic() {
+                byte_class(&[to_ebcdic(c.to_ascii_uppercase())?, to_ebcdic(c.to_ascii_lowercase())?])
+            } else {
+                byte_class(&[to_ebcdic(c)?])
+            })
+        }).collect::<Result<String>>()?,
+    };
+    if source.is_empty() {
+        return Err(anyhow!("Search pattern is empty"));
+    }
+    // Unicode mode off: \xNN means a raw byte and '.' matches any byte
+    RegexBuilder::new(&source)
+        .case_insensitive(ignore_case && !matches!(pattern, Pattern::Hex(_) | Pattern::Ebcdic(_)))
+        .unicode(false)
+        .build()
+        .map_err(|e| anyhow!("Invalid search pattern: {}", e))
+}
+
+/// Finds every match in the disk's sectors taken in logical order, so matches may span sectors.
+/// Literal hex, text and EBCDIC patterns also report overlapping matches (one starting inside
+/// the previous one); a regex resumes after each match, or `[0-9]+` would report every suffix.
+pub fn search(disk: &Disk, pattern: &Pattern, regex: &Regex) -> Vec<Hit> {
+    let overlapping = !matches!(pattern, Pattern::Regex(_));
+    let sectors = disk.sectors(Order::Logical);
+    let mut stream = Vec::new();
+    let mut starts = Vec::with_capacity(sectors.len());
+    for (_, sector) in &sectors {
+        starts.push(stream.len());
+        match &sector.data {
+            Some(data) => stream.extend_from_slice(data),
+            None => stream.resize(stream.len() + sector.size, 0),
+        }
+    }
+    let locate = |offset: usize| starts.partition_point(|&s| s <= offset) - 1;
+
+    let mut hits = Vec::new();
+    let mut at = 0;
+    while let Some(m) = regex.find_at(&stream, at) {
+        if m.is_empty() {
+            at = m.end() + 1;
+            if at > stream.len() {
+                break;
+            }
+            continue;
+        }
+        let lba = locate(m.start());
+        hits.push(Hit {
+            lba,
+            offset: m.start() - starts[lba],
+            bytes: m.as_bytes().to_vec(),
+            spans: locate(m.end() - 1) - lba + 1,
+        });
+        at = if overlapping { m.start() + 1 } else { m.end() };
+    }
+    hits
+}
@@ -56,11 +56,469 @@ test_scp() {
     grep "SuperCard Pro Image" $TEMP_DIR/scp_display.txt && echo "    OK: Display header parsed" || { echo "    FAIL: Header parsing failed"; exit 1; }
 }
 
+test_reinterleave() {
+    local imd=$TEST_DIR/360k/360k.imd
+    local img=$TEST_DIR/360k/360k.img
+    echo "Testing reinterleave..."
+    $BIN --input $imd reinterleave --interleave 1 --output $TEMP_DIR/seq.imd
+    cmp $imd $TEMP_DIR/seq.imd && echo "    OK: 1:1 rewrite matches original" || { echo "    FAIL: 1:1 rewrite differs"; exit 1; }
+    $BIN --input $imd reinterleave --interleave 3 --skew 2 --output $TEMP_DIR/il3.imd
+    $BIN --input $TEMP_DIR/il3.imd display | grep "Interleave: 3:1" > /dev/null && echo "    OK: Interleave detected" || { echo "    FAIL: Interleave not detected"; exit 1; }
+    $BIN --input $TEMP_DIR/il3.imd convert --format img --output $TEMP_DIR/il3.img --imdmeta $TEMP_DIR/il3.imd.meta
+    cmp $img $TEMP_DIR/il3.img && echo "    OK: Sector data preserved" || { echo "    FAIL: Sector data differs"; exit 1; }
+    $BIN --input $TEMP_DIR/il3.imd display | grep "Track-to-track Skew: 2 sectors" > /dev/null && echo "    OK: Skew per cylinder on a double-sided disk" || { echo "    FAIL: Skew on double-sided disk"; exit 1; }
+    $BIN --input $TEMP_DIR/il3.imd read --chs 1/1/1 | grep "(physical 2)" > /dev/null && echo "    OK: Both heads share the cylinder's skew" || { echo "    FAIL: Head 1 skew"; exit 1; }
+    $BIN --input $imd reinterleave --interleave 2 --output $TEMP_DIR/il2.hfe && $BIN --input $TEMP_DIR/il2.hfe display --ascii | sed -n 3p | grep "Cyl 0, Head 0, Sector 6," > /dev/null && echo "    OK: Reinterleaved to .hfe" || { echo "    FAIL: Reinterleave to .hfe"; exit 1; }
+    for ext in td0 scp; do
+        $BIN --input $imd reinterleave --interleave 2 --skew 1 --output $TEMP_DIR/il2.$ext > /dev/null || { echo "    FAIL: Reinterleave to .$ext"; exit 1; }
+        $BIN --input $TEMP_DIR/il2.$ext convert --format imd --output $TEMP_DIR/il2_$ext.imd > /dev/null
+        $BIN --input $TEMP_DIR/il2_$ext.imd display | grep "Interleave: 2:1" > /dev/null || { echo "    FAIL: .$ext lost the sector order"; exit 1; }
+        $BIN --input $TEMP_DIR/il2.$ext convert --format img --output $TEMP_DIR/il2_$ext.img > /dev/null
+        cmp $img $TEMP_DIR/il2_$ext.img > /dev/null && echo "    OK: Reinterleaved to .$ext" || { echo "    FAIL: .$ext sector data differs"; exit 1; }
+    done
+    $BIN --input $imd reinterleave --interleave 2 --output $TEMP_DIR/il2.img 2>&1 | grep "Cannot reinterleave to" > /dev/null || { echo "    FAIL: .img output accepted"; exit 1; }
+    echo "    OK: Outputs without sector order refused"
+}
+
+test_browse() {
+    echo "Testing browse..."
+    $BIN --input $TEST_DIR/880k/880k.scp browse --edit 2>&1 | grep "Cannot edit .*captured flux" > /dev/null && echo "    OK: --edit refused for flux captures" || { echo "    FAIL: --edit accepted for .scp"; exit 1; }
+    $BIN --input $TEST_DIR/kryoflux browse --edit 2>&1 | grep "Cannot edit" > /dev/null && echo "    OK: --edit refused for KryoFlux streams" || { echo "    FAIL: --edit accepted for KryoFlux"; exit 1; }
+}
+
+test_td0() {
+    local td0=$TEST_DIR/1.44M/1.44M.td0
+    echo "Testing .td0..."
+    $BIN --input $td0 display | grep "Comment: 1.44MB 3.5 inch floppy disk image" > /dev/null && echo "    OK: Header and comment parsed" || { echo "    FAIL: Header parsing failed"; exit 1; }
+    $BIN --input $td0 convert --format img --output $TEMP_DIR/td0.img > /dev/null
+    cmp -n $SIZE_1_44M $TEST_DIR/1.44M/1.44M.img $TEMP_DIR/td0.img && echo "    OK: Sector data matches reference .img" || { echo "    FAIL: Sector data differs"; exit 1; }
+    $BIN --input $td0 convert --format td0 --output $TEMP_DIR/td0-rt.td0 > /dev/null
+    $BIN --input $TEMP_DIR/td0-rt.td0 convert --format img --output $TEMP_DIR/td0-rt.img > /dev/null
+    cmp $TEMP_DIR/td0.img $TEMP_DIR/td0-rt.img && echo "    OK: Rewritten .td0 keeps the sectors" || { echo "    FAIL: Rewritten .td0 differs"; exit 1; }
+}
+
+test_read() {
+    local imd=$TEST_DIR/360k/360k.imd
+    local img=$TEST_DIR/360k/360k.img
+    echo "Testing read..."
+    $BIN --input $imd read --lba 0 --count 720 --raw --output $TEMP_DIR/read.bin
+    cmp $img $TEMP_DIR/read.bin && echo "    OK: Raw read matches reference .img" || { echo "    FAIL: Raw read differs"; exit 1; }
+    $BIN --input $img read --chs 0/0/1 | grep "MSDOS3.2" > /dev/null && echo "    OK: Hex dump shows boot sector" || { echo "    FAIL: Hex dump"; exit 1; }
+}
+
+test_write() {
+    local imd=$TEST_DIR/360k/360k.imd
+    echo "Testing write..."
+    cp $imd $TEMP_DIR/patch.imd
+    $BIN --input $imd read --lba 0 --raw --output $TEMP_DIR/boot.bin
+    $BIN --input $TEMP_DIR/patch.imd write --lba 0 --hex "$(printf 'e5%.0s' $(seq 512))"
+    $BIN --input $TEMP_DIR/patch.imd read --lba 0 | grep "e5 e5 e5" > /dev/null && echo "    OK: Sector patched" || { echo "    FAIL: Sector not patched"; exit 1; }
+    $BIN --input $TEMP_DIR/patch.imd write --lba 0 --from $TEMP_DIR/boot.bin
+    cmp $imd $TEMP_DIR/patch.imd && echo "    OK: Restored sector matches original" || { echo "    FAIL: Restored image differs"; exit 1; }
+}
+
+test_search() {
+    local imd=$TEST_DIR/360k/360k.imd
+    echo "Testing search..."
+    $BIN --input $imd search --hex "55 aa fd ff ff" | grep "spans 2 sectors" > /dev/null && echo "    OK: Match across sector boundary" || { echo "    FAIL: Boundary match"; exit 1; }
+    $BIN --input $imd search --text "MSDOS   SYS" | grep "\[FORMAT.EXE\]" > /dev/null && echo "    OK: Owning file reported" || { echo "    FAIL: Owning file"; exit 1; }
+    cp $TEST_DIR/360k/360k.img $TEMP_DIR/overlap.img
+    printf 'AQQQQZ' | dd of=$TEMP_DIR/overlap.img bs=1 seek=12288 conv=notrunc 2> /dev/null
+    $BIN --input $TEMP_DIR/overlap.img search --hex "51 51 51" | grep "^2 matches found" > /dev/null && echo "    OK: Overlapping matches found" || { echo "    FAIL: Overlapping matches"; exit 1; }
+    printf 'x12345x' | dd of=$TEMP_DIR/overlap.img bs=1 seek=12400 conv=notrunc 2> /dev/null
+    $BIN --input $TEMP_DIR/overlap.img search --regex "[0-9]+" > $TEMP_DIR/regex.txt
+    grep -q '"12345"' $TEMP_DIR/regex.txt && ! grep -q '"2345"' $TEMP_DIR/regex.txt && echo "    OK: Regex match reported once" || { echo "    FAIL: Regex suffixes reported"; exit 1; }
+    # Point cluster 2 at itself in the first FAT so the first file's chain loops
+    cp $TEST_DIR/360k/360k.img $TEMP_DIR/loop.img
+    printf '\x02\x40' | dd of=$TEMP_DIR/loop.img bs=1 seek=515 conv=notrunc 2> /dev/null
+    $BIN --input $TEMP_DIR/loop.img search --text "MSDOS   SYS" > $TEMP_DIR/loop.txt
+    grep -q "Warning: cannot map sectors to files" $TEMP_DIR/loop.txt && grep -q "^4 matches found" $TEMP_DIR/loop.txt && echo "    OK: Damaged FAT still searched" || { echo "    FAIL: Damaged FAT aborts search"; exit 1; }
+}
+
+test_boot() {
+    echo "Testing boot sector analysis..."
+    $BIN --input $TEST_DIR/360k/360k.imd display | grep "Boot Loader: MS-DOS 3.2" > /dev/null && echo "    OK: Boot loader identified" || { echo "    FAIL: Boot loader not identified"; exit 1; }
+    $BIN --input $TEST_DIR/1.44M/1.44M.img display | grep 'label "1440TEST"' > /dev/null && echo "    OK: Volume label decoded" || { echo "    FAIL: Volume label not decoded"; exit 1; }
+    $BIN --input $TEST_DIR/360k/360k.imd display | grep "Warning" && { echo "    FAIL: Unexpected geometry warning"; exit 1; } || echo "    OK: BPB matches geometry"
+    $BIN --input $TEST_DIR/1.44M/1.44M.img display | grep "Boot Loader: MS-DOS 7 (Windows 95/98) (CRC-32 424F0392, fingerprint match)" > /dev/null && echo "    OK: Windows 9x loader identified" || { echo "    FAIL: Windows 9x loader not identified"; exit 1; }
+    cp $TEST_DIR/360k/360k.img $TEMP_DIR/heuristic.img
+    printf 'IBMBIO  COM' | dd of=$TEMP_DIR/heuristic.img bs=1 seek=400 conv=notrunc 2> /dev/null
+    printf 'Your PC is now Stoned!' | dd of=$TEMP_DIR/heuristic.img bs=1 seek=420 conv=notrunc 2> /dev/null
+    $BIN --input $TEMP_DIR/heuristic.img display | grep "Boot Loader: PC-DOS or DR-DOS (heuristic: system file names only" > /dev/null && echo "    OK: File name identification labelled heuristic" || { echo "    FAIL: File name heuristic"; exit 1; }
+    $BIN --input $TEMP_DIR/heuristic.img display | grep "unknown version; CRC-32 [0-9A-F]* has no fingerprint)" > /dev/null && echo "    OK: Heuristic loader has no fingerprint" || { echo "    FAIL: Heuristic loader claims a fingerprint"; exit 1; }
+    $BIN --input $TEMP_DIR/heuristic.img display | grep "Note: built-in boot fingerprints cover only MS-DOS 3.2 and Windows 95/98" > /dev/null && echo "    OK: Fingerprint scope noted" || { echo "    FAIL: Fingerprint scope not noted"; exit 1; }
+    $BIN --input $TEST_DIR/360k/360k.img display | grep "Note: built-in boot fingerprints" > /dev/null && { echo "    FAIL: Scope note on a fingerprinted loader"; exit 1; }
+    $BIN --input $TEMP_DIR/heuristic.img display | grep "text from the Stoned virus (heuristic text match)" > /dev/null && echo "    OK: Virus text labelled heuristic" || { echo "    FAIL: Virus text heuristic"; exit 1; }
+    printf '# From tests/1.44M\nloader 424F0392 Windows 98 loader\nvirus F956DD42 Test\n' > $TEMP_DIR/fingerprints.txt
+    $BIN --input $TEST_DIR/360k/360k.img display --fingerprints $TEMP_DIR/fingerprints.txt | grep "Warning: boot sector is the Test virus (CRC-32 match)" > /dev/null && echo "    OK: Virus found by fingerprint file" || { echo "    FAIL: Virus fingerprint"; exit 1; }
+    $BIN --input $TEST_DIR/1.44M/1.44M.img display --fingerprints $TEMP_DIR/fingerprints.txt | grep "Boot Loader: MS-DOS 7 (Windows 95/98)" > /dev/null && echo "    OK: Built-in hash takes precedence" || { echo "    FAIL: Fingerprint precedence"; exit 1; }
+    # A 2-byte first sector (IMD size table) whose hash is a listed virus has no OEM name to show
+    printf 'IMD 1.18: tiny\r\n\x1a\x05\x00\x00\x01\xff\x01\x02\x00\x01\xab\xcd' > $TEMP_DIR/tiny.imd
+    printf 'virus E9FFC9D0 Tiny\n' > $TEMP_DIR/tiny-fingerprints.txt
+    $BIN --input $TEMP_DIR/tiny.imd display --fingerprints $TEMP_DIR/tiny-fingerprints.txt 2>&1 | grep "Boot Sector: OEM \"\", signature missing" > /dev/null && echo "    OK: Tiny boot sector shown without panicking" || { echo "    FAIL: Tiny boot sector"; exit 1; }
+    printf 'loader XYZ Bad\n' > $TEMP_DIR/bad-fingerprints.txt
+    $BIN --input $TEST_DIR/1.44M/1.44M.img display --fingerprints $TEMP_DIR/bad-fingerprints.txt 2>&1 | grep "fingerprints line 1: invalid CRC-32 'XYZ'" > /dev/null && echo "    OK: Bad fingerprint rejected" || { echo "    FAIL: Bad fingerprint accepted"; exit 1; }
+    # 640 sectors (8 sectors/track, 2 heads, 40 cylinders) with the 3.5" 640K descriptor 0xFB, which is a 1280-sector format
+    head -c 327680 $TEST_DIR/360k/360k.img > $TEMP_DIR/640.img
+    printf '\x80\x02\xFB' | dd of=$TEMP_DIR/640.img bs=1 seek=19 conv=notrunc 2> /dev/null
+    printf '\x08' | dd of=$TEMP_DIR/640.img bs=1 seek=24 conv=notrunc 2> /dev/null
+    $BIN --input $TEMP_DIR/640.img display | grep "media descriptor 0xFB is unusual for a 640-sector disk (expected 0xFF)" > /dev/null && echo "    OK: 0xFB flagged on a 640-sector disk" || { echo "    FAIL: 0xFB accepted for 640 sectors"; exit 1; }
+}
+
+test_geometry() {
+    echo "Testing BPB geometry inference..."
+    for size in 360k 720k 1.2M 1.44M; do
+        $BIN --input $TEST_DIR/$size/$size.img display | grep "Geometry Source: boot sector BPB" > /dev/null || { echo "    FAIL: $size geometry not taken from BPB"; exit 1; }
+        $BIN --input $TEST_DIR/$size/$size.imd convert --format img --output $TEMP_DIR/geo.img --imdmeta $TEMP_DIR/geo.meta
+        $BIN --input $TEMP_DIR/geo.img convert --format imd --output $TEMP_DIR/geo.imd --imdmeta $TEMP_DIR/geo.meta
+        cmp $TEST_DIR/$size/$size.imd $TEMP_DIR/geo.imd || { echo "    FAIL: $size roundtrip without --geometry differs"; exit 1; }
+    done
+    echo "    OK: Geometry and mode inferred from BPB"
+    head -c 102400 /dev/zero > $TEMP_DIR/odd.img
+    printf 'MARKER' | dd of=$TEMP_DIR/odd.img bs=1 seek=2304 conv=notrunc 2> /dev/null
+    $BIN --input $TEMP_DIR/odd.img read --chs 0/0/10 > /dev/null 2>&1 && { echo "    FAIL: Odd-sized .img opened without --geometry"; exit 1; }
+    $BIN --input $TEMP_DIR/odd.img --geometry 40,1,10,256,2 read --chs 0/0/10 | grep "MARKER" > /dev/null && echo "    OK: read honours --geometry" || { echo "    FAIL: read ignored --geometry"; exit 1; }
+    $BIN --input $TEMP_DIR/odd.img --geometry 40,1,10,256,2 search --text MARKER | grep "Cyl 0, Head 0, Sector 10, offset 0" > /dev/null && echo "    OK: search honours --geometry" || { echo "    FAIL: search ignored --geometry"; exit 1; }
+    $BIN --input $TEMP_DIR/odd.img --geometry 40,1,10,256,2 write --chs 1/0/1 --hex "aa bb" --output $TEMP_DIR/odd-written.img > /dev/null
+    [ "$(od -An -tx1 -j2560 -N2 $TEMP_DIR/odd-written.img | tr -d ' ')" = "aabb" ] && echo "    OK: write honours --geometry" || { echo "    FAIL: write ignored --geometry"; exit 1; }
+    $BIN --input $TEMP_DIR/odd.img --geometry 40,1,10,256,2 reinterleave --interleave 2 --output $TEMP_DIR/odd.imd > /dev/null
+    $BIN --input $TEMP_DIR/odd.imd convert --format img --output $TEMP_DIR/odd-back.img > /dev/null
+    cmp $TEMP_DIR/odd.img $TEMP_DIR/odd-back.img && echo "    OK: reinterleave honours --geometry" || { echo "    FAIL: reinterleave ignored --geometry"; exit 1; }
+}
+
+test_adf() {
+    local adf=$TEST_DIR/880k/880k.adf
+    echo "Testing ADF..."
+    $BIN --input $adf display | grep 'Volume: "TestDisk"' > /dev/null && echo "    OK: Root block parsed" || { echo "    FAIL: Root block not parsed"; exit 1; }
+    $BIN --input $adf ls | grep "s/Startup-Sequence" > /dev/null && echo "    OK: Directories listed" || { echo "    FAIL: Directory listing"; exit 1; }
+    $BIN --input $adf extract --output $TEMP_DIR/ffs
+    $BIN --input $TEST_DIR/880k/880k-ofs.adf extract --output $TEMP_DIR/ofs
+    diff -r $TEMP_DIR/ffs $TEMP_DIR/ofs && [ $(wc -c < $TEMP_DIR/ffs/Big.bin) -eq 40000 ] && echo "    OK: OFS and FFS files extracted" || { echo "    FAIL: Extracted files differ"; exit 1; }
+    $BIN --input $adf convert --format imd --output $TEMP_DIR/adf.imd
+    $BIN --input $adf convert --format adf --output $TEMP_DIR/copy.adf --validate | grep "Note: --validate only checks .img and .imd output; the .adf file was not read back" > /dev/null && echo "    OK: --validate does not claim to check .adf" || { echo "    FAIL: --validate message for .adf"; exit 1; }
+    $BIN --input $TEMP_DIR/adf.imd convert --format adf --output $TEMP_DIR/back.adf
+    cmp $adf $TEMP_DIR/back.adf && echo "    OK: ADF -> IMD -> ADF roundtrip" || { echo "    FAIL: ADF roundtrip differs"; exit 1; }
+}
+
+test_amiga_scp() {
+    # Flux for tracks 0, 80 and 88 only; sector 5 of track 0 is damaged in both revolutions
+    local scp=$TEST_DIR/880k/880k.scp
+    echo "Testing Amiga MFM decoding from .scp..."
+    $BIN --input $scp display | grep "Track 0 (Cyl 0, Head 0): bad data checksum in sector 5" > /dev/null && echo "    OK: Bad checksum reported" || { echo "    FAIL: Bad checksum not reported"; exit 1; }
+    $BIN --input $scp extract --file ReadMe --file s/Startup-Sequence --output $TEMP_DIR/scp_files
+    cmp $TEMP_DIR/scp_files/ReadMe $TEMP_DIR/ffs/ReadMe && cmp $TEMP_DIR/scp_files/s/Startup-Sequence $TEMP_DIR/ffs/s/Startup-Sequence && echo "    OK: Files decoded from flux" || { echo "    FAIL: Decoded files differ"; exit 1; }
+    $BIN --input $scp convert --format adf --output $TEMP_DIR/scp.adf
+    [ $(wc -c < $TEMP_DIR/scp.adf) -eq 901120 ] && echo "    OK: ADF written" || { echo "    FAIL: ADF size"; exit 1; }
+}
+
+test_cpm() {
+    echo "Testing CP/M..."
+    head -c 204800 /dev/zero | tr '\0' '\345' > $TEMP_DIR/kpii.img
+    $BIN --input $TEMP_DIR/kpii.img convert --format imd --output $TEMP_DIR/kpii.imd --geometry 40,1,10,512,5
+    head -c 20480 $TEST_DIR/360k/360k.img > $TEMP_DIR/data.bin
+    $BIN --input $TEMP_DIR/kpii.imd inject --diskdef kpii --from $TEMP_DIR/data.bin --name 3:DATA.BIN
+    $BIN --input $TEMP_DIR/kpii.imd ls --diskdef kpii | grep "3:DATA.BIN" > /dev/null && echo "    OK: Injected file listed" || { echo "    FAIL: File not listed"; exit 1; }
+    $BIN --input $TEMP_DIR/kpii.imd display | grep "Likely CP/M Format: kpii" > /dev/null && echo "    OK: Format recognised" || { echo "    FAIL: Format not recognised"; exit 1; }
+    $BIN --input $TEMP_DIR/kpii.imd extract --output $TEMP_DIR/cpm
+    cmp $TEMP_DIR/data.bin $TEMP_DIR/cpm/user3/DATA.BIN && echo "    OK: Extracted file matches" || { echo "    FAIL: Extracted file differs"; exit 1; }
+    printf 'diskdef big\n  seclen 512\n  tracks 80\n  sectrk 10\n  blocksize 2048\n  maxdir 128\n  boottrk 1\nend\n' > $TEMP_DIR/big.defs
+    $BIN --input $TEMP_DIR/kpii.imd inject --diskdefs $TEMP_DIR/big.defs --diskdef big --from $TEMP_DIR/data.bin --name BIG.BIN 2>&1 | grep "Image is too small for diskdef 'big'" > /dev/null && echo "    OK: Diskdef larger than the image rejected" || { echo "    FAIL: Oversized diskdef accepted"; exit 1; }
+    printf 'diskdef small\n  seclen 256\n  tracks 40\n  sectrk 10\n  blocksize 1024\n  maxdir 64\n  boottrk 1\nend\n' > $TEMP_DIR/small.defs
+    $BIN --input $TEMP_DIR/kpii.imd inject --diskdefs $TEMP_DIR/small.defs --diskdef small --from $TEMP_DIR/data.bin --name SMALL.BIN 2>&1 | grep "expects 256-byte sectors but the image has 512-byte sectors" > /dev/null && echo "    OK: Sector size mismatch rejected" || { echo "    FAIL: Sector size mismatch accepted"; exit 1; }
+    printf 'diskdef boot\n  seclen 512\n  tracks 2\n  sectrk 10\n  boottrk 2\nend\n' > $TEMP_DIR/boot.defs
+    $BIN --input $TEMP_DIR/kpii.imd ls --diskdefs $TEMP_DIR/boot.defs --diskdef boot 2>&1 | grep "leave no data area" > /dev/null && echo "    OK: Diskdef without a data area rejected" || { echo "    FAIL: Diskdef without a data area accepted"; exit 1; }
+}
+
+test_cbm() {
+    # 35-track 1541 disk with three files on tracks 17 and 19; the -errors copy flags track 1 sector 0 with code 05
+    local d64=$TEST_DIR/170k/170k.d64
+    local errors=$TEST_DIR/170k/170k-errors.d64
+    echo "Testing Commodore D64/G64..."
+    $BIN --input $d64 display | grep 'Filesystem: CBM DOS (disk "FLOPPYTOOL TEST", id "FT 2A", 656 blocks free)' > /dev/null && echo "    OK: CBM DOS detected" || { echo "    FAIL: CBM DOS not detected"; exit 1; }
+    $BIN --input $d64 ls | grep "LOCKED .* PRG< 3 blocks" > /dev/null && echo "    OK: Locked file listed" || { echo "    FAIL: Locked file not listed"; exit 1; }
+    $BIN --input $d64 extract --output $TEMP_DIR/cbm
+    $BIN --input $d64 convert --format g64 --output $TEMP_DIR/cbm.g64
+    $BIN --input $TEMP_DIR/cbm.g64 convert --format d64 --output $TEMP_DIR/cbm.d64
+    cmp $d64 $TEMP_DIR/cbm.d64 && echo "    OK: D64 -> G64 -> D64 matches" || { echo "    FAIL: D64 round trip differs"; exit 1; }
+    $BIN --input $errors convert --format g64 --output $TEMP_DIR/cbm-errors.g64
+    $BIN --input $TEMP_DIR/cbm-errors.g64 display | grep "Track 1: bad data checksum in sector 0" > /dev/null && echo "    OK: Error byte written as bad checksum" || { echo "    FAIL: Bad checksum not reported"; exit 1; }
+    $BIN --input $TEMP_DIR/cbm-errors.g64 convert --format d64 --output $TEMP_DIR/cbm-errors.d64
+    cmp $errors $TEMP_DIR/cbm-errors.d64 && echo "    OK: Error bytes preserved" || { echo "    FAIL: Error bytes differ"; exit 1; }
+    echo "Testing Commodore GCR decoding from .scp..."
+    # Flux for tracks 17-19 only; one sector on track 19 is damaged in the first revolution
+    $BIN --input $TEST_DIR/170k/170k.scp extract --output $TEMP_DIR/cbm_scp
+    for f in HELLO README LOCKED; do
+        cmp $TEMP_DIR/cbm/$f $TEMP_DIR/cbm_scp/$f || { echo "    FAIL: $f decoded from flux differs"; exit 1; }
+    done
+    echo "    OK: Files decoded from flux"
+}
+
+test_apple() {
+    # DOS 3.3 disk in DOS order; dos32.nib holds its first 455 sectors as a 13-sector (5-and-3) disk
+    local do=$TEST_DIR/140k/140k.do
+    echo "Testing Apple II sector images..."
+    $BIN --input $do convert --format po --output $TEMP_DIR/apple.po
+    cmp -s $do $TEMP_DIR/apple.po && { echo "    FAIL: .po has the same sector order as .do"; exit 1; }
+    cp $TEMP_DIR/apple.po $TEMP_DIR/apple.dsk
+    $BIN --input $TEMP_DIR/apple.dsk display | grep "ProDOS order" > /dev/null && echo "    OK: .dsk sector order detected" || { echo "    FAIL: .dsk order not detected"; exit 1; }
+    $BIN --input $TEMP_DIR/apple.po convert --format do --output $TEMP_DIR/apple.do
+    cmp $do $TEMP_DIR/apple.do && echo "    OK: DO -> PO -> DO matches" || { echo "    FAIL: Sector order round trip differs"; exit 1; }
+    $BIN --input $do convert --format nib --output $TEMP_DIR/apple.nib
+    $BIN --input $TEMP_DIR/apple.nib convert --format do --output $TEMP_DIR/apple-nib.do
+    cmp $do $TEMP_DIR/apple-nib.do && echo "    OK: DO -> NIB -> DO matches" || { echo "    FAIL: NIB round trip differs"; exit 1; }
+    $BIN --input $do convert --format woz --output $TEMP_DIR/apple.woz
+    $BIN --input $TEMP_DIR/apple.woz display | grep "CRC32 .* (valid)" > /dev/null && echo "    OK: WOZ CRC valid" || { echo "    FAIL: WOZ CRC"; exit 1; }
+    $BIN --input $TEMP_DIR/apple.woz convert --format do --output $TEMP_DIR/apple-woz.do
+    cmp $do $TEMP_DIR/apple-woz.do && echo "    OK: DO -> WOZ -> DO matches" || { echo "    FAIL: WOZ round trip differs"; exit 1; }
+    $BIN --input $TEST_DIR/140k/dos32.nib read --lba 0 --count 455 --raw --output $TEMP_DIR/dos32.bin
+    head -c 116480 $do | cmp - $TEMP_DIR/dos32.bin && echo "    OK: 13-sector disk decoded" || { echo "    FAIL: 5-and-3 decode differs"; exit 1; }
+    echo "Testing Apple GCR decoding from .scp..."
+    # Flux for tracks 0, 17 and 18 only; one sector on track 18 is damaged in the first revolution
+    $BIN --input $TEST_DIR/140k/140k.scp convert --format do --output $TEMP_DIR/apple-scp.do
+    cmp -n 4096 $do $TEMP_DIR/apple-scp.do && cmp -n 8192 -i 69632 $do $TEMP_DIR/apple-scp.do && echo "    OK: Tracks decoded from flux" || { echo "    FAIL: Decoded tracks differ"; exit 1; }
+}
+
+test_apple_fs() {
+    local do=$TEST_DIR/140k/140k.do po=$TEST_DIR/140k/140k.po
+    echo "Testing Apple DOS 3.3 and ProDOS files..."
+    $BIN --input $do display | grep "Filesystem: Apple DOS 3.3 (volume 254, 486 sectors free)" > /dev/null && echo "    OK: DOS 3.3 detected" || { echo "    FAIL: DOS 3.3 not detected"; exit 1; }
+    $BIN --input $do ls | grep "DATA.BIN .* 600 .* B \$2000 4 sectors" > /dev/null && echo "    OK: DOS 3.3 catalog listed" || { echo "    FAIL: DOS 3.3 catalog"; exit 1; }
+    $BIN --input $do extract --output $TEMP_DIR/dos33 > /dev/null
+    [ "$(wc -c < $TEMP_DIR/dos33/HELLO | tr -d ' ')" = "300" ] && [ "$(wc -c < $TEMP_DIR/dos33/DATA.BIN | tr -d ' ')" = "600" ] && echo "    OK: DOS 3.3 files extracted without headers" || { echo "    FAIL: DOS 3.3 extract"; exit 1; }
+    $BIN --input $po display | grep 'Filesystem: ProDOS (volume "/TESTDISK", 262 blocks free)' > /dev/null && echo "    OK: ProDOS detected" || { echo "    FAIL: ProDOS not detected"; exit 1; }
+    $BIN --input $po ls | grep "GAMES/BIG.DAT .* 131272" > /dev/null && echo "    OK: ProDOS subdirectory listed" || { echo "    FAIL: ProDOS listing"; exit 1; }
+    # BIG.DAT is a sparse tree file: one data block, 255 unallocated, then 200 bytes
+    $BIN --input $po extract --file games/big.dat --output $TEMP_DIR/prodos > /dev/null
+    [ "$(head -c 131072 $TEMP_DIR/prodos/GAMES/BIG.DAT | tail -c 130560 | tr -d '\000' | wc -c | tr -d ' ')" = "0" ] && [ "$(wc -c < $TEMP_DIR/prodos/GAMES/BIG.DAT | tr -d ' ')" = "131272" ] && echo "    OK: Sparse ProDOS file extracted" || { echo "    FAIL: Sparse blocks not zero-filled"; exit 1; }
+}
+
+test_st() {
+    local st=$TEST_DIR/st/st.st msa=$TEST_DIR/st/st.msa
+    echo "Testing Atari ST and MSA images..."
+    # 80 tracks, one side, 10 sectors; st.msa was packed independently of floppytool
+    $BIN --input $st display | grep "Detected Geometry: 80 cylinders, 1 heads, 10 sectors/track" > /dev/null && echo "    OK: Geometry from ST boot sector" || { echo "    FAIL: ST geometry"; exit 1; }
+    $BIN --input $st display | grep "Boot Sector: executable" > /dev/null && echo "    OK: Executable boot sector" || { echo "    FAIL: ST boot checksum"; exit 1; }
+    $BIN --input $msa convert --format st --output $TEMP_DIR/msa.st
+    cmp $st $TEMP_DIR/msa.st && echo "    OK: MSA -> ST matches" || { echo "    FAIL: MSA decompression differs"; exit 1; }
+    $BIN --input $st convert --format msa --output $TEMP_DIR/st.msa
+    $BIN --input $TEMP_DIR/st.msa display | grep "Tracks: 0-79, 80 of 80 compressed" > /dev/null || { echo "    FAIL: MSA tracks not compressed"; exit 1; }
+    $BIN --input $TEMP_DIR/st.msa convert --format st --output $TEMP_DIR/st-rt.st
+    cmp $st $TEMP_DIR/st-rt.st && echo "    OK: ST -> MSA -> ST matches" || { echo "    FAIL: MSA round trip differs"; exit 1; }
+    $BIN --input $msa convert --format imd --output $TEMP_DIR/st.imd
+    $BIN --input $TEMP_DIR/st.imd convert --format st --output $TEMP_DIR/imd.st
+    cmp $st $TEMP_DIR/imd.st && echo "    OK: MSA -> IMD -> ST matches" || { echo "    FAIL: IMD round trip differs"; exit 1; }
+    $BIN --input $msa ls | grep "DATA.BIN .* 3000" > /dev/null && echo "    OK: Files listed from MSA" || { echo "    FAIL: MSA listing"; exit 1; }
+}
+
+test_dsk() {
+    local dsk=$TEST_DIR/cpc/cpc.dsk protected=$TEST_DIR/cpc/protected.dsk
+    echo "Testing Amstrad CPC DSK images..."
+    # Both hold the same CP/M data-format disk; protected.dsk is Extended DSK with odd sectors on track 39
+    $BIN --input $dsk ls | grep "README.TXT" > /dev/null && echo "    OK: CP/M files listed from standard DSK" || { echo "    FAIL: CPC listing"; exit 1; }
+    $BIN --input $dsk convert --format img --output $TEMP_DIR/cpc.img
+    $BIN --input $dsk convert --format imd --output $TEMP_DIR/cpc.imd
+    $BIN --input $TEMP_DIR/cpc.imd convert --format dsk --output $TEMP_DIR/cpc-imd.dsk
+    $BIN --input $TEMP_DIR/cpc-imd.dsk convert --format img --output $TEMP_DIR/cpc-rt.img
+    cmp $TEMP_DIR/cpc.img $TEMP_DIR/cpc-rt.img && echo "    OK: DSK -> IMD -> DSK keeps the sectors" || { echo "    FAIL: DSK round trip differs"; exit 1; }
+    $BIN --input $protected display | grep -A4 "Flagged Sectors: 4" > $TEMP_DIR/flagged.txt
+    grep "Sector ID C2 .*ST1 20 ST2 20, data error, 3 copies (weak)" $TEMP_DIR/flagged.txt > /dev/null && echo "    OK: Weak sector found" || { echo "    FAIL: Weak sector"; exit 1; }
+    $BIN --input $protected convert --format dsk --output $TEMP_DIR/protected.dsk
+    $BIN --input $TEMP_DIR/protected.dsk display | grep -A4 "Flagged Sectors: 4" | cmp - $TEMP_DIR/flagged.txt && echo "    OK: Extended DSK round trip keeps status and weak copies" || { echo "    FAIL: Extended DSK round trip"; exit 1; }
+    # Cyl 1 starts at 0x1400 (Disk-Info block plus one 0x1300-byte track); its first sector, C1, at 0x1500
+    for image in $dsk $protected; do
+        $BIN --input $image write --chs 1/0/193 --hex "de ad be ef" --output $TEMP_DIR/patched.dsk > /dev/null
+        [ "$(cmp -l $image $TEMP_DIR/patched.dsk | awk '{print $1}' | tr '\n' ' ')" = "5377 5378 5379 5380 " ] && echo "    OK: $(basename $image) write changes only the sector's bytes" || { echo "    FAIL: $(basename $image) write changed other bytes"; exit 1; }
+    done
+    $BIN --input $protected convert --format imd --output $TEMP_DIR/protected.imd
+    $BIN --input $TEMP_DIR/protected.imd convert --format dsk --output $TEMP_DIR/protected-imd.dsk
+    $BIN --input $TEMP_DIR/protected-imd.dsk display | grep -A4 "Flagged Sectors: 4" | sed 's/, 3 copies (weak)//' | cmp - <(sed 's/, 3 copies (weak)//' $TEMP_DIR/flagged.txt) && echo "    OK: Status survives IMD" || { echo "    FAIL: Status lost through IMD"; exit 1; }
+}
+
+test_hfe() {
+    local v3=$TEST_DIR/hfe/v3.hfe
+    echo "Testing HxC HFE images..."
+    for size in 360k 720k 1.2M 1.44M; do
+        $BIN --input $TEST_DIR/$size/$size.imd convert --format hfe --output $TEMP_DIR/$size.hfe
+        $BIN --input $TEMP_DIR/$size.hfe convert --format img --output $TEMP_DIR/$size-hfe.img
+        cmp $TEST_DIR/$size/$size.img $TEMP_DIR/$size-hfe.img && echo "    OK: $size IMD -> HFE -> IMG matches" || { echo "    FAIL: $size HFE round trip differs"; exit 1; }
+    done
+    $BIN --input $TEMP_DIR/1.44M.hfe display | grep "Interface Mode: IBM PC HD" > /dev/null && echo "    OK: HD interface mode" || { echo "    FAIL: HFE interface mode"; exit 1; }
+    # 8-inch single density: FM at 360 RPM
+    head -c 256256 $TEST_DIR/1.44M/1.44M.img > $TEMP_DIR/fm.img
+    $BIN --input $TEMP_DIR/fm.img convert --format imd --geometry 77,1,26,128,0 --output $TEMP_DIR/fm.imd
+    $BIN --input $TEMP_DIR/fm.imd convert --format hfe --output $TEMP_DIR/fm.hfe
+    $BIN --input $TEMP_DIR/fm.hfe display | grep "Bit Rate: 250 kbps, 360 RPM" > /dev/null || { echo "    FAIL: FM bit rate"; exit 1; }
+    $BIN --input $TEMP_DIR/fm.hfe convert --format img --output $TEMP_DIR/fm-rt.img
+    cmp $TEMP_DIR/fm.img $TEMP_DIR/fm-rt.img && echo "    OK: FM IMD -> HFE -> IMG matches" || { echo "    FAIL: FM HFE round trip differs"; exit 1; }
+    # Amiga disks are written as Amiga MFM tracks, not IBM ones
+    $BIN --input $TEST_DIR/880k/880k.adf convert --format hfe --output $TEMP_DIR/amiga.hfe
+    $BIN --input $TEMP_DIR/amiga.hfe display | grep "Encoding: Amiga MFM" > /dev/null && echo "    OK: ADF written as Amiga MFM" || { echo "    FAIL: ADF written as IBM"; exit 1; }
+    $BIN --input $TEMP_DIR/amiga.hfe convert --format adf --output $TEMP_DIR/amiga-hfe.adf
+    cmp $TEST_DIR/880k/880k.adf $TEMP_DIR/amiga-hfe.adf && echo "    OK: ADF -> HFE -> ADF matches" || { echo "    FAIL: Amiga HFE round trip differs"; exit 1; }
+    # Side 0 of 720k cylinders 0-2 as HFEv3 with opcodes in their stored (bit-reversed) form and a
+    # 500 kbps header. Cyl 0 sets 250 kbps, has an index mark, NOPs and a data byte split into two
+    # SKIP_BITS pieces; Cyl 1 switches to 500 kbps (cells doubled) after sector 6 and has a random
+    # byte in sector 9; Cyl 2 switches between 300 and 250 kbps.
+    $BIN --input $v3 display > $TEMP_DIR/v3.txt
+    grep "Cyl 1, Head 0: 1 sectors with CRC errors" $TEMP_DIR/v3.txt > /dev/null && echo "    OK: HFEv3 random byte gives a CRC error" || { echo "    FAIL: HFEv3 decoding"; exit 1; }
+    grep "Cyl 2, Head 0: bit rate changes between 250 and 300 kbps mid-track" $TEMP_DIR/v3.txt > /dev/null && echo "    OK: HFEv3 uneven rate change reported" || { echo "    FAIL: HFEv3 uneven rate change"; exit 1; }
+    $BIN --input $v3 convert --format img --output $TEMP_DIR/v3.img > /dev/null
+    cmp -n 4608 $TEMP_DIR/v3.img $TEST_DIR/720k/720k.img && echo "    OK: HFEv3 opcodes and skipped bits decoded" || { echo "    FAIL: HFEv3 track 0 differs"; exit 1; }
+    cmp -n 4096 $TEMP_DIR/v3.img $TEST_DIR/720k/720k.img 4608 9216 && echo "    OK: HFEv3 sectors after a rate change decoded" || { echo "    FAIL: HFEv3 track 1 differs"; exit 1; }
+    $BIN --input $v3 convert --format imd --output $TEMP_DIR/v3.imd > /dev/null
+    [ $($BIN --input $TEMP_DIR/v3.imd display --ascii | grep -c "Mode 5:") -eq 18 ] && echo "    OK: HFEv3 tracks take their SET_BITRATE rate" || { echo "    FAIL: HFEv3 rate ignored"; exit 1; }
+}
+
+test_kryoflux() {
+    local kf=$TEST_DIR/kryoflux
+    echo "Testing KryoFlux streams..."
+    # Cylinder 0 of 720k, two revolutions per track; sector 5 of head 0 is damaged in the first
+    $BIN --input $kf display > $TEMP_DIR/kf_display.txt
+    grep "sck=24027428" $TEMP_DIR/kf_display.txt > /dev/null && echo "    OK: KFInfo clocks read" || { echo "    FAIL: KFInfo"; exit 1; }
+    grep "IBM FM/MFM Decode: 18 sectors good, 0 with bad data checksums" $TEMP_DIR/kf_display.txt > /dev/null && echo "    OK: Revolutions merged" || { echo "    FAIL: KryoFlux decode"; exit 1; }
+    head -c 9216 $TEST_DIR/720k/720k.img > $TEMP_DIR/cyl0.img
+    $BIN --input $kf convert --format img --output $TEMP_DIR/kf.img
+    cmp $TEMP_DIR/cyl0.img $TEMP_DIR/kf.img && echo "    OK: KryoFlux -> IMG matches" || { echo "    FAIL: KryoFlux sectors differ"; exit 1; }
+    $BIN --input $kf/track00.1.raw convert --format scp --output $TEMP_DIR/kf.scp
+    $BIN --input $TEMP_DIR/kf.scp convert --format img --output $TEMP_DIR/kf-scp.img
+    cmp $TEMP_DIR/cyl0.img $TEMP_DIR/kf-scp.img && echo "    OK: KryoFlux -> SCP -> IMG matches" || { echo "    FAIL: SCP from KryoFlux differs"; exit 1; }
+    $BIN --input $TEMP_DIR/kf.scp convert --format scp --output $TEMP_DIR/kf-rt.scp
+    cmp $TEMP_DIR/kf.scp $TEMP_DIR/kf-rt.scp && echo "    OK: SCP rewrite is unchanged" || { echo "    FAIL: SCP rewrite differs"; exit 1; }
+    $BIN --input $TEMP_DIR/kf.scp display | grep "Track 1 (Cyl 0, Head 1): RPM 300.00, 300.00" > /dev/null && echo "    OK: SCP revolution RPM" || { echo "    FAIL: SCP RPM"; exit 1; }
+    # The same ticks at 50 ns resolution are twice as long
+    printf '\x01' | dd of=$TEMP_DIR/kf.scp bs=1 seek=11 conv=notrunc 2> /dev/null
+    $BIN --input $TEMP_DIR/kf.scp display > $TEMP_DIR/kf50.txt
+    grep "Resolution: 50 ns" $TEMP_DIR/kf50.txt > /dev/null && grep "Track 1 (Cyl 0, Head 1): RPM 150.00, 150.00" $TEMP_DIR/kf50.txt > /dev/null && echo "    OK: SCP resolution applied" || { echo "    FAIL: SCP resolution"; exit 1; }
+}
+
+test_scp_footer() {
+    local scp=$TEST_DIR/scp/extended.scp
+    echo "Testing SCP footer and extended mode..."
+    # One track, with the TDH table at 0x80 and a footer holding five strings
+    $BIN --input $scp display > $TEMP_DIR/scpx.txt
+    grep "Track 0 (Cyl 0, Head 0): RPM 300.00, 50 flux values, Offset 0x00000084" $TEMP_DIR/scpx.txt > /dev/null && echo "    OK: Extended mode track table" || { echo "    FAIL: SCP extended mode"; exit 1; }
+    grep "Drive Model: FD-235" $TEMP_DIR/scpx.txt > /dev/null && grep "Created: 2023-11-14 22:13:20 UTC" $TEMP_DIR/scpx.txt > /dev/null && echo "    OK: Footer parsed" || { echo "    FAIL: SCP footer"; exit 1; }
+    head -1 $TEMP_DIR/scpx.txt | grep "^SuperCard Pro Image" > /dev/null && ! grep -q "Header Hex" $TEMP_DIR/scpx.txt && echo "    OK: No debug header dump" || { echo "    FAIL: Debug header dump printed"; exit 1; }
+    $BIN --input $scp convert --format scp --output $TEMP_DIR/scpx.scp
+    $BIN --input $TEMP_DIR/scpx.scp display > $TEMP_DIR/scpx2.txt
+    grep "Comments: Extended mode with footer" $TEMP_DIR/scpx2.txt > /dev/null && grep "Created: 2023-11-14 22:13:20 UTC" $TEMP_DIR/scpx2.txt > /dev/null && echo "    OK: Footer kept when writing" || { echo "    FAIL: SCP footer not written"; exit 1; }
+}
+
+test_scp_48tpi() {
+    local scp=$TEST_DIR/scp/48tpi.scp
+    echo "Testing SCP single-sided 48 TPI capture..."
+    # Head 0 of 360k cylinders 0 and 1 on drive cylinders 0 and 2, tracks numbered consecutively
+    $BIN --input $scp display > $TEMP_DIR/scp48.txt
+    grep "Track 2 (Cyl 2, Head 0)" $TEMP_DIR/scp48.txt > /dev/null && grep "odd drive cylinders skipped" $TEMP_DIR/scp48.txt > /dev/null && echo "    OK: Tracks mapped to cylinders" || { echo "    FAIL: SCP track numbering"; exit 1; }
+    $BIN --input $scp flux-stats --csv $TEMP_DIR/scp48.csv > $TEMP_DIR/scp48-fs.txt
+    grep "Track 4 (Cyl 1, Head 0)" $TEMP_DIR/scp48-fs.txt > /dev/null && grep "Track 2 (unused by the disk)" $TEMP_DIR/scp48-fs.txt > /dev/null && grep "^4,1,0," $TEMP_DIR/scp48.csv > /dev/null && echo "    OK: flux-stats shows disk cylinders" || { echo "    FAIL: flux-stats cylinders"; exit 1; }
+    $BIN --input $scp convert --format imd --output $TEMP_DIR/scp48.imd
+    $BIN --input $TEMP_DIR/scp48.imd display | grep "Detected Geometry: 2 cylinders, 1 heads" > /dev/null && echo "    OK: 2 cylinders, 1 head" || { echo "    FAIL: SCP 48 TPI geometry"; exit 1; }
+    $BIN --input $scp convert --format img --output $TEMP_DIR/scp48.img
+    { head -c 4608 $TEST_DIR/360k/360k.img; tail -c +9217 $TEST_DIR/360k/360k.img | head -c 4608; } > $TEMP_DIR/scp48-ref.img
+    cmp $TEMP_DIR/scp48-ref.img $TEMP_DIR/scp48.img && echo "    OK: Sectors match 360k" || { echo "    FAIL: SCP 48 TPI sectors differ"; exit 1; }
+}
+
+test_protection() {
+    local dir=$TEST_DIR/protection
+    echo "Testing copy-protection analysis..."
+    # speedlock.imd: loader text, CRC error, repeated, extra and out-of-range IDs, a foreign ID field, deleted data and a 1024-byte sector
+    $BIN --input $dir/speedlock.imd analyze-protection > $TEMP_DIR/prot.txt
+    grep "Copy-Protection Markers: 7" $TEMP_DIR/prot.txt > /dev/null && grep "Cyl 1, Head 0: sector ID 4 appears 2 times" $TEMP_DIR/prot.txt > /dev/null && grep "sector IDs outside 1-9: 247" $TEMP_DIR/prot.txt > /dev/null && grep "oversized sector 9: 1024 bytes" $TEMP_DIR/prot.txt > /dev/null && echo "    OK: Sector markers found" || { echo "    FAIL: IMD protection markers"; exit 1; }
+    grep "Speedlock (likely)" $TEMP_DIR/prot.txt > /dev/null && echo "    OK: Speedlock identified" || { echo "    FAIL: Speedlock not identified"; exit 1; }
+    # weak.scp: one track written 4% fast, with a weak sector 3, two sector 5s, a wide gap and no flux for 200 us
+    $BIN --input $dir/weak.scp analyze-protection > $TEMP_DIR/prot-scp.txt
+    grep "long track: .* +3.9%" $TEMP_DIR/prot-scp.txt > /dev/null && grep "weak bits: sectors 3 read differently" $TEMP_DIR/prot-scp.txt > /dev/null && grep "sector ID 5 appears 2 times in one revolution" $TEMP_DIR/prot-scp.txt > /dev/null && echo "    OK: Long track, weak bits and repeated ID found" || { echo "    FAIL: SCP flux markers"; exit 1; }
+    grep "gap of 316 bytes after sector 7" $TEMP_DIR/prot-scp.txt > /dev/null && grep "1 area without flux" $TEMP_DIR/prot-scp.txt > /dev/null && echo "    OK: Wide gap and no-flux area found" || { echo "    FAIL: SCP gap markers"; exit 1; }
+    $BIN --input $TEST_DIR/kryoflux analyze-protection | grep "No copy-protection markers found." > /dev/null && echo "    OK: Clean capture has no markers" || { echo "    FAIL: KryoFlux false positive"; exit 1; }
+    $BIN --input $TEST_DIR/cpc/protected.dsk convert --format img --output $TEMP_DIR/protected.img | grep "Warning: 6 copy-protection markers" > /dev/null && echo "    OK: Conversion to IMG warns" || { echo "    FAIL: No conversion warning"; exit 1; }
+}
+
+test_sector_order() {
+    local imd=$TEST_DIR/ids/offset.imd
+    echo "Testing .imd to .img sector order..."
+    # offset.imd: two tracks with sector IDs 0x41-0x48 recorded 2:1 interleaved
+    $BIN --input $imd convert --format img --output $TEMP_DIR/offset.img --imdmeta $TEMP_DIR/offset.meta
+    $BIN --input $imd convert --format img --output $TEMP_DIR/offset-base.img --imdmeta $TEMP_DIR/offset.meta --sector-order base=0x41
+    cmp $TEMP_DIR/offset.img $TEMP_DIR/offset-base.img && head -c 16 $TEMP_DIR/offset.img | grep "CYL 0 SECTOR 41" > /dev/null && dd if=$TEMP_DIR/offset.img bs=512 skip=15 count=1 2>/dev/null | head -c 16 | grep "CYL 1 SECTOR 48" > /dev/null && echo "    OK: IDs 0x41-0x48 placed in ID order" || { echo "    FAIL: Offset sector IDs"; exit 1; }
+    $BIN --input $imd convert --format img --output $TEMP_DIR/offset-1.img --imdmeta $TEMP_DIR/offset.meta --sector-order base=1 | grep "sector IDs outside 1-8 placed after them: 65" > /dev/null && echo "    OK: Out-of-range IDs reported" || { echo "    FAIL: Out-of-range IDs not reported"; exit 1; }
+    # Slots 1-8 are zero-filled and sectors 0x41-0x48 follow them on each track
+    [ $(wc -c < $TEMP_DIR/offset-1.img) -eq $(( $(wc -c < $TEMP_DIR/offset.img) * 2 )) ] && echo "    OK: Out-of-range sectors kept" || { echo "    FAIL: Out-of-range sectors dropped"; exit 1; }
+    # missing.imd: track 0 lacks sector 5, track 1 has sectors 1-9
+    local missing=$TEST_DIR/ids/missing.imd
+    for order in id base=1 physical; do
+        $BIN --input $missing convert --format img --output $TEMP_DIR/missing.img --imdmeta $TEMP_DIR/missing.meta --sector-order $order > $TEMP_DIR/missing.txt
+        [ $(wc -c < $TEMP_DIR/missing.img) -eq 9216 ] && dd if=$TEMP_DIR/missing.img bs=512 skip=9 count=1 2>/dev/null | head -c 16 | grep "CYL 1 SECTOR 01" > /dev/null || { echo "    FAIL: Missing sector shifts later tracks ($order)"; exit 1; }
+    done
+    grep "Geometry for reverse conversion: 2,1,9,512,5" $TEMP_DIR/missing.txt > /dev/null && $BIN --input $missing convert --format img --output $TEMP_DIR/missing.img --imdmeta $TEMP_DIR/missing.meta | grep "Cyl 0, Head 0: ID 5 missing, zero-filled" > /dev/null && echo "    OK: Missing sector zero-filled and reported" || { echo "    FAIL: Missing sector not reported"; exit 1; }
+    # speedlock.imd has a repeated ID 4 and an ID 247 on cylinder 1
+    local prot=$TEST_DIR/protection/speedlock.imd
+    $BIN --input $prot convert --format img --output $TEMP_DIR/prot.img --imdmeta $TEMP_DIR/prot.meta > $TEMP_DIR/prot.txt
+    grep "sector ID 4 appears 2 times; kept one copy" $TEMP_DIR/prot.txt > /dev/null && echo "    OK: Repeated ID reported" || { echo "    FAIL: Repeated ID not reported"; exit 1; }
+    ! grep "Geometry for reverse conversion" $TEMP_DIR/prot.txt > /dev/null && grep "prot.img is 14848 bytes, not the 13824 bytes of 3 cylinders x 1 heads x 9 sectors x 512 bytes" $TEMP_DIR/prot.txt > /dev/null && echo "    OK: No reverse geometry for an enlarged image" || { echo "    FAIL: Reverse geometry for an enlarged image"; exit 1; }
+    $BIN --input $prot convert --format img --output $TEMP_DIR/prot-phys.img --imdmeta $TEMP_DIR/prot.meta --sector-order physical | grep "sector ID 4 appears 2 times; all copies kept" > /dev/null && echo "    OK: Physical order keeps both copies" || { echo "    FAIL: Physical order"; exit 1; }
+}
+
+test_malformed() {
+    echo "Testing malformed input..."
+    # Truncated and corrupted images must fail with a located error, never a panic (exit code 101)
+    head -c 3000 $TEST_DIR/360k/360k.imd > $TEMP_DIR/short.imd
+    $BIN --input $TEMP_DIR/short.imd display > $TEMP_DIR/err.txt 2>&1; [ $? -eq 1 ] && grep "Invalid .imd file at offset 2626 (0xA42), Cyl 0, Head 0, Sector ID 8: sector data: needs 512 bytes but only 374 remain" $TEMP_DIR/err.txt > /dev/null && echo "    OK: Truncated .imd located" || { echo "    FAIL: Truncated .imd"; cat $TEMP_DIR/err.txt; exit 1; }
+    cp $TEST_DIR/360k/360k.imd $TEMP_DIR/size.imd
+    printf '\x09' | dd of=$TEMP_DIR/size.imd bs=1 seek=46 conv=notrunc 2> /dev/null
+    $BIN --input $TEMP_DIR/size.imd convert --format img --output $TEMP_DIR/size.img > $TEMP_DIR/err.txt 2>&1; [ $? -eq 1 ] && grep "offset 46 (0x2E), Cyl 0, Head 0: sector size code 9" $TEMP_DIR/err.txt > /dev/null && echo "    OK: Bad sector size code rejected" || { echo "    FAIL: Bad sector size code"; cat $TEMP_DIR/err.txt; exit 1; }
+    printf 'IMD\x1a\x00\x00\x09\x01' > $TEMP_DIR/short.meta
+    $BIN --input $TEST_DIR/360k/360k.img convert --format imd --output $TEMP_DIR/meta.imd --imdmeta $TEMP_DIR/short.meta > $TEMP_DIR/err.txt 2>&1; [ $? -eq 1 ] && grep "Invalid .imd.meta file at offset 7 (0x7), Cyl 0, Head 0: sector IDs: needs 9 bytes but only 1 remain" $TEMP_DIR/err.txt > /dev/null && echo "    OK: Truncated .imd.meta located" || { echo "    FAIL: Truncated .imd.meta"; cat $TEMP_DIR/err.txt; exit 1; }
+    printf 'no terminator' > $TEMP_DIR/bad.meta
+    $BIN --input $TEST_DIR/360k/360k.img convert --format imd --output $TEMP_DIR/meta.imd --imdmeta $TEMP_DIR/bad.meta > $TEMP_DIR/err.txt 2>&1; [ $? -eq 1 ] && grep "Invalid .imd.meta file at offset 0" $TEMP_DIR/err.txt > /dev/null && echo "    OK: .imd.meta without header rejected" || { echo "    FAIL: .imd.meta without header"; cat $TEMP_DIR/err.txt; exit 1; }
+    # One sector whose size table entry is 0, stored compressed
+    printf 'IMD 1.18: zero\r\n\x1a\x05\x00\x00\x01\xff\x01\x00\x00\x02\xe5' > $TEMP_DIR/zero.imd
+    $BIN --input $TEMP_DIR/zero.imd reinterleave --interleave 1 --output $TEMP_DIR/zero-out.imd > $TEMP_DIR/err.txt 2>&1; [ $? -eq 1 ] && grep "Invalid .imd file at offset 23 (0x17), Cyl 0, Head 0: sector ID 1 has size 0 in the size table" $TEMP_DIR/err.txt > /dev/null && echo "    OK: Zero-byte sector rejected" || { echo "    FAIL: Zero-byte sector"; cat $TEMP_DIR/err.txt; exit 1; }
+    head -c 30000 $TEST_DIR/cpc/cpc.dsk > $TEMP_DIR/short.dsk
+    $BIN --input $TEMP_DIR/short.dsk display > $TEMP_DIR/err.txt 2>&1; [ $? -eq 1 ] && grep "Invalid .dsk file at offset 29440 (0x7300), Cyl 6, Head 0" $TEMP_DIR/err.txt > /dev/null && echo "    OK: Truncated .dsk located" || { echo "    FAIL: Truncated .dsk"; cat $TEMP_DIR/err.txt; exit 1; }
+    head -c 5000 $TEST_DIR/170k/170k.scp > $TEMP_DIR/short.scp
+    $BIN --input $TEMP_DIR/short.scp display > $TEMP_DIR/err.txt 2>&1; [ $? -eq 1 ] && grep "Invalid .scp file at offset" $TEMP_DIR/err.txt > /dev/null && echo "    OK: Truncated .scp located" || { echo "    FAIL: Truncated .scp"; cat $TEMP_DIR/err.txt; exit 1; }
+}
+
+test_flux_stats() {
+    local kf=$TEST_DIR/kryoflux
+    echo "Testing flux statistics..."
+    $BIN --input $kf flux-stats > $TEMP_DIR/fs.txt
+    grep "All Tracks: " $TEMP_DIR/fs.txt > /dev/null && grep "MFM at 250 kbps" $TEMP_DIR/fs.txt > /dev/null && echo "    OK: MFM cell detected" || { echo "    FAIL: flux-stats cell"; exit 1; }
+    $BIN --input $kf flux-stats --track 1 --csv $TEMP_DIR/fs.csv > $TEMP_DIR/fs1.txt
+    grep "Outliers: 0.00%" $TEMP_DIR/fs1.txt > /dev/null && grep "Histogram (100 ns bins)" $TEMP_DIR/fs1.txt > /dev/null && echo "    OK: Clean track has no outliers" || { echo "    FAIL: flux-stats track"; exit 1; }
+    head -1 $TEMP_DIR/fs.csv | grep "^track,cylinder,head,bin_start_ns,bin_end_ns,count$" > /dev/null && [ $(wc -l < $TEMP_DIR/fs.csv) -gt 3 ] && echo "    OK: Histogram CSV written" || { echo "    FAIL: flux-stats CSV"; exit 1; }
+}
+
 test_conversion 360k 40,2,9,512,4   # 5.25-inch DD, 250 kbps
 test_conversion 720k 80,2,9,512,5   # 3.5-inch DD, 500 kbps (should be 250 kbps)
 test_conversion 1.2M 80,2,15,512,3  # 5.25-inch HD, 500 kbps
 test_conversion 1.44M 80,2,18,512,3 # 3.5-inch HD, 500 kbps (should be mode 5)
 test_scp
+test_reinterleave
+test_td0
+test_browse
+test_read
+test_write
+test_search
+test_boot
+test_geometry
+test_adf
+test_amiga_scp
+test_cpm
+test_cbm
+test_apple
+test_apple_fs
+test_st
+test_dsk
+test_hfe
+test_kryoflux
+test_flux_stats
+test_scp_footer
+test_scp_48tpi
+test_protection
+test_sector_order
+test_malformed
 
 echo "Cleaning up..."
 rm -rf $TEMP_DIR